@@ -20,6 +20,7 @@
 #define ARCHIVE_OFFSET 0xffffffff82800000
 
 archive_header_t *archive_system;
+uint64_t archive_system_length;
 
 bool archive_initialize(uint64_t modules_count, multiboot_module_t *modules)
 {
@@ -50,6 +51,7 @@ bool archive_initialize(uint64_t modules_count, multiboot_module_t *modules)
         }
 
         archive_system = (archive_header_t *) ARCHIVE_OFFSET;
+        archive_system_length = bytes;
 
         return true;
       }
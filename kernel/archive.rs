@@ -17,6 +17,9 @@ use core::ptr;
 
 use crate::multiboot;
 use crate::c_ffi::CStr;
+use crate::memory::{VirtualAddress, PhysicalAddress};
+#[cfg_attr(test, allow(unused_imports))]
+use crate::paging::{self, PagesetExt, PAGE_SIZE};
 
 pub unsafe fn initialize(modules: *const multiboot::Module,
                          modules_count: u32) -> bool {
@@ -29,6 +32,7 @@ pub unsafe fn initialize(modules: *const multiboot::Module,
 
 pub struct Archive {
     header: *const ffi::ArchiveHeader,
+    length: usize,
 }
 
 impl Archive {
@@ -45,18 +49,92 @@ impl Archive {
             }
         }
     }
+
+    /// If `data` is part of the archive, the same slice with a `'static`
+    /// lifetime: the archive is never unmapped. This lets the contents of
+    /// files be used after the process that loaded them is gone.
+    pub fn as_static(&self, data: &[u8]) -> Option<&'static [u8]> {
+        let start = self.header as usize;
+        let data_start = data.as_ptr() as usize;
+
+        let offset = data_start.checked_sub(start)?;
+
+        if offset.checked_add(data.len())? > self.length {
+            return None;
+        }
+
+        unsafe { Some(slice::from_raw_parts(data.as_ptr(), data.len())) }
+    }
+
+    /// The physical address of the archive page at `vaddr`, if the whole page
+    /// is part of the archive. The archive builder page-aligns every file, so
+    /// that pages of a file can be mapped directly into processes.
+    pub fn frame(&self, vaddr: VirtualAddress) -> Option<PhysicalAddress> {
+        let offset = vaddr.checked_sub(self.header as usize)?;
+
+        if vaddr % PAGE_SIZE != 0 ||
+            offset.checked_add(PAGE_SIZE)? > self.length {
+            return None;
+        }
+
+        self.lookup(vaddr)
+    }
+
+    /// The size of the archive in bytes.
+    pub fn len(&self) -> usize {
+        self.length
+    }
+
+    /// True if the physical page at `paddr` holds part of the archive. The
+    /// bootloader loads it into physically contiguous memory.
+    pub fn contains_frame(&self, paddr: PhysicalAddress) -> bool {
+        let start = self.lookup(self.header as usize);
+
+        start.map_or(false, |start|
+            paddr >= start && paddr - start < self.length)
+    }
+
+    /// The physical address of `vaddr` in the kernel pageset.
+    #[cfg(not(test))]
+    fn lookup(&self, vaddr: VirtualAddress) -> Option<PhysicalAddress> {
+        unsafe { paging::kernel_pageset().lookup(vaddr) }
+    }
+
+    /// Tests have no kernel pageset, so the archive is identity mapped.
+    #[cfg(test)]
+    fn lookup(&self, vaddr: VirtualAddress) -> Option<PhysicalAddress> {
+        Some(vaddr)
+    }
+}
+
+#[cfg(not(test))]
+pub fn system() -> Archive {
+    unsafe {
+        Archive {
+            header: ffi::archive_system,
+            length: ffi::archive_system_length as usize,
+        }
+    }
 }
 
+/// Tests aren't linked with a system archive. This one is never read, only
+/// compared against.
+#[cfg(test)]
 pub fn system() -> Archive {
     Archive {
-        header: unsafe { ffi::archive_system }
+        header: TEST_SYSTEM_ADDR as *const ffi::ArchiveHeader,
+        length: 16 * PAGE_SIZE,
     }
 }
 
+#[cfg(test)]
+pub const TEST_SYSTEM_ADDR: VirtualAddress = 0x7_0000_0000;
+
 /// C interface. See `kit/kernel/include/archive.h`.
 pub mod ffi {
     use crate::multiboot;
     use crate::archive::utils;
+    use crate::process;
 
     use crate::ptr::UserPtr;
 
@@ -70,6 +148,8 @@ pub mod ffi {
     extern {
         pub static archive_system: *const ArchiveHeader;
 
+        pub static archive_system_length: u64;
+
         pub fn archive_initialize(modules_count: u64,
                                   modules: *const multiboot::Module) -> i8;
 
@@ -79,11 +159,15 @@ pub mod ffi {
                            length: *mut u64) -> i8;
     }
 
+    /// Most environment entries a spawned program can be given.
+    const ENVP_MAX: usize = 1024;
+
     #[no_mangle]
     pub extern fn archive_utils_spawn(
         filename: UserPtr<u8>,
         argc: c_int,
-        argv: UserPtr<UserPtr<u8>>
+        argv: UserPtr<UserPtr<u8>>,
+        envp: UserPtr<UserPtr<u8>>
     ) -> int64_t {
 
         if argc < 0 {
@@ -121,19 +205,55 @@ pub mod ffi {
             Ok(arg_buffer)
         }).collect::<Result<Vec<Vec<u8>>, crate::ptr::Error>>());
 
-        utils::spawn(filename, &argv)
+        // Children inherit the environment unless they're given one.
+        let env = if envp.is_null() {
+            process::current().lock().mem()
+                .map(|mem| mem.lock().environment().to_vec())
+                .unwrap_or_default()
+        } else {
+            try_i!(read_envp(envp))
+        };
+
+        utils::spawn(filename, &argv, &env)
             .map(|pid| pid as i64)
             .unwrap_or_else(|e| -((e as u32) as i64))
     }
+
+    /// Read a NULL-terminated table of `NAME=value` strings.
+    fn read_envp(envp: UserPtr<UserPtr<u8>>)
+                 -> Result<Vec<Vec<u8>>, crate::ptr::Error> {
+        let mut env = vec![];
+
+        for index in 0..ENVP_MAX {
+            let ptr = envp.add(index).read()?;
+
+            if ptr.is_null() {
+                return Ok(env);
+            }
+
+            let mut entry_buffer: Vec<u8> = vec![0; 256];
+
+            let len = ptr.read_c_string(&mut entry_buffer)?.len();
+
+            entry_buffer.truncate(len);
+
+            env.push(entry_buffer);
+        }
+
+        Err(crate::ptr::Error::BufferTooSmall)
+    }
 }
 
 /// Archive utilities.
 pub mod utils {
+    use alloc::vec::Vec;
+
     use crate::archive;
     use crate::process::{self, Process};
     use crate::elf::Elf;
     use crate::scheduler;
-    use crate::c_ffi::CStr;
+    use crate::sync::Spinlock;
+    use crate::c_ffi::{CStr, cstring_from_str};
 
     #[derive(Debug)]
     pub enum SpawnError {
@@ -142,15 +262,152 @@ pub mod utils {
         ElfVerifyError,
         ElfNotExecutable,
         ExecLoadError,
-        SetArgsError
+        SetArgsError,
+        BadInterpreterLine,
+        InterpreterNotFound,
+        TooManyInterpreters,
     }
 
     use self::SpawnError::*;
 
-    pub fn spawn<'a, A>(filename: CStr<'a>, argv: &[A])
+    /// How many interpreters can be scripts themselves before giving up.
+    const MAX_INTERPRETER_DEPTH: usize = 4;
+
+    /// The longest `#!` line allowed, not counting the `#!`.
+    const MAX_INTERPRETER_LINE: usize = 128;
+
+    /// Interpreter paths by file extension.
+    type InterpreterTable = Vec<(Vec<u8>, Vec<u8>)>;
+
+    /// Interpreters for files that are neither ELF nor start with `#!`, by
+    /// file extension. Checked before [DEFAULT_INTERPRETERS].
+    static INTERPRETERS: Spinlock<InterpreterTable> =
+        Spinlock::new(Vec::new());
+
+    const DEFAULT_INTERPRETERS: &[(&str, &str)] = &[
+        ("fs", "bin/kitforth"),
+    ];
+
+    /// Run files ending in `.extension` with `interpreter`, a path in the
+    /// archive, replacing whatever was registered for it before.
+    pub fn register_interpreter(extension: &str, interpreter: &str) {
+        register_in(&mut INTERPRETERS.lock(), extension, interpreter);
+    }
+
+    fn register_in(interpreters: &mut InterpreterTable,
+                   extension: &str,
+                   interpreter: &str) {
+
+        interpreters.retain(|(ext, _)| ext != extension.as_bytes());
+
+        interpreters.push((extension.into(), interpreter.into()));
+    }
+
+    /// A program that runs a file, and the extra argument to give it, if any.
+    #[derive(Debug, PartialEq, Eq)]
+    struct Interpreter {
+        path: Vec<u8>,
+        arg:  Option<Vec<u8>>,
+    }
+
+    /// The interpreter to run `data` (the contents of `filename`) with, or
+    /// `None` if it should be loaded as an ELF executable.
+    fn interpreter_of(filename: &[u8], data: &[u8])
+        -> Result<Option<Interpreter>, SpawnError> {
+
+        interpreter_in(&INTERPRETERS.lock(), filename, data)
+    }
+
+    /// [interpreter_of], with `interpreters` in place of the ones registered.
+    fn interpreter_in(interpreters: &InterpreterTable,
+                      filename: &[u8],
+                      data: &[u8])
+        -> Result<Option<Interpreter>, SpawnError> {
+
+        if let Some(line) = data.strip_prefix(b"#!") {
+            return parse_interpreter_line(line).map(Some);
+        }
+
+        if Elf::new(data).is_some() {
+            return Ok(None);
+        }
+
+        let name = filename.rsplit(|&c| c == b'/').next().unwrap_or(filename);
+
+        let extension = match name.iter().rposition(|&c| c == b'.') {
+            Some(dot) if dot > 0 => &name[(dot + 1)..],
+            _ => return Ok(None),
+        };
+
+        let registered = interpreters.iter()
+            .find(|(ext, _)| ext == extension)
+            .map(|(_, path)| path.clone());
+
+        Ok(registered
+            .or_else(|| DEFAULT_INTERPRETERS.iter()
+                .find(|(ext, _)| ext.as_bytes() == extension)
+                .map(|(_, path)| path.as_bytes().to_vec()))
+            .map(|path| Interpreter { path, arg: None }))
+    }
+
+    /// Parse what follows `#!`: a path, and optionally one argument which is
+    /// the rest of the line. Archive paths are relative, so leading slashes
+    /// are dropped.
+    fn parse_interpreter_line(data: &[u8])
+        -> Result<Interpreter, SpawnError> {
+
+        let limit = data.len().min(MAX_INTERPRETER_LINE + 1);
+
+        let line = match data[..limit].iter().position(|&c| c == b'\n') {
+            Some(end) => &data[..end],
+            None if data.len() <= MAX_INTERPRETER_LINE => data,
+            None => return Err(BadInterpreterLine),
+        };
+
+        if line.contains(&0) {
+            return Err(BadInterpreterLine);
+        }
+
+        fn is_space(c: &u8) -> bool {
+            *c == b' ' || *c == b'\t' || *c == b'\r'
+        }
+
+        fn trim(mut s: &[u8]) -> &[u8] {
+            while s.first().map_or(false, is_space) { s = &s[1..]; }
+            while s.last().map_or(false, is_space) { s = &s[..(s.len() - 1)]; }
+            s
+        }
+
+        let line = trim(line);
+
+        let (path, arg) = match line.iter().position(is_space) {
+            Some(space) => (&line[..space], trim(&line[space..])),
+            None => (line, &b""[..]),
+        };
+
+        let path = &path[path.iter().take_while(|&&c| c == b'/').count()..];
+
+        if path.is_empty() {
+            return Err(BadInterpreterLine);
+        }
+
+        Ok(Interpreter {
+            path: path.to_vec(),
+            arg:  Some(arg.to_vec()).filter(|arg| !arg.is_empty()),
+        })
+    }
+
+    /// Start a program from the system archive with the given arguments and
+    /// environment (`NAME=value` strings).
+    ///
+    /// Scripts are run by the interpreter named on their `#!` line, or the one
+    /// registered for their extension, as `interpreter [arg] filename
+    /// argv[1..]`.
+    pub fn spawn<'a, A, E>(filename: CStr<'a>, argv: &[A], env: &[E])
         -> Result<process::Id, SpawnError>
     where
         A: AsRef<[u8]>,
+        E: AsRef<[u8]>,
     {
 
         if filename.is_empty() {
@@ -159,7 +416,37 @@ pub mod utils {
 
         let system = archive::system();
 
-        let data = system.get(filename).ok_or(FileNotFound)?;
+        let mut data = system.get(filename).ok_or(FileNotFound)?;
+
+        let mut program = filename.as_bytes().to_vec();
+
+        let mut args: Vec<Vec<u8>> =
+            argv.iter().map(|arg| arg.as_ref().to_vec()).collect();
+
+        let mut depth = 0;
+
+        while let Some(interpreter) = interpreter_of(&program, data)? {
+            depth += 1;
+
+            if depth > MAX_INTERPRETER_DEPTH {
+                return Err(TooManyInterpreters);
+            }
+
+            let mut interpreter_args = vec![interpreter.path.clone()];
+
+            interpreter_args.extend(interpreter.arg);
+            interpreter_args.push(program);
+            interpreter_args.extend(args.drain(..).skip(1));
+
+            let path = cstring_from_str(
+                core::str::from_utf8(&interpreter.path)
+                    .map_err(|_| BadInterpreterLine)?);
+
+            data = system.get(CStr::new(&path)).ok_or(InterpreterNotFound)?;
+
+            program = interpreter.path;
+            args = interpreter_args;
+        }
 
         let elf = Elf::new(data).ok_or(ElfVerifyError)?;
 
@@ -174,7 +461,7 @@ pub mod utils {
 
             process.load(&exec).map_err(|_| ExecLoadError)?;
 
-            process.set_args(argv).map_err(|_| SetArgsError)?;
+            process.set_args(&args, env).map_err(|_| SetArgsError)?;
 
             process.run();
         }
@@ -183,4 +470,52 @@ pub mod utils {
 
         Ok(process_id)
     }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        fn interpreter(path: &str, arg: Option<&str>) -> Interpreter {
+            Interpreter {
+                path: path.into(),
+                arg:  arg.map(|arg| arg.into()),
+            }
+        }
+
+        #[test]
+        fn interpreter_lines_are_parsed() {
+            assert_eq!(parse_interpreter_line(b"/bin/kitforth\n\\ code").ok(),
+                Some(interpreter("bin/kitforth", None)));
+
+            assert_eq!(parse_interpreter_line(b" bin/sh  -e -x \r\n").ok(),
+                Some(interpreter("bin/sh", Some("-e -x"))));
+
+            assert_eq!(parse_interpreter_line(b"bin/sh").ok(),
+                Some(interpreter("bin/sh", None)));
+
+            assert!(parse_interpreter_line(b"  \nbin/sh").is_err());
+            assert!(parse_interpreter_line(b"bin/\0sh\n").is_err());
+            assert!(parse_interpreter_line(&[b'a'; 200]).is_err());
+        }
+
+        #[test]
+        fn interpreters_are_found_by_extension() {
+            assert_eq!(interpreter_of(b"kitforth/box.fs", b": x ;").ok(),
+                Some(Some(interpreter("bin/kitforth", None))));
+
+            assert_eq!(interpreter_of(b"box.fs", b"#!bin/other\n").ok(),
+                Some(Some(interpreter("bin/other", None))));
+
+            // Not the global table, so other tests don't see it.
+            let mut interpreters = vec![];
+
+            register_in(&mut interpreters, "lua", "bin/lua");
+
+            assert_eq!(interpreter_in(&interpreters, b"x.lua", b"print()").ok(),
+                Some(Some(interpreter("bin/lua", None))));
+
+            assert_eq!(interpreter_of(b"bin.d/.profile", b"").ok(), Some(None));
+            assert_eq!(interpreter_of(b"hello.txt", b"hello").ok(), Some(None));
+        }
+    }
 }
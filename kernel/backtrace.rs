@@ -0,0 +1,306 @@
+/*******************************************************************************
+ *
+ * kit/kernel/backtrace.rs
+ *
+ * vim:ft=rust:ts=4:sw=4:et:tw=80
+ *
+ * Copyright (C) 2015-2021, Devyn Cairns
+ * Redistribution of this file is permitted under the terms of the simplified
+ * BSD license. See LICENSE for more information.
+ *
+ ******************************************************************************/
+
+//! Kernel stack backtraces.
+//!
+//! The kernel is built with frame pointers, so the stack can be unwound by
+//! following the saved `rbp` chain. Addresses are resolved against the kernel's
+//! own `.symtab`, which the bootloader loads for us and describes in the
+//! multiboot ELF section header table.
+
+use core::fmt::{self, Write};
+use core::slice;
+
+use crate::constants::KERNEL_OFFSET;
+use crate::elf::{Symbol, SymbolTable};
+use crate::interrupt::InterruptStack;
+use crate::multiboot;
+use crate::paging;
+use crate::terminal::console;
+
+/// Maximum number of stack frames to show.
+const MAX_FRAMES: usize = 64;
+
+static mut KERNEL_SYMBOLS: Option<SymbolTable<'static>> = None;
+
+/// Find the kernel's symbol table.
+///
+/// # Safety
+///
+/// Must only be called once, during boot, while the low region is still
+/// identity mapped.
+pub unsafe fn initialize(mb_info: &multiboot::Info) {
+    if let Some([(symtab, symtab_len), (strtab, strtab_len)]) =
+            mb_info.elf_symbol_table() {
+
+        KERNEL_SYMBOLS = Some(SymbolTable::new(
+            slice::from_raw_parts(
+                (KERNEL_OFFSET + symtab) as *const u8, symtab_len),
+            slice::from_raw_parts(
+                (KERNEL_OFFSET + strtab) as *const u8, strtab_len)));
+    } else {
+        warn!("Bootloader did not provide kernel symbols");
+    }
+}
+
+/// Find the kernel symbol containing `addr`.
+pub fn symbolize(addr: usize) -> Option<Symbol<'static>> {
+    unsafe { KERNEL_SYMBOLS.as_ref()?.symbol_containing(addr) }
+}
+
+/// Get the frame pointer of the caller.
+#[inline(always)]
+pub fn frame_pointer() -> usize {
+    let rbp: usize;
+    unsafe {
+        asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack));
+    }
+    rbp
+}
+
+/// Print a backtrace starting from the frame at `rbp` to the console and the
+/// log. If `rip` is given, it's shown as the first frame.
+pub fn print(rip: Option<usize>, rbp: usize) {
+    let _ = writeln!(console(), "\nBacktrace:");
+    error!("Backtrace:");
+
+    let frames = rip.into_iter().chain(Frames { rbp, count: 0 });
+
+    for (index, addr) in frames.enumerate() {
+        // Return addresses point after the call, so look up the call instead.
+        // The interrupted instruction pointer is exact.
+        let is_return = index > 0 || rip.is_none();
+
+        let frame = Frame { index, addr, is_return };
+
+        let _ = writeln!(console(), "  {}", frame);
+        error!("  {}", frame);
+    }
+}
+
+/// Print a backtrace of kernel code that was interrupted by an exception.
+pub fn print_interrupted(stack: &InterruptStack) {
+    print(Some(stack.rip as usize), stack.rbp as usize);
+}
+
+/// Return addresses found by following the frame pointer chain.
+struct Frames {
+    rbp: usize,
+    count: usize,
+}
+
+impl Iterator for Frames {
+    type Item = usize;
+
+    fn next(&mut self) -> Option<usize> {
+        if self.count >= MAX_FRAMES || self.rbp % 8 != 0 ||
+            !paging::is_kernel_mapped(self.rbp, 16) {
+            return None;
+        }
+
+        // [rbp] = caller's rbp, [rbp + 8] = return address
+        let (next_rbp, return_addr) = unsafe {
+            let frame = self.rbp as *const usize;
+            (*frame, *frame.add(1))
+        };
+
+        if return_addr == 0 {
+            return None;
+        }
+
+        self.count += 1;
+
+        // Callers' frames must be higher up the stack. Stop after this one if
+        // not.
+        self.rbp = if next_rbp > self.rbp { next_rbp } else { 0 };
+
+        Some(return_addr)
+    }
+}
+
+/// A line of a backtrace.
+struct Frame {
+    index: usize,
+    addr: usize,
+    is_return: bool,
+}
+
+impl fmt::Display for Frame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "#{:<2} {:#018x}", self.index, self.addr)?;
+
+        let lookup_addr =
+            if self.is_return { self.addr.wrapping_sub(1) } else { self.addr };
+
+        if let Some(symbol) = symbolize(lookup_addr) {
+            write!(f, " {}+{:#x}", Demangle(symbol.name),
+                self.addr - symbol.value)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Displays a Rust (legacy mangling scheme) symbol name in its readable form,
+/// without the hash. Other names are displayed as-is.
+pub struct Demangle<'a>(pub &'a str);
+
+impl fmt::Display for Demangle<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let path = match legacy_path(self.0) {
+            Some(path) => path,
+            None => return f.write_str(self.0),
+        };
+
+        for (index, component) in Components(path).enumerate() {
+            if index > 0 {
+                f.write_str("::")?;
+            }
+            write_component(f, component)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// The length-prefixed components of a legacy mangled name, without the
+/// `_ZN`, the `E`, any suffix after it, or the hash, after checking that they
+/// can all be parsed.
+fn legacy_path(name: &str) -> Option<&str> {
+    let rest = name.strip_prefix("_ZN")
+        .or_else(|| name.strip_prefix("__ZN"))?;
+
+    let mut components = Components(rest);
+    let mut last = None;
+    let mut len = 0;
+
+    while let Some(component) = components.next() {
+        last = Some((len, component));
+        len = rest.len() - components.0.len();
+    }
+
+    if !components.0.starts_with('E') || last.is_none() {
+        return None;
+    }
+
+    let (before_last, last) = last.unwrap();
+
+    let is_hash = last.len() == 17 && last.starts_with('h') &&
+        last[1..].bytes().all(|b| b.is_ascii_hexdigit());
+
+    if is_hash && before_last > 0 {
+        Some(&rest[..before_last])
+    } else {
+        Some(&rest[..len])
+    }
+}
+
+/// Iterates over length-prefixed components, e.g. `4core3fmt`.
+struct Components<'a>(&'a str);
+
+impl<'a> Iterator for Components<'a> {
+    type Item = &'a str;
+
+    fn next(&mut self) -> Option<&'a str> {
+        let digits = self.0.bytes().take_while(|b| b.is_ascii_digit()).count();
+
+        let len: usize = self.0[..digits].parse().ok()?;
+        let end = digits.checked_add(len)?;
+
+        let component = self.0.get(digits..end)?;
+        self.0 = &self.0[end..];
+
+        Some(component)
+    }
+}
+
+fn write_component(f: &mut fmt::Formatter, component: &str) -> fmt::Result {
+    let mut rest = component;
+
+    // A leading underscore is added to components starting with an escape.
+    if rest.starts_with("_$") {
+        rest = &rest[1..];
+    }
+
+    while !rest.is_empty() {
+        if rest.starts_with('$') {
+            if let Some(end) = rest[1..].find('$') {
+                let code = &rest[1..(end + 1)];
+
+                let ch = match code {
+                    "SP" => Some('@'),
+                    "BP" => Some('*'),
+                    "RF" => Some('&'),
+                    "LT" => Some('<'),
+                    "GT" => Some('>'),
+                    "LP" => Some('('),
+                    "RP" => Some(')'),
+                    "C"  => Some(','),
+                    _ if code.starts_with('u') => {
+                        u32::from_str_radix(&code[1..], 16).ok()
+                            .and_then(core::char::from_u32)
+                    },
+                    _ => None,
+                };
+
+                if let Some(ch) = ch {
+                    f.write_char(ch)?;
+                    rest = &rest[(end + 2)..];
+                    continue;
+                }
+            }
+        } else if rest.starts_with("..") {
+            f.write_str("::")?;
+            rest = &rest[2..];
+            continue;
+        }
+
+        let ch = rest.chars().next().unwrap();
+        f.write_char(ch)?;
+        rest = &rest[ch.len_utf8()..];
+    }
+
+    Ok(())
+}
+
+/// C interface. See `kit/kernel/include/backtrace.h`.
+pub mod ffi {
+    use super::*;
+
+    /// Print a backtrace of kernel code interrupted by an exception.
+    #[no_mangle]
+    pub unsafe extern fn backtrace_print_interrupted(
+        stack: *const InterruptStack) {
+        print_interrupted(&*stack);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn demangle_legacy() {
+        let demangle = |name| format!("{}", Demangle(name));
+
+        assert_eq!(demangle("_ZN4core9panicking5panic17h0123456789abcdefE"),
+            "core::panicking::panic");
+        assert_eq!(demangle("_ZN48_$LT$kernel..Foo$u20$as$u20$core..fmt..\
+            Debug$GT$3fmt17h0123456789abcdefE"),
+            "<kernel::Foo as core::fmt::Debug>::fmt");
+        assert_eq!(demangle("_ZN6kernel11kernel_main17hfedcba9876543210E.llvm.1"),
+            "kernel::kernel_main");
+        assert_eq!(demangle("_ZN6kernel4mainE"), "kernel::main");
+        assert_eq!(demangle("kernel_main"), "kernel_main");
+        assert_eq!(demangle("_ZN6kernel4ma"), "_ZN6kernel4ma");
+    }
+}
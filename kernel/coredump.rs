@@ -0,0 +1,526 @@
+/*******************************************************************************
+ *
+ * kit/kernel/coredump.rs
+ *
+ * vim:ft=rust:ts=4:sw=4:et:tw=80
+ *
+ * Copyright (C) 2015-2021, Devyn Cairns
+ * Redistribution of this file is permitted under the terms of the simplified
+ * BSD license. See LICENSE for more information.
+ *
+ ******************************************************************************/
+
+//! ELF core dumps of crashed user processes.
+//!
+//! The core file has a `PT_NOTE` segment with `NT_PRSTATUS` (registers) and
+//! `NT_PRPSINFO` (process name) notes in the Linux x86_64 layout, followed by
+//! a `PT_LOAD` segment for each mapped region of the process, so it can be
+//! loaded into gdb along with the program's ELF file.
+//!
+//! Command line options:
+//!
+//! * `coredump=com1`: Write core dumps to serial port 1, hex encoded between
+//!   `-----BEGIN KIT CORE` and `-----END KIT CORE-----` lines. To extract:
+//!   `sed -n '/BEGIN KIT CORE/,/END KIT CORE/{//!p}' serial.log | xxd -r -p`
+//! * `coredump=buffer`: Keep the most recent core dump in a kernel buffer. See
+//!   [last] and [write_last_to_serial].
+//!
+//! Core dumps are not produced otherwise.
+
+use core::sync::atomic::{AtomicU8, Ordering};
+use core::fmt::Write;
+
+use alloc::sync::Arc;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use displaydoc::Display;
+
+use crate::cmdline::Cmdline;
+use crate::elf::{ElfType, Machine, RegionType};
+use crate::paging::{PageType, PAGE_SIZE};
+use crate::process::{self, Id, RcProcess};
+use crate::process::target::GeneralRegisters;
+use crate::serial::{self, SerialPort};
+use crate::sync::Spinlock;
+use crate::util::align_up;
+
+/// Where core dumps go.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Destination {
+    None = 0,
+    Com1 = 1,
+    Buffer = 2,
+}
+
+impl Destination {
+    fn from_u8(value: u8) -> Destination {
+        match value {
+            1 => Destination::Com1,
+            2 => Destination::Buffer,
+            _ => Destination::None,
+        }
+    }
+}
+
+static DESTINATION: AtomicU8 = AtomicU8::new(Destination::None as u8);
+
+static LAST_CORE: Spinlock<Option<Arc<Vec<u8>>>> = Spinlock::new(None);
+
+#[derive(Debug, Display)]
+pub enum Error {
+    /// Process {0} has no memory to dump
+    NoMemory(Id),
+    /// Serial port error: {0}
+    SerialError(serial::Error),
+}
+
+impl crate::error::Error for Error { }
+
+impl From<serial::Error> for Error {
+    fn from(error: serial::Error) -> Error {
+        Error::SerialError(error)
+    }
+}
+
+/// Configure core dumps from the command line.
+pub fn initialize(cmdline: &Cmdline) {
+    for (key, value) in cmdline.iter() {
+        if key == "coredump" {
+            set_destination(match value {
+                "com1" => Destination::Com1,
+                "buffer" => Destination::Buffer,
+                _ => Destination::None,
+            });
+        }
+    }
+}
+
+pub fn destination() -> Destination {
+    Destination::from_u8(DESTINATION.load(Ordering::Relaxed))
+}
+
+pub fn set_destination(destination: Destination) {
+    DESTINATION.store(destination as u8, Ordering::Relaxed);
+}
+
+/// The most recent core dump, if the destination is [Destination::Buffer].
+pub fn last() -> Option<Arc<Vec<u8>>> {
+    LAST_CORE.lock().clone()
+}
+
+/// Write the most recent buffered core dump to serial port 1, in the same
+/// format as `coredump=com1`. Returns false if there isn't one.
+pub fn write_last_to_serial() -> Result<bool, Error> {
+    if let Some(core) = last() {
+        let mut sink = HexSink::begin(serial::com1(), "buffered")?;
+        sink.write(&core)?;
+        sink.end()?;
+        Ok(true)
+    } else {
+        Ok(false)
+    }
+}
+
+/// Dump the memory and registers of a process that is being killed with
+/// `signal` to the configured destination.
+///
+/// The process must not be running, so that its registers are saved.
+pub fn dump(process: &RcProcess, signal: i32) -> Result<(), Error> {
+    let destination = destination();
+
+    if destination == Destination::None {
+        return Ok(());
+    }
+
+    let (info, mem) = {
+        let process = process.lock();
+
+        let regs = unsafe { process.hw_state().user().registers().general() };
+
+        let info = CoreInfo {
+            pid: process.id(),
+            ppid: process.parent(),
+            pgid: process.pgid(),
+            name: process.name(),
+            signal: linux_signal(signal),
+            regs,
+        };
+
+        (info, process.mem().ok_or(Error::NoMemory(process.id()))?)
+    };
+
+    let mut mem = mem.lock();
+
+    let segments: Vec<Segment> = mem.mapped_regions().into_iter()
+        .map(|(vaddr, size, page_type)| Segment { vaddr, size, page_type })
+        .collect();
+
+    let read = |vaddr: usize, page: &mut [u8]| {
+        if mem.copy_out(vaddr, page).is_err() {
+            page.iter_mut().for_each(|byte| *byte = 0);
+        }
+    };
+
+    info!("Writing core dump of process {} ({}) to {:?}",
+        info.pid, info.name, destination);
+
+    match destination {
+        Destination::Com1 => {
+            let label = format!("pid={} name={}", info.pid, info.name);
+            let mut sink = HexSink::begin(serial::com1(), &label)?;
+
+            write_core(&mut sink, &info, &segments, read)?;
+
+            sink.end()?;
+        },
+        Destination::Buffer => {
+            let mut core = vec![];
+
+            write_core(&mut core, &info, &segments, read)?;
+
+            *LAST_CORE.lock() = Some(Arc::new(core));
+        },
+        Destination::None => (),
+    }
+
+    Ok(())
+}
+
+/// Closest Linux signal number to a Kit exit signal, for gdb.
+fn linux_signal(signal: i32) -> u32 {
+    match signal {
+        process::SIG_INTERRUPT      => 2,  // SIGINT
+        process::SIG_TRAP           => 5,  // SIGTRAP
+        process::SIG_BAD_MEM_ACCESS => 11, // SIGSEGV
+        process::SIG_STACK_OVERFLOW => 11, // SIGSEGV
+        _                           => 9,  // SIGKILL
+    }
+}
+
+/// Something the core file can be written to.
+trait Sink {
+    fn write(&mut self, bytes: &[u8]) -> Result<(), Error>;
+}
+
+impl Sink for Vec<u8> {
+    fn write(&mut self, bytes: &[u8]) -> Result<(), Error> {
+        self.extend_from_slice(bytes);
+        Ok(())
+    }
+}
+
+/// Writes hex encoded lines between marker lines to a serial port.
+struct HexSink {
+    port: SerialPort,
+    column: usize,
+}
+
+impl HexSink {
+    /// Bytes per line.
+    const LINE: usize = 32;
+
+    fn begin(mut port: SerialPort, label: &str) -> Result<HexSink, Error> {
+        let _ = write!(port, "\n-----BEGIN KIT CORE {}-----\n", label);
+
+        Ok(HexSink { port, column: 0 })
+    }
+
+    fn end(mut self) -> Result<(), Error> {
+        if self.column != 0 {
+            self.port.write_byte(b'\n')?;
+        }
+
+        let _ = write!(self.port, "-----END KIT CORE-----\n");
+        Ok(())
+    }
+}
+
+impl Sink for HexSink {
+    fn write(&mut self, bytes: &[u8]) -> Result<(), Error> {
+        const DIGITS: &[u8; 16] = b"0123456789abcdef";
+
+        for &byte in bytes {
+            self.port.write_byte(DIGITS[(byte >> 4) as usize])?;
+            self.port.write_byte(DIGITS[(byte & 0xf) as usize])?;
+
+            self.column += 1;
+
+            if self.column == HexSink::LINE {
+                self.port.write_byte(b'\n')?;
+                self.column = 0;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Process details that go in the notes.
+struct CoreInfo {
+    pid: Id,
+    ppid: Id,
+    pgid: Id,
+    name: Arc<String>,
+    signal: u32,
+    regs: GeneralRegisters,
+}
+
+/// A region of memory to dump as a `PT_LOAD` segment.
+struct Segment {
+    vaddr: usize,
+    size: usize,
+    page_type: PageType,
+}
+
+const EHDR_SIZE: usize = 64;
+const PHDR_SIZE: usize = 56;
+
+const NT_PRSTATUS: u32 = 1;
+const NT_PRPSINFO: u32 = 3;
+
+const PRSTATUS_SIZE: usize = 336;
+const PRPSINFO_SIZE: usize = 136;
+
+/// Selectors to report, matching the GDT.
+const USER_CODE_SEL: u64 = 0x2b;
+const USER_DATA_SEL: u64 = 0x23;
+
+/// Write a core file. `read` fills a page-sized buffer with the memory at an
+/// address within one of the `segments`.
+fn write_core<S, F>(sink: &mut S,
+                    info: &CoreInfo,
+                    segments: &[Segment],
+                    mut read: F)
+                    -> Result<(), Error>
+    where S: Sink, F: FnMut(usize, &mut [u8]) {
+
+    let notes = notes(info);
+
+    let phnum = 1 + segments.len();
+    let notes_offset = EHDR_SIZE + PHDR_SIZE * phnum;
+    let load_offset = align_up(notes_offset + notes.len(), PAGE_SIZE);
+
+    let mut header = Vec::with_capacity(load_offset);
+
+    // ELF header
+    header.extend_from_slice(b"\x7fELF");
+    header.extend_from_slice(&[2, 1, 1, 0]); // 64-bit, LE, version 1, SysV
+    header.extend_from_slice(&[0; 8]);
+    put_u16(&mut header, ElfType::CoreDump.value());
+    put_u16(&mut header, Machine::Amd64.value());
+    put_u32(&mut header, 1);                  // e_version
+    put_u64(&mut header, 0);                  // e_entry
+    put_u64(&mut header, EHDR_SIZE as u64);   // e_phoff
+    put_u64(&mut header, 0);                  // e_shoff
+    put_u32(&mut header, 0);                  // e_flags
+    put_u16(&mut header, EHDR_SIZE as u16);   // e_ehsize
+    put_u16(&mut header, PHDR_SIZE as u16);   // e_phentsize
+    put_u16(&mut header, phnum as u16);       // e_phnum
+    put_u16(&mut header, 0);                  // e_shentsize
+    put_u16(&mut header, 0);                  // e_shnum
+    put_u16(&mut header, 0);                  // e_shstrndx
+
+    // Program headers
+    put_phdr(&mut header, RegionType::Note, 4 /* R */,
+        notes_offset, 0, notes.len(), 4);
+
+    let mut offset = load_offset;
+
+    for segment in segments {
+        let flags =
+            4 |
+            if segment.page_type.is_writable() { 2 } else { 0 } |
+            if segment.page_type.is_executable() { 1 } else { 0 };
+
+        put_phdr(&mut header, RegionType::Load, flags,
+            offset, segment.vaddr, segment.size, PAGE_SIZE);
+
+        offset += segment.size;
+    }
+
+    // Notes, then pad to the first segment
+    header.extend_from_slice(&notes);
+    header.resize(load_offset, 0);
+
+    sink.write(&header)?;
+
+    // Segments
+    let mut page = vec![0; PAGE_SIZE];
+
+    for segment in segments {
+        for vaddr in (segment.vaddr..(segment.vaddr + segment.size))
+                .step_by(PAGE_SIZE) {
+            read(vaddr, &mut page);
+            sink.write(&page)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn notes(info: &CoreInfo) -> Vec<u8> {
+    let r = &info.regs;
+
+    // struct elf_prstatus
+    let mut prstatus = Vec::with_capacity(PRSTATUS_SIZE);
+
+    put_u32(&mut prstatus, info.signal);      // pr_info.si_signo
+    put_u32(&mut prstatus, 0);                // pr_info.si_code
+    put_u32(&mut prstatus, 0);                // pr_info.si_errno
+    put_u16(&mut prstatus, info.signal as u16); // pr_cursig
+    prstatus.resize(32, 0);                   // pr_sigpend, pr_sighold
+    put_u32(&mut prstatus, info.pid);         // pr_pid
+    put_u32(&mut prstatus, info.ppid);        // pr_ppid
+    put_u32(&mut prstatus, info.pgid);        // pr_pgrp
+    put_u32(&mut prstatus, info.pgid);        // pr_sid
+    prstatus.resize(112, 0);                  // pr_utime etc.
+
+    // pr_reg: struct user_regs_struct
+    for &value in &[
+        r.r15, r.r14, r.r13, r.r12, r.rbp, r.rbx, r.r11, r.r10,
+        r.r9, r.r8, r.rax, r.rcx, r.rdx, r.rsi, r.rdi,
+        !0, // orig_rax
+        r.rip, USER_CODE_SEL, r.rflags, r.rsp, USER_DATA_SEL,
+        0, 0, // fs_base, gs_base
+        USER_DATA_SEL, USER_DATA_SEL, 0, 0, // ds, es, fs, gs
+    ] {
+        put_u64(&mut prstatus, value);
+    }
+
+    prstatus.resize(PRSTATUS_SIZE, 0);        // pr_fpvalid
+
+    // struct elf_prpsinfo
+    let mut prpsinfo = Vec::with_capacity(PRPSINFO_SIZE);
+
+    prpsinfo.extend_from_slice(&[0, b'R', 0, 0]); // state, sname, zomb, nice
+    prpsinfo.resize(24, 0);                   // pr_flag, pr_uid, pr_gid
+    put_u32(&mut prpsinfo, info.pid);         // pr_pid
+    put_u32(&mut prpsinfo, info.ppid);        // pr_ppid
+    put_u32(&mut prpsinfo, info.pgid);        // pr_pgrp
+    put_u32(&mut prpsinfo, info.pgid);        // pr_sid
+
+    let name = info.name.as_bytes();
+    let fname = name.rsplit(|&b| b == b'/').next().unwrap_or(name);
+
+    put_str(&mut prpsinfo, fname, 16);        // pr_fname
+    put_str(&mut prpsinfo, name, 80);         // pr_psargs
+
+    let mut notes = vec![];
+
+    put_note(&mut notes, NT_PRSTATUS, &prstatus);
+    put_note(&mut notes, NT_PRPSINFO, &prpsinfo);
+
+    notes
+}
+
+fn put_u16(buf: &mut Vec<u8>, value: u16) {
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+
+fn put_u32(buf: &mut Vec<u8>, value: u32) {
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+
+fn put_u64(buf: &mut Vec<u8>, value: u64) {
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+
+/// Put a NUL-terminated string in a fixed size field, truncating it if needed.
+fn put_str(buf: &mut Vec<u8>, string: &[u8], size: usize) {
+    let len = string.len().min(size - 1);
+
+    buf.extend_from_slice(&string[..len]);
+    buf.extend((len..size).map(|_| 0));
+}
+
+fn put_phdr(buf: &mut Vec<u8>,
+            region_type: RegionType,
+            flags: u32,
+            offset: usize,
+            vaddr: usize,
+            size: usize,
+            align: usize) {
+    put_u32(buf, region_type.value());
+    put_u32(buf, flags);
+    put_u64(buf, offset as u64);
+    put_u64(buf, vaddr as u64);
+    put_u64(buf, 0);           // p_paddr
+    put_u64(buf, size as u64); // p_filesz
+    put_u64(buf, size as u64); // p_memsz
+    put_u64(buf, align as u64);
+}
+
+fn put_note(buf: &mut Vec<u8>, note_type: u32, desc: &[u8]) {
+    const NAME: &[u8] = b"CORE\0";
+
+    put_u32(buf, NAME.len() as u32);
+    put_u32(buf, desc.len() as u32);
+    put_u32(buf, note_type);
+
+    buf.extend_from_slice(NAME);
+    buf.resize(align_up(buf.len(), 4), 0);
+
+    buf.extend_from_slice(desc);
+    buf.resize(align_up(buf.len(), 4), 0);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::elf::Elf;
+
+    #[test]
+    fn core_is_readable_elf() {
+        let info = CoreInfo {
+            pid: 3,
+            ppid: 1,
+            pgid: 3,
+            name: Arc::new("bin/crashy".into()),
+            signal: 11,
+            regs: GeneralRegisters { rip: 0x401000, ..Default::default() },
+        };
+
+        let segments = [
+            Segment {
+                vaddr: 0x400000,
+                size: 2 * PAGE_SIZE,
+                page_type: PageType::default().user().executable(),
+            },
+        ];
+
+        let mut core = vec![];
+
+        write_core(&mut core, &info, &segments, |vaddr, page| {
+            page.iter_mut().for_each(|b| *b = (vaddr >> 12) as u8);
+        }).unwrap();
+
+        let elf = Elf::new(&core).unwrap();
+        let elf64 = elf.as_elf64_le().unwrap();
+
+        assert_eq!(elf64.elf_type(), ElfType::CoreDump);
+        assert_eq!(elf64.machine(), Machine::Amd64);
+
+        let phdrs: Vec<_> = elf64.program_headers().collect();
+
+        assert_eq!(phdrs.len(), 2);
+
+        assert_eq!(phdrs[0].region_type, RegionType::Note);
+        assert_eq!(phdrs[0].data.len(),
+            2 * (12 + 8) + PRSTATUS_SIZE + PRPSINFO_SIZE);
+
+        assert_eq!(phdrs[1].region_type, RegionType::Load);
+        assert_eq!(phdrs[1].mem_offset, 0x400000);
+        assert!(phdrs[1].executable && !phdrs[1].writable);
+        assert_eq!(phdrs[1].data[0], 0x00);
+        assert_eq!(phdrs[1].data[PAGE_SIZE], 0x01);
+
+        // rip is the 17th register in pr_reg
+        let note = phdrs[0].data;
+        let rip_offset = 12 + 8 + 112 + 16 * 8;
+        assert_eq!(&note[rip_offset..rip_offset + 8],
+            &0x401000u64.to_le_bytes());
+    }
+}
@@ -0,0 +1,542 @@
+/*******************************************************************************
+ *
+ * kit/kernel/debugger.rs
+ *
+ * vim:ft=rust:ts=4:sw=4:et:tw=80
+ *
+ * Copyright (C) 2015-2021, Devyn Cairns
+ * Redistribution of this file is permitted under the terms of the simplified
+ * BSD license. See LICENSE for more information.
+ *
+ ******************************************************************************/
+
+//! Debugging of user processes by their host.
+//!
+//! A process can attach to a user process it created (its *host*), which stops
+//! it. While the target is stopped, the host can read and write its memory and
+//! saved registers, set breakpoints, and continue or single-step it. When the
+//! target hits a breakpoint or finishes a step it stops again, and the host
+//! finds out by waiting on it, just like when it is stopped by job control.
+
+use core::mem;
+
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+
+use displaydoc::Display;
+
+use crate::process::{self, Id, RcProcess};
+use crate::process::target::GeneralRegisters;
+
+/// The `int3` instruction.
+const INT3: u8 = 0xcc;
+
+/// Why a traced process last stopped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    /// Stopped by the host, or by job control.
+    Stopped,
+    /// Executed an `int3` at the given address.
+    Breakpoint(usize),
+    /// Executed a single instruction with the trap flag set.
+    Step,
+}
+
+impl Event {
+    /// The value reported to user space. See `kit/kernel/include/syscall.h`.
+    pub fn code(&self) -> i32 {
+        match *self {
+            Event::Stopped       => 1,
+            Event::Breakpoint(_) => 2,
+            Event::Step          => 3,
+        }
+    }
+}
+
+/// The kind of trap taken by a user process.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Trap {
+    /// Debug exception, i.e. after a single step.
+    Debug,
+    /// Breakpoint exception, i.e. after an `int3`.
+    Breakpoint,
+}
+
+/// Debugger state kept in a traced process.
+#[derive(Debug)]
+pub struct Tracee {
+    host: Id,
+
+    /// Original bytes at each breakpoint address.
+    breakpoints: BTreeMap<usize, u8>,
+
+    event: Option<Event>,
+
+    /// The host asked to stop after one instruction.
+    stepping: bool,
+
+    /// A breakpoint that was lifted to step over it, and must be put back.
+    step_over: Option<usize>,
+}
+
+impl Tracee {
+    fn new(host: Id) -> Tracee {
+        Tracee {
+            host,
+            breakpoints: BTreeMap::new(),
+            event: None,
+            stepping: false,
+            step_over: None,
+        }
+    }
+
+    pub fn host(&self) -> Id {
+        self.host
+    }
+}
+
+#[derive(Debug, Display, PartialEq, Eq)]
+pub enum Error {
+    /// Process {0} is not a user process that can be debugged
+    NotDebuggable(Id),
+    /// Process {0} is not debugged by the calling process
+    NotHost(Id),
+    /// Process {0} already has a debugger attached
+    AlreadyAttached(Id),
+    /// Process {0} must be stopped first
+    NotStopped(Id),
+    /// Instruction pointer 0x{0:x} is not a user address
+    BadInstructionPointer(u64),
+    /// There is no breakpoint at 0x{0:x}
+    NoBreakpoint(usize),
+    /// Process error: {0}
+    ProcessError(process::Error),
+}
+
+impl crate::error::Error for Error { }
+
+impl From<process::Error> for Error {
+    fn from(error: process::Error) -> Error {
+        Error::ProcessError(error)
+    }
+}
+
+/// Get a process that `host` may debug, if it's stopped.
+fn stopped_tracee(host: Id, id: Id) -> Result<RcProcess, Error> {
+    let target = process::by_id(id)
+        .ok_or(Error::ProcessError(process::Error::UnknownPid(id)))?;
+
+    {
+        let process = target.lock();
+
+        if process.tracee().map(|t| t.host) != Some(host) {
+            return Err(Error::NotHost(id));
+        }
+
+        if !process.is_stopped() {
+            return Err(Error::NotStopped(id));
+        }
+    }
+
+    Ok(target)
+}
+
+/// Attach `host` to the process `id`, which must have been created by it, and
+/// stop it.
+pub fn attach(host: Id, id: Id) -> Result<(), Error> {
+    let target = process::by_id(id)
+        .ok_or(Error::ProcessError(process::Error::UnknownPid(id)))?;
+
+    {
+        let mut process = target.lock();
+
+        if process.id() == host || process.parent() != host {
+            return Err(Error::NotHost(id));
+        }
+
+        if process.mem().is_none() || !process.is_alive() {
+            return Err(Error::NotDebuggable(id));
+        }
+
+        if process.tracee().is_some() {
+            return Err(Error::AlreadyAttached(id));
+        }
+
+        let mut tracee = Tracee::new(host);
+        tracee.event = Some(Event::Stopped);
+
+        process.set_tracee(Some(tracee));
+
+        if process.is_stopped() {
+            return Ok(());
+        }
+    }
+
+    drop(target);
+
+    process::stop(id)?;
+
+    Ok(())
+}
+
+/// Remove all breakpoints from the process `id` and let it run freely again.
+pub fn detach(host: Id, id: Id) -> Result<(), Error> {
+    let target = process::by_id(id)
+        .ok_or(Error::ProcessError(process::Error::UnknownPid(id)))?;
+
+    let (breakpoints, stopped) = {
+        let mut process = target.lock();
+
+        if process.tracee().map(|t| t.host) != Some(host) {
+            return Err(Error::NotHost(id));
+        }
+
+        let tracee = process.set_tracee(None).unwrap();
+
+        unsafe {
+            process.hw_state_mut().user_mut().registers_mut()
+                .set_trap_flag(false);
+        }
+
+        (tracee.breakpoints, process.is_stopped())
+    };
+
+    for (vaddr, original) in breakpoints {
+        let _ = copy_to_process(&target, vaddr, &[original]);
+    }
+
+    drop(target);
+
+    if stopped {
+        process::r#continue(id)?;
+    }
+
+    Ok(())
+}
+
+/// Detach `host` from every process it is debugging. Called when it exits.
+pub fn detach_all(host: Id) {
+    let targets: Vec<Id> = process::all().into_iter()
+        .filter_map(|rc_process| {
+            let process = rc_process.lock();
+
+            process.tracee()
+                .filter(|t| t.host == host)
+                .map(|_| process.id())
+        })
+        .collect();
+
+    for id in targets {
+        let _ = detach(host, id);
+    }
+}
+
+/// Stop the process `id`, which `host` is debugging.
+pub fn stop(host: Id, id: Id) -> Result<(), Error> {
+    let target = process::by_id(id)
+        .ok_or(Error::ProcessError(process::Error::UnknownPid(id)))?;
+
+    {
+        let mut process = target.lock();
+
+        let tracee = process.tracee_mut()
+            .filter(|t| t.host == host)
+            .ok_or(Error::NotHost(id))?;
+
+        tracee.event = Some(Event::Stopped);
+    }
+
+    drop(target);
+
+    process::stop(id)?;
+
+    Ok(())
+}
+
+/// Continue the stopped process `id`. If `step` is true, it will stop again
+/// after executing one instruction.
+pub fn resume(host: Id, id: Id, step: bool) -> Result<(), Error> {
+    let target = stopped_tracee(host, id)?;
+
+    let lifted = {
+        let mut process = target.lock();
+
+        let rip = unsafe {
+            process.hw_state().user().registers().instruction_pointer()
+        };
+
+        let tracee = process.tracee_mut().unwrap();
+
+        // A breakpoint at the instruction pointer has to be lifted for one
+        // instruction, or we would just hit it again.
+        let lifted = tracee.breakpoints.get(&rip).map(|&orig| (rip, orig));
+
+        tracee.event = None;
+        tracee.stepping = step;
+        tracee.step_over = lifted.map(|(vaddr, _)| vaddr);
+
+        unsafe {
+            process.hw_state_mut().user_mut().registers_mut()
+                .set_trap_flag(step || lifted.is_some());
+        }
+
+        lifted
+    };
+
+    if let Some((vaddr, original)) = lifted {
+        copy_to_process(&target, vaddr, &[original])?;
+    }
+
+    drop(target);
+
+    process::r#continue(id)?;
+
+    Ok(())
+}
+
+/// Why the stopped process `id` stopped.
+pub fn event(host: Id, id: Id) -> Result<Event, Error> {
+    let target = stopped_tracee(host, id)?;
+
+    let process = target.lock();
+
+    // Anything we didn't see coming was job control.
+    Ok(process.tracee().unwrap().event.unwrap_or(Event::Stopped))
+}
+
+/// Read the saved general purpose registers of the stopped process `id`.
+pub fn registers(host: Id, id: Id) -> Result<GeneralRegisters, Error> {
+    let target = stopped_tracee(host, id)?;
+
+    let process = target.lock();
+
+    Ok(unsafe { process.hw_state().user().registers().general() })
+}
+
+/// Replace the saved general purpose registers of the stopped process `id`.
+/// They take effect when it is continued.
+pub fn set_registers(host: Id, id: Id, regs: &GeneralRegisters)
+    -> Result<(), Error> {
+
+    let target = stopped_tracee(host, id)?;
+
+    let mut process = target.lock();
+
+    let ok = unsafe {
+        process.hw_state_mut().user_mut().registers_mut().set_general(regs)
+    };
+
+    if ok {
+        Ok(())
+    } else {
+        Err(Error::BadInstructionPointer(regs.rip))
+    }
+}
+
+/// Read memory of the stopped process `id`. Breakpoints are not visible.
+pub fn read_memory(host: Id, id: Id, vaddr: usize, buf: &mut [u8])
+    -> Result<(), Error> {
+
+    let target = stopped_tracee(host, id)?;
+
+    copy_from_process(&target, vaddr, buf)?;
+
+    let process = target.lock();
+
+    for (&bp, &original) in process.tracee().unwrap().breakpoints
+            .range(vaddr..vaddr + buf.len()) {
+        buf[bp - vaddr] = original;
+    }
+
+    Ok(())
+}
+
+/// Write memory of the stopped process `id`, even if it's read-only to the
+/// process. Breakpoints within the range are kept.
+pub fn write_memory(host: Id, id: Id, vaddr: usize, data: &[u8])
+    -> Result<(), Error> {
+
+    let target = stopped_tracee(host, id)?;
+
+    let mut data = data.to_vec();
+    let mut originals = vec![];
+
+    {
+        let process = target.lock();
+
+        for (&bp, _) in process.tracee().unwrap().breakpoints
+                .range(vaddr..vaddr + data.len()) {
+            originals.push((bp, data[bp - vaddr]));
+            data[bp - vaddr] = INT3;
+        }
+    }
+
+    copy_to_process(&target, vaddr, &data)?;
+
+    let mut process = target.lock();
+    let tracee = process.tracee_mut().unwrap();
+
+    for (bp, original) in originals {
+        tracee.breakpoints.insert(bp, original);
+    }
+
+    Ok(())
+}
+
+/// Set a breakpoint at `vaddr` in the stopped process `id`.
+pub fn set_breakpoint(host: Id, id: Id, vaddr: usize) -> Result<(), Error> {
+    let target = stopped_tracee(host, id)?;
+
+    if target.lock().tracee().unwrap().breakpoints.contains_key(&vaddr) {
+        return Ok(());
+    }
+
+    let mut original = [0];
+
+    copy_from_process(&target, vaddr, &mut original)?;
+    copy_to_process(&target, vaddr, &[INT3])?;
+
+    target.lock().tracee_mut().unwrap().breakpoints.insert(vaddr, original[0]);
+
+    Ok(())
+}
+
+/// Remove the breakpoint at `vaddr` in the stopped process `id`.
+pub fn clear_breakpoint(host: Id, id: Id, vaddr: usize) -> Result<(), Error> {
+    let target = stopped_tracee(host, id)?;
+
+    let original = target.lock().tracee_mut().unwrap().breakpoints
+        .remove(&vaddr)
+        .ok_or(Error::NoBreakpoint(vaddr))?;
+
+    copy_to_process(&target, vaddr, &[original])
+}
+
+/// Handle a trap taken by the current process in user mode, stopping it if a
+/// debugger is attached. The user registers must already have been saved to
+/// its `HwState`, and any changes to them must be applied on return.
+///
+/// Returns false if no debugger is attached, in which case the process should
+/// be killed.
+pub fn handle_trap(trap: Trap) -> bool {
+    let rc_process = process::current();
+
+    let (id, should_stop, reinsert) = {
+        let mut process = rc_process.lock();
+
+        let id = process.id();
+
+        let rip = unsafe {
+            process.hw_state().user().registers().instruction_pointer()
+        };
+
+        let tracee = match process.tracee_mut() {
+            Some(tracee) => tracee,
+            None => return false,
+        };
+
+        match trap {
+            Trap::Breakpoint => {
+                // The instruction pointer is just past the int3.
+                let vaddr = rip.wrapping_sub(1);
+                let ours = tracee.breakpoints.contains_key(&vaddr);
+
+                tracee.event = Some(Event::Breakpoint(vaddr));
+
+                // Back up to our breakpoint, so the original instruction runs
+                // on continue.
+                if ours {
+                    unsafe {
+                        process.hw_state_mut().user_mut().registers_mut()
+                            .set_instruction_pointer(vaddr);
+                    }
+                }
+
+                (id, true, None)
+            },
+            Trap::Debug => {
+                let reinsert = tracee.step_over.take()
+                    .filter(|vaddr| tracee.breakpoints.contains_key(vaddr));
+
+                let stepping = mem::replace(&mut tracee.stepping, false);
+
+                // Only stepping over a breakpoint on the way to continuing
+                // doesn't count as a stop.
+                let should_stop = stepping || reinsert.is_none();
+
+                if should_stop {
+                    tracee.event = Some(Event::Step);
+                }
+
+                unsafe {
+                    process.hw_state_mut().user_mut().registers_mut()
+                        .set_trap_flag(false);
+                }
+
+                (id, should_stop, reinsert)
+            },
+        }
+    };
+
+    if let Some(vaddr) = reinsert {
+        let _ = copy_to_process(&rc_process, vaddr, &[INT3]);
+    }
+
+    drop(rc_process);
+
+    if should_stop {
+        // Returns once the host continues us.
+        let _ = process::stop(id);
+    }
+
+    true
+}
+
+fn copy_from_process(target: &RcProcess, vaddr: usize, buf: &mut [u8])
+    -> Result<(), Error> {
+
+    let (id, mem) = {
+        let process = target.lock();
+        (process.id(), process.mem())
+    };
+
+    let mem = mem.ok_or(Error::NotDebuggable(id))?;
+    let mut mem = mem.lock();
+
+    Ok(mem.copy_out(vaddr, buf)?)
+}
+
+fn copy_to_process(target: &RcProcess, vaddr: usize, data: &[u8])
+    -> Result<(), Error> {
+
+    let (id, mem) = {
+        let process = target.lock();
+        (process.id(), process.mem())
+    };
+
+    let mem = mem.ok_or(Error::NotDebuggable(id))?;
+    let mut mem = mem.lock();
+
+    Ok(mem.copy_in(vaddr, data)?)
+}
+
+/// C interface. See `kit/kernel/include/debugger.h`.
+pub mod ffi {
+    use crate::c_ffi::*;
+
+    use super::Trap;
+
+    /// Interrupt vector of the debug exception.
+    const INTERRUPT_DEBUG: uint64_t = 0x1;
+
+    #[no_mangle]
+    pub extern fn debugger_handle_trap(index: uint64_t) -> bool {
+        let trap = if index == INTERRUPT_DEBUG {
+            Trap::Debug
+        } else {
+            Trap::Breakpoint
+        };
+
+        super::handle_trap(trap)
+    }
+}
@@ -0,0 +1,1014 @@
+/*******************************************************************************
+ *
+ * kit/kernel/elf/mod.rs
+ *
+ * vim:ft=rust:ts=4:sw=4:et:tw=80
+ *
+ * Copyright (C) 2015-2021, Devyn Cairns
+ * Redistribution of this file is permitted under the terms of the simplified
+ * BSD license. See LICENSE for more information.
+ *
+ ******************************************************************************/
+
+//! Executable and Linkable Format loader.
+
+use core::ptr;
+use core::slice;
+
+use alloc::vec::Vec;
+
+use crate::archive;
+use crate::c_ffi::CStr;
+use crate::process::{self, Process, Image, ProcessMem, RcProcessMem};
+use crate::process::vma::{Vma, Backing, Growth};
+use crate::process::target::{PIE_BASE_ADDR, USER_ADDR_LIMIT};
+use crate::paging::{self, PageType, PAGE_SIZE};
+use crate::util::{align_up, align_down};
+
+mod parse;
+
+pub use self::parse::*;
+
+impl<'a> Elf<'a> {
+    pub fn as_executable(&'a self) -> Option<Executable<'a>> {
+        Executable::new(self)
+    }
+}
+
+/// Auxiliary vector entry types. The vector is passed to the entry point as
+/// `(type, value)` pairs ending with `AT_NULL`.
+pub const AT_NULL: usize = 0;
+pub const AT_PHDR: usize = 3;
+pub const AT_PHENT: usize = 4;
+pub const AT_PHNUM: usize = 5;
+pub const AT_PAGESZ: usize = 6;
+pub const AT_BASE: usize = 7;
+pub const AT_ENTRY: usize = 9;
+pub const AT_RANDOM: usize = 25;
+
+/// Kit-specific: points to a [SystemInfo](crate::process::SystemInfo).
+pub const AT_KIT_SYSINFO: usize = 0x4b1700;
+
+/// A program that can be loaded into a process: either a fixed-address
+/// executable, or a position-independent one that's relocated to
+/// [PIE_BASE_ADDR] as it's loaded, or wherever the process's
+/// [Layout](crate::process::layout::Layout) says.
+///
+/// If the program names an interpreter (`PT_INTERP`), the interpreter is loaded
+/// from the system archive into the library area and started instead. It gets
+/// the program's symbol relocations and shared libraries to deal with; we only
+/// apply the program's relative relocations.
+#[derive(Clone, Copy)]
+pub struct Executable<'a> {
+    elf: &'a Elf<'a>,
+    elf64_le: Elf64Le<'a>,
+    base: usize,
+}
+
+/// Which relocations the kernel applies when loading an image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Linking {
+    /// Nothing else will see the image, so every relocation must be applied.
+    Complete,
+    /// A dynamic linker takes care of symbol relocations, so only apply the
+    /// relative ones.
+    Deferred,
+}
+
+impl<'a> Executable<'a> {
+    pub fn new(elf: &'a Elf<'a>) -> Option<Executable<'a>> {
+        let elf64_le = Executable::check(elf)?;
+
+        let base = match elf64_le.elf_type() {
+            ElfType::Executable => 0,
+
+            ElfType::Dynamic => PIE_BASE_ADDR
+                .wrapping_sub(align_down(elf64_le.extent()?.0, PAGE_SIZE)),
+
+            _ => return None,
+        };
+
+        Some(Executable {
+            elf: elf,
+            elf64_le: elf64_le,
+            base: base,
+        })
+    }
+
+    /// A shared library or interpreter, to be loaded at `addr`. It must be
+    /// position-independent and can't have an interpreter of its own.
+    fn library(elf: &'a Elf<'a>, addr: usize) -> Option<Executable<'a>> {
+        let elf64_le = Executable::check(elf)?;
+
+        if elf64_le.elf_type() != ElfType::Dynamic ||
+            elf64_le.has_interpreter() {
+            return None;
+        }
+
+        let base =
+            addr.wrapping_sub(align_down(elf64_le.extent()?.0, PAGE_SIZE));
+
+        Some(Executable {
+            elf: elf,
+            elf64_le: elf64_le,
+            base: base,
+        })
+    }
+
+    /// Check that the file is for this machine and ABI.
+    fn check(elf: &'a Elf<'a>) -> Option<Elf64Le<'a>> {
+        if elf.os_abi() != 0 {
+            return None;
+        }
+
+        if elf.abi_version() != 0 {
+            return None;
+        }
+
+        let elf64_le = Elf64Le::new(elf.buffer())?;
+
+        if elf64_le.machine() != Machine::Amd64 /* FIXME */ {
+            return None;
+        }
+
+        Some(elf64_le)
+    }
+
+    /// The offset from the program's link-time addresses to where it's
+    /// loaded. Zero unless it's position-independent.
+    pub fn base(&self) -> usize {
+        self.base
+    }
+
+    /// Check that the segments are safe to load at [base](Self::base): they
+    /// must be within user space, not overlap each other, and not be both
+    /// writable and executable unless the file [asks for
+    /// it](Elf64Le::wants_jit).
+    pub fn validate(&self) -> Result<(), process::Error> {
+        use process::Error::InvalidImage;
+
+        let loads = || self.elf64_le.program_headers()
+            .filter(|phdr| phdr.region_type == RegionType::Load);
+
+        // Page-aligned, loaded address range of each segment
+        let range = |phdr: &ElfProgramHeader| -> Option<(usize, usize)> {
+            let start = phdr.mem_offset.wrapping_add(self.base);
+            let end = start.checked_add(phdr.mem_size)?;
+
+            if end > USER_ADDR_LIMIT {
+                return None;
+            }
+
+            Some((align_down(start, PAGE_SIZE), align_up(end, PAGE_SIZE)))
+        };
+
+        for (index, phdr) in loads().enumerate() {
+            if phdr.data.len() > phdr.mem_size {
+                return Err(InvalidImage(
+                    "segment file size exceeds memory size"));
+            }
+
+            if phdr.writable && phdr.executable && !self.elf64_le.wants_jit() {
+                return Err(InvalidImage(
+                    "segment is both writable and executable"));
+            }
+
+            let (start, end) = range(&phdr)
+                .ok_or(InvalidImage("segment outside of user space"))?;
+
+            // Segments can't share pages, since they'd have to share
+            // permissions too.
+            let overlaps = loads().take(index)
+                .filter_map(|other| range(&other))
+                .any(|(other_start, other_end)| {
+                    start < end && other_start < other_end &&
+                        start < other_end && other_start < end
+                });
+
+            if overlaps {
+                return Err(InvalidImage("segments overlap"));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Set up the segments and apply relocations. The process's pageset must be
+    /// the current one.
+    ///
+    /// If the file is in the system archive, the segments are demand-paged:
+    /// their pages are only allocated and filled in from the archive when
+    /// they're first touched. Otherwise, the segments are mapped and copied in
+    /// here, and get their final permissions after relocation.
+    unsafe fn load_segments(&self, mem: &RcProcessMem, linking: Linking)
+                            -> Result<(), process::Error> {
+
+        self.validate()?;
+
+        let loads = || self.elf64_le.program_headers()
+            .filter(|phdr| phdr.region_type == RegionType::Load);
+
+        if let Some(file) = archive::system().as_static(self.elf.buffer()) {
+            for phdr in loads() {
+                phdr_map_lazy(&phdr, file, self.base, &mut *mem.lock())?;
+            }
+
+            return self.relocate(mem, linking);
+        }
+
+        for phdr in loads() {
+            phdr_load(&phdr, self.base, &mut *mem.lock())?;
+        }
+
+        // Relocations have to be applied before the pages become read-only.
+        self.relocate(mem, linking)?;
+
+        for phdr in loads() {
+            phdr_protect(&phdr, self.base, &mut *mem.lock())?;
+        }
+
+        Ok(())
+    }
+
+    /// Apply the program's relocations. Its segments must already be set up in
+    /// the current pageset. Pages that haven't been touched yet are filled in
+    /// as relocations are written to them.
+    unsafe fn relocate(&self, mem: &RcProcessMem, linking: Linking)
+                       -> Result<(), process::Error> {
+        self.resolve_relocations(linking, |target, value| {
+            mem.lock().populate(target, 8, true)?;
+
+            // Demand-paged segments already have their final permissions, which
+            // might not include writing.
+            paging::without_write_protect(|| {
+                ptr::write_unaligned(target as *mut u64, value);
+            });
+
+            Ok(())
+        })
+    }
+
+    /// Work out the relocations that `linking` calls for, and pass the loaded
+    /// address and new 8-byte value of each to `write`.
+    fn resolve_relocations<F>(&self, linking: Linking, mut write: F)
+                              -> Result<(), process::Error>
+        where F: FnMut(usize, u64) -> Result<(), process::Error> {
+
+        use process::Error::InvalidImage;
+
+        if self.elf64_le.elf_type() != ElfType::Dynamic {
+            return Ok(());
+        }
+
+        let relocations = self.elf64_le.relocations()
+            .ok_or(InvalidImage("relocation table not in file"))?;
+
+        for relocation in relocations {
+            let target = relocation.offset.wrapping_add(self.base);
+
+            match relocation.kind {
+                R_X86_64_NONE => (),
+
+                R_X86_64_RELATIVE => {
+                    if !self.is_loaded(relocation.offset, 8) {
+                        return Err(InvalidImage("relocation out of bounds"));
+                    }
+
+                    let value =
+                        self.base.wrapping_add(relocation.addend as usize);
+
+                    write(target, value as u64)?;
+                },
+
+                _ if linking == Linking::Deferred => (),
+
+                _ => return Err(InvalidImage("unsupported relocation type")),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// True if `vaddr..(vaddr + len)` (link-time addresses) is within one of
+    /// the loaded segments.
+    fn is_loaded(&self, vaddr: usize, len: usize) -> bool {
+        let end = match vaddr.checked_add(len) {
+            Some(end) => end,
+            None => return false,
+        };
+
+        self.elf64_le.program_headers()
+            .filter(|phdr| phdr.region_type == RegionType::Load)
+            .any(|phdr| vaddr >= phdr.mem_offset &&
+                end <= phdr.mem_offset + phdr.mem_size)
+    }
+
+    /// Where the program headers are once loaded, for `AT_PHDR`.
+    fn loaded_program_headers(&self) -> Option<usize> {
+        let phoff = self.elf64_le.program_header_offset();
+
+        let vaddr = self.elf64_le.program_headers()
+            .find(|phdr| phdr.region_type == RegionType::ProgramHeader)
+            .map(|phdr| phdr.mem_offset)
+            .or_else(|| {
+                // No PT_PHDR: look for the segment that loads them.
+                self.elf64_le.program_headers()
+                    .filter(|phdr| phdr.region_type == RegionType::Load)
+                    .find(|phdr| phoff >= phdr.file_offset &&
+                        phoff < phdr.file_offset + phdr.data.len())
+                    .map(|phdr| phdr.mem_offset + (phoff - phdr.file_offset))
+            })?;
+
+        Some(vaddr.wrapping_add(self.base))
+    }
+
+    /// The auxiliary vector to pass to the entry point. `interpreter_base` is
+    /// only included if nonzero.
+    fn aux_vector(&self, interpreter_base: usize) -> Vec<(usize, usize)> {
+        let mut aux = vec![];
+
+        if let Some(phdr) = self.loaded_program_headers() {
+            aux.push((AT_PHDR, phdr));
+        }
+
+        aux.push((AT_PHENT, PHDR_SIZE));
+        aux.push((AT_PHNUM, self.elf64_le.program_header_count()));
+        aux.push((AT_PAGESZ, PAGE_SIZE));
+
+        if interpreter_base != 0 {
+            aux.push((AT_BASE, interpreter_base));
+        }
+
+        aux.push((AT_ENTRY, self.elf64_le.entry().wrapping_add(self.base)));
+
+        aux
+    }
+}
+
+impl<'a> Image for Executable<'a> {
+    fn load_into(&self, process: &mut Process)
+                 -> Result<(), process::Error> {
+        let mem = process.mem().unwrap();
+
+        let pie_base = mem.lock().layout().pie_base;
+
+        // Position-independent programs go wherever the process wants them.
+        let this = match self.elf64_le.elf_type() {
+            ElfType::Dynamic => Executable {
+                base: self.base.wrapping_add(pie_base)
+                    .wrapping_sub(PIE_BASE_ADDR),
+                ..*self
+            },
+            _ => *self,
+        };
+
+        this.load(process, &mem)
+    }
+}
+
+impl<'a> Executable<'a> {
+    /// Load into `process`, whose memory is `mem`, at [base](Self::base).
+    fn load(&self, process: &mut Process, mem: &RcProcessMem)
+            -> Result<(), process::Error> {
+
+        let interpreter = self.elf64_le.interpreter();
+
+        let linking = match interpreter {
+            Some(_) => Linking::Deferred,
+            None => Linking::Complete,
+        };
+
+        let mut entry = self.elf64_le.entry().wrapping_add(self.base);
+        let mut interpreter_base = 0;
+
+        // Only the program can ask for this, not its libraries.
+        if self.elf64_le.wants_jit() {
+            mem.lock().allow_jit();
+        }
+
+        in_pageset(mem, || -> Result<(), process::Error> {
+            unsafe { self.load_segments(mem, linking)?; }
+
+            if let Some(path) = interpreter {
+                let (base, interpreter_entry) = load_interpreter(mem, path)?;
+
+                interpreter_base = base;
+                entry = interpreter_entry;
+            }
+
+            Ok(())
+        })?;
+
+        {
+            let mut mem = mem.lock();
+
+            mem.set_image_base(self.base);
+            mem.set_aux_vector(self.aux_vector(interpreter_base));
+
+            // Linkers record -z stack-size here.
+            let stack_size = self.elf64_le.program_headers()
+                .find(|phdr| phdr.region_type == RegionType::GnuStack)
+                .map(|phdr| phdr.mem_size)
+                .filter(|&size| size > 0);
+
+            if let Some(size) = stack_size {
+                mem.set_stack_limit(size)?;
+            }
+        }
+
+        process.set_entry_point(entry);
+
+        Ok(())
+    }
+}
+
+/// Load a shared library into the library area of `mem`, leaving its symbol
+/// relocations to the dynamic linker. Returns the library's base.
+pub fn load_library(mem: &RcProcessMem, data: &[u8])
+                    -> Result<usize, process::Error> {
+    in_pageset(mem, || {
+        load_library_with(mem, data, Linking::Deferred)
+            .map(|(base, _)| base)
+    })
+}
+
+/// Find the interpreter named by `PT_INTERP` in the system archive and load it.
+/// Returns its base and entry point.
+fn load_interpreter(mem: &RcProcessMem, path: &[u8])
+                    -> Result<(usize, usize), process::Error> {
+    use process::Error::InvalidImage;
+
+    if path.last() != Some(&0) {
+        return Err(InvalidImage("interpreter path not terminated"));
+    }
+
+    let system = archive::system();
+
+    let data = system.get(CStr::new(path))
+        .ok_or(InvalidImage("interpreter not found"))?;
+
+    // The interpreter has nobody to link it, so it must be self-contained.
+    load_library_with(mem, data, Linking::Complete)
+}
+
+/// Reserve space in the library area of `mem` and load the image there. The
+/// process's pageset must be the current one. Returns its base and entry point.
+fn load_library_with(mem: &RcProcessMem, data: &[u8], linking: Linking)
+                     -> Result<(usize, usize), process::Error> {
+    use process::Error::InvalidImage;
+
+    let elf = Elf::new(data).ok_or(InvalidImage("not an ELF file"))?;
+
+    let elf64_le = Executable::check(&elf)
+        .ok_or(InvalidImage("not a shared library"))?;
+
+    let (lowest, end) = elf64_le.extent()
+        .ok_or(InvalidImage("no loadable segments"))?;
+
+    let size = end.checked_add(PAGE_SIZE - 1)
+        .map(|end| align_down(end, PAGE_SIZE) - align_down(lowest, PAGE_SIZE))
+        .ok_or(process::Error::Overflow)?;
+
+    let addr = mem.lock().reserve_library_space(size)?;
+
+    let loaded = Executable::library(&elf, addr)
+        .ok_or(InvalidImage("not a shared library"))
+        .and_then(|library| {
+            unsafe { library.load_segments(mem, linking)?; }
+
+            Ok((library.base,
+                library.elf64_le.entry().wrapping_add(library.base)))
+        });
+
+    if loaded.is_err() {
+        // Don't leave part of it behind.
+        mem.lock().release_library_space(addr, size)?;
+    }
+
+    loaded
+}
+
+/// Run `f` with the pageset of `mem` as the current one, restoring the
+/// previous pageset after.
+fn in_pageset<T, F: FnOnce() -> T>(mem: &RcProcessMem, f: F) -> T {
+    let new_pageset = mem.lock().pageset();
+
+    unsafe {
+        let original_pageset = paging::current_pageset();
+        paging::set_current_pageset(Some(new_pageset));
+
+        let result = f();
+
+        paging::set_current_pageset(original_pageset);
+
+        result
+    }
+}
+
+/// Map the pages for a segment at `base` + its address, and copy its data in.
+/// The pages are left writable by the kernel only, until [phdr_protect].
+unsafe fn phdr_load<'a>(phdr: &ElfProgramHeader<'a>,
+                        base: usize,
+                        mem: &mut ProcessMem)
+                        -> Result<(), process::Error> {
+
+    let vaddr = phdr.mem_offset.wrapping_add(base);
+
+    // What we need first while writing the pages
+    let page_type_init = PageType::default().writable();
+
+    mem.map_allocate(vaddr, phdr.mem_size, page_type_init)?;
+
+    // Access the memory directly via a slice into userspace.
+    let memory = slice::from_raw_parts_mut(vaddr as *mut u8, phdr.mem_size);
+
+    phdr.load_into(memory)
+        .map_err(|_| process::Error::InvalidImage("segment doesn't fit"))?;
+
+    Ok(())
+}
+
+/// Set up a segment at `base` + its address to be filled in from `file`, the
+/// whole ELF file, as its pages are touched. Read-only pages that line up with
+/// the file share its memory instead of being copied.
+fn phdr_map_lazy<'a>(phdr: &ElfProgramHeader<'a>,
+                     file: &'static [u8],
+                     base: usize,
+                     mem: &mut ProcessMem)
+                     -> Result<(), process::Error> {
+
+    if phdr.mem_size == 0 {
+        return Ok(());
+    }
+
+    let vaddr = phdr.mem_offset.wrapping_add(base);
+
+    // Same range as phdr.data, but 'static
+    let data = phdr.file_offset.checked_add(phdr.data.len())
+        .and_then(|end| file.get(phdr.file_offset..end))
+        .ok_or(process::Error::InvalidImage("segment data not in file"))?;
+
+    // Checked by Executable::validate()
+    let end = vaddr + phdr.mem_size;
+
+    mem.map_lazy(Vma {
+        start:     align_down(vaddr, PAGE_SIZE),
+        end:       align_up(end, PAGE_SIZE),
+        page_type: phdr_page_type(phdr),
+        backing:   Backing::File { vaddr, data, size: phdr.mem_size },
+        growth:    Growth::Fixed,
+    })
+}
+
+/// Change a loaded segment's pages to the permissions it asks for.
+unsafe fn phdr_protect<'a>(phdr: &ElfProgramHeader<'a>,
+                           base: usize,
+                           mem: &mut ProcessMem)
+                           -> Result<(), process::Error> {
+
+    mem.set_permissions(phdr.mem_offset.wrapping_add(base), phdr.mem_size,
+        phdr_page_type(phdr))?;
+
+    Ok(())
+}
+
+fn phdr_page_type<'a>(phdr: &ElfProgramHeader<'a>) -> PageType {
+    let mut page_type = PageType::default().user();
+
+    if phdr.writable   { page_type = page_type.writable(); }
+    if phdr.executable { page_type = page_type.executable(); }
+
+    page_type
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use alloc::vec::Vec;
+
+    fn put(buf: &mut Vec<u8>, offset: usize, bytes: &[u8]) {
+        buf[offset..(offset + bytes.len())].copy_from_slice(bytes);
+    }
+
+    /// A static PIE with one segment loaded at zero, and a dynamic section with
+    /// two relocations.
+    fn static_pie() -> Vec<u8> {
+        const PHDRS: usize = 64;
+        const DYNAMIC: usize = PHDRS + 2 * 56;
+        const RELA: usize = DYNAMIC + 4 * 16;
+        const END: usize = RELA + 2 * 24;
+
+        let mut buf = vec![0u8; END];
+
+        put(&mut buf, 0, b"\x7fELF\x02\x01\x01");
+        put(&mut buf, 16, &3u16.to_le_bytes()); // ET_DYN
+        put(&mut buf, 18, &62u16.to_le_bytes()); // EM_X86_64
+        put(&mut buf, 24, &0x1000u64.to_le_bytes()); // e_entry
+        put(&mut buf, 32, &(PHDRS as u64).to_le_bytes());
+        put(&mut buf, 54, &56u16.to_le_bytes());
+        put(&mut buf, 56, &2u16.to_le_bytes());
+
+        // PT_LOAD: the whole file at vaddr 0, plus some bss
+        put(&mut buf, PHDRS, &1u32.to_le_bytes());
+        put(&mut buf, PHDRS + 4, &6u32.to_le_bytes());
+        put(&mut buf, PHDRS + 32, &(END as u64).to_le_bytes());
+        put(&mut buf, PHDRS + 40, &0x2000u64.to_le_bytes());
+
+        // PT_DYNAMIC
+        let phdr = PHDRS + 56;
+        put(&mut buf, phdr, &2u32.to_le_bytes());
+        put(&mut buf, phdr + 8, &(DYNAMIC as u64).to_le_bytes());
+        put(&mut buf, phdr + 16, &(DYNAMIC as u64).to_le_bytes());
+        put(&mut buf, phdr + 32, &64u64.to_le_bytes());
+        put(&mut buf, phdr + 40, &64u64.to_le_bytes());
+
+        for (index, &(tag, value)) in [
+            (DT_RELA, RELA as u64),
+            (DT_RELASZ, 48),
+            (DT_RELAENT, 24),
+            (DT_NULL, 0),
+        ].iter().enumerate() {
+            put(&mut buf, DYNAMIC + index * 16, &tag.to_le_bytes());
+            put(&mut buf, DYNAMIC + index * 16 + 8, &value.to_le_bytes());
+        }
+
+        for (index, &(offset, addend)) in
+            [(0x1800u64, 0x1000u64), (0x1808, 0x1234)].iter().enumerate() {
+
+            let o = RELA + index * 24;
+            put(&mut buf, o, &offset.to_le_bytes());
+            put(&mut buf, o + 8, &(R_X86_64_RELATIVE as u64).to_le_bytes());
+            put(&mut buf, o + 16, &addend.to_le_bytes());
+        }
+
+        buf
+    }
+
+    #[test]
+    fn static_pie_is_relocated_to_base() {
+        let buf = static_pie();
+        let elf = Elf::new(&buf).unwrap();
+        let exec = elf.as_executable().unwrap();
+
+        assert_eq!(exec.base(), PIE_BASE_ADDR);
+        assert!(exec.is_loaded(0x1808, 8));
+        assert!(!exec.is_loaded(0x1ffc, 8));
+
+        let elf64 = elf.as_elf64_le().unwrap();
+        let relocations: Vec<_> = elf64.relocations().unwrap().collect();
+
+        assert_eq!(relocations, [
+            Relocation {
+                offset: 0x1800, kind: R_X86_64_RELATIVE, symbol: 0,
+                addend: 0x1000
+            },
+            Relocation {
+                offset: 0x1808, kind: R_X86_64_RELATIVE, symbol: 0,
+                addend: 0x1234
+            },
+        ]);
+    }
+
+    /// Apply [static_pie]'s relocations to a buffer standing in for its
+    /// loaded image.
+    fn relocated(buf: &[u8], linking: Linking)
+                 -> Result<Vec<u8>, process::Error> {
+        let elf = Elf::new(buf).unwrap();
+        let exec = elf.as_executable().unwrap();
+
+        let mut image = vec![0u8; 0x2000];
+
+        exec.resolve_relocations(linking, |target, value| {
+            let offset = target - exec.base();
+            put(&mut image, offset, &value.to_le_bytes());
+            Ok(())
+        })?;
+
+        Ok(image)
+    }
+
+    #[test]
+    fn relative_relocations_are_written_as_base_plus_addend() {
+        let image = relocated(&static_pie(), Linking::Complete).unwrap();
+
+        let read = |offset: usize| {
+            let mut bytes = [0u8; 8];
+            bytes.copy_from_slice(&image[offset..(offset + 8)]);
+            u64::from_le_bytes(bytes)
+        };
+
+        assert_eq!(read(0x1800), (PIE_BASE_ADDR + 0x1000) as u64);
+        assert_eq!(read(0x1808), (PIE_BASE_ADDR + 0x1234) as u64);
+    }
+
+    #[test]
+    fn unsupported_relocations_are_rejected() {
+        let mut buf = static_pie();
+
+        // Make the second relocation R_X86_64_64, which needs a symbol.
+        let rela = 64 + 2 * 56 + 4 * 16;
+        put(&mut buf, rela + 24 + 8, &1u64.to_le_bytes());
+
+        assert_eq!(relocated(&buf, Linking::Complete),
+            Err(process::Error::InvalidImage("unsupported relocation type")));
+
+        // The dynamic linker is left to deal with it.
+        let image = relocated(&buf, Linking::Deferred).unwrap();
+
+        assert_eq!(image[0x1808..0x1810], [0u8; 8]);
+    }
+
+    #[test]
+    fn aux_vector_locates_program_headers() {
+        let buf = static_pie();
+        let elf = Elf::new(&buf).unwrap();
+        let exec = elf.as_executable().unwrap();
+
+        // No PT_PHDR, so it's found through the PT_LOAD that covers e_phoff.
+        assert_eq!(exec.aux_vector(0), [
+            (AT_PHDR, PIE_BASE_ADDR + 64),
+            (AT_PHENT, 56),
+            (AT_PHNUM, 2),
+            (AT_PAGESZ, PAGE_SIZE),
+            (AT_ENTRY, PIE_BASE_ADDR + 0x1000),
+        ]);
+    }
+
+    /// [static_pie] with section headers, a symbol table and a dynamic symbol
+    /// table.
+    fn with_symbols() -> Vec<u8> {
+        let mut buf = static_pie();
+
+        let append = |buf: &mut Vec<u8>, bytes: &[u8]| {
+            let offset = buf.len();
+            buf.extend_from_slice(bytes);
+            offset
+        };
+
+        let shstrtab = b"\0.shstrtab\0.strtab\0.symtab\0.dynsym\0.bss\0";
+        let strtab = b"\0main\0helper\0local\0puts\0";
+
+        let symbol = |name: u32, info: u8, shndx: u16, value: u64, size: u64| {
+            let mut entry = vec![0u8; 24];
+            put(&mut entry, 0, &name.to_le_bytes());
+            entry[4] = info;
+            put(&mut entry, 6, &shndx.to_le_bytes());
+            put(&mut entry, 8, &value.to_le_bytes());
+            put(&mut entry, 16, &size.to_le_bytes());
+            entry
+        };
+
+        let symtab = [
+            symbol(0, 0, 0, 0, 0),
+            symbol(1, 0x12, 1, 0x1000, 0x20), // main: global function
+            symbol(6, 0x02, 1, 0x1100, 0), // helper: local, unsized
+            symbol(13, 0x01, 1, 0x1800, 8), // local: local object
+        ].concat();
+
+        let dynsym = [
+            symbol(0, 0, 0, 0, 0),
+            symbol(1, 0x12, 1, 0x1000, 0x20), // main
+            symbol(19, 0x12, 0, 0, 0), // puts: undefined
+        ].concat();
+
+        let shstrtab_offset = append(&mut buf, shstrtab);
+        let strtab_offset = append(&mut buf, strtab);
+        let symtab_offset = append(&mut buf, &symtab);
+        let dynsym_offset = append(&mut buf, &dynsym);
+
+        let shoff = buf.len();
+
+        // (name, type, offset, size, link, entsize)
+        let sections = [
+            (0, 0, 0, 0, 0, 0),
+            (1, 3, shstrtab_offset, shstrtab.len(), 0, 0),
+            (11, 3, strtab_offset, strtab.len(), 0, 0),
+            (19, 2, symtab_offset, symtab.len(), 2, 24),
+            (27, 11, dynsym_offset, dynsym.len(), 2, 24),
+            (35, 8, 0xffff_0000, 0x1000, 0, 0), // .bss isn't in the file
+        ];
+
+        buf.resize(shoff + sections.len() * 64, 0);
+
+        for (index, &(name, sh_type, offset, size, link, entsize)) in
+            sections.iter().enumerate() {
+
+            let o = shoff + index * 64;
+            put(&mut buf, o, &(name as u32).to_le_bytes());
+            put(&mut buf, o + 4, &(sh_type as u32).to_le_bytes());
+            put(&mut buf, o + 24, &(offset as u64).to_le_bytes());
+            put(&mut buf, o + 32, &(size as u64).to_le_bytes());
+            put(&mut buf, o + 40, &(link as u32).to_le_bytes());
+            put(&mut buf, o + 56, &(entsize as u64).to_le_bytes());
+        }
+
+        put(&mut buf, 40, &(shoff as u64).to_le_bytes());
+        put(&mut buf, 58, &64u16.to_le_bytes());
+        put(&mut buf, 60, &(sections.len() as u16).to_le_bytes());
+        put(&mut buf, 62, &1u16.to_le_bytes());
+
+        buf
+    }
+
+    #[test]
+    fn sections_are_named() {
+        let buf = with_symbols();
+        let elf64 = Elf64Le::new(&buf).unwrap();
+
+        let names: Vec<_> = elf64.sections()
+            .map(|section| section.name.unwrap())
+            .collect();
+
+        assert_eq!(names,
+            ["", ".shstrtab", ".strtab", ".symtab", ".dynsym", ".bss"]);
+
+        let bss = elf64.section_by_name(".bss").unwrap();
+
+        assert_eq!(bss.section_type, SectionType::NoBits);
+        assert_eq!((bss.size, bss.data.len()), (0x1000, 0));
+
+        let strings: Vec<_> = elf64.section_names().unwrap().iter().collect();
+
+        assert_eq!(strings[1], (1, ".shstrtab"));
+        assert_eq!(strings[5], (35, ".bss"));
+    }
+
+    #[test]
+    fn symbols_are_found_by_address() {
+        let buf = with_symbols();
+        let elf64 = Elf64Le::new(&buf).unwrap();
+
+        let name = |addr| elf64.symbol_containing(addr).map(|s| s.name);
+
+        assert_eq!(name(0x1010), Some("main"));
+        assert_eq!(name(0x1234), Some("helper"));
+        assert_eq!(name(0x1804), Some("local"));
+        assert_eq!(name(0x0fff), None);
+
+        let main = elf64.symbol_table().unwrap().get(1).unwrap();
+
+        assert_eq!(main.kind, SymbolKind::Function);
+        assert_eq!(main.binding, SymbolBinding::Global);
+    }
+
+    #[test]
+    fn stripped_files_fall_back_to_dynsym() {
+        let mut buf = with_symbols();
+
+        // Make .symtab something else.
+        let shoff = buf.len() - 6 * 64;
+        put(&mut buf, shoff + 3 * 64 + 4, &1u32.to_le_bytes());
+
+        let elf64 = Elf64Le::new(&buf).unwrap();
+
+        assert!(elf64.symbol_table().is_none());
+        assert_eq!(elf64.symbol_containing(0x1010).map(|s| s.name),
+            Some("main"));
+        assert_eq!(elf64.symbol_containing(0x1234), None);
+    }
+
+    #[test]
+    fn exports_are_defined_global_symbols() {
+        let buf = with_symbols();
+        let elf64 = Elf64Le::new(&buf).unwrap();
+
+        let exports: Vec<_> = elf64.exports().map(|s| s.name).collect();
+
+        assert_eq!(exports, ["main"]);
+    }
+
+    #[test]
+    fn writable_executable_segment_is_rejected() {
+        let mut buf = static_pie();
+        put(&mut buf, 64 + 4, &7u32.to_le_bytes()); // PF_R | PF_W | PF_X
+
+        let elf = Elf::new(&buf).unwrap();
+        let exec = elf.as_executable().unwrap();
+
+        assert_eq!(exec.validate(), Err(process::Error::InvalidImage(
+            "segment is both writable and executable")));
+    }
+
+    #[test]
+    fn writable_executable_segment_is_allowed_for_jit() {
+        let mut buf = static_pie();
+        put(&mut buf, 64 + 4, &7u32.to_le_bytes()); // PF_R | PF_W | PF_X
+
+        // Turn PT_DYNAMIC into PT_OPENBSD_WXNEEDED.
+        put(&mut buf, 64 + 56, &0x65a3dbe7u32.to_le_bytes());
+
+        let elf = Elf::new(&buf).unwrap();
+        let exec = elf.as_executable().unwrap();
+
+        assert_eq!(exec.validate(), Ok(()));
+    }
+
+    #[test]
+    fn overlapping_segments_are_rejected() {
+        let mut buf = static_pie();
+
+        // Turn PT_DYNAMIC into a second PT_LOAD inside the first one.
+        put(&mut buf, 64 + 56, &1u32.to_le_bytes());
+
+        let elf = Elf::new(&buf).unwrap();
+        let exec = elf.as_executable().unwrap();
+
+        assert_eq!(exec.validate(),
+            Err(process::Error::InvalidImage("segments overlap")));
+    }
+
+    #[test]
+    fn kernel_space_segment_is_rejected() {
+        let mut buf = static_pie();
+        put(&mut buf, 16, &2u16.to_le_bytes()); // ET_EXEC
+        put(&mut buf, 64 + 16, &0xffff_8000_0000_0000u64.to_le_bytes());
+
+        let elf = Elf::new(&buf).unwrap();
+        let exec = elf.as_executable().unwrap();
+
+        assert_eq!(exec.validate(),
+            Err(process::Error::InvalidImage("segment outside of user space")));
+    }
+
+    #[test]
+    fn truncated_program_headers_are_rejected() {
+        let buf = static_pie();
+
+        // Cuts off the PT_DYNAMIC header
+        assert!(Elf64Le::new(&buf[0..(64 + 56 + 8)]).is_none());
+
+        // Cuts off the data of the PT_LOAD
+        assert!(Elf64Le::new(&buf[0..(buf.len() - 1)]).is_none());
+    }
+
+    /// Corrupt the test image in lots of random ways, and make sure nothing
+    /// panics. This is a cheap version of the fuzz target in `kernel/fuzz`.
+    #[test]
+    fn corrupted_images_dont_panic() {
+        let original = with_symbols();
+        let mut state = 0x2545_f491_4f6c_dd1du64;
+
+        let mut next = || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as usize
+        };
+
+        for _ in 0..20000 {
+            let mut buf = original.clone();
+
+            for _ in 0..(1 + next() % 4) {
+                let index = next() % buf.len();
+
+                buf[index] = match next() % 3 {
+                    0 => next() as u8,
+                    1 => 0xff,
+                    _ => 0,
+                };
+            }
+
+            if next() % 8 == 0 {
+                let len = next() % buf.len();
+                buf.truncate(len);
+            }
+
+            exercise(&buf);
+        }
+    }
+
+    fn exercise(buf: &[u8]) {
+        let elf = match Elf::new(buf) {
+            Some(elf) => elf,
+            None => return,
+        };
+
+        if let Some(elf64) = elf.as_elf64_le() {
+            for phdr in elf64.program_headers() {
+                let mut memory = vec![0u8; phdr.mem_size.min(0x4000)];
+                let _ = phdr.load_into(&mut memory);
+            }
+
+            let _ = elf64.interpreter();
+            let _ = elf64.extent();
+            let _ = elf64.symbol_containing(0x1000);
+            let _ = elf64.exports().count();
+
+            for section in elf64.sections() {
+                let _ = StringTable::new(section.data).iter().count();
+            }
+            let _ = elf64.dynamic_entries().count();
+
+            if let Some(relocations) = elf64.relocations() {
+                let _ = relocations.count();
+            }
+        }
+
+        if let Some(exec) = elf.as_executable() {
+            let _ = exec.validate();
+            let _ = exec.is_loaded(0x1800, 8);
+            let _ = exec.aux_vector(0);
+        }
+    }
+}
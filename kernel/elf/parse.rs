@@ -0,0 +1,873 @@
+/*******************************************************************************
+ *
+ * kit/kernel/elf/parse.rs
+ *
+ * vim:ft=rust:ts=4:sw=4:et:tw=80
+ *
+ * Copyright (C) 2015-2021, Devyn Cairns
+ * Redistribution of this file is permitted under the terms of the simplified
+ * BSD license. See LICENSE for more information.
+ *
+ ******************************************************************************/
+
+//! ELF file parsing.
+//!
+//! Nothing here trusts the file: offsets, sizes and counts are all checked
+//! before they're used, and malformed input gives `None` instead of a panic.
+//! This only depends on `core`, so that it can be fuzzed on the host. See
+//! `kernel/fuzz`.
+
+use core::convert::TryInto;
+
+static MAGIC: &'static [u8] = b"\x7fELF";
+
+#[derive(Clone, Copy)]
+pub struct Elf<'a> {
+    buffer: &'a [u8],
+    ident:  [u8; 16],
+}
+
+impl<'a> Elf<'a> {
+    pub fn new(buffer: &'a [u8]) -> Option<Elf<'a>> {
+        // Require at least 16 bytes.
+        let ident: [u8; 16] = buffer.get(0..16)?.try_into().ok()?;
+
+        // Match magic string and version number (1).
+        if &ident[0..4] != MAGIC || ident[6] != 1 {
+            return None;
+        }
+
+        Some(Elf {
+            buffer: buffer,
+            ident: ident,
+        })
+    }
+
+    pub fn buffer(&self) -> &'a [u8] {
+        self.buffer
+    }
+
+    pub fn os_abi(&self) -> u8 {
+        self.ident[7]
+    }
+
+    pub fn abi_version(&self) -> u8 {
+        self.ident[8]
+    }
+
+    pub fn as_elf64_le(&self) -> Option<Elf64Le<'a>> {
+        Elf64Le::new(self.buffer)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ElfType {
+    None,
+    Relocatable,
+    Executable,
+    Dynamic,
+    CoreDump,
+    Unknown(u16),
+}
+
+impl ElfType {
+    /// The `e_type` value.
+    pub fn value(self) -> u16 {
+        match self {
+            ElfType::None        => 0,
+            ElfType::Relocatable => 1,
+            ElfType::Executable  => 2,
+            ElfType::Dynamic     => 3,
+            ElfType::CoreDump    => 4,
+            ElfType::Unknown(n)  => n,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Machine {
+    None,
+    Intel386,
+    Amd64,
+    Unknown(u16),
+}
+
+impl Machine {
+    /// The `e_machine` value.
+    pub fn value(self) -> u16 {
+        match self {
+            Machine::None       => 0,
+            Machine::Intel386   => 3,
+            Machine::Amd64      => 62,
+            Machine::Unknown(n) => n,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct Elf64Le<'a> {
+    buffer: &'a [u8],
+    header: [u8; EHDR_SIZE],
+}
+
+impl<'a> Elf64Le<'a> {
+    /// Check the file header and the program header table. Every program
+    /// header must be within the file and refer to data within it, so that
+    /// iterating over them can't fail later.
+    pub fn new(buffer: &'a [u8]) -> Option<Elf64Le<'a>> {
+        // Require at least 64 bytes.
+        let header: [u8; EHDR_SIZE] =
+            buffer.get(0..EHDR_SIZE)?.try_into().ok()?;
+
+        // Require 64-bit class.
+        if header[4] != 2 {
+            return None;
+        }
+
+        // Require little endian.
+        if header[5] != 1 {
+            return None;
+        }
+
+        let elf = Elf64Le {
+            buffer: buffer,
+            header: header,
+        };
+
+        if elf.program_header_count() > 0 &&
+            elf.program_header_size() < PHDR_SIZE {
+            return None;
+        }
+
+        for index in 0..elf.program_header_count() {
+            elf.program_header(index)?;
+        }
+
+        Some(elf)
+    }
+
+    pub fn elf_type(&self) -> ElfType {
+        match self.header_u16(16) {
+            0 => ElfType::None,
+            1 => ElfType::Relocatable,
+            2 => ElfType::Executable,
+            3 => ElfType::Dynamic,
+            4 => ElfType::CoreDump,
+            n => ElfType::Unknown(n),
+        }
+    }
+
+    pub fn machine(&self) -> Machine {
+        match self.header_u16(18) {
+            0  => Machine::None,
+            3  => Machine::Intel386,
+            62 => Machine::Amd64,
+            n  => Machine::Unknown(n),
+        }
+    }
+
+    pub fn entry(&self) -> usize {
+        self.header_u64(24) as usize
+    }
+
+    pub fn program_headers(&self) -> ElfProgramHeaders<'a> {
+        ElfProgramHeaders {
+            elf: *self,
+            index: 0,
+        }
+    }
+
+    /// The file offset of the program header table.
+    pub fn program_header_offset(&self) -> usize {
+        self.header_u64(32) as usize
+    }
+
+    pub fn program_header_count(&self) -> usize {
+        self.header_u16(56) as usize
+    }
+
+    fn program_header_size(&self) -> usize {
+        self.header_u16(54) as usize
+    }
+
+    /// Read a program header, if it's within the buffer and refers to data
+    /// within the buffer.
+    fn program_header(&self, index: usize) -> Option<ElfProgramHeader<'a>> {
+        let o = index.checked_mul(self.program_header_size())?
+            .checked_add(self.program_header_offset())?;
+
+        let entry = self.buffer.get(o..o.checked_add(PHDR_SIZE)?)?;
+
+        let flags = read_u32_at(entry, 4)?;
+
+        let data_start = read_u64_at(entry, 8)? as usize;
+        let data_end   = data_start.checked_add(read_u64_at(entry, 32)? as usize)?;
+
+        let mem_offset = read_u64_at(entry, 16)? as usize;
+        let mem_size   = read_u64_at(entry, 40)? as usize;
+
+        // The end of the segment must be representable.
+        mem_offset.checked_add(mem_size)?;
+
+        Some(ElfProgramHeader {
+            region_type: match read_u32_at(entry, 0)? {
+                0 => RegionType::Null,
+                1 => RegionType::Load,
+                2 => RegionType::Dynamic,
+                3 => RegionType::Interpreter,
+                4 => RegionType::Note,
+                6 => RegionType::ProgramHeader,
+                0x6474e551 => RegionType::GnuStack,
+                0x65a3dbe7 => RegionType::WxNeeded,
+                n => RegionType::Unknown(n),
+            },
+            readable:    flags & 4 == 4,
+            writable:    flags & 2 == 2,
+            executable:  flags & 1 == 1,
+            data:        self.buffer.get(data_start..data_end)?,
+            file_offset: data_start,
+            mem_offset:  mem_offset,
+            mem_size:    mem_size,
+        })
+    }
+
+    /// The section headers. Sections that aren't within the file are skipped.
+    pub fn sections(&self) -> SectionHeaders<'a> {
+        SectionHeaders {
+            elf: *self,
+            names: self.section_names(),
+            index: 0,
+        }
+    }
+
+    /// Find a section by name, e.g. `.text`.
+    pub fn section_by_name(&self, name: &str) -> Option<SectionHeader<'a>> {
+        self.sections().find(|section| section.name == Some(name))
+    }
+
+    /// The string table holding the section names (`.shstrtab`).
+    pub fn section_names(&self) -> Option<StringTable<'a>> {
+        let index = self.header_u16(62) as usize;
+
+        self.section(index, None)
+            .filter(|section| section.section_type == SectionType::StringTable)
+            .map(|section| StringTable::new(section.data))
+    }
+
+    /// The `.symtab` section and its string table, if present.
+    pub fn symbol_table(&self) -> Option<SymbolTable<'a>> {
+        self.symbols_of_type(SectionType::SymbolTable)
+    }
+
+    /// The `.dynsym` section and its string table, if present. These are the
+    /// symbols a shared object exports and imports, and they're still there
+    /// after the file is stripped.
+    pub fn dynamic_symbol_table(&self) -> Option<SymbolTable<'a>> {
+        self.symbols_of_type(SectionType::DynamicSymbols)
+    }
+
+    fn symbols_of_type(&self, section_type: SectionType)
+                       -> Option<SymbolTable<'a>> {
+        let symtab = self.sections()
+            .find(|section| section.section_type == section_type)?;
+
+        let strtab = self.section(symtab.link as usize, None)?;
+
+        if symtab.entry_size != SYM_SIZE {
+            return None;
+        }
+
+        Some(SymbolTable::new(symtab.data, strtab.data))
+    }
+
+    /// Find the function or object that contains `addr`, from `.symtab` if
+    /// there is one, or else `.dynsym`. See [SymbolTable::symbol_containing].
+    pub fn symbol_containing(&self, addr: usize) -> Option<Symbol<'a>> {
+        self.symbol_table()
+            .and_then(|symbols| symbols.symbol_containing(addr))
+            .or_else(|| self.dynamic_symbol_table()?.symbol_containing(addr))
+    }
+
+    /// The symbols defined by this file that other objects can link against:
+    /// global and weak symbols in `.dynsym`.
+    pub fn exports(&self) -> impl Iterator<Item=Symbol<'a>> + 'a {
+        self.dynamic_symbol_table()
+            .into_iter()
+            .flat_map(|symbols| symbols.symbols())
+            .filter(|symbol| symbol.is_defined() &&
+                symbol.binding != SymbolBinding::Local &&
+                symbol.kind != SymbolKind::Section &&
+                symbol.kind != SymbolKind::File)
+    }
+
+    /// True if there's a `PT_INTERP` header, i.e. the program must be started
+    /// by a dynamic linker.
+    pub fn has_interpreter(&self) -> bool {
+        self.interpreter().is_some()
+    }
+
+    /// The path of the dynamic linker from the `PT_INTERP` header, including
+    /// its terminating NUL.
+    pub fn interpreter(&self) -> Option<&'a [u8]> {
+        self.program_headers()
+            .find(|phdr| phdr.region_type == RegionType::Interpreter)
+            .map(|phdr| phdr.data)
+    }
+
+    /// True if there's a `PT_OPENBSD_WXNEEDED` header, i.e. the program asks to
+    /// be allowed writable and executable memory, like a JIT compiler would.
+    pub fn wants_jit(&self) -> bool {
+        self.program_headers()
+            .any(|phdr| phdr.region_type == RegionType::WxNeeded)
+    }
+
+    /// The `(tag, value)` entries of the `PT_DYNAMIC` segment, up to `DT_NULL`.
+    /// Empty if there isn't one.
+    pub fn dynamic_entries(&self) -> impl Iterator<Item=(u64, u64)> + 'a {
+        let data = self.program_headers()
+            .find(|phdr| phdr.region_type == RegionType::Dynamic)
+            .map(|phdr| phdr.data)
+            .unwrap_or(&[]);
+
+        data.chunks_exact(DYN_SIZE)
+            .filter_map(|entry| {
+                Some((read_u64_at(entry, 0)?, read_u64_at(entry, 8)?))
+            })
+            .take_while(|&(tag, _)| tag != DT_NULL)
+    }
+
+    /// The `DT_RELA` relocations, or `None` if the dynamic section refers to a
+    /// table that isn't in the file.
+    pub fn relocations(&self) -> Option<Relocations<'a>> {
+        let mut rela = None;
+        let mut rela_size = 0;
+        let mut rela_entry_size = RELA_SIZE;
+
+        for (tag, value) in self.dynamic_entries() {
+            match tag {
+                DT_RELA    => rela = Some(value as usize),
+                DT_RELASZ  => rela_size = value as usize,
+                DT_RELAENT => rela_entry_size = value as usize,
+                _ => ()
+            }
+        }
+
+        let table = match rela {
+            Some(vaddr) => self.file_data_at(vaddr, rela_size)?,
+            None => &[],
+        };
+
+        if rela_entry_size != RELA_SIZE {
+            return None;
+        }
+
+        Some(Relocations { table })
+    }
+
+    /// The lowest address and the end of the highest address of the `PT_LOAD`
+    /// segments, or `None` if there aren't any.
+    pub fn extent(&self) -> Option<(usize, usize)> {
+        let loads = || self.program_headers()
+            .filter(|phdr| phdr.region_type == RegionType::Load);
+
+        // Segment ends can't overflow; that's checked in program_header().
+        let lowest = loads().map(|phdr| phdr.mem_offset).min()?;
+        let end = loads().map(|phdr| phdr.mem_offset + phdr.mem_size).max()?;
+
+        Some((lowest, end))
+    }
+
+    /// Find the file data that gets loaded at `vaddr..(vaddr + len)`.
+    pub fn file_data_at(&self, vaddr: usize, len: usize) -> Option<&'a [u8]> {
+        self.program_headers()
+            .filter(|phdr| phdr.region_type == RegionType::Load)
+            .find_map(|phdr| {
+                let start = vaddr.checked_sub(phdr.mem_offset)?;
+                phdr.data.get(start..start.checked_add(len)?)
+            })
+    }
+
+    fn section_count(&self) -> usize {
+        self.header_u16(60) as usize
+    }
+
+    /// Read a section header, if it's within the buffer and refers to data
+    /// within the buffer. The name is looked up in `names` if given.
+    fn section(&self, index: usize, names: Option<&StringTable<'a>>)
+               -> Option<SectionHeader<'a>> {
+        let e_shoff     = self.header_u64(40) as usize;
+        let e_shentsize = self.header_u16(58) as usize;
+
+        if index >= self.section_count() || e_shentsize < SHDR_SIZE {
+            return None;
+        }
+
+        let o = index.checked_mul(e_shentsize)?.checked_add(e_shoff)?;
+
+        let entry = self.buffer.get(o..o.checked_add(SHDR_SIZE)?)?;
+
+        let section_type = match read_u32_at(entry, 4)? {
+            0  => SectionType::Null,
+            1  => SectionType::ProgramBits,
+            2  => SectionType::SymbolTable,
+            3  => SectionType::StringTable,
+            4  => SectionType::Rela,
+            5  => SectionType::Hash,
+            6  => SectionType::Dynamic,
+            7  => SectionType::Note,
+            8  => SectionType::NoBits,
+            9  => SectionType::Rel,
+            11 => SectionType::DynamicSymbols,
+            n  => SectionType::Unknown(n),
+        };
+
+        let offset = read_u64_at(entry, 24)? as usize;
+        let size   = read_u64_at(entry, 32)? as usize;
+
+        // NOBITS sections (.bss) take up no room in the file.
+        let data = match section_type {
+            SectionType::NoBits | SectionType::Null => &[],
+            _ => self.buffer.get(offset..offset.checked_add(size)?)?,
+        };
+
+        let name = read_u32_at(entry, 0)? as usize;
+
+        Some(SectionHeader {
+            index:        index,
+            name:         names.and_then(|names| names.get(name)),
+            section_type: section_type,
+            flags:        read_u64_at(entry, 8)?,
+            addr:         read_u64_at(entry, 16)? as usize,
+            offset:       offset,
+            size:         size,
+            link:         read_u32_at(entry, 40)?,
+            info:         read_u32_at(entry, 44)?,
+            align:        read_u64_at(entry, 48)? as usize,
+            entry_size:   read_u64_at(entry, 56)? as usize,
+            data:         data,
+        })
+    }
+
+    /// The header is always all there, so reading from it at a fixed offset
+    /// can't fail.
+    fn header_u16(&self, offset: usize) -> u16 {
+        read_u16_at(&self.header, offset).unwrap_or(0)
+    }
+
+    fn header_u64(&self, offset: usize) -> u64 {
+        read_u64_at(&self.header, offset).unwrap_or(0)
+    }
+}
+
+const EHDR_SIZE: usize = 64;
+const SHDR_SIZE: usize = 64;
+const SYM_SIZE: usize = 24;
+
+pub const PHDR_SIZE: usize = 56;
+
+const DYN_SIZE: usize = 16;
+const RELA_SIZE: usize = 24;
+
+pub const DT_NULL: u64 = 0;
+pub const DT_RELA: u64 = 7;
+pub const DT_RELASZ: u64 = 8;
+pub const DT_RELAENT: u64 = 9;
+
+pub const R_X86_64_NONE: u32 = 0;
+pub const R_X86_64_RELATIVE: u32 = 8;
+
+/// Iterates over the section headers.
+pub struct SectionHeaders<'a> {
+    elf: Elf64Le<'a>,
+    names: Option<StringTable<'a>>,
+    index: usize,
+}
+
+impl<'a> Iterator for SectionHeaders<'a> {
+    type Item = SectionHeader<'a>;
+
+    fn next(&mut self) -> Option<SectionHeader<'a>> {
+        while self.index < self.elf.section_count() {
+            self.index += 1;
+
+            if let Some(section) =
+                self.elf.section(self.index - 1, self.names.as_ref()) {
+                return Some(section);
+            }
+        }
+
+        None
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SectionType {
+    Null,
+    ProgramBits,
+    SymbolTable,
+    StringTable,
+    Rela,
+    Hash,
+    Dynamic,
+    Note,
+    NoBits,
+    Rel,
+    DynamicSymbols,
+    Unknown(u32),
+}
+
+impl SectionType {
+    /// The `sh_type` value.
+    pub fn value(self) -> u32 {
+        match self {
+            SectionType::Null           => 0,
+            SectionType::ProgramBits    => 1,
+            SectionType::SymbolTable    => 2,
+            SectionType::StringTable    => 3,
+            SectionType::Rela           => 4,
+            SectionType::Hash           => 5,
+            SectionType::Dynamic        => 6,
+            SectionType::Note           => 7,
+            SectionType::NoBits         => 8,
+            SectionType::Rel            => 9,
+            SectionType::DynamicSymbols => 11,
+            SectionType::Unknown(n)     => n,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SectionHeader<'a> {
+    pub index:        usize,
+    /// `None` if there's no section name table or the name isn't in it.
+    pub name:         Option<&'a str>,
+    pub section_type: SectionType,
+    /// Section flags (`sh_flags`)
+    pub flags:        u64,
+    pub addr:         usize,
+    pub offset:       usize,
+    pub size:         usize,
+    pub link:         u32,
+    pub info:         u32,
+    pub align:        usize,
+    pub entry_size:   usize,
+    /// The contents in the file. Empty for `NOBITS` sections.
+    pub data:         &'a [u8],
+}
+
+/// A table of NUL-terminated strings, such as `.strtab`. Strings are referred
+/// to by their byte offset in the table.
+#[derive(Clone, Copy)]
+pub struct StringTable<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> StringTable<'a> {
+    pub fn new(data: &'a [u8]) -> StringTable<'a> {
+        StringTable { data }
+    }
+
+    /// Read the string starting at `offset`.
+    pub fn get(&self, offset: usize) -> Option<&'a str> {
+        let bytes = self.data.get(offset..)?;
+        let len = bytes.iter().position(|&b| b == 0)?;
+
+        core::str::from_utf8(&bytes[..len]).ok()
+    }
+
+    /// All of the `(offset, string)` pairs in the table, skipping any that
+    /// aren't valid UTF-8.
+    pub fn iter(&self) -> impl Iterator<Item=(usize, &'a str)> + 'a {
+        let data = self.data;
+
+        data.split(|&b| b == 0)
+            .scan(0, |offset, bytes| {
+                let start = *offset;
+                *offset += bytes.len() + 1;
+                Some((start, bytes))
+            })
+            .take_while(move |&(start, _)| start < data.len())
+            .filter_map(|(start, bytes)| {
+                Some((start, core::str::from_utf8(bytes).ok()?))
+            })
+    }
+}
+
+/// An ELF64 symbol table (`Elf64_Sym` entries) and the string table its
+/// names refer to.
+#[derive(Clone, Copy)]
+pub struct SymbolTable<'a> {
+    symbols: &'a [u8],
+    strings: StringTable<'a>,
+}
+
+impl<'a> SymbolTable<'a> {
+    pub fn new(symbols: &'a [u8], strings: &'a [u8]) -> SymbolTable<'a> {
+        SymbolTable { symbols, strings: StringTable::new(strings) }
+    }
+
+    /// Read the symbol at `index`, as referred to by relocations.
+    pub fn get(&self, index: usize) -> Option<Symbol<'a>> {
+        let o = index.checked_mul(SYM_SIZE)?;
+
+        self.read(self.symbols.get(o..o.checked_add(SYM_SIZE)?)?)
+    }
+
+    /// All of the symbols, skipping the null symbol.
+    pub fn symbols(&self) -> impl Iterator<Item=Symbol<'a>> + 'a {
+        let table = *self;
+
+        self.symbols.chunks_exact(SYM_SIZE).skip(1)
+            .filter_map(move |entry| table.read(entry))
+    }
+
+    /// Find the function or object that contains `addr`, or the closest
+    /// unsized symbol before it.
+    pub fn symbol_containing(&self, addr: usize) -> Option<Symbol<'a>> {
+        let mut best: Option<Symbol<'a>> = None;
+
+        for symbol in self.symbols() {
+            let (value, size) = (symbol.value, symbol.size);
+
+            // Only defined data, code, and untyped (assembly) symbols
+            let kind_ok = match symbol.kind {
+                SymbolKind::NoType |
+                SymbolKind::Object |
+                SymbolKind::Function => true,
+                _ => false,
+            };
+
+            if !symbol.is_defined() || !kind_ok || value > addr ||
+                symbol.name.is_empty() {
+                continue;
+            }
+
+            let contains = addr - value < size;
+
+            let better = match best {
+                _ if size != 0 && !contains => false,
+                Some(ref best) if best.size != 0 => false,
+                Some(ref best) => contains || value > best.value,
+                None => true,
+            };
+
+            if better {
+                best = Some(symbol);
+            }
+        }
+
+        best
+    }
+
+    fn read(&self, entry: &[u8]) -> Option<Symbol<'a>> {
+        let info = *entry.get(4)?;
+
+        Some(Symbol {
+            // Unnamed, or the name isn't readable
+            name:    self.strings.get(read_u32_at(entry, 0)? as usize)
+                .unwrap_or(""),
+            value:   read_u64_at(entry, 8)? as usize,
+            size:    read_u64_at(entry, 16)? as usize,
+            kind:    match info & 0xf {
+                0 => SymbolKind::NoType,
+                1 => SymbolKind::Object,
+                2 => SymbolKind::Function,
+                3 => SymbolKind::Section,
+                4 => SymbolKind::File,
+                n => SymbolKind::Unknown(n),
+            },
+            binding: match info >> 4 {
+                0 => SymbolBinding::Local,
+                1 => SymbolBinding::Global,
+                2 => SymbolBinding::Weak,
+                n => SymbolBinding::Unknown(n),
+            },
+            section: read_u16_at(entry, 6)?,
+        })
+    }
+}
+
+fn read_u16_at(bytes: &[u8], offset: usize) -> Option<u16> {
+    let bytes = bytes.get(offset..offset.checked_add(2)?)?;
+    Some(u16::from_le_bytes(bytes.try_into().ok()?))
+}
+
+fn read_u32_at(bytes: &[u8], offset: usize) -> Option<u32> {
+    let bytes = bytes.get(offset..offset.checked_add(4)?)?;
+    Some(u32::from_le_bytes(bytes.try_into().ok()?))
+}
+
+fn read_u64_at(bytes: &[u8], offset: usize) -> Option<u64> {
+    let bytes = bytes.get(offset..offset.checked_add(8)?)?;
+    Some(u64::from_le_bytes(bytes.try_into().ok()?))
+}
+
+/// A symbol from the symbol table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Symbol<'a> {
+    pub name:    &'a str,
+    pub value:   usize,
+    pub size:    usize,
+    pub kind:    SymbolKind,
+    pub binding: SymbolBinding,
+    /// Index of the section it's defined in, or `SHN_UNDEF` (0).
+    pub section: u16,
+}
+
+impl<'a> Symbol<'a> {
+    /// False if the symbol refers to something in another object.
+    pub fn is_defined(&self) -> bool {
+        self.section != SHN_UNDEF
+    }
+}
+
+pub const SHN_UNDEF: u16 = 0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SymbolKind {
+    NoType,
+    Object,
+    Function,
+    Section,
+    File,
+    Unknown(u8),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SymbolBinding {
+    Local,
+    Global,
+    Weak,
+    Unknown(u8),
+}
+
+/// Iterates over a table of `Elf64_Rela` entries.
+pub struct Relocations<'a> {
+    table: &'a [u8],
+}
+
+impl<'a> Iterator for Relocations<'a> {
+    type Item = Relocation;
+
+    fn next(&mut self) -> Option<Relocation> {
+        let entry = self.table.get(0..RELA_SIZE)?;
+        self.table = &self.table[RELA_SIZE..];
+
+        let info = read_u64_at(entry, 8)?;
+
+        Some(Relocation {
+            offset: read_u64_at(entry, 0)? as usize,
+            kind:   info as u32,
+            symbol: (info >> 32) as u32,
+            addend: read_u64_at(entry, 16)? as i64,
+        })
+    }
+}
+
+/// A relocation with an addend.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Relocation {
+    /// Where to apply it, as a link-time virtual address.
+    pub offset: usize,
+    /// The `R_X86_64_*` relocation type.
+    pub kind:   u32,
+    /// Index into the dynamic symbol table.
+    pub symbol: u32,
+    pub addend: i64,
+}
+
+pub struct ElfProgramHeaders<'a> {
+    elf: Elf64Le<'a>,
+    index: usize,
+}
+
+impl<'a> Iterator for ElfProgramHeaders<'a> {
+    type Item = ElfProgramHeader<'a>;
+
+    fn next(&mut self) -> Option<ElfProgramHeader<'a>> {
+        if self.index < self.elf.program_header_count() {
+            self.index += 1;
+
+            // Checked by Elf64Le::new(), so this won't end early.
+            self.elf.program_header(self.index - 1)
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegionType {
+    Null,
+    Load,
+    Dynamic,
+    Interpreter,
+    Note,
+    ProgramHeader,
+    /// `PT_GNU_STACK`: the permissions and size the program wants its stack
+    /// to have.
+    GnuStack,
+    /// `PT_OPENBSD_WXNEEDED`, from `ld -z wxneeded`: the program needs memory
+    /// that's both writable and executable, to generate code in.
+    WxNeeded,
+    Unknown(u32),
+}
+
+impl RegionType {
+    /// The `p_type` value.
+    pub fn value(self) -> u32 {
+        match self {
+            RegionType::Null          => 0,
+            RegionType::Load          => 1,
+            RegionType::Dynamic       => 2,
+            RegionType::Interpreter   => 3,
+            RegionType::Note          => 4,
+            RegionType::ProgramHeader => 6,
+            RegionType::GnuStack      => 0x6474e551,
+            RegionType::WxNeeded      => 0x65a3dbe7,
+            RegionType::Unknown(n)    => n,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct ElfProgramHeader<'a> {
+    pub region_type: RegionType,
+    pub readable:    bool,
+    pub writable:    bool,
+    pub executable:  bool,
+    pub data:        &'a [u8],
+    pub file_offset: usize,
+    pub mem_offset:  usize,
+    pub mem_size:    usize,
+}
+
+impl<'a> ElfProgramHeader<'a> {
+    /// Copy the data referenced by the program header into the destination
+    /// buffer, and zero the rest of `mem_size`.
+    ///
+    /// Returns `Err` with the number of bytes that don't fit: either the
+    /// destination is smaller than `mem_size`, or the header is invalid and
+    /// has more data than `mem_size`.
+    pub fn load_into(&self, destination: &mut [u8]) -> Result<(), usize> {
+        if self.data.len() > self.mem_size {
+            return Err(self.data.len() - self.mem_size);
+        }
+
+        if destination.len() < self.mem_size {
+            return Err(self.mem_size - destination.len());
+        }
+
+        let destination = &mut destination[0..self.mem_size];
+
+        let (data, zeroes) = destination.split_at_mut(self.data.len());
+
+        data.copy_from_slice(self.data);
+
+        for byte in zeroes {
+            *byte = 0;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,391 @@
+/*******************************************************************************
+ *
+ * kit/kernel/fault.rs
+ *
+ * vim:ft=rust:ts=4:sw=4:et:tw=80
+ *
+ * Copyright (C) 2015-2021, Devyn Cairns
+ * Redistribution of this file is permitted under the terms of the simplified
+ * BSD license. See LICENSE for more information.
+ *
+ ******************************************************************************/
+
+//! Reports of faults in user processes.
+//!
+//! A report shows the process, what kind of fault it was, the saved registers,
+//! and a backtrace found by following the frame pointer chain on the user
+//! stack. Addresses are resolved with the `.symtab` of the program's ELF file
+//! in the system archive, if it has one.
+//!
+//! All page faults come here first: see [handle_page_fault]. Those on
+//! demand-paged memory, just below the stack, and on copy-on-write pages are
+//! resolved by the process's [Vma](crate::process::vma::Vma)s, and the rest are
+//! reported with the reason they were rejected.
+
+use core::fmt::{self, Write};
+use core::convert::TryInto;
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use crate::archive;
+use crate::backtrace;
+use crate::c_ffi::cstring_from_str;
+use crate::c_ffi::CStr;
+use crate::elf::Elf;
+use crate::interrupt::InterruptStack;
+use crate::paging;
+use crate::process::{self, ProcessMem};
+use crate::process::vma::Access;
+use crate::process::target::USER_ADDR_LIMIT;
+use crate::terminal::console;
+
+/// Maximum number of stack frames to show.
+const MAX_FRAMES: usize = 32;
+
+/// A processor exception that can be taken in user mode.
+#[derive(Debug, Clone)]
+pub enum Fault {
+    GeneralProtection { error_code: u64 },
+    /// A page fault that couldn't be resolved, because of `reason`.
+    PageFault {
+        address: usize,
+        error: PageFaultError,
+        reason: process::Error,
+    },
+    /// A page fault in the guard below the stack.
+    StackOverflow { address: usize },
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Fault::GeneralProtection { error_code: 0 } =>
+                write!(f, "general protection fault"),
+            Fault::GeneralProtection { error_code } =>
+                write!(f, "general protection fault, selector {:#x}",
+                    error_code),
+            Fault::PageFault { address, error, ref reason } =>
+                write!(f, "page fault at {:#018x}: {} ({})",
+                    address, error, reason),
+            Fault::StackOverflow { address } =>
+                write!(f, "stack overflow at {:#018x}", address),
+        }
+    }
+}
+
+/// The error code pushed by a page fault.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PageFaultError(pub u64);
+
+impl PageFaultError {
+    /// The page was present, so the fault was a protection violation.
+    pub fn is_protection_violation(self) -> bool { self.0 & 1 != 0 }
+
+    /// The access was a write.
+    pub fn is_write(self) -> bool { self.0 & 2 != 0 }
+
+    /// The access was made in user mode.
+    pub fn is_user(self) -> bool { self.0 & 4 != 0 }
+
+    /// A reserved bit was set in a page table entry.
+    pub fn is_reserved_bit(self) -> bool { self.0 & 8 != 0 }
+
+    /// The access was an instruction fetch.
+    pub fn is_instruction_fetch(self) -> bool { self.0 & 16 != 0 }
+
+    /// The kind of access that faulted.
+    pub fn access(self) -> Access {
+        if self.is_instruction_fetch() { Access::Execute }
+        else if self.is_write() { Access::Write }
+        else { Access::Read }
+    }
+}
+
+impl fmt::Display for PageFaultError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let access =
+            if self.is_instruction_fetch() { "instruction fetch" }
+            else if self.is_write() { "write" }
+            else { "read" };
+
+        let cause =
+            if self.is_protection_violation() { "protection violation" }
+            else { "page not present" };
+
+        let mode = if self.is_user() { "user" } else { "supervisor" };
+
+        write!(f, "{} on {} in {} mode", cause, access, mode)?;
+
+        if self.is_reserved_bit() {
+            write!(f, ", reserved bit set")?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Try to resolve a page fault at `address` by filling in a demand-paged page
+/// of the current process, growing its stack, or copying a page it shares with
+/// another process since a fork. If that works, the access can be retried.
+///
+/// Faults in kernel mode are resolved too, as long as they're on a user address
+/// and the process's memory isn't locked: that happens when a system call
+/// touches a user buffer that hasn't been used yet.
+pub fn resolve_page_fault(address: usize, error: PageFaultError)
+                          -> Result<(), process::Error> {
+    let copy_on_write = error.is_protection_violation() && error.is_write();
+
+    if address >= USER_ADDR_LIMIT || error.is_reserved_bit() ||
+        (error.is_protection_violation() && !copy_on_write) {
+        return Err(process::Error::ProtectionViolation(address));
+    }
+
+    let mem = process::current().try_lock().and_then(|p| p.mem())
+        .ok_or(process::Error::NotMapped(address))?;
+
+    let mut mem = mem.try_lock().ok_or(process::Error::NotMapped(address))?;
+
+    // The fault must be in this process's address space.
+    let current = unsafe { paging::current_pageset() };
+
+    if !current.map_or(false, |current| Arc::ptr_eq(&current, &mem.pageset())) {
+        return Err(process::Error::NotMapped(address));
+    }
+
+    mem.handle_page_fault(address, error.access())
+}
+
+/// Handle a page fault at `address` taken with `stack`.
+///
+/// If it can't be resolved, a user process is sent a report and killed with
+/// `SIG_STACK_OVERFLOW` or `SIG_BAD_MEM_ACCESS`. In kernel mode, that's a bug,
+/// so the kernel panics after showing where it happened.
+pub fn handle_page_fault(stack: &InterruptStack, address: usize) {
+    let error = PageFaultError(stack.err_code);
+
+    let reason = match resolve_page_fault(address, error) {
+        Ok(()) => return,
+        Err(reason) => reason,
+    };
+
+    debug!("unresolved page fault at {:#x}: {}", address, reason);
+
+    let (fault, signal) = match reason {
+        process::Error::StackOverflow(_) =>
+            (Fault::StackOverflow { address }, process::SIG_STACK_OVERFLOW),
+        reason =>
+            (Fault::PageFault { address, error, reason },
+                process::SIG_BAD_MEM_ACCESS),
+    };
+
+    if stack.is_user() {
+        report_user(fault, stack);
+
+        let id = process::current().lock().id();
+
+        let _ = process::signal(id, signal);
+    } else {
+        error!("{} in kernel mode, rip={:#x}", fault, stack.rip);
+
+        backtrace::print_interrupted(stack);
+
+        panic!("{} in kernel mode, rip={:#x}", fault, stack.rip);
+    }
+}
+
+/// Print a report of a fault taken by the current process in user mode to the
+/// console.
+pub fn report_user(fault: Fault, stack: &InterruptStack) {
+    let (id, name, mem) = {
+        let process = process::current();
+        let process = process.lock();
+        (process.id(), process.name(), process.mem())
+    };
+
+    info!("process {} ({}): {}, rip={:#x}", id, name, fault, stack.rip);
+
+    let (frames, image_base) = match mem {
+        Some(ref mem) => {
+            let mut mem = mem.lock();
+            (walk_stack(&mut mem, stack), mem.image_base())
+        },
+        None => (vec![stack.rip as usize], 0),
+    };
+
+    // Look up the program the process was spawned from.
+    let archive = archive::system();
+    let filename = cstring_from_str(&name);
+    let elf = archive.get(CStr::new(&filename)).and_then(Elf::new);
+    let elf64 = elf.as_ref().and_then(|elf| elf.as_elf64_le());
+
+    // Symbols have link-time addresses.
+    let symbolize = |addr: usize| {
+        let addr = addr.wrapping_sub(image_base);
+
+        elf64.as_ref()?.symbol_containing(addr)
+            .map(|symbol| (symbol.name, addr - symbol.value))
+    };
+
+    let _ = write_report(&mut *console(), id, &name, fault, stack, &frames,
+        symbolize);
+}
+
+fn write_report<'a, W, F>(out: &mut W,
+                          id: process::Id,
+                          name: &str,
+                          fault: Fault,
+                          stack: &InterruptStack,
+                          frames: &[usize],
+                          symbolize: F)
+                          -> fmt::Result
+    where W: Write + ?Sized, F: Fn(usize) -> Option<(&'a str, usize)> {
+
+    writeln!(out, "Process {} ({}) crashed: {}", id, name, fault)?;
+
+    let s = stack;
+
+    for line in &[
+        [("rax", s.rax), ("rbx", s.rbx), ("rcx", s.rcx)],
+        [("rdx", s.rdx), ("rsi", s.rsi), ("rdi", s.rdi)],
+        [("rbp", s.rbp), ("rsp", s.user_rsp), ("r8 ", s.r8)],
+        [("r9 ", s.r9), ("r10", s.r10), ("r11", s.r11)],
+        [("r12", s.r12), ("r13", s.r13), ("r14", s.r14)],
+        [("r15", s.r15), ("rip", s.rip), ("flg", s.rflags)],
+    ] {
+        for (register, value) in line {
+            write!(out, "  {}={:016x}", register, value)?;
+        }
+        writeln!(out)?;
+    }
+
+    writeln!(out, "Backtrace:")?;
+
+    for (index, &addr) in frames.iter().enumerate() {
+        write!(out, "  #{:<2} {:#018x}", index, addr)?;
+
+        // Return addresses point after the call, which might be the start of
+        // the next function. Look up the call instead.
+        let lookup_addr = if index == 0 { addr } else { addr.wrapping_sub(1) };
+
+        if let Some((symbol, offset)) = symbolize(lookup_addr) {
+            write!(out, " {}+{:#x}", symbol, offset + (addr - lookup_addr))?;
+        }
+
+        writeln!(out)?;
+    }
+
+    Ok(())
+}
+
+/// Find the instruction pointer and return addresses by following saved frame
+/// pointers up the user stack.
+fn walk_stack(mem: &mut ProcessMem, stack: &InterruptStack) -> Vec<usize> {
+    let mut frames = vec![stack.rip as usize];
+    let mut rbp = stack.rbp as usize;
+
+    while frames.len() < MAX_FRAMES && rbp != 0 && rbp % 8 == 0 {
+        // [rbp] = caller's rbp, [rbp + 8] = return address
+        let mut frame = [0u8; 16];
+
+        if mem.copy_out(rbp, &mut frame).is_err() {
+            break;
+        }
+
+        let next_rbp = usize::from_le_bytes(frame[0..8].try_into().unwrap());
+        let return_addr =
+            usize::from_le_bytes(frame[8..16].try_into().unwrap());
+
+        if return_addr == 0 {
+            break;
+        }
+
+        frames.push(return_addr);
+
+        // The stack grows down, so callers' frames must be higher up.
+        if next_rbp <= rbp {
+            break;
+        }
+
+        rbp = next_rbp;
+    }
+
+    frames
+}
+
+/// C interface. See `kit/kernel/include/fault.h`.
+pub mod ffi {
+    use super::*;
+
+    const INDEX_GENERAL_PROTECTION: u64 = 0xd;
+
+    /// Report a general protection fault taken in user mode.
+    #[no_mangle]
+    pub unsafe extern fn fault_report_user(stack: *const InterruptStack) {
+        let stack = &*stack;
+
+        if stack.index == INDEX_GENERAL_PROTECTION {
+            report_user(Fault::GeneralProtection {
+                error_code: stack.err_code
+            }, stack);
+        }
+    }
+
+    /// Handle a page fault (0xe) from either mode.
+    #[no_mangle]
+    pub unsafe extern fn fault_handle_page(stack: *const InterruptStack) {
+        let cr2: usize;
+
+        asm!("mov {}, cr2", out(reg) cr2, options(nomem, nostack));
+
+        handle_page_fault(&*stack, cr2);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use alloc::string::String;
+
+    #[test]
+    fn page_fault_error_decoding() {
+        assert_eq!(format!("{}", PageFaultError(0x4)),
+            "page not present on read in user mode");
+        assert_eq!(format!("{}", PageFaultError(0x7)),
+            "protection violation on write in user mode");
+        assert_eq!(format!("{}", PageFaultError(0x15)),
+            "protection violation on instruction fetch in user mode");
+        assert_eq!(format!("{}", PageFaultError(0xa)),
+            "page not present on write in supervisor mode, reserved bit set");
+    }
+
+    #[test]
+    fn report_symbolizes_return_addresses() {
+        let stack = InterruptStack {
+            index: 0xe, rip: 0x401010, ..Default::default()
+        };
+
+        let fault = Fault::PageFault {
+            address: 0,
+            error: PageFaultError(0x6),
+            reason: process::Error::NotMapped(0),
+        };
+
+        let mut out = String::new();
+
+        write_report(&mut out, 3, "bin/crashy", fault, &stack,
+            &[0x401010, 0x402000],
+            |addr| match addr {
+                0x401000..=0x40100f => None,
+                0x401010..=0x401fff => Some(("crash", addr - 0x401010)),
+                _ => None,
+            }).unwrap();
+
+        assert!(out.starts_with("Process 3 (bin/crashy) crashed: page fault \
+            at 0x0000000000000000: page not present on write in user mode \
+            (Address 0x0 is not mapped for user access)\n"));
+        assert!(out.contains("#0  0x0000000000401010 crash+0x0\n"));
+        assert!(out.contains("#1  0x0000000000402000 crash+0xff0\n"));
+    }
+}
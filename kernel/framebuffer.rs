@@ -15,7 +15,7 @@ use core::slice;
 use alloc::vec::Vec;
 
 use crate::terminal::VgaConfig;
-use crate::paging::{PAGE_SIZE, PagesetExt, kernel_pageset, PageType};
+use crate::paging::{PAGE_SIZE, GenericPageset, kernel_pageset, PageType};
 use crate::util::align_up;
 use crate::sync::Spinlock;
 
@@ -259,9 +259,10 @@ impl LinearFramebuffer {
     pub unsafe fn map(config: LinearPixelConfig, paddr: usize)
         -> LinearFramebuffer {
 
-        kernel_pageset().map_pages_with_type(
+        kernel_pageset().map_contiguous(
             config.buffer as usize,
-            (paddr..).step_by(PAGE_SIZE).take(config.size_in_pages()),
+            paddr,
+            config.size_in_pages(),
             PageType::default().writable()).unwrap();
 
         LinearFramebuffer::new(config)
@@ -0,0 +1,77 @@
+/*******************************************************************************
+ *
+ * kit/kernel/fuzz/fuzz_targets/elf.rs
+ *
+ * vim:ft=rust:ts=4:sw=4:et:tw=80
+ *
+ * Copyright (C) 2015-2021, Devyn Cairns
+ * Redistribution of this file is permitted under the terms of the simplified
+ * BSD license. See LICENSE for more information.
+ *
+ ******************************************************************************/
+
+//! Feeds arbitrary bytes to the kernel's ELF parser, which must never panic.
+//!
+//! Run with `make fuzz-kernel`, or `cargo +nightly fuzz run elf` from here.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+#[path = "../../elf/parse.rs"]
+#[allow(dead_code)]
+mod parse;
+
+use parse::*;
+
+fuzz_target!(|data: &[u8]| {
+    let elf = match Elf::new(data) {
+        Some(elf) => elf,
+        None => return,
+    };
+
+    let _ = (elf.os_abi(), elf.abi_version());
+
+    let elf64 = match elf.as_elf64_le() {
+        Some(elf64) => elf64,
+        None => return,
+    };
+
+    let _ = (elf64.elf_type(), elf64.machine(), elf64.entry());
+
+    for phdr in elf64.program_headers() {
+        // Don't let huge bss sizes run the fuzzer out of memory.
+        let mut memory = vec![0u8; phdr.mem_size.min(1 << 20)];
+
+        if phdr.load_into(&mut memory).is_ok() {
+            assert_eq!(&memory[0..phdr.data.len()], phdr.data);
+        }
+    }
+
+    let _ = elf64.interpreter();
+    let _ = elf64.extent();
+    let _ = elf64.dynamic_entries().count();
+
+    if let Some(relocations) = elf64.relocations() {
+        let _ = relocations.count();
+    }
+
+    for section in elf64.sections() {
+        let _ = StringTable::new(section.data).iter().count();
+    }
+
+    let _ = elf64.section_by_name(".text");
+
+    let tables = [elf64.symbol_table(), elf64.dynamic_symbol_table()];
+
+    for symbols in tables.iter().flatten() {
+        let _ = symbols.symbols().count();
+        let _ = symbols.get(1);
+
+        for addr in &[0, elf64.entry(), usize::MAX] {
+            let _ = symbols.symbol_containing(*addr);
+        }
+    }
+
+    let _ = elf64.exports().count();
+});
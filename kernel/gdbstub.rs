@@ -0,0 +1,805 @@
+/*******************************************************************************
+ *
+ * kit/kernel/gdbstub.rs
+ *
+ * vim:ft=rust:ts=4:sw=4:et:tw=80
+ *
+ * Copyright (C) 2015-2021, Devyn Cairns
+ * Redistribution of this file is permitted under the terms of the simplified
+ * BSD license. See LICENSE for more information.
+ *
+ ******************************************************************************/
+
+//! GDB remote serial protocol stub.
+//!
+//! Enabled with `gdbstub=com2` on the kernel command line. The kernel stops
+//! during boot until gdb connects and continues it. With QEMU:
+//!
+//! ```text
+//! qemu-system-x86_64 ... -serial stdio -serial tcp::1234,server,nowait
+//! gdb build/kernel.elf -ex 'target remote :1234'
+//! ```
+//!
+//! The stub runs inside the trap handler with interrupts disabled, polling the
+//! serial port, so the rest of the system is frozen while gdb is in control.
+//! Pressing Ctrl-C in gdb stops the kernel at the next timer tick, and a kernel
+//! panic stops it for good: continuing only reports the panic again.
+//!
+//! Processes are listed as threads, with thread ID = process ID + 1, because
+//! gdb reserves thread ID 0. The thread that stopped has its full register
+//! state, which can be modified; other threads show the kernel registers saved
+//! when they were last switched out. Lower half memory is accessed through the
+//! selected thread's address space.
+
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::cmdline::Cmdline;
+use crate::interrupt::InterruptStack;
+use crate::paging::{self, target::KERNEL_SPACE_START};
+use crate::process::{self, Id, RcProcess};
+use crate::process::target::{GeneralRegisters, USER_ADDR_LIMIT};
+use crate::process::target::{EFLAGS_TF, USER_EFLAGS_MASK};
+use crate::serial::{self, SerialPort};
+use crate::sync::Spinlock;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Set when gdb asks for a single step, until the debug trap arrives.
+static STEPPING: AtomicBool = AtomicBool::new(false);
+
+static BREAKPOINTS: Spinlock<[Option<Breakpoint>; MAX_BREAKPOINTS]> =
+    Spinlock::new([None; MAX_BREAKPOINTS]);
+
+const MAX_BREAKPOINTS: usize = 32;
+
+/// Largest packet we accept, as advertised to gdb.
+const PACKET_SIZE: usize = 1024;
+
+const SIGINT: u8 = 2;
+const SIGTRAP: u8 = 5;
+const SIGABRT: u8 = 6;
+
+const INDEX_DEBUG: u64 = 0x1;
+
+const KERNEL_CODE_SEL: u64 = 0x08;
+const KERNEL_DATA_SEL: u64 = 0x10;
+
+const CTRL_C: u8 = 0x03;
+const INT3: u8 = 0xcc;
+
+/// A software breakpoint set by gdb.
+#[derive(Debug, Clone, Copy)]
+struct Breakpoint {
+    /// Process whose address space it's in, for lower half addresses.
+    id: Id,
+    addr: usize,
+    original: u8,
+}
+
+/// Set up the stub from the command line, and wait for gdb if it's enabled.
+///
+/// Interrupts must be initialized first.
+pub fn initialize(cmdline: &Cmdline) {
+    for (key, value) in cmdline.iter() {
+        if key == "gdbstub" {
+            if value != "com2" {
+                warn!("gdbstub: unsupported port {:?}", value);
+                continue;
+            }
+
+            match serial::com2().initialize() {
+                Ok(()) => ENABLED.store(true, Ordering::SeqCst),
+                Err(e) => warn!("gdbstub: COM2: {}", e),
+            }
+        }
+    }
+
+    if enabled() {
+        info!("gdbstub: waiting for gdb on COM2");
+
+        unsafe {
+            asm!("int3");
+        }
+    }
+}
+
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::SeqCst)
+}
+
+/// Handle a debug (0x1) or breakpoint (0x3) trap. Returns false if it's not
+/// for gdb, e.g. a user process breakpoint that belongs to [crate::debugger].
+pub fn handle_trap(stack: &mut InterruptStack) -> bool {
+    if !enabled() {
+        return false;
+    }
+
+    let stepped =
+        stack.index == INDEX_DEBUG && STEPPING.swap(false, Ordering::SeqCst);
+
+    if stepped {
+        stack.rflags &= !(EFLAGS_TF as u64);
+    }
+
+    if stepped || !stack.is_user() || is_user_breakpoint(stack) {
+        stop(SIGTRAP, stack, true);
+        true
+    } else {
+        false
+    }
+}
+
+/// Check whether gdb has sent Ctrl-C, and stop if so. Called on timer ticks.
+pub fn poll(stack: &mut InterruptStack) {
+    if enabled() && serial::com2().try_read_byte() == Some(CTRL_C) {
+        stop(SIGINT, stack, true);
+    }
+}
+
+/// Let gdb inspect the kernel after a panic. Never returns if the stub is
+/// enabled.
+pub fn on_panic() {
+    if !enabled() {
+        return;
+    }
+
+    let mut stack = InterruptStack {
+        cs: KERNEL_CODE_SEL,
+        ss: KERNEL_DATA_SEL,
+        ..InterruptStack::default()
+    };
+
+    unsafe {
+        asm!(
+            "lea {rip}, [rip]",
+            "mov {rsp}, rsp",
+            "mov {rbp}, rbp",
+            "pushfq",
+            "pop {rflags}",
+            rip = out(reg) stack.rip,
+            rsp = out(reg) stack.user_rsp,
+            rbp = out(reg) stack.rbp,
+            rflags = out(reg) stack.rflags,
+        );
+    }
+
+    loop {
+        stop(SIGABRT, &mut stack, false);
+    }
+}
+
+/// Is this a user mode breakpoint trap from one of gdb's breakpoints?
+fn is_user_breakpoint(stack: &InterruptStack) -> bool {
+    let addr = (stack.rip as usize).wrapping_sub(1);
+
+    let id = match process::try_snapshot() {
+        Some((id, _)) => id,
+        None => return false,
+    };
+
+    BREAKPOINTS.try_lock().map(|breakpoints| {
+        breakpoints.iter().flatten().any(|b| b.addr == addr && b.id == id)
+    }).unwrap_or(false)
+}
+
+/// Tell gdb we've stopped and handle its requests until it resumes.
+///
+/// If `can_resume` is false, a request to continue or step just returns, and
+/// the caller is expected to stop again.
+fn stop(signal: u8, stack: &mut InterruptStack, can_resume: bool) {
+    let stopped = process::try_snapshot()
+        .map(|(id, _)| thread_id(id))
+        .unwrap_or(thread_id(0));
+
+    let mut session = Session {
+        port: serial::com2(),
+        stack,
+        signal,
+        stopped,
+        selected: stopped,
+    };
+
+    let mut request = [0; PACKET_SIZE];
+    let mut reply = Reply::new();
+
+    // gdb is waiting for this if it asked us to continue.
+    let _ = session.stop_reply(&mut reply);
+    let _ = session.send(&reply);
+
+    loop {
+        let len = session.receive(&mut request);
+
+        reply.clear();
+
+        match session.handle(&request[..len], &mut reply, can_resume) {
+            Ok(Action::Reply) => (),
+            Ok(Action::Resume) => break,
+            // The reply didn't fit
+            Err(fmt::Error) => {
+                reply.clear();
+                let _ = reply.write_str("E01");
+            },
+        }
+
+        let _ = session.send(&reply);
+    }
+
+    // Interrupts from user mode restore the registers saved for the process on
+    // the way out, so keep those up to date.
+    if session.stack.is_user() {
+        unsafe {
+            process::ffi::process_save_user_interrupt(&*session.stack);
+        }
+    }
+}
+
+fn thread_id(id: Id) -> u64 {
+    id as u64 + 1
+}
+
+fn process_id(thread_id: u64) -> Option<Id> {
+    thread_id.checked_sub(1).map(|id| id as Id)
+}
+
+/// Find a process without waiting for locks.
+fn find_process(id: Id) -> Option<RcProcess> {
+    process::try_snapshot()?.1.into_iter()
+        .find(|process| process.try_lock().map(|p| p.id()) == Some(id))
+}
+
+enum Action {
+    Reply,
+    Resume,
+}
+
+struct Session<'a> {
+    port: SerialPort,
+    stack: &'a mut InterruptStack,
+    signal: u8,
+    /// The thread that stopped.
+    stopped: u64,
+    /// The thread selected with `Hg` for register and memory access.
+    selected: u64,
+}
+
+impl<'a> Session<'a> {
+    /// Receive a packet into the buffer, acknowledging it, and return its
+    /// length.
+    fn receive(&mut self, buf: &mut [u8; PACKET_SIZE]) -> usize {
+        loop {
+            while !matches!(self.port.read_byte(), Ok(b'$')) { }
+
+            let mut len = 0;
+            let mut sum = 0u8;
+            let mut overflow = false;
+
+            loop {
+                match self.port.read_byte() {
+                    Ok(b'#') => break,
+                    Ok(b'$') => { len = 0; sum = 0; overflow = false; },
+                    Ok(byte) => {
+                        sum = sum.wrapping_add(byte);
+
+                        if len < buf.len() {
+                            buf[len] = byte;
+                            len += 1;
+                        } else {
+                            overflow = true;
+                        }
+                    },
+                    Err(_) => overflow = true,
+                }
+            }
+
+            let checksum = [
+                self.port.read_byte().unwrap_or(0),
+                self.port.read_byte().unwrap_or(0),
+            ];
+
+            if !overflow && parse_hex(&checksum) == Some(sum as u64) {
+                let _ = self.port.write_byte(b'+');
+                return len;
+            } else {
+                let _ = self.port.write_byte(b'-');
+            }
+        }
+    }
+
+    /// Send a reply packet. We don't wait for gdb to acknowledge it.
+    fn send(&mut self, reply: &Reply) -> Result<(), serial::Error> {
+        let payload = reply.as_bytes();
+        let sum = payload.iter().fold(0u8, |sum, &b| sum.wrapping_add(b));
+
+        self.port.write_byte(b'$')?;
+        self.port.write_bytes(payload)?;
+        let _ = write!(self.port, "#{:02x}", sum);
+        Ok(())
+    }
+
+    fn stop_reply(&self, reply: &mut Reply) -> fmt::Result {
+        write!(reply, "T{:02x}thread:{:x};", self.signal, self.stopped)
+    }
+
+    fn handle(&mut self, request: &[u8], reply: &mut Reply, can_resume: bool)
+        -> Result<Action, fmt::Error> {
+
+        let request = match core::str::from_utf8(request) {
+            Ok(request) if request.is_ascii() => request,
+            _ => return Ok(Action::Reply),
+        };
+
+        let (command, args) = request.split_at(request.len().min(1));
+
+        match command {
+            "?" => self.stop_reply(reply)?,
+            "g" => self.read_registers(reply)?,
+            "G" => self.write_registers(args, reply)?,
+            "m" => self.read_memory(args, reply)?,
+            "M" => self.write_memory(args, reply)?,
+            "c" | "s" => {
+                if can_resume {
+                    if let Some(addr) = parse_hex(args.as_bytes()) {
+                        self.stack.rip = addr;
+                    }
+
+                    if command == "s" {
+                        self.stack.rflags |= EFLAGS_TF as u64;
+                        STEPPING.store(true, Ordering::SeqCst);
+                    }
+                }
+                return Ok(Action::Resume);
+            },
+            "D" => {
+                reply.write_str("OK")?;
+                let _ = self.send(reply);
+                return Ok(Action::Resume);
+            },
+            "k" => return Ok(Action::Resume),
+            "Z" | "z" => self.breakpoint(command == "Z", args, reply)?,
+            "H" => {
+                let thread = parse_thread(&args[args.len().min(1)..]);
+
+                if args.starts_with('g') {
+                    self.selected = thread.unwrap_or(self.stopped);
+                }
+
+                reply.write_str("OK")?;
+            },
+            "T" => {
+                let alive = parse_thread(args)
+                    .and_then(process_id)
+                    .and_then(find_process)
+                    .is_some();
+
+                reply.write_str(if alive { "OK" } else { "E01" })?;
+            },
+            "q" => self.query(args, reply)?,
+            // Not supported: gdb will use something else
+            _ => (),
+        }
+
+        Ok(Action::Reply)
+    }
+
+    fn query(&mut self, args: &str, reply: &mut Reply) -> fmt::Result {
+        if args.starts_with("Supported") {
+            write!(reply, "PacketSize={:x}", PACKET_SIZE)
+        } else if args == "fThreadInfo" {
+            match process::try_snapshot() {
+                Some((_, processes)) => {
+                    let mut first = true;
+
+                    for process in processes {
+                        if let Some(process) = process.try_lock() {
+                            reply.write_str(if first { "m" } else { "," })?;
+                            write!(reply, "{:x}", thread_id(process.id()))?;
+                            first = false;
+                        }
+                    }
+
+                    if first {
+                        write!(reply, "m{:x}", self.stopped)?;
+                    }
+
+                    Ok(())
+                },
+                None => write!(reply, "m{:x}", self.stopped),
+            }
+        } else if args == "sThreadInfo" {
+            reply.write_str("l")
+        } else if args == "C" {
+            write!(reply, "QC{:x}", self.stopped)
+        } else if args == "Attached" {
+            reply.write_str("1")
+        } else if let Some(thread) = args.strip_prefix("ThreadExtraInfo,") {
+            let process = parse_thread(thread)
+                .and_then(process_id)
+                .and_then(find_process);
+
+            let mut info = Reply::new();
+
+            match process.as_ref().and_then(|p| p.try_lock()) {
+                Some(process) => write!(info, "{} ({})", process.name(),
+                    process.state().short_description())?,
+                None => info.write_str("(busy)")?,
+            }
+
+            reply.write_hex(info.as_bytes())
+        } else {
+            Ok(())
+        }
+    }
+
+    fn selected_registers(&self) -> Option<(GeneralRegisters, [u64; 6])> {
+        if self.selected == self.stopped {
+            let s = &self.stack;
+
+            Some((GeneralRegisters {
+                rax: s.rax, rcx: s.rcx, rdx: s.rdx, rbx: s.rbx,
+                rsp: s.user_rsp, rbp: s.rbp, rsi: s.rsi, rdi: s.rdi,
+                r8: s.r8, r9: s.r9, r10: s.r10, r11: s.r11,
+                r12: s.r12, r13: s.r13, r14: s.r14, r15: s.r15,
+                rip: s.rip,
+                rflags: s.rflags,
+            }, [s.cs, s.ss, s.ss, s.ss, 0, 0]))
+        } else {
+            let process = process_id(self.selected).and_then(find_process)?;
+            let process = process.try_lock()?;
+
+            let regs = unsafe { process.hw_state().kernel().general_registers() };
+
+            Some((regs, [KERNEL_CODE_SEL, KERNEL_DATA_SEL, KERNEL_DATA_SEL,
+                KERNEL_DATA_SEL, 0, 0]))
+        }
+    }
+
+    /// Registers in the order gdb expects for amd64: 16 general purpose
+    /// registers and `rip` as 64-bit values, then `eflags`, `cs`, `ss`, `ds`,
+    /// `es`, `fs`, `gs` as 32-bit values. We leave out the rest.
+    fn read_registers(&self, reply: &mut Reply) -> fmt::Result {
+        let (r, segments) = match self.selected_registers() {
+            Some(registers) => registers,
+            None => return reply.write_str("E01"),
+        };
+
+        for &value in &[
+            r.rax, r.rbx, r.rcx, r.rdx, r.rsi, r.rdi, r.rbp, r.rsp,
+            r.r8, r.r9, r.r10, r.r11, r.r12, r.r13, r.r14, r.r15,
+            r.rip,
+        ] {
+            reply.write_hex(&value.to_le_bytes())?;
+        }
+
+        reply.write_hex(&(r.rflags as u32).to_le_bytes())?;
+
+        for &value in &segments {
+            reply.write_hex(&(value as u32).to_le_bytes())?;
+        }
+
+        Ok(())
+    }
+
+    /// Only the stopped thread's registers can be written. Segment registers
+    /// are left alone.
+    fn write_registers(&mut self, args: &str, reply: &mut Reply)
+        -> fmt::Result {
+
+        if self.selected != self.stopped {
+            return reply.write_str("E01");
+        }
+
+        let mut values = [0u64; 17];
+        let mut rflags = None;
+
+        for (index, chunk) in args.as_bytes().chunks(16).enumerate() {
+            if index < values.len() {
+                match decode_le(chunk) {
+                    Some(value) => values[index] = value,
+                    None => return reply.write_str("E01"),
+                }
+            } else if index == values.len() {
+                rflags = chunk.get(..8).and_then(decode_le);
+            }
+        }
+
+        let [rax, rbx, rcx, rdx, rsi, rdi, rbp, rsp,
+             r8, r9, r10, r11, r12, r13, r14, r15, rip] = values;
+
+        if self.stack.is_user() && rip as usize >= USER_ADDR_LIMIT {
+            return reply.write_str("E01");
+        }
+
+        let s = &mut *self.stack;
+
+        s.rax = rax; s.rbx = rbx; s.rcx = rcx; s.rdx = rdx;
+        s.rsi = rsi; s.rdi = rdi; s.rbp = rbp; s.user_rsp = rsp;
+        s.r8 = r8; s.r9 = r9; s.r10 = r10; s.r11 = r11;
+        s.r12 = r12; s.r13 = r13; s.r14 = r14; s.r15 = r15;
+        s.rip = rip;
+
+        if let Some(rflags) = rflags {
+            let mask = USER_EFLAGS_MASK as u64;
+            s.rflags = (s.rflags & !mask) | (rflags & mask);
+        }
+
+        reply.write_str("OK")
+    }
+
+    fn read_memory(&self, args: &str, reply: &mut Reply) -> fmt::Result {
+        let (addr, len) = match parse_addr_len(args) {
+            Some(addr_len) => addr_len,
+            None => return reply.write_str("E01"),
+        };
+
+        let mut buf = [0u8; PACKET_SIZE / 2];
+        let len = len.min(buf.len());
+
+        if self.access_memory(addr, Access::Read(&mut buf[..len])) {
+            reply.write_hex(&buf[..len])
+        } else {
+            reply.write_str("E14")
+        }
+    }
+
+    fn write_memory(&self, args: &str, reply: &mut Reply) -> fmt::Result {
+        let (addr_len, hex) = args.split_once(':').unwrap_or((args, ""));
+
+        let (addr, len) = match parse_addr_len(addr_len) {
+            Some(addr_len) => addr_len,
+            None => return reply.write_str("E01"),
+        };
+
+        let mut buf = [0u8; PACKET_SIZE / 2];
+
+        if len > buf.len() || hex.len() != len * 2 {
+            return reply.write_str("E01");
+        }
+
+        for (byte, pair) in buf.iter_mut().zip(hex.as_bytes().chunks(2)) {
+            match parse_hex(pair) {
+                Some(value) => *byte = value as u8,
+                None => return reply.write_str("E01"),
+            }
+        }
+
+        if self.access_memory(addr, Access::Write(&buf[..len])) {
+            reply.write_str("OK")
+        } else {
+            reply.write_str("E14")
+        }
+    }
+
+    /// `Z0,addr,kind` / `z0,addr,kind`: set or clear a software breakpoint.
+    /// Other kinds aren't supported.
+    fn breakpoint(&self, set: bool, args: &str, reply: &mut Reply)
+        -> fmt::Result {
+
+        let addr = match args.strip_prefix("0,")
+            .and_then(|rest| rest.split(',').next())
+            .and_then(|addr| parse_hex(addr.as_bytes())) {
+            Some(addr) => addr as usize,
+            None => return Ok(()),
+        };
+
+        let id = if addr >= KERNEL_SPACE_START {
+            0
+        } else {
+            match process_id(self.selected) {
+                Some(id) => id,
+                None => return reply.write_str("E01"),
+            }
+        };
+
+        let mut breakpoints = match BREAKPOINTS.try_lock() {
+            Some(breakpoints) => breakpoints,
+            None => return reply.write_str("E01"),
+        };
+
+        let existing = breakpoints.iter().position(|b| {
+            b.map(|b| b.addr == addr && b.id == id).unwrap_or(false)
+        });
+
+        let ok = if set {
+            let mut original = [0u8];
+
+            match (existing, breakpoints.iter().position(|b| b.is_none())) {
+                (Some(_), _) => true,
+                (None, Some(free)) => {
+                    let inserted =
+                        self.access_memory(addr, Access::Read(&mut original)) &&
+                        self.access_memory(addr, Access::Write(&[INT3]));
+
+                    if inserted {
+                        breakpoints[free] = Some(Breakpoint {
+                            id, addr, original: original[0]
+                        });
+                    }
+
+                    inserted
+                },
+                (None, None) => false,
+            }
+        } else {
+            match existing {
+                Some(index) => {
+                    let original = breakpoints[index].unwrap().original;
+                    breakpoints[index] = None;
+                    self.access_memory(addr, Access::Write(&[original]))
+                },
+                None => true,
+            }
+        };
+
+        reply.write_str(if ok { "OK" } else { "E01" })
+    }
+
+    /// Read or write memory. Upper half addresses are kernel memory, and
+    /// lower half addresses are in the selected thread's process.
+    fn access_memory(&self, addr: usize, access: Access) -> bool {
+        let len = access.len();
+
+        if addr >= KERNEL_SPACE_START {
+            if !paging::is_kernel_mapped(addr, len) {
+                return false;
+            }
+
+            // Kernel code can be written too, for breakpoints.
+            unsafe {
+                match access {
+                    Access::Read(buf) => buf.as_mut_ptr()
+                        .copy_from(addr as *const u8, len),
+                    Access::Write(data) => paging::without_write_protect(||
+                        (addr as *mut u8).copy_from(data.as_ptr(), len)),
+                }
+            }
+
+            true
+        } else {
+            let mem = process_id(self.selected)
+                .and_then(find_process)
+                .and_then(|process| process.try_lock()?.mem());
+
+            let mut mem = match mem.as_ref().and_then(|mem| mem.try_lock()) {
+                Some(mem) => mem,
+                None => return false,
+            };
+
+            match access {
+                Access::Read(buf) => mem.copy_out(addr, buf).is_ok(),
+                Access::Write(data) => mem.copy_in(addr, data).is_ok(),
+            }
+        }
+    }
+}
+
+enum Access<'a> {
+    Read(&'a mut [u8]),
+    Write(&'a [u8]),
+}
+
+impl Access<'_> {
+    fn len(&self) -> usize {
+        match self {
+            Access::Read(buf) => buf.len(),
+            Access::Write(data) => data.len(),
+        }
+    }
+}
+
+/// A packet being built, which must fit in [PACKET_SIZE].
+struct Reply {
+    buf: [u8; PACKET_SIZE],
+    len: usize,
+}
+
+impl Reply {
+    fn new() -> Reply {
+        Reply { buf: [0; PACKET_SIZE], len: 0 }
+    }
+
+    fn clear(&mut self) {
+        self.len = 0;
+    }
+
+    fn as_bytes(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+
+    fn write_hex(&mut self, bytes: &[u8]) -> fmt::Result {
+        for byte in bytes {
+            write!(self, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Write for Reply {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let end = self.len + s.len();
+
+        // These characters would have to be escaped.
+        if end > self.buf.len() || s.bytes().any(|b| b"$#}*".contains(&b)) {
+            return Err(fmt::Error);
+        }
+
+        self.buf[self.len..end].copy_from_slice(s.as_bytes());
+        self.len = end;
+        Ok(())
+    }
+}
+
+fn parse_hex(hex: &[u8]) -> Option<u64> {
+    if hex.is_empty() || hex.len() > 16 {
+        return None;
+    }
+
+    hex.iter().try_fold(0u64, |value, &digit| {
+        let digit = (digit as char).to_digit(16)?;
+        Some(value << 4 | digit as u64)
+    })
+}
+
+/// Decode a little endian value from hex digit pairs.
+fn decode_le(hex: &[u8]) -> Option<u64> {
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+
+    hex.chunks(2).rev().try_fold(0u64, |value, pair| {
+        Some(value << 8 | parse_hex(pair)?)
+    })
+}
+
+/// A thread ID, where `-1` and `0` (any thread) are `None`.
+fn parse_thread(thread: &str) -> Option<u64> {
+    parse_hex(thread.as_bytes()).filter(|&id| id != 0)
+}
+
+fn parse_addr_len(args: &str) -> Option<(usize, usize)> {
+    let (addr, len) = args.split_once(',')?;
+
+    Some((parse_hex(addr.as_bytes())? as usize,
+          parse_hex(len.as_bytes())? as usize))
+}
+
+/// C interface. See `kit/kernel/include/gdbstub.h`.
+pub mod ffi {
+    use super::*;
+
+    #[no_mangle]
+    pub unsafe extern fn gdbstub_handle_trap(stack: *mut InterruptStack)
+        -> bool {
+        handle_trap(&mut *stack)
+    }
+
+    #[no_mangle]
+    pub unsafe extern fn gdbstub_poll(stack: *mut InterruptStack) {
+        poll(&mut *stack)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hex_parsing() {
+        assert_eq!(parse_hex(b"ffffffff80001000"), Some(0xffffffff80001000));
+        assert_eq!(parse_hex(b"1g"), None);
+        assert_eq!(decode_le(b"3412000000000000"), Some(0x1234));
+        assert_eq!(parse_thread("-1"), None);
+        assert_eq!(parse_thread("0"), None);
+        assert_eq!(parse_thread("2a"), Some(0x2a));
+        assert_eq!(parse_addr_len("401000,10"), Some((0x401000, 0x10)));
+    }
+
+    #[test]
+    fn reply_rejects_unescaped() {
+        let mut reply = Reply::new();
+
+        assert!(reply.write_str("OK").is_ok());
+        assert!(reply.write_str("#").is_err());
+        assert_eq!(reply.as_bytes(), b"OK");
+    }
+}
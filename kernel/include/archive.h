@@ -76,6 +76,12 @@ static inline archive_entry_t *archive_next(archive_iterator_t *iterator)
 
 extern archive_header_t *archive_system;
 
+/**
+ * Length of the system archive in bytes. It stays mapped in the kernel for as
+ * long as the system runs.
+ */
+extern uint64_t archive_system_length;
+
 bool archive_initialize(uint64_t modules_count, multiboot_module_t *modules);
 
 bool archive_get(archive_header_t *header, const char *entry_name,
@@ -84,6 +90,6 @@ bool archive_get(archive_header_t *header, const char *entry_name,
 bool archive_verify(archive_entry_t *entry, uint8_t *buffer);
 
 int64_t archive_utils_spawn(const char *filename, int argc,
-    const char *const *argv);
+    const char *const *argv, const char *const *envp);
 
 #endif
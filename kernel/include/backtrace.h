@@ -0,0 +1,25 @@
+/*******************************************************************************
+ *
+ * kit/kernel/include/backtrace.h
+ * - kernel stack backtraces
+ *
+ * vim:ts=2:sw=2:et:tw=80:ft=c
+ *
+ * Copyright (C) 2015-2021, Devyn Cairns
+ * Redistribution of this file is permitted under the terms of the simplified
+ * BSD license. See LICENSE for more information.
+ *
+ ******************************************************************************/
+
+#ifndef BACKTRACE_H
+#define BACKTRACE_H
+
+struct interrupt_stack;
+
+/**
+ * Prints a symbolized backtrace of kernel code that was interrupted by an
+ * exception, to the console and the log.
+ */
+void backtrace_print_interrupted(const struct interrupt_stack *stack);
+
+#endif
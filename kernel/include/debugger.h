@@ -0,0 +1,30 @@
+/*******************************************************************************
+ *
+ * kit/kernel/include/debugger.h
+ * - debugging of user processes by their host
+ *
+ * vim:ts=2:sw=2:et:tw=80:ft=c
+ *
+ * Copyright (C) 2015-2021, Devyn Cairns
+ * Redistribution of this file is permitted under the terms of the simplified
+ * BSD license. See LICENSE for more information.
+ *
+ ******************************************************************************/
+
+#ifndef DEBUGGER_H
+#define DEBUGGER_H
+
+#include <stdint.h>
+#include <stdbool.h>
+
+/**
+ * Handles a debug (0x1) or breakpoint (0x3) trap taken in user mode, stopping
+ * the current process if a debugger is attached.
+ *
+ * The user registers must have been saved with process_save_user_interrupt()
+ * first. Returns false if there is no debugger, and the process should be
+ * killed.
+ */
+bool debugger_handle_trap(uint64_t index);
+
+#endif
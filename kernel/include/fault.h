@@ -0,0 +1,35 @@
+/*******************************************************************************
+ *
+ * kit/kernel/include/fault.h
+ * - reports of faults in user processes
+ *
+ * vim:ts=2:sw=2:et:tw=80:ft=c
+ *
+ * Copyright (C) 2015-2021, Devyn Cairns
+ * Redistribution of this file is permitted under the terms of the simplified
+ * BSD license. See LICENSE for more information.
+ *
+ ******************************************************************************/
+
+#ifndef FAULT_H
+#define FAULT_H
+
+#include <stdint.h>
+
+struct interrupt_stack;
+
+/**
+ * Prints a report of a general protection fault (0xd) taken by the current
+ * process in user mode, with its registers and a symbolized backtrace.
+ */
+void fault_report_user(const struct interrupt_stack *stack);
+
+/**
+ * Handles a page fault (0xe) from either mode, according to the current
+ * process's virtual memory areas. Returns if the faulting instruction can be
+ * retried, or the process was killed with SIG_STACK_OVERFLOW or
+ * SIG_BAD_MEM_ACCESS. Panics if it was in kernel mode and can't be resolved.
+ */
+void fault_handle_page(const struct interrupt_stack *stack);
+
+#endif
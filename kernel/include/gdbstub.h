@@ -0,0 +1,34 @@
+/*******************************************************************************
+ *
+ * kit/kernel/include/gdbstub.h
+ * - GDB remote serial protocol stub
+ *
+ * vim:ts=2:sw=2:et:tw=80:ft=c
+ *
+ * Copyright (C) 2015-2021, Devyn Cairns
+ * Redistribution of this file is permitted under the terms of the simplified
+ * BSD license. See LICENSE for more information.
+ *
+ ******************************************************************************/
+
+#ifndef GDBSTUB_H
+#define GDBSTUB_H
+
+#include <stdbool.h>
+
+struct interrupt_stack;
+
+/**
+ * Handles a debug (0x1) or breakpoint (0x3) trap if it belongs to gdb,
+ * stopping until gdb resumes. Returns false if the trap should be handled
+ * normally.
+ */
+bool gdbstub_handle_trap(struct interrupt_stack *stack);
+
+/**
+ * Checks whether gdb has asked to interrupt the kernel, and stops if so.
+ * Called on every timer tick.
+ */
+void gdbstub_poll(struct interrupt_stack *stack);
+
+#endif
@@ -42,4 +42,26 @@ void keyboard_sleep_dequeue(keyboard_event_t *event);
 void keyboard_handle_keypress(uint8_t keycode);
 void keyboard_handle_keyrelease(uint8_t keycode);
 
+/**
+ * Job control actions for the console's foreground process group.
+ */
+typedef enum keyboard_job_control
+{
+  KEYBOARD_JOB_INTERRUPT = 1, // Ctrl-C
+  KEYBOARD_JOB_STOP      = 2  // Ctrl-Z
+} keyboard_job_control_t;
+
+/**
+ * Requests a job control action. Returns 1 if the request was accepted (and the
+ * key should not be passed on), or 0 if there is no foreground process group.
+ */
+int keyboard_request_job_control(keyboard_job_control_t action);
+
+/**
+ * Carries out a pending job control action. Must only be called once the
+ * keyboard IRQ has been acknowledged, as it may not return to the caller
+ * immediately.
+ */
+void keyboard_dispatch_job_control();
+
 #endif
@@ -0,0 +1,39 @@
+/*******************************************************************************
+ *
+ * kit/kernel/include/monitor.h
+ * - interactive kernel monitor
+ *
+ * vim:ts=2:sw=2:et:tw=80:ft=c
+ *
+ * Copyright (C) 2015-2021, Devyn Cairns
+ * Redistribution of this file is permitted under the terms of the simplified
+ * BSD license. See LICENSE for more information.
+ *
+ ******************************************************************************/
+
+#ifndef MONITOR_H
+#define MONITOR_H
+
+#include <stdint.h>
+#include <stdbool.h>
+
+/**
+ * Offers a key press to the monitor. Ctrl+Alt+Scroll Lock switches the keyboard
+ * over to it. Returns true if the key was taken and should not be passed on.
+ */
+bool monitor_handle_keypress(uint8_t keycode, char keychar, bool ctrl_down,
+    bool alt_down);
+
+/**
+ * Returns true while the keyboard is switched over to the monitor, in which
+ * case key releases should not be passed on either.
+ */
+bool monitor_keyboard_active();
+
+/**
+ * Picks up serial input and wakes the monitor if it has anything to do. Must
+ * only be called once the IRQ has been acknowledged.
+ */
+void monitor_poll();
+
+#endif
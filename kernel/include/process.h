@@ -25,15 +25,41 @@ process_id_t process_current_id();
 
 int process_wait_exit_status(process_id_t pid, int *exit_status);
 
+/**
+ * Waits until the process exits or stops. Returns 0 and sets 'exit_status' if
+ * it exited, 1 if it stopped, or -1 on error. 'exit_status' is checked as a
+ * pointer into the current process's user memory.
+ */
+int process_wait_status(process_id_t pid, int *exit_status);
+
 /**
  * Adjusts the length of the current process's heap by 'amount' bytes and
  * returns a pointer to the new end of the heap.
  */
 void *process_adjust_heap(int64_t amount);
 
+/**
+ * Returns where the system archive should be mapped in the current process.
+ */
+uint64_t process_archive_addr();
+
 void process_exit(int status);
 
+struct interrupt_stack;
+
+/**
+ * Copies the user registers from an interrupt taken in user mode into the
+ * current process, and back again before returning, so they can be changed
+ * while it's in the kernel.
+ */
+void process_save_user_interrupt(const struct interrupt_stack *stack);
+void process_restore_user_interrupt(struct interrupt_stack *stack);
+
 #define SIG_BAD_MEM_ACCESS -2
+#define SIG_INTERRUPT      -3
+#define SIG_TRAP           -4
+#define SIG_KILL           -5
+#define SIG_STACK_OVERFLOW -6
 
 int process_signal(process_id_t pid, int signal);
 
@@ -38,7 +38,8 @@ void syscall_initialize();
   int syscall_sleep();
 
 #define SYSCALL_SPAWN 0x5
-  int64_t syscall_spawn(const char *file, int argc, const char *const *argv);
+  int64_t syscall_spawn(const char *file, int argc, const char *const *argv,
+      const char *const *envp);
 
 #define SYSCALL_WAIT_PROCESS 0x6
   int syscall_wait_process(process_id_t id, int *exit_status);
@@ -52,6 +53,28 @@ void syscall_initialize();
 #define SYSCALL_DEBUG 0x9
   int32_t syscall_debug(uint32_t operation, uint64_t argument);
 
+#define SYSCALL_SET_PRIORITY 0xa
+  int syscall_set_priority(process_id_t pid, int nice);
+
+#define SYSCALL_SET_FOREGROUND 0xb
+  int64_t syscall_set_foreground(process_id_t pgid);
+
+#define SYSCALL_STOP_PROCESS 0xc
+  int syscall_stop_process(process_id_t pid);
+
+#define SYSCALL_CONTINUE_PROCESS 0xd
+  int syscall_continue_process(process_id_t pid);
+
+#define SYSCALL_TRACE 0xe
+  int64_t syscall_trace(uint32_t request, process_id_t pid, uint64_t addr,
+                        uint64_t data);
+
+#define SYSCALL_LOAD_LIBRARY 0xf
+  int64_t syscall_load_library(const char *name);
+
+#define SYSCALL_FORK 0x10
+  int64_t syscall_fork();
+
 extern const uint64_t syscall_table[];
 extern const uint64_t syscall_table_size;
 
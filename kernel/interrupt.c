@@ -14,9 +14,15 @@
 #include "interrupt.h"
 #include "interrupt_8259pic.h"
 #include "ps2_8042.h"
+#include "keyboard.h"
 #include "memory.h"
 #include "scheduler.h"
 #include "process.h"
+#include "debugger.h"
+#include "fault.h"
+#include "backtrace.h"
+#include "gdbstub.h"
+#include "monitor.h"
 #include "debug.h"
 
 // We use this to tell if we came from user code
@@ -173,6 +179,10 @@ void interrupt_initialize()
   MAP_INTERRUPT(62);
   MAP_INTERRUPT(63);
 
+  // Allow int3 from user code, for breakpoints.
+  interrupt_set_gate(3, (uintptr_t) &interrupt_isr_stub_3,
+    GDT_SEL_KERNEL_CODE, INTERRUPT_TYPE_NORMAL, GDT_PRIVILEGE_USER);
+
   // Set IDT register.
   interrupt_idtr_t idtr;
 
@@ -210,66 +220,85 @@ void interrupt_set_gate(uint8_t index, uintptr_t routine_address,
 /**
  * Called from interrupt_isr_stub_common.
  */
-void interrupt_handler(interrupt_stack_t stack) {
+void interrupt_handler(interrupt_stack_t *stack) {
 /*
   DEBUG_BEGIN_VALUES();
-    DEBUG_HEX(stack.ds);
-    DEBUG_HEX(stack.r15);
-    DEBUG_HEX(stack.r14);
-    DEBUG_HEX(stack.r13);
-    DEBUG_HEX(stack.r12);
-    DEBUG_HEX(stack.r11);
-    DEBUG_HEX(stack.r10);
-    DEBUG_HEX(stack.r9);
-    DEBUG_HEX(stack.r8);
-    DEBUG_HEX(stack.rsp);
-    DEBUG_HEX(stack.rbp);
-    DEBUG_HEX(stack.rdi);
-    DEBUG_HEX(stack.rsi);
-    DEBUG_HEX(stack.rdx);
-    DEBUG_HEX(stack.rcx);
-    DEBUG_HEX(stack.rbx);
-    DEBUG_HEX(stack.rax);
-    DEBUG_HEX(stack.index);
-    DEBUG_HEX(stack.err_code);
-    DEBUG_HEX(stack.rip);
-    DEBUG_HEX(stack.cs);
-    DEBUG_HEX(stack.rflags);
-    DEBUG_HEX(stack.user_rsp);
-    DEBUG_HEX(stack.ss);
+    DEBUG_HEX(stack->ds);
+    DEBUG_HEX(stack->r15);
+    DEBUG_HEX(stack->r14);
+    DEBUG_HEX(stack->r13);
+    DEBUG_HEX(stack->r12);
+    DEBUG_HEX(stack->r11);
+    DEBUG_HEX(stack->r10);
+    DEBUG_HEX(stack->r9);
+    DEBUG_HEX(stack->r8);
+    DEBUG_HEX(stack->rsp);
+    DEBUG_HEX(stack->rbp);
+    DEBUG_HEX(stack->rdi);
+    DEBUG_HEX(stack->rsi);
+    DEBUG_HEX(stack->rdx);
+    DEBUG_HEX(stack->rcx);
+    DEBUG_HEX(stack->rbx);
+    DEBUG_HEX(stack->rax);
+    DEBUG_HEX(stack->index);
+    DEBUG_HEX(stack->err_code);
+    DEBUG_HEX(stack->rip);
+    DEBUG_HEX(stack->cs);
+    DEBUG_HEX(stack->rflags);
+    DEBUG_HEX(stack->user_rsp);
+    DEBUG_HEX(stack->ss);
   DEBUG_END_VALUES();
 */
 
-  switch (stack.index)
+  bool from_user = stack->cs == USER_CD64_SEL;
+
+  if (from_user) {
+    process_save_user_interrupt(stack);
+  }
+
+  switch (stack->index)
   {
+    case 0x1:
+    case 0x3:
+      // Single-step and breakpoint traps
+      if (gdbstub_handle_trap(stack)) {
+        break;
+      }
+      if (from_user) {
+        if (!debugger_handle_trap(stack->index)) {
+          process_signal(process_current_id(), SIG_TRAP);
+        }
+      }
+      else {
+        DEBUG_FORMAT("kernel trap %#lx, rip=%#lx", stack->index, stack->rip);
+      }
+      break;
     case 0x6:
-      DEBUG_FORMAT("invalid opcode, rip=%#lx", stack.rip);
+      DEBUG_FORMAT("invalid opcode, rip=%#lx", stack->rip);
+      if (!from_user) {
+        backtrace_print_interrupted(stack);
+      }
       while (true) hlt();
     case 0xd:
-      DEBUG_FORMAT("general protection fault, rip=%#lx, err_code=%#lx, cs=%#lx",
-          stack.rip, stack.err_code, stack.cs);
-      if (stack.cs == USER_CD64_SEL) {
+      if (from_user) {
+        fault_report_user(stack);
         process_signal(process_current_id(), SIG_BAD_MEM_ACCESS);
       }
       else {
+        DEBUG_FORMAT("general protection fault, rip=%#lx, err_code=%#lx, cs=%#lx",
+            stack->rip, stack->err_code, stack->cs);
+        backtrace_print_interrupted(stack);
         while (true) hlt();
       }
     case 0xe:
-      {
-        uint64_t cr2;
-        __asm__ volatile("mov %%cr2, %0" : "=r" (cr2));
-
-        DEBUG_FORMAT("page fault, rip=%#lx, cr2=%#lx", stack.rip, cr2);
-        if (stack.cs == USER_CD64_SEL) {
-          process_signal(process_current_id(), SIG_BAD_MEM_ACCESS);
-        }
-        else {
-          while (true) hlt();
-        }
-      }
+      // Page fault: resolved, reported or fatal in Rust
+      fault_handle_page(stack);
+      break;
     case INTERRUPT_INDEX_IRQ + 0:
       // Timer
       interrupt_irq_done(0);
+      gdbstub_poll(stack);
+      monitor_poll();
       scheduler_preempt();
       break;
     case INTERRUPT_INDEX_IRQ + 1:
@@ -277,9 +306,18 @@ void interrupt_handler(interrupt_stack_t stack) {
       ps2_8042_handle_irq1();
 
       interrupt_irq_done(1);
+
+      monitor_poll();
+
+      // May switch away from the current process, so only after EOI.
+      keyboard_dispatch_job_control();
       break;
     default:
-      DEBUG_MESSAGE_HEX("interrupt not implemented", stack.index);
+      DEBUG_MESSAGE_HEX("interrupt not implemented", stack->index);
+  }
+
+  if (from_user) {
+    process_restore_user_interrupt(stack);
   }
 }
 
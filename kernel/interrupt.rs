@@ -35,6 +35,32 @@ pub unsafe fn wait() {
     asm!("sti; hlt; cli");
 }
 
+/// Run `f` with interrupts disabled, and then put them back the way they were.
+#[cfg(not(test))]
+pub fn without_interrupts<T, F: FnOnce() -> T>(f: F) -> T {
+    let rflags: u64;
+
+    unsafe {
+        asm!("pushfq", "pop {}", out(reg) rflags);
+        disable();
+    }
+
+    let result = f();
+
+    // IF
+    if rflags & (1 << 9) != 0 {
+        unsafe { enable(); }
+    }
+
+    result
+}
+
+/// Tests run in user mode, where interrupts can't be disabled.
+#[cfg(test)]
+pub fn without_interrupts<T, F: FnOnce() -> T>(f: F) -> T {
+    f()
+}
+
 /// Briefly enable interrupts to allow a pending interrupt to be serviced.
 #[inline]
 pub unsafe fn accept() {
@@ -45,6 +71,33 @@ pub unsafe fn accept() {
     disable();
 }
 
+/// Code segment selector of 64-bit user code.
+pub const USER_CD64_SEL: u64 = 0x2b;
+
+/// The stack we get from `interrupt_isr_stub_common`.
+///
+/// Must match `interrupt_stack_t` in `kit/kernel/include/interrupt.h`.
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct InterruptStack {
+    pub ds: u64,
+    pub r15: u64, pub r14: u64, pub r13: u64, pub r12: u64,
+    pub r11: u64, pub r10: u64, pub r9: u64, pub r8: u64,
+    pub rsp: u64, pub rbp: u64, pub rdi: u64, pub rsi: u64,
+    pub rdx: u64, pub rcx: u64, pub rbx: u64, pub rax: u64,
+    pub index: u64, pub err_code: u64,
+    pub rip: u64, pub cs: u64, pub rflags: u64, pub user_rsp: u64, pub ss: u64,
+}
+
+assert_eq_size!(InterruptStack, [u8; 192]);
+
+impl InterruptStack {
+    /// True if the interrupt was taken while running user code.
+    pub fn is_user(&self) -> bool {
+        self.cs == USER_CD64_SEL
+    }
+}
+
 /// C interface. See `kit/kernel/include/interrupt.h`.
 pub mod ffi {
     extern {
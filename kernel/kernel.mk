@@ -20,7 +20,8 @@ KERNEL_ASFLAGS=-march=generic64
 KERNEL_RUSTFLAGS=-C debuginfo=2 -C target-cpu=generic \
 					-C target-feature=-sse3,-ssse3,-3dnow \
 					-C no-redzone -C code-model=kernel \
-					-C relocation-model=static -C opt-level=1 -C panic=abort
+					-C relocation-model=static -C opt-level=1 -C panic=abort \
+					-C force-frame-pointers=yes
 
 ifeq ($(CC),clang)
 	KERNEL_CFLAGS+=-target x86_64-pc-none-elf
@@ -45,7 +46,11 @@ clean-kernel:
 test-kernel:
 	cd kernel && ${CARGO} +nightly test ${KERNEL_TESTS}
 
-.PHONY: all-kernel doc-kernel clean-kernel test-kernel
+# Needs cargo-fuzz (cargo install cargo-fuzz)
+fuzz-kernel:
+	cd kernel/fuzz && ${CARGO} +nightly fuzz run elf
+
+.PHONY: all-kernel doc-kernel clean-kernel test-kernel fuzz-kernel
 
 build/kernel/.dir: build/.dir
 	mkdir -p build/kernel
@@ -53,7 +53,14 @@ pub mod keyboard;
 pub mod archive;
 pub mod process;
 pub mod elf;
+pub mod random;
 pub mod scheduler;
+pub mod debugger;
+pub mod coredump;
+pub mod fault;
+pub mod backtrace;
+pub mod gdbstub;
+pub mod monitor;
 pub mod syscall;
 pub mod c_ffi;
 pub mod error;
@@ -64,6 +71,7 @@ use cmdline::Cmdline;
 use memory::InitMemoryMap;
 
 use alloc::string::String;
+use alloc::vec::Vec;
 
 /// Main kernel entry point.
 #[no_mangle]
@@ -82,6 +90,12 @@ pub extern fn kernel_main() -> ! {
     }
 
     log::initialize(&cmdline);
+    coredump::initialize(&cmdline);
+    process::layout::initialize(&cmdline);
+
+    unsafe {
+        backtrace::initialize(&mb_info);
+    }
 
     info!("BOOT: Hello, I'm Kit.");
 
@@ -105,6 +119,8 @@ pub extern fn kernel_main() -> ! {
         terminal::initialize(&mb_info);
     }
 
+    gdbstub::initialize(&cmdline);
+
     console().reset().unwrap();
     console().set_color(Color::Red, Color::White).unwrap();
 
@@ -166,17 +182,35 @@ pub extern fn kernel_main() -> ! {
         process::initialize();
     }
 
+    monitor::initialize(&cmdline);
+
     let pid;
 
     {
         let init = cmdline.iter().find(|(key, _)| *key == "init")
             .map(|(_, value)| value);
 
+        // env.NAME=value options make up the initial environment.
+        let env: Vec<String> = cmdline.iter()
+            .filter_map(|(key, value)| {
+                let name = key.strip_prefix("env.")?;
+                Some(format!("{}={}", name, value))
+            })
+            .collect();
+
+        // interp.EXT=PATH options run files ending in .EXT with PATH.
+        for (key, value) in cmdline.iter() {
+            if let Some(extension) = key.strip_prefix("interp.") {
+                archive::utils::register_interpreter(extension, value);
+            }
+        }
+
         if let Some(init) = init {
             let init_cstring = c_ffi::cstring_from_str(init);
 
             pid = archive::utils::spawn(
-                c_ffi::CStr::new(&init_cstring), &[&init_cstring]).unwrap();
+                c_ffi::CStr::new(&init_cstring), &[&init_cstring], &env)
+                .unwrap();
 
             process::wait(pid).unwrap();
         } else {
@@ -213,6 +247,10 @@ fn panic_handler(panic_info: &PanicInfo) -> ! {
         let _ = write!(console(), ": {}", message);
     }
 
+    backtrace::print(None, backtrace::frame_pointer());
+
+    gdbstub::on_panic();
+
     unsafe {
         loop { asm!("cli; hlt"); }
     }
@@ -16,6 +16,7 @@
 #include "keyboard.h"
 #include "debug.h"
 #include "memory.h"
+#include "monitor.h"
 #include "scheduler.h"
 #include "x86_64.h"
 
@@ -198,6 +199,28 @@ void keyboard_handle_keypress(uint8_t keycode)
   event.alt_down   = keyboard_alt_down;
   event.shift_down = keyboard_shift_down;
 
+  // Ctrl+Alt+Scroll Lock enters the kernel monitor, which then takes all keys
+  // until it's done.
+  if (monitor_handle_keypress(event.keycode, event.keychar, event.ctrl_down,
+        event.alt_down))
+  {
+    return;
+  }
+
+  // Ctrl-C and Ctrl-Z are job control keys for the foreground process group,
+  // if there is one.
+  if (event.ctrl_down && event.keychar == 'c' &&
+      keyboard_request_job_control(KEYBOARD_JOB_INTERRUPT))
+  {
+    return;
+  }
+
+  if (event.ctrl_down && event.keychar == 'z' &&
+      keyboard_request_job_control(KEYBOARD_JOB_STOP))
+  {
+    return;
+  }
+
   keyboard_enqueue(&event);
 }
 
@@ -230,5 +253,10 @@ void keyboard_handle_keyrelease(uint8_t keycode)
   event.alt_down   = keyboard_alt_down;
   event.shift_down = keyboard_shift_down;
 
+  if (monitor_keyboard_active())
+  {
+    return;
+  }
+
   keyboard_enqueue(&event);
 }
@@ -12,9 +12,14 @@
 
 //! Generic keyboard input handler.
 
+use core::sync::atomic::AtomicU8;
+use core::sync::atomic::Ordering::*;
+
 use displaydoc::Display;
 
 use crate::error::Error;
+use crate::process;
+use crate::terminal;
 
 pub mod ps2;
 
@@ -38,9 +43,87 @@ pub enum KeyboardInitError {
 
 impl Error for KeyboardInitError { }
 
+/// Job control actions that can be requested from the keyboard, and which apply
+/// to the console's foreground process group.
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JobControl {
+    /// Kill the foreground process group with `SIG_INTERRUPT` (Ctrl-C).
+    Interrupt = 1,
+    /// Stop the foreground process group (Ctrl-Z).
+    Stop = 2,
+}
+
+impl JobControl {
+    fn from_u8(value: u8) -> Option<JobControl> {
+        match value {
+            1 => Some(JobControl::Interrupt),
+            2 => Some(JobControl::Stop),
+            _ => None
+        }
+    }
+}
+
+/// Job control action waiting for [dispatch_job_control], or zero.
+static PENDING_JOB_CONTROL: AtomicU8 = AtomicU8::new(0);
+
+/// Request a job control action from the keyboard interrupt handler.
+///
+/// Nothing happens until [dispatch_job_control] is called, which must only be
+/// done after the keyboard IRQ has been acknowledged, since it may switch away
+/// from the current process.
+///
+/// Returns false if there is no foreground process group, in which case the
+/// key should be handled normally.
+pub fn request_job_control(action: JobControl) -> bool {
+    if terminal::foreground_pgid().is_some() {
+        PENDING_JOB_CONTROL.store(action as u8, Relaxed);
+        true
+    } else {
+        false
+    }
+}
+
+/// Carry out any pending job control action on the foreground process group.
+///
+/// If the current process is in the foreground process group, this may not
+/// return (`Interrupt`), or may only return after it has been continued
+/// (`Stop`).
+pub fn dispatch_job_control() {
+    let action = JobControl::from_u8(PENDING_JOB_CONTROL.swap(0, Relaxed));
+
+    if let (Some(action), Some(pgid)) = (action, terminal::foreground_pgid()) {
+        debug!("Keyboard job control: {:?} pgid {}", action, pgid);
+
+        match action {
+            JobControl::Interrupt =>
+                process::signal_group(pgid, process::SIG_INTERRUPT),
+            JobControl::Stop =>
+                process::stop_group(pgid),
+        }
+    }
+}
+
 /// C interface. See `kit/kernel/include/keyboard.h`.
 pub mod ffi {
+    use crate::c_ffi::c_int;
+
+    use super::JobControl;
+
     extern {
         pub fn keyboard_initialize();
     }
+
+    #[no_mangle]
+    pub extern fn keyboard_request_job_control(action: c_int) -> c_int {
+        match JobControl::from_u8(action as u8) {
+            Some(action) => super::request_job_control(action) as c_int,
+            None => 0
+        }
+    }
+
+    #[no_mangle]
+    pub extern fn keyboard_dispatch_job_control() {
+        super::dispatch_job_control();
+    }
 }
@@ -11,8 +11,14 @@
  ******************************************************************************/
 
 //! Physical memory management and kernel heap.
+//!
+//! Physical pages are handed out by a [buddy allocator](buddy), so a request
+//! for `n` pages gets them all in one physically contiguous piece as long as
+//! memory isn't too fragmented for that.
 
 use core::cmp::min;
+use core::fmt;
+use core::mem;
 use core::ops::Range;
 use core::alloc::{GlobalAlloc, Layout};
 
@@ -20,15 +26,15 @@ use core::sync::atomic::AtomicUsize;
 use core::sync::atomic::Ordering::*;
 
 use alloc::vec::Vec;
-use alloc::sync::Arc;
 
 use crate::paging::{self, kernel_pageset};
 use crate::paging::{PagesetExt, PageType, Page};
 use crate::paging::PAGE_SIZE;
 
+use crate::interrupt;
 use crate::multiboot;
 use crate::process::Id as ProcessId;
-use crate::sync::LockFreeList;
+use crate::sync::{Spinlock, LockFreeList};
 use crate::sync::lock_free_list::Node;
 use crate::util::align_up;
 
@@ -36,6 +42,9 @@ pub mod pool;
 
 mod large_heap;
 
+pub mod buddy;
+use buddy::BuddyAllocator;
+
 pub mod region_math;
 pub use region_math::RegionSet;
 
@@ -99,8 +108,13 @@ fn handle_alloc_error(layout: Layout) -> ! {
 
 #[derive(Debug)]
 struct RegionState {
-    alloc_regions: LockFreeList<AllocRegionState>,
-    free_regions: LockFreeList<FreeRegion<PhysicalAddress>>,
+    /// Regions used by processes, and which processes use each of them.
+    alloc_regions: Spinlock<Vec<AllocRegionState>>,
+    /// Regions used by the kernel itself, which are never shared. The kernel
+    /// heap records its pages here when it grows, so unlike `alloc_regions`,
+    /// nothing may be allocated or freed while this is locked.
+    kernel_regions: Spinlock<Vec<Range<PhysicalAddress>>>,
+    free: BuddyAllocator,
     total_page_count: usize,
     free_page_count: AtomicUsize,
 }
@@ -115,7 +129,7 @@ pub type PageCount = usize;
 struct AllocRegionState {
     start: PhysicalAddress,
     length: PageCount,
-    users: Arc<Vec<RegionUser>>
+    users: Vec<RegionUser>
 }
 
 impl AllocRegionState {
@@ -185,7 +199,9 @@ impl InitMemoryMap {
 /// Loads the memory map information into the region tree in order to know where
 /// in physical memory it's safe to allocate fresh pages.
 pub unsafe fn initialize(memory_map: &InitMemoryMap) {
-    let free_regions = LockFreeList::new();
+    let free = BuddyAllocator::new();
+
+    let mut total_page_count = 0;
 
     for range in memory_map.heap_usable().iter() {
         let addr = range.start;
@@ -210,23 +226,17 @@ pub unsafe fn initialize(memory_map: &InitMemoryMap) {
             }
         }
 
-        // If the entry has at least one page, add it to the free regions.
-        if pages > 0 {
-            free_regions.push(Node::new(FreeRegion {
-                start: physical_base,
-                length: pages.into(),
-            }));
-        }
-    }
+        free.free_range(physical_base, pages);
 
-    let total_page_count = free_regions.iter().fold(0,
-        |s, r| s + r.length.load(Relaxed));
+        total_page_count += pages;
+    }
 
     REGION_STATE = Some(RegionState {
         total_page_count,
         free_page_count: total_page_count.into(),
-        free_regions,
-        alloc_regions: LockFreeList::new(),
+        free,
+        alloc_regions: Spinlock::new(vec![]),
+        kernel_regions: Spinlock::new(vec![]),
     });
 }
 
@@ -307,73 +317,220 @@ pub unsafe fn enable_large_heap() {
     debug!("Large heap enabled.");
 }
 
+/// Acquire `pages` physically contiguous pages for `owner`, and return where
+/// they start. Fails if there isn't a big enough free block; use
+/// [acquire_up_to] if fewer pages will do.
 pub fn acquire_region(owner: RegionUser, pages: PageCount)
-                      -> Option<(PhysicalAddress, PageCount)> {
+                      -> Option<PhysicalAddress> {
+
+    // Safety: initialized once
+    let state = unsafe { 
+        REGION_STATE.as_ref().expect("memory::initialize() not called")
+    };
+
+    let order = buddy::order_for(pages)?;
+
+    let paddr = state.free.allocate(order).or_else(|| {
+        trace!("No free physical region of {} pages available.", pages);
+        None
+    })?;
+
+    // Give back the rest of the block.
+    state.free.free_range(paddr + pages * PAGE_SIZE, (1 << order) - pages);
+
+    record_acquired(state, owner, paddr, pages);
+
+    Some(paddr)
+}
+
+/// Acquire between one and `pages` physically contiguous pages for `owner`, and
+/// return where they start and how many there are.
+///
+/// All of them are returned if there's a free block big enough (up to
+/// [buddy::MAX_ORDER]), and otherwise the biggest block there is, so callers
+/// have to be ready to loop.
+pub fn acquire_up_to(owner: RegionUser, pages: PageCount)
+                     -> Option<(PhysicalAddress, PageCount)> {
 
     // Safety: initialized once
     let state = unsafe { 
         REGION_STATE.as_ref().expect("memory::initialize() not called")
     };
 
-    let free_page_count = state.free_page_count.load(Relaxed);
-    if free_page_count < pages {
-        trace!("free_page_count={} < {}", free_page_count, pages);
-        return None;
+    let wanted = min(pages, 1 << buddy::MAX_ORDER);
+
+    if let Some(paddr) = acquire_region(owner, wanted) {
+        return Some((paddr, wanted));
+    }
+
+    // Nothing that big is free, so take the biggest block that is.
+    let (paddr, order) = (0..buddy::order_for(wanted)?).rev()
+        .find_map(|order| state.free.allocate(order).map(|p| (p, order)))
+        .or_else(|| {
+            trace!("No free physical region available.");
+            None
+        })?;
+
+    record_acquired(state, owner, paddr, 1 << order);
+
+    Some((paddr, 1 << order))
+}
+
+fn record_acquired(
+    state: &RegionState,
+    owner: RegionUser,
+    paddr: PhysicalAddress,
+    pages: PageCount
+) {
+    state.free_page_count.fetch_sub(pages, Relaxed);
+
+    if owner == RegionUser::Kernel {
+        with_room(&state.kernel_regions, 1, |regions| {
+            regions.push(paddr .. (paddr + pages * PAGE_SIZE));
+        });
+        return;
     }
 
-    let mut alloc_start = 0;
-    let mut acq_pages = 0;
+    with_regions(state, |regions| {
+        regions.push(AllocRegionState {
+            start: paddr,
+            length: pages,
+            users: vec![owner],
+        });
+    });
+}
 
-    // Find the first free region with (any) space
-    for free_region in state.free_regions.iter() {
-        let mut original_length = 0;
+/// Run `f` with the allocated regions locked and interrupts disabled, so that
+/// every change to who uses a page happens at once. Growing the kernel heap
+/// only touches `kernel_regions`, so `f` may allocate.
+fn with_regions<T, F>(state: &RegionState, f: F) -> T
+    where F: FnOnce(&mut Vec<AllocRegionState>) -> T {
+
+    interrupt::without_interrupts(|| f(&mut state.alloc_regions.lock()))
+}
 
-        // Take as many pages as we can from it, up to pages
-        let res = free_region.length.fetch_update(Relaxed, Relaxed,
-            |length| {
-                original_length = length;
+/// Run `f` on `list`, locked with interrupts disabled, once it has room for
+/// `room` more entries. The list is grown beforehand if it doesn't, so that `f`
+/// never has to allocate with the lock held.
+fn with_room<T, R, F>(list: &Spinlock<Vec<T>>, room: usize, f: F) -> R
+    where F: FnOnce(&mut Vec<T>) -> R {
 
-                if length > 0 {
-                    acq_pages = min(length, pages);
-                    Some(length - min(length, pages))
-                } else {
-                    acq_pages = 0;
-                    None
-                }
+    let mut f = Some(f);
+
+    loop {
+        let full = interrupt::without_interrupts(|| {
+            let mut list = list.lock();
+
+            if list.capacity() - list.len() >= room {
+                Ok((f.take().unwrap())(&mut list))
+            } else {
+                Err(list.capacity())
             }
-        );
+        });
 
-        // Our allocated region will be at the end, since we can only update
-        // length
-        alloc_start = free_region.start +
-            ((original_length - acq_pages) * PAGE_SIZE);
+        let capacity = match full {
+            Ok(result) => return result,
+            Err(capacity) => capacity,
+        };
 
-        // Remove free region if it's empty
-        if free_region.length.load(Relaxed) == 0 {
-            state.free_regions.remove(&free_region);
-        }
+        let mut bigger = Vec::with_capacity(capacity * 2 + room);
 
-        if res.is_ok() {
-            break;
-        }
+        interrupt::without_interrupts(|| {
+            let mut list = list.lock();
+
+            // It might have been grown while we weren't looking.
+            if list.capacity() < bigger.capacity() {
+                bigger.extend(list.drain(..));
+                mem::swap(&mut *list, &mut bigger);
+            }
+        });
+
+        // Whichever one we're left with is freed here, without the lock.
+        drop(bigger);
     }
+}
 
-    if acq_pages > 0 {
-        state.free_page_count.fetch_sub(acq_pages, Relaxed);
+/// Give back kernel pages recorded by [record_acquired]. Each piece is freed
+/// after unlocking, since the buddy allocator may allocate.
+fn release_kernel_region(
+    state: &RegionState,
+    paddr: PhysicalAddress,
+    pages: PageCount
+) {
+    let region_to_release = paddr .. (paddr + pages * PAGE_SIZE);
+
+    let mut pages_to_release = pages;
 
-        state.alloc_regions.push(Node::new(AllocRegionState {
-            start: alloc_start,
-            length: acq_pages,
-            users: vec![owner].into()
-        }));
+    while pages_to_release > 0 {
+        // Cutting a region out of the middle of one leaves one more behind.
+        let excluded = with_room(&state.kernel_regions, 1, |regions| {
+            let index = regions.iter().position(|region| {
+                region_math::overlaps(region, &region_to_release)
+            })?;
 
-        Some((alloc_start, acq_pages))
-    } else {
-        trace!("No free physical region available.");
-        None
+            let region = regions.swap_remove(index);
+
+            let cut = region_math::cut(region, region_to_release.clone())
+                .unwrap();
+
+            regions.extend([cut.before, cut.after].iter().flatten().cloned());
+
+            Some(cut.excluded)
+        });
+
+        let excluded = match excluded {
+            Some(excluded) => excluded,
+            None => break,
+        };
+
+        let excluded_length = (excluded.end - excluded.start)/PAGE_SIZE;
+
+        state.free.free_range(excluded.start, excluded_length);
+
+        state.free_page_count.fetch_add(excluded_length, Relaxed);
+
+        pages_to_release -= excluded_length;
+    }
+
+    if pages_to_release > 0 {
+        panic!("Wanted to release kernel physical region {:016x} x {}, \
+            but can't find at least {} pages.",
+            paddr, pages, pages_to_release);
     }
 }
 
+/// Take the first region overlapping `range` that `matches` out of `regions`,
+/// put back the parts of it outside of `range`, and return the part inside.
+fn take_overlapping<F>(
+    regions: &mut Vec<AllocRegionState>,
+    range: &Range<PhysicalAddress>,
+    matches: F,
+) -> Option<AllocRegionState>
+    where F: Fn(&AllocRegionState) -> bool {
+
+    let index = regions.iter().position(|region| {
+        region_math::overlaps(&region.range(), range) && matches(region)
+    })?;
+
+    let region = regions.swap_remove(index);
+
+    let cut = region_math::cut(region.range(), range.clone()).unwrap();
+
+    for r in [cut.before, cut.after].iter().flatten() {
+        regions.push(AllocRegionState {
+            start: r.start,
+            length: (r.end - r.start)/PAGE_SIZE,
+            users: region.users.clone(),
+        });
+    }
+
+    Some(AllocRegionState {
+        start: cut.excluded.start,
+        length: (cut.excluded.end - cut.excluded.start)/PAGE_SIZE,
+        users: region.users,
+    })
+}
+
 pub fn release_region(
     user: RegionUser,
     paddr: PhysicalAddress,
@@ -384,68 +541,44 @@ pub fn release_region(
         REGION_STATE.as_ref().expect("memory::initialize() not called")
     };
 
+    if user == RegionUser::Kernel {
+        release_kernel_region(state, paddr, pages);
+        return;
+    }
+
     let region_to_release = paddr .. (paddr + pages * PAGE_SIZE);
 
-    let mut pages_to_release = pages;
-    let mut tries = 0;
-
-    while pages_to_release > 0 && tries < 1000 {
-        // Find an overlapping region
-        let region = state.alloc_regions
-            .drain_filter(|region| {
-                region_math::overlaps(
-                    &region.range(), &region_to_release) &&
-                region.users.contains(&user)
-            })
-            .next();
-
-        if let Some(region) = region {
-            let cut = region_math::cut(
-                region.range(),
-                region_to_release.clone()).unwrap();
-
-            // Insert the before and after regions that still exist
-            for r in [cut.before, cut.after].iter().flatten() {
-                state.alloc_regions.push(Node::new(AllocRegionState {
-                    start: r.start,
-                    length: (r.end - r.start)/PAGE_SIZE,
-                    users: region.users.clone(),
-                }));
-            }
+    let pages_to_release = with_regions(state, |regions| {
+        let mut pages_to_release = pages;
 
-            let excluded_length =
-                (cut.excluded.end - cut.excluded.start)/PAGE_SIZE;
-
-            if region.users.len() > 1 {
-                // If there are other users, re-insert the excluded region with
-                // the other users
-                let reinsert = AllocRegionState {
-                    start: cut.excluded.start,
-                    length: excluded_length,
-                    users: region.users.iter().cloned()
-                        .filter(|u| *u != user)
-                        .collect::<Vec<_>>()
-                        .into(),
-                };
-                state.alloc_regions.push(Node::new(reinsert));
-            } else {
-                // We owned it exclusively. Just release the data to the free
-                // region list.
+        while pages_to_release > 0 {
+            let mut region = match take_overlapping(
+                regions, &region_to_release,
+                |region| region.users.contains(&user)) {
+
+                Some(region) => region,
+                None => break,
+            };
+
+            pages_to_release -= region.length;
 
-                release_to_free_region_list(&state.free_regions,
-                    cut.excluded.start, excluded_length, "physical");
+            region.users.retain(|u| *u != user);
+
+            if !region.users.is_empty() {
+                // There are other users, so it stays theirs.
+                regions.push(region);
+            } else {
+                // We owned it exclusively. Just give it back to the buddy
+                // allocator.
+                state.free.free_range(region.start, region.length);
 
                 // Update the counter
-                state.free_page_count.fetch_add(excluded_length, Relaxed);
+                state.free_page_count.fetch_add(region.length, Relaxed);
             }
-
-            pages_to_release -= excluded_length;
-        } else {
-            // Increment retry counter. It's possible it'll be there if we look
-            // again
-            tries += 1;
         }
-    }
+
+        pages_to_release
+    });
 
     if pages_to_release > 0 {
         panic!("Wanted to release physical region {:?}, {:016x} x {}, \
@@ -454,6 +587,75 @@ pub fn release_region(
     }
 }
 
+/// Add `new_user` as another user of pages already used by `user`, so that
+/// they aren't freed until both have released them. Only pages used by
+/// processes can be shared.
+pub fn share_region(
+    user: RegionUser,
+    new_user: RegionUser,
+    paddr: PhysicalAddress,
+    pages: PageCount
+) {
+    // Safety: initialized once
+    let state = unsafe { 
+        REGION_STATE.as_ref().expect("memory::initialize() not called")
+    };
+
+    assert!(user != RegionUser::Kernel && new_user != RegionUser::Kernel,
+        "kernel physical regions can't be shared");
+
+    let region_to_share = paddr .. (paddr + pages * PAGE_SIZE);
+
+    let pages_to_share = with_regions(state, |regions| {
+        let mut pages_to_share = pages;
+
+        while pages_to_share > 0 {
+            // Find an overlapping region that doesn't already have the new user
+            let mut region = match take_overlapping(
+                regions, &region_to_share,
+                |region| region.users.contains(&user) &&
+                    !region.users.contains(&new_user)) {
+
+                Some(region) => region,
+                None => break,
+            };
+
+            pages_to_share -= region.length;
+
+            region.users.push(new_user);
+            regions.push(region);
+        }
+
+        pages_to_share
+    });
+
+    if pages_to_share > 0 {
+        panic!("Wanted to share physical region {:?}, {:016x} x {} with {:?}, \
+            but can't find at least {} pages.",
+            user, paddr, pages, new_user, pages_to_share);
+    }
+}
+
+/// How many users the page at `paddr` has. Zero if it isn't allocated.
+pub fn region_user_count(paddr: PhysicalAddress) -> usize {
+    // Safety: initialized once
+    let state = unsafe { 
+        REGION_STATE.as_ref().expect("memory::initialize() not called")
+    };
+
+    let process_users = with_regions(state, |regions| {
+        regions.iter()
+            .find(|region| region.range().contains(&paddr))
+            .map(|region| region.users.len())
+    });
+
+    process_users.unwrap_or_else(|| {
+        with_room(&state.kernel_regions, 0, |regions| {
+            regions.iter().filter(|region| region.contains(&paddr)).count()
+        })
+    })
+}
+
 fn release_to_free_region_list(
     list: &LockFreeList<FreeRegion<usize>>,
     start: usize,
@@ -538,7 +740,7 @@ fn kernel_acquire_and_map<F>(
 
     while cur_pages > 0 {
         let (got_paddr, got_pages) =
-            match acquire_region(RegionUser::Kernel, cur_pages) {
+            match acquire_up_to(RegionUser::Kernel, cur_pages) {
                 Some(x) => x,
                 None => return Err(pages - cur_pages)
             };
@@ -595,17 +797,15 @@ pub fn allocate_kernel_stack() -> *mut u8 {
     large_heap::allocate_kernel_stack(heap_state)
 }
 
-pub fn debug_print_allocator_stats() {
-    use crate::terminal::console;
-
+pub fn debug_print_allocator_stats<W: fmt::Write + ?Sized>(out: &mut W) {
     unsafe {
         match KERNEL_HEAP {
             KernelHeap::InitialHeap(count) => {
-                let _ = writeln!(console(), "Initial heap: {} / {}",
+                let _ = writeln!(out, "Initial heap: {} / {}",
                     count, INITIAL_HEAP_LENGTH);
             },
             KernelHeap::LargeHeap(ref state) => {
-                large_heap::debug_print_allocator_stats(state);
+                large_heap::debug_print_allocator_stats(state, out);
             },
             #[cfg(test)]
             KernelHeap::StdHeap => {
@@ -615,31 +815,70 @@ pub fn debug_print_allocator_stats() {
     }
 }
 
-pub fn debug_print_physical_mem_stats() {
-    use crate::terminal::console;
-
+pub fn debug_print_physical_mem_stats<W: fmt::Write + ?Sized>(out: &mut W) {
     // Safety: initialized once
     let state = unsafe { 
         REGION_STATE.as_ref().expect("memory::initialize() not called")
     };
 
-    for region in state.free_regions.iter() {
-        let _ = writeln!(console(), "FREE {:016x} - {:016x}",
-            region.start,
-            region.start + region.length.load(Relaxed) * PAGE_SIZE);
+    let blocks = state.free.free_blocks();
+
+    // Adjacent blocks that aren't buddies make up one free region.
+    let mut regions: Vec<Range<PhysicalAddress>> = vec![];
+
+    for &(start, order) in &blocks {
+        let end = start + buddy::block_size(order);
+
+        match regions.last_mut() {
+            Some(last) if last.end == start => last.end = end,
+            _ => regions.push(start..end),
+        }
+    }
+
+    for region in &regions {
+        let _ = writeln!(out, "FREE {:016x} - {:016x}",
+            region.start, region.end);
     }
 
     let free = state.free_page_count.load(Relaxed);
     let total = state.total_page_count;
     let used = total - free;
 
-    let _ = writeln!(console(), "Pages: {} free / {} used / {} total",
+    let _ = writeln!(out, "Pages: {} free / {} used / {} total",
         free, used, total);
 
-    let _ = writeln!(console(), "Bytes: {} M free / {} M used / {} M total",
+    let _ = writeln!(out, "Bytes: {} M free / {} M used / {} M total",
         free * PAGE_SIZE / 1048576,
         used * PAGE_SIZE / 1048576,
         total * PAGE_SIZE / 1048576);
+
+    // Fragmentation
+    let mut counts = [0usize; buddy::MAX_ORDER + 1];
+
+    for &(_, order) in &blocks {
+        counts[order] += 1;
+    }
+
+    let _ = write!(out, "Free blocks by order:");
+
+    for (order, &count) in counts.iter().enumerate().filter(|(_, &c)| c > 0) {
+        let _ = write!(out, " {}:{}", order, count);
+    }
+
+    let _ = writeln!(out);
+
+    let largest = blocks.iter().map(|&(_, order)| 1 << order).max()
+        .unwrap_or(0);
+
+    let _ = writeln!(out, "Largest free block: {} pages ({} K)",
+        largest, largest * PAGE_SIZE / 1024);
+
+    // How much of the free memory can't be had in one piece.
+    if free > 0 {
+        let _ = writeln!(out, "Fragmentation: {}% of free pages outside the \
+            largest block, {} free regions",
+            (free - min(largest, free)) * 100 / free, regions.len());
+    }
 }
 
 /// C foreign interface.
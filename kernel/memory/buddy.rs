@@ -0,0 +1,435 @@
+/*******************************************************************************
+ *
+ * kit/kernel/memory/buddy.rs
+ *
+ * vim:ft=rust:ts=4:sw=4:et:tw=80
+ *
+ * Copyright (C) 2015-2021, Devyn Cairns
+ * Redistribution of this file is permitted under the terms of the simplified
+ * BSD license. See LICENSE for more information.
+ *
+ ******************************************************************************/
+
+//! Buddy allocator for physical pages.
+//!
+//! Free memory is kept as blocks of 2^order pages, aligned to their size, with
+//! one list per order. Allocating splits the smallest block that's big enough
+//! in half until it's the right size, and freeing merges a block with its buddy
+//! (the other half of the block they were split from) for as long as the buddy
+//! is free too.
+//!
+//! The lists are on the kernel heap rather than in the free pages themselves,
+//! because physical memory isn't mapped anywhere we could write to it. Each is
+//! indexed by address, so a buddy can be found and taken out without searching.
+//! They're all behind one lock, since a split or merge touches several of them.
+//! The kernel heap comes here for more memory when it grows, so nothing may be
+//! allocated while the lock is held: the lists are grown beforehand instead.
+
+use core::mem;
+
+use alloc::vec::Vec;
+
+use crate::interrupt;
+use crate::paging::PAGE_SIZE;
+use crate::sync::Spinlock;
+
+use super::{PhysicalAddress, PageCount};
+
+/// Blocks are at most 2^MAX_ORDER pages (1 GiB).
+pub const MAX_ORDER: usize = 18;
+
+const ORDERS: usize = MAX_ORDER + 1;
+
+type FreeLists = [FreeList; ORDERS];
+
+/// Size of a block of `order` in bytes.
+pub const fn block_size(order: usize) -> usize {
+    PAGE_SIZE << order
+}
+
+/// The smallest order of block that holds `pages` pages. None if that's zero or
+/// more than a block of [MAX_ORDER].
+pub fn order_for(pages: PageCount) -> Option<usize> {
+    (0..ORDERS).find(|&order| 1 << order >= pages).filter(|_| pages > 0)
+}
+
+/// Marks an unused slot in [FreeList::index].
+const EMPTY: usize = usize::MAX;
+
+/// The start of each free block of one order.
+#[derive(Debug, Default)]
+struct FreeList {
+    blocks: Vec<PhysicalAddress>,
+
+    /// Where each block is in `blocks`, as a hash table keyed by the block's
+    /// address, with linear probing. Its length is a power of two, and it's
+    /// kept at most half full.
+    index: Vec<usize>,
+}
+
+impl FreeList {
+    fn with_capacity(capacity: usize) -> FreeList {
+        FreeList {
+            blocks: Vec::with_capacity(capacity),
+            index: vec![EMPTY; (capacity * 2).next_power_of_two()],
+        }
+    }
+
+    /// True if another block can be pushed without allocating.
+    fn has_room(&self) -> bool {
+        self.blocks.len() < self.blocks.capacity() &&
+            (self.blocks.len() + 1) * 2 <= self.index.len()
+    }
+
+    fn len(&self) -> usize {
+        self.blocks.len()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+    }
+
+    /// The slot `block` would like to be at in the index.
+    fn home(&self, block: PhysicalAddress) -> usize {
+        let bits = self.index.len().trailing_zeros();
+
+        // Fibonacci hashing: the top bits of the product are well mixed.
+        let hash = ((block / PAGE_SIZE) as u64)
+            .wrapping_mul(0x9e37_79b9_7f4a_7c15);
+
+        (hash.checked_shr(64 - bits).unwrap_or(0)) as usize
+    }
+
+    /// The slot holding `block`, or the empty slot where it would go.
+    fn slot(&self, block: PhysicalAddress) -> Result<usize, usize> {
+        let mask = self.index.len() - 1;
+
+        let mut slot = self.home(block);
+
+        loop {
+            match self.index[slot] {
+                EMPTY => return Err(slot),
+                pos if self.blocks[pos] == block => return Ok(slot),
+                _ => slot = (slot + 1) & mask,
+            }
+        }
+    }
+
+    fn push(&mut self, block: PhysicalAddress) {
+        debug_assert!(self.has_room());
+
+        match self.slot(block) {
+            Ok(_) => panic!("block {:016x} freed twice", block),
+            Err(slot) => self.index[slot] = self.blocks.len(),
+        }
+
+        self.blocks.push(block);
+    }
+
+    fn pop(&mut self) -> Option<PhysicalAddress> {
+        let block = *self.blocks.last()?;
+
+        self.remove(block);
+
+        Some(block)
+    }
+
+    /// Take `block` out of the list. False if it wasn't there.
+    fn remove(&mut self, block: PhysicalAddress) -> bool {
+        if self.is_empty() {
+            return false;
+        }
+
+        let slot = match self.slot(block) {
+            Ok(slot) => slot,
+            Err(_) => return false,
+        };
+
+        let pos = self.index[slot];
+        let last = self.blocks.len() - 1;
+
+        self.unindex(slot);
+
+        // The last block is about to take its place.
+        if pos != last {
+            let moved_slot = self.slot(self.blocks[last]).unwrap();
+
+            self.index[moved_slot] = pos;
+        }
+
+        self.blocks.swap_remove(pos);
+
+        true
+    }
+
+    /// Empty `slot`, moving back any entries after it that were pushed past it,
+    /// so that probing never stops early.
+    fn unindex(&mut self, slot: usize) {
+        let mask = self.index.len() - 1;
+
+        let mut hole = slot;
+        let mut next = (slot + 1) & mask;
+
+        while self.index[next] != EMPTY {
+            let home = self.home(self.blocks[self.index[next]]);
+
+            // It can fill the hole unless its home is after the hole.
+            let from_home = next.wrapping_sub(home) & mask;
+
+            if from_home >= next.wrapping_sub(hole) & mask {
+                self.index[hole] = self.index[next];
+                hole = next;
+            }
+
+            next = (next + 1) & mask;
+        }
+
+        self.index[hole] = EMPTY;
+    }
+
+    /// Fill this empty list with the blocks of `other`. This one must have
+    /// enough capacity.
+    fn fill_from(&mut self, other: &FreeList) {
+        debug_assert!(self.is_empty());
+        debug_assert!(other.len() < self.blocks.capacity());
+
+        for &block in &other.blocks {
+            self.push(block);
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct BuddyAllocator {
+    free: Spinlock<FreeLists>,
+}
+
+impl BuddyAllocator {
+    /// An allocator with no free memory. Add some with
+    /// [free_range](BuddyAllocator::free_range).
+    pub fn new() -> BuddyAllocator {
+        BuddyAllocator {
+            free: Spinlock::new(Default::default()),
+        }
+    }
+
+    /// Run `f` with the free lists locked and interrupts disabled.
+    fn locked<T, F>(&self, f: F) -> T
+        where F: FnOnce(&mut FreeLists) -> T {
+
+        interrupt::without_interrupts(|| f(&mut self.free.lock()))
+    }
+
+    /// Run `f` on the free lists once every list from `order` up has room for
+    /// one more block, which is as many as a split or merge starting at `order`
+    /// can add.
+    fn with_room<T, F>(&self, order: usize, f: F) -> T
+        where F: FnOnce(&mut FreeLists) -> T {
+
+        let mut f = Some(f);
+
+        loop {
+            let full = self.locked(|lists| {
+                let full = (order..ORDERS).find(|&o| !lists[o].has_room());
+
+                match full {
+                    Some(full) => Err((full, lists[full].blocks.capacity())),
+                    None => Ok((f.take().unwrap())(lists)),
+                }
+            });
+
+            let (full, capacity) = match full {
+                Ok(result) => return result,
+                Err(full) => full,
+            };
+
+            let mut bigger = FreeList::with_capacity(capacity * 2 + 8);
+
+            self.locked(|lists| {
+                // It might have been grown while we weren't looking.
+                if lists[full].blocks.capacity() < bigger.blocks.capacity() {
+                    bigger.fill_from(&lists[full]);
+                    mem::swap(&mut lists[full], &mut bigger);
+                }
+            });
+
+            // Whichever one we're left with is freed here, without the lock.
+            drop(bigger);
+        }
+    }
+
+    /// Take a block of `order` out of the free lists and return its start.
+    pub fn allocate(&self, order: usize) -> Option<PhysicalAddress> {
+        self.with_room(order, |lists| {
+            let found = (order..ORDERS).find(|&o| !lists[o].is_empty())?;
+
+            let block = lists[found].pop()?;
+
+            // Give back the halves we don't need, leaving the first one.
+            for split in (order..found).rev() {
+                lists[split].push(block + block_size(split));
+            }
+
+            Some(block)
+        })
+    }
+
+    /// Put the block of `order` at `block` back in the free lists, merging it
+    /// with its buddy if that's free.
+    pub fn free(&self, block: PhysicalAddress, order: usize) {
+        debug_assert!(block % block_size(order) == 0,
+            "block {:016x} isn't aligned to order {}", block, order);
+
+        self.with_room(order, |lists| {
+            let (mut block, mut order) = (block, order);
+
+            while order < MAX_ORDER {
+                let buddy = block ^ block_size(order);
+
+                if !lists[order].remove(buddy) {
+                    break;
+                }
+
+                block = block.min(buddy);
+                order += 1;
+            }
+
+            lists[order].push(block);
+        })
+    }
+
+    /// Free `pages` pages at `start`, which don't have to make up a whole
+    /// block, as the biggest blocks that fit.
+    pub fn free_range(&self, mut start: PhysicalAddress, mut pages: PageCount) {
+        debug_assert!(start % PAGE_SIZE == 0);
+
+        while pages > 0 {
+            let order = (0..ORDERS).rev()
+                .find(|&order| start % block_size(order) == 0 &&
+                    pages >= 1 << order)
+                .unwrap_or(0);
+
+            self.free(start, order);
+
+            start += block_size(order);
+            pages -= 1 << order;
+        }
+    }
+
+    /// Every free block as `(start, order)`, in address order.
+    pub fn free_blocks(&self) -> Vec<(PhysicalAddress, usize)> {
+        loop {
+            let count: usize = self.locked(|lists| {
+                lists.iter().map(FreeList::len).sum()
+            });
+
+            let mut blocks = Vec::with_capacity(count);
+
+            let copied = self.locked(|lists| {
+                if lists.iter().map(FreeList::len).sum::<usize>() > count {
+                    return false;
+                }
+
+                for (order, list) in lists.iter().enumerate() {
+                    blocks.extend(
+                        list.blocks.iter().map(|&block| (block, order)));
+                }
+
+                true
+            });
+
+            if copied {
+                blocks.sort();
+                return blocks;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn orders_are_rounded_up() {
+        assert_eq!(order_for(0), None);
+        assert_eq!(order_for(1), Some(0));
+        assert_eq!(order_for(3), Some(2));
+        assert_eq!(order_for(4), Some(2));
+        assert_eq!(order_for(1 << MAX_ORDER), Some(MAX_ORDER));
+        assert_eq!(order_for((1 << MAX_ORDER) + 1), None);
+    }
+
+    #[test]
+    fn ranges_are_freed_as_aligned_blocks() {
+        let buddy = BuddyAllocator::new();
+
+        // Pages 3..16
+        buddy.free_range(3 * PAGE_SIZE, 13);
+
+        assert_eq!(buddy.free_blocks(), [
+            (3 * PAGE_SIZE, 0),
+            (4 * PAGE_SIZE, 2),
+            (8 * PAGE_SIZE, 3),
+        ]);
+    }
+
+    #[test]
+    fn blocks_are_split_and_merged() {
+        let buddy = BuddyAllocator::new();
+
+        buddy.free_range(0, 8);
+
+        let a = buddy.allocate(0).unwrap();
+        let b = buddy.allocate(1).unwrap();
+
+        assert_eq!(a, 0);
+        assert_eq!(b, 2 * PAGE_SIZE);
+        assert_eq!(buddy.free_blocks(), [(PAGE_SIZE, 0), (4 * PAGE_SIZE, 2)]);
+
+        assert_eq!(buddy.allocate(3), None);
+
+        buddy.free(a, 0);
+        buddy.free(b, 1);
+
+        assert_eq!(buddy.free_blocks(), [(0, 3)]);
+    }
+
+    #[test]
+    fn lists_grow_without_losing_blocks() {
+        let buddy = BuddyAllocator::new();
+
+        // Every other page, so none of them merge.
+        for page in 0..100 {
+            buddy.free(page * 2 * PAGE_SIZE, 0);
+        }
+
+        assert_eq!(buddy.free_blocks().len(), 100);
+
+        let mut allocated: Vec<_> =
+            (0..100).map(|_| buddy.allocate(0).unwrap()).collect();
+
+        allocated.sort();
+
+        assert_eq!(allocated,
+            (0..100).map(|page| page * 2 * PAGE_SIZE).collect::<Vec<_>>());
+        assert_eq!(buddy.allocate(0), None);
+    }
+    #[test]
+    fn buddies_are_found_among_many_free_blocks() {
+        let buddy = BuddyAllocator::new();
+
+        for page in (0..1024).step_by(2) {
+            buddy.free(page * PAGE_SIZE, 0);
+        }
+
+        assert_eq!(buddy.free_blocks().len(), 512);
+
+        // Backwards, so blocks move around in the lists as they're taken out.
+        for page in (1..1024).step_by(2).rev() {
+            buddy.free(page * PAGE_SIZE, 0);
+        }
+
+        assert_eq!(buddy.free_blocks(), [(0, 10)]);
+    }
+}
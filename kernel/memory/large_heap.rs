@@ -21,6 +21,7 @@ use alloc::vec::Vec;
 use core::sync::atomic::AtomicUsize;
 use core::sync::atomic::Ordering::*;
 use core::cmp;
+use core::fmt;
 
 use super::{VirtualAddress, PageCount, FreeRegion};
 use super::KSTACK_SIZE;
@@ -175,19 +176,19 @@ pub unsafe fn initialize() -> HeapState {
     }
 }
 
-pub fn debug_print_allocator_stats(state: &HeapState) {
-    use crate::terminal::console;
+pub fn debug_print_allocator_stats<W>(state: &HeapState, out: &mut W)
+    where W: fmt::Write + ?Sized {
 
-    let _ = writeln!(console(), "Large heap: {:016x} - {:016x}",
+    let _ = writeln!(out, "Large heap: {:016x} - {:016x}",
         state.start, state.end);
 
     {
         let pools = state.pools.lock();
 
-        let _ = writeln!(console(), "OSIZE RSIZE FREE     USED     CAPACITY");
+        let _ = writeln!(out, "OSIZE RSIZE FREE     USED     CAPACITY");
 
         for (size, pool) in pools.iter() {
-            let _ = writeln!(console(), "{:<5} {:<5} {:<8} {:<8} {:<8}",
+            let _ = writeln!(out, "{:<5} {:<5} {:<8} {:<8} {:<8}",
                 size,
                 pool.region_pages(),
                 pool.objects_free(),
@@ -199,7 +200,7 @@ pub fn debug_print_allocator_stats(state: &HeapState) {
     {
         let stacks_end = state.stacks_end.lock();
 
-        let _ = writeln!(console(), "Stacks: {:p} - {:p}",
+        let _ = writeln!(out, "Stacks: {:p} - {:p}",
             state.stacks_start, *stacks_end);
     }
 
@@ -0,0 +1,680 @@
+/*******************************************************************************
+ *
+ * kit/kernel/monitor.rs
+ *
+ * vim:ft=rust:ts=4:sw=4:et:tw=80
+ *
+ * Copyright (C) 2015-2021, Devyn Cairns
+ * Redistribution of this file is permitted under the terms of the simplified
+ * BSD license. See LICENSE for more information.
+ *
+ ******************************************************************************/
+
+//! Interactive kernel monitor.
+//!
+//! The monitor is a kernel thread running at the highest priority, so it keeps
+//! responding even when userland is wedged. Input comes straight from interrupt
+//! handlers, without going through any process:
+//!
+//! * Pressing Ctrl+Alt+Scroll Lock switches the keyboard over to the monitor.
+//!   Keys go to the monitor instead of the console's processes until `exit`.
+//! * Every line received on COM1 is a monitor command, unless the kernel was
+//!   booted with `serial=disable`.
+//!
+//! Type `help` for a list of commands.
+
+use core::cell::UnsafeCell;
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering::*};
+
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use crate::cmdline::Cmdline;
+use crate::log;
+use crate::memory;
+use crate::paging::{self, PageType, PAGE_SIZE};
+use crate::process::{self, RcProcess};
+use crate::scheduler::{self, NICE_MIN};
+use crate::serial;
+use crate::terminal::console;
+
+/// Keycode of Scroll Lock, which enters the monitor with Ctrl+Alt.
+const KEYCODE_SCROLL_LOCK: u8 = (0 << 5) + 14;
+
+/// Longest command line accepted.
+const MAX_LINE: usize = 256;
+
+const PROMPT: &str = "monitor> ";
+
+static mut MONITOR: Option<RcProcess> = None;
+
+static SERIAL_ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// The keyboard is switched over to the monitor.
+static KEYBOARD_ACTIVE: AtomicBool = AtomicBool::new(false);
+
+/// The keyboard was just switched over, so the monitor should greet the user.
+static KEYBOARD_ENTERED: AtomicBool = AtomicBool::new(false);
+
+static KEYBOARD_INPUT: InputQueue = InputQueue::new();
+
+static SERIAL_INPUT: InputQueue = InputQueue::new();
+
+/// Start the monitor thread. Must be called after [process::initialize].
+pub fn initialize(cmdline: &Cmdline) {
+    let serial = !cmdline.iter().any(|p| p == ("serial", "disable"));
+
+    let id = process::spawn_kthread("monitor", run);
+
+    let monitor = process::by_id(id).expect("monitor thread disappeared");
+
+    if let Err(e) = scheduler::set_nice(&monitor, NICE_MIN) {
+        warn!("monitor: failed to raise priority: {}", e);
+    }
+
+    unsafe {
+        MONITOR = Some(monitor);
+    }
+
+    SERIAL_ENABLED.store(serial, SeqCst);
+
+    info!("monitor: press Ctrl+Alt+Scroll Lock{}",
+        if serial { " or type on COM1" } else { "" });
+}
+
+fn initialized() -> bool {
+    unsafe { MONITOR.is_some() }
+}
+
+/// Handle a key press from the keyboard interrupt handler. Returns true if the
+/// key was taken by the monitor and should not be passed on.
+pub fn handle_keypress(keycode: u8, keychar: u8, ctrl: bool, alt: bool)
+                       -> bool {
+    if !initialized() {
+        return false;
+    }
+
+    if ctrl && alt && keycode == KEYCODE_SCROLL_LOCK {
+        if !KEYBOARD_ACTIVE.swap(true, SeqCst) {
+            KEYBOARD_ENTERED.store(true, SeqCst);
+        }
+        return true;
+    }
+
+    if KEYBOARD_ACTIVE.load(SeqCst) {
+        if keychar != 0 {
+            KEYBOARD_INPUT.push(keychar);
+        }
+        true
+    } else {
+        false
+    }
+}
+
+/// True if keys are going to the monitor, including key releases.
+pub fn keyboard_active() -> bool {
+    KEYBOARD_ACTIVE.load(SeqCst)
+}
+
+/// Pick up serial input and wake the monitor if it has anything to do. Called
+/// from interrupt handlers, after the IRQ has been acknowledged.
+pub fn poll() {
+    if !initialized() {
+        return;
+    }
+
+    if SERIAL_ENABLED.load(Relaxed) {
+        let mut com1 = serial::com1();
+
+        while let Some(byte) = com1.try_read_byte() {
+            SERIAL_INPUT.push(byte);
+        }
+    }
+
+    if has_work() {
+        wake();
+    }
+}
+
+fn has_work() -> bool {
+    KEYBOARD_ENTERED.load(SeqCst) ||
+        !KEYBOARD_INPUT.is_empty() ||
+        !SERIAL_INPUT.is_empty()
+}
+
+fn wake() {
+    let monitor = match unsafe { MONITOR.as_ref() } {
+        Some(monitor) => monitor,
+        None => return
+    };
+
+    // We may have interrupted the monitor itself on its way to sleep. Don't
+    // wait for the lock; the next timer tick will try again.
+    let sleeping = monitor.try_lock()
+        .map(|process| process.is_sleeping())
+        .unwrap_or(false);
+
+    if sleeping {
+        let _ = scheduler::awaken(monitor.clone());
+    }
+}
+
+/// The monitor thread.
+fn run() {
+    let mut keyboard = LineEditor::default();
+    let mut serial = LineEditor::default();
+
+    loop {
+        if KEYBOARD_ENTERED.swap(false, SeqCst) {
+            let out = &mut Output(Source::Keyboard);
+
+            keyboard.clear();
+
+            let _ = write!(out, "\nKit kernel monitor. Type 'help' for a list \
+                of commands, or 'exit' to leave.\n{}", PROMPT);
+        }
+
+        while let Some(byte) = KEYBOARD_INPUT.pop() {
+            if !keyboard_active() {
+                continue;
+            }
+
+            let out = &mut Output(Source::Keyboard);
+
+            if let Some(line) = keyboard.input(byte, out) {
+                if execute(&line, out) == Action::Exit {
+                    KEYBOARD_ACTIVE.store(false, SeqCst);
+                } else {
+                    let _ = out.write_str(PROMPT);
+                }
+            }
+        }
+
+        while let Some(byte) = SERIAL_INPUT.pop() {
+            let out = &mut Output(Source::Serial);
+
+            if let Some(line) = serial.input(byte, out) {
+                execute(&line, out);
+                let _ = out.write_str(PROMPT);
+            }
+        }
+
+        // Input that arrives after this check is picked up on the next timer
+        // tick, by [poll].
+        if !has_work() {
+            process::sleep();
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Source {
+    Keyboard,
+    Serial,
+}
+
+/// Writes to the console or COM1, depending on where the command came from.
+struct Output(Source);
+
+impl fmt::Write for Output {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        match self.0 {
+            Source::Keyboard => console().write_str(s),
+            Source::Serial => {
+                let mut com1 = serial::com1();
+
+                for (index, line) in s.split('\n').enumerate() {
+                    if index > 0 {
+                        com1.write_str("\r\n")?;
+                    }
+                    com1.write_str(line)?;
+                }
+
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Collects a line of input, echoing it back.
+#[derive(Debug, Default)]
+struct LineEditor {
+    line: String,
+    last_cr: bool,
+}
+
+impl LineEditor {
+    fn clear(&mut self) {
+        self.line.clear();
+        self.last_cr = false;
+    }
+
+    /// Handle a byte of input. Returns the line once it's complete.
+    fn input<W>(&mut self, byte: u8, out: &mut W) -> Option<String>
+        where W: Write + Backspace {
+
+        let last_cr = self.last_cr;
+        self.last_cr = byte == b'\r';
+
+        match byte {
+            // Terminals may send CR LF for enter.
+            b'\n' if last_cr => None,
+
+            b'\r' | b'\n' => {
+                let _ = out.write_char('\n');
+                Some(core::mem::replace(&mut self.line, String::new()))
+            },
+
+            0x08 | 0x7f => {
+                if self.line.pop().is_some() {
+                    let _ = out.backspace();
+                }
+                None
+            },
+
+            0x20..=0x7e if self.line.len() < MAX_LINE => {
+                self.line.push(byte as char);
+                let _ = out.write_char(byte as char);
+                None
+            },
+
+            _ => None
+        }
+    }
+}
+
+/// Erasing the last character echoed.
+trait Backspace {
+    fn backspace(&mut self) -> fmt::Result;
+}
+
+impl Backspace for Output {
+    fn backspace(&mut self) -> fmt::Result {
+        match self.0 {
+            // The console erases the character itself.
+            Source::Keyboard => self.write_char('\x08'),
+            Source::Serial => self.write_str("\x08 \x08"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Action {
+    Continue,
+    Exit,
+}
+
+const HELP: &[(&str, &str)] = &[
+    ("help",          "show this list"),
+    ("ps",            "list processes"),
+    ("mem",           "show heap and physical memory stats"),
+    ("kill PID",      "kill a process"),
+    ("log FILTER",    "set log levels, e.g. 'debug,kernel::memory=trace'"),
+    ("pt [PID]",      "dump the page tables of a process, or the kernel"),
+    ("reboot",        "reset the machine"),
+    ("exit",          "give the keyboard back to the console"),
+];
+
+fn execute<W: Write>(line: &str, out: &mut W) -> Action {
+    let mut words = line.split_whitespace();
+
+    let command = match words.next() {
+        Some(command) => command,
+        None => return Action::Continue,
+    };
+
+    let arg = words.next();
+
+    let _ = match command {
+        "help" => {
+            HELP.iter().try_for_each(|(usage, description)| {
+                writeln!(out, "  {:<12} {}", usage, description)
+            })
+        },
+        "ps" => {
+            process::debug_print_processes(out);
+            Ok(())
+        },
+        "mem" => {
+            memory::debug_print_allocator_stats(out);
+            memory::debug_print_physical_mem_stats(out);
+            Ok(())
+        },
+        "kill" => kill(arg, out),
+        "log" => match arg {
+            Some(filter) if log::global().set_log_levels(filter).is_ok() =>
+                writeln!(out, "Log levels set to {}", filter),
+            _ => writeln!(out, "Invalid log level filter"),
+        },
+        "pt" => dump_page_tables(arg, out),
+        "reboot" => {
+            let _ = writeln!(out, "Rebooting...");
+            reboot()
+        },
+        "exit" => return Action::Exit,
+        _ => writeln!(out, "Unknown command '{}'. Try 'help'.", command),
+    };
+
+    Action::Continue
+}
+
+fn parse_pid<W: Write>(arg: Option<&str>, out: &mut W)
+                       -> Result<Option<process::Id>, fmt::Error> {
+    match arg.map(|arg| arg.parse()) {
+        Some(Ok(pid)) => Ok(Some(pid)),
+        Some(Err(_)) => {
+            writeln!(out, "Invalid PID '{}'", arg.unwrap())?;
+            Ok(None)
+        },
+        None => {
+            writeln!(out, "Missing PID")?;
+            Ok(None)
+        }
+    }
+}
+
+fn kill<W: Write>(arg: Option<&str>, out: &mut W) -> fmt::Result {
+    let pid = match parse_pid(arg, out)? {
+        Some(pid) => pid,
+        None => return Ok(()),
+    };
+
+    let kernel_id = process::kernel().lock().id();
+    let monitor_id = process::current().lock().id();
+
+    if pid == kernel_id || pid == monitor_id {
+        return writeln!(out, "Refusing to kill process {}", pid);
+    }
+
+    match process::signal(pid, process::SIG_KILL) {
+        Ok(()) => writeln!(out, "Killed process {}", pid),
+        Err(e) => writeln!(out, "Failed to kill process {}: {}", pid, e),
+    }
+}
+
+fn dump_page_tables<W: Write>(arg: Option<&str>, out: &mut W) -> fmt::Result {
+    let pageset = match arg {
+        Some(_) => {
+            let pid = match parse_pid(arg, out)? {
+                Some(pid) => pid,
+                None => return Ok(()),
+            };
+
+            let process = match process::by_id(pid) {
+                Some(process) => process,
+                None => return writeln!(out, "No such process {}", pid),
+            };
+
+            let pageset = process.lock().pageset();
+
+            match pageset {
+                Some(pageset) => Some(pageset),
+                None => return writeln!(out,
+                    "Process {} has no address space of its own", pid),
+            }
+        },
+        None => None,
+    };
+
+    let walk = |emit: &mut dyn FnMut(usize, usize, PageType)| {
+        match pageset {
+            Some(ref pageset) => pageset.lock().for_each_mapped(emit),
+            None => unsafe { paging::kernel_pageset().for_each_mapped(emit) },
+        }
+    };
+
+    // Allocating can map more of the kernel heap, which would change the
+    // kernel pageset while we're walking it. So count the runs first, and then
+    // collect them into space reserved up front without going over it.
+    let mut count = 0;
+    let mut run = None;
+
+    walk(&mut |vaddr, paddr, page_type| {
+        if Run::extend(&mut run, vaddr, paddr, page_type).is_some() {
+            count += 1;
+        }
+    });
+
+    let mut runs = Vec::with_capacity(count + 1);
+    let mut truncated = false;
+    let mut run = None;
+
+    walk(&mut |vaddr, paddr, page_type| {
+        if let Some(done) = Run::extend(&mut run, vaddr, paddr, page_type) {
+            if runs.len() < runs.capacity() {
+                runs.push(done);
+            } else {
+                truncated = true;
+            }
+        }
+    });
+
+    if let Some(last) = run {
+        if runs.len() < runs.capacity() {
+            runs.push(last);
+        } else {
+            truncated = true;
+        }
+    }
+
+    writeln!(out, "{:<16}   {:<16}   {:<16} {:>8} FLAGS",
+        "START", "END", "PHYSICAL", "PAGES")?;
+
+    for run in runs {
+        writeln!(out, "{}", run)?;
+    }
+
+    if truncated {
+        writeln!(out, "(page tables changed while printing; truncated)")?;
+    }
+
+    Ok(())
+}
+
+/// Pages that are contiguous both virtually and physically, with the same
+/// permissions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Run {
+    vaddr: usize,
+    paddr: usize,
+    pages: usize,
+    page_type: PageType,
+}
+
+impl Run {
+    /// Add a page to the current run, if it continues it. Otherwise, start a
+    /// new run and return the finished one.
+    fn extend(run: &mut Option<Run>,
+              vaddr: usize,
+              paddr: usize,
+              page_type: PageType)
+              -> Option<Run> {
+
+        if let Some(ref mut current) = run {
+            let size = current.pages * PAGE_SIZE;
+
+            if current.vaddr + size == vaddr &&
+                current.paddr + size == paddr &&
+                current.page_type == page_type {
+
+                current.pages += 1;
+                return None;
+            }
+        }
+
+        run.replace(Run { vaddr, paddr, pages: 1, page_type })
+    }
+}
+
+impl fmt::Display for Run {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:016x} - {:016x} @ {:016x} {:>8} {}{}{}",
+            self.vaddr,
+            self.vaddr + self.pages * PAGE_SIZE,
+            self.paddr,
+            self.pages,
+            if self.page_type.is_executable() { 'x' } else { '-' },
+            if self.page_type.is_writable()   { 'w' } else { '-' },
+            if self.page_type.is_user()       { 'u' } else { '-' })
+    }
+}
+
+/// Reset the machine through the keyboard controller, or by triple faulting if
+/// that doesn't work.
+fn reboot() -> ! {
+    unsafe {
+        crate::interrupt::disable();
+
+        // Wait (not forever) for the controller's input buffer to be empty,
+        // then pulse the reset line.
+        for _ in 0..100_000 {
+            if inb(0x64) & 0x02 == 0 {
+                break;
+            }
+        }
+
+        outb(0x64, 0xfe);
+
+        // With an empty IDT, any exception is a triple fault.
+        let null_idt = [0u64; 2];
+
+        asm!("lidt [{}]", "int3", in(reg) &null_idt, options(noreturn));
+    }
+}
+
+unsafe fn inb(addr: u16) -> u8 {
+    let byte: u8;
+    asm!("in al, dx", out("al") byte, in("dx") addr);
+    byte
+}
+
+unsafe fn outb(addr: u16, byte: u8) {
+    asm!("out dx, al", in("dx") addr, in("al") byte);
+}
+
+/// A byte queue filled by interrupt handlers and drained by the monitor
+/// thread. Interrupts don't nest, so there's only ever one producer and one
+/// consumer, and no lock is needed.
+struct InputQueue {
+    bytes: UnsafeCell<[u8; InputQueue::SIZE]>,
+    /// Next byte to pop.
+    head: AtomicUsize,
+    /// Next free slot.
+    tail: AtomicUsize,
+}
+
+unsafe impl Sync for InputQueue { }
+
+impl InputQueue {
+    const SIZE: usize = 256;
+
+    const fn new() -> InputQueue {
+        InputQueue {
+            bytes: UnsafeCell::new([0; InputQueue::SIZE]),
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+        }
+    }
+
+    /// Add a byte. Returns false if the queue is full and it was dropped.
+    fn push(&self, byte: u8) -> bool {
+        let tail = self.tail.load(Relaxed);
+        let next = (tail + 1) % InputQueue::SIZE;
+
+        if next == self.head.load(Acquire) {
+            return false;
+        }
+
+        unsafe { (*self.bytes.get())[tail] = byte; }
+
+        self.tail.store(next, Release);
+        true
+    }
+
+    fn pop(&self) -> Option<u8> {
+        let head = self.head.load(Relaxed);
+
+        if head == self.tail.load(Acquire) {
+            return None;
+        }
+
+        let byte = unsafe { (*self.bytes.get())[head] };
+
+        self.head.store((head + 1) % InputQueue::SIZE, Release);
+        Some(byte)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.head.load(Acquire) == self.tail.load(Acquire)
+    }
+}
+
+/// C interface. See `kit/kernel/include/monitor.h`.
+pub mod ffi {
+    use crate::c_ffi::*;
+
+    #[no_mangle]
+    pub extern fn monitor_handle_keypress(keycode: uint8_t,
+                                          keychar: c_char,
+                                          ctrl_down: bool,
+                                          alt_down: bool)
+                                          -> bool {
+        super::handle_keypress(keycode, keychar as u8, ctrl_down, alt_down)
+    }
+
+    #[no_mangle]
+    pub extern fn monitor_keyboard_active() -> bool {
+        super::keyboard_active()
+    }
+
+    #[no_mangle]
+    pub extern fn monitor_poll() {
+        super::poll()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn input_queue_wraps_and_fills() {
+        let queue = InputQueue::new();
+
+        for round in 0..3 {
+            for i in 0..(InputQueue::SIZE - 1) {
+                assert!(queue.push((i + round) as u8));
+            }
+            assert!(!queue.push(0));
+
+            for i in 0..(InputQueue::SIZE - 1) {
+                assert_eq!(queue.pop(), Some((i + round) as u8));
+            }
+            assert_eq!(queue.pop(), None);
+            assert!(queue.is_empty());
+        }
+    }
+
+    #[test]
+    fn runs_coalesce_contiguous_pages() {
+        let rw = PageType::default().writable();
+        let ro = PageType::default();
+
+        let mut run = None;
+
+        assert_eq!(Run::extend(&mut run, 0x1000, 0x8000, rw), None);
+        assert_eq!(Run::extend(&mut run, 0x2000, 0x9000, rw), None);
+
+        // Physically discontiguous
+        assert_eq!(Run::extend(&mut run, 0x3000, 0x20000, rw),
+            Some(Run { vaddr: 0x1000, paddr: 0x8000, pages: 2, page_type: rw }));
+
+        // Different permissions
+        assert_eq!(Run::extend(&mut run, 0x4000, 0x21000, ro),
+            Some(Run { vaddr: 0x3000, paddr: 0x20000, pages: 1, page_type: rw }));
+
+        assert_eq!(run,
+            Some(Run { vaddr: 0x4000, paddr: 0x21000, pages: 1, page_type: ro }));
+    }
+}
@@ -39,10 +39,10 @@ use core::fmt;
 use alloc::vec::Vec;
 
 use crate::c_ffi::CStr;
-use crate::constants::{KERNEL_OFFSET, translate_low_addr};
+use crate::constants::{KERNEL_OFFSET, KERNEL_LOW_END, translate_low_addr};
 use crate::paging::{PAGE_SIZE, Page, PageType};
 use crate::memory::{VirtualAddress, PageCount, PhysicalAddress};
-use crate::util::align_up;
+use crate::util::{align_up, align_down};
 
 /// How many bytes from the start of the file we search for the header.
 pub const SEARCH: usize                 = 8192;
@@ -314,6 +314,57 @@ impl Info {
         }
     }
 
+    /// The physical `(addr, size)` of the kernel's ELF symbol table and its
+    /// string table, if the bootloader loaded them.
+    ///
+    /// Only available while the low region is identity mapped, or after
+    /// [generate_identity_maps](Info::generate_identity_maps) maps them.
+    pub unsafe fn elf_symbol_table(&self) -> Option<[(usize, usize); 2]> {
+        const SHT_SYMTAB: u32 = 2;
+
+        if self.flags & info_flags::ELF_SHDR == 0 {
+            return None;
+        }
+
+        let table = self.u.as_elf_section_header_table();
+
+        let base: *const u8 = translate_low_addr(table.addr)?;
+
+        let section = |index: u32| -> Option<(u32, usize, usize, u32)> {
+            if index >= table.num {
+                return None;
+            }
+
+            // Elf64_Shdr: sh_type, sh_addr, sh_size, sh_link
+            let shdr = base.add((index * table.size) as usize);
+
+            Some((
+                (shdr.add(4) as *const u32).read_unaligned(),
+                (shdr.add(16) as *const u64).read_unaligned() as usize,
+                (shdr.add(32) as *const u64).read_unaligned() as usize,
+                (shdr.add(40) as *const u32).read_unaligned(),
+            ))
+        };
+
+        let (_, symtab_addr, symtab_size, link) = (0..table.num)
+            .filter_map(|index| section(index))
+            .find(|&(sh_type, ..)| sh_type == SHT_SYMTAB)?;
+
+        let (_, strtab_addr, strtab_size, _) = section(link)?;
+
+        let regions = [(symtab_addr, symtab_size), (strtab_addr, strtab_size)];
+
+        // Make sure they're in the low region, like everything else the
+        // bootloader gives us.
+        for &(addr, size) in &regions {
+            if addr.checked_add(size)? > KERNEL_LOW_END as usize {
+                return None;
+            }
+        }
+
+        Some(regions)
+    }
+
     /// Parse available memory maps
     pub unsafe fn parse_available(&self, out: &mut Vec<(usize, usize)>) {
         if let Some(entries) = self.mmap_entries() {
@@ -350,6 +401,21 @@ impl Info {
         out.push((KERNEL_OFFSET, 0x100000/PAGE_SIZE,
             Some((0x0, PageType::default().writable()))));
 
+        // Keep the kernel symbol table, read-only. This goes first in case it
+        // shares pages with anything that needs other permissions.
+        if let Some(regions) = self.elf_symbol_table() {
+            for &(addr, size) in &regions {
+                let begin = align_down(addr, PAGE_SIZE);
+                let end = align_up(addr + size, PAGE_SIZE);
+
+                out.push((
+                    KERNEL_OFFSET + begin,
+                    (end - begin) / PAGE_SIZE,
+                    Some((begin, PageType::default()))
+                ));
+            }
+        }
+
         // Load kernel section symbols
         extern {
             static _bootstrap_begin: u8;
@@ -373,13 +439,13 @@ impl Info {
         let bootstrap_begin =
             (&_bootstrap_begin as *const u8).wrapping_add(KERNEL_OFFSET);
         let bootstrap_data_begin =
-            (&_bootstrap_begin as *const u8).wrapping_add(KERNEL_OFFSET);
+            (&_bootstrap_data_begin as *const u8).wrapping_add(KERNEL_OFFSET);
         let bootstrap_end =
             (&_bootstrap_end as *const u8).wrapping_add(KERNEL_OFFSET);
 
         let sections: [(*const u8, *const u8, PageType); 8] = [
             (bootstrap_begin, bootstrap_data_begin,
-             PageType::default()),
+             PageType::default().executable()),
             (bootstrap_data_begin, bootstrap_end,
              PageType::default().writable()),
             // The rest are high memory addressed, just map them accordingly
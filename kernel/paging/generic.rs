@@ -82,6 +82,26 @@ pub trait Pageset<'a>: Sized {
 
         self.modify_while(vaddr, |page| callback.take().map(|c| c(page)))
     }
+
+    /// Map `pages` pages of physically contiguous memory starting at `paddr`
+    /// to `vaddr`.
+    ///
+    /// Targets with larger page sizes use them wherever `vaddr` and `paddr` are
+    /// suitably aligned, which saves page table entries and TLB space. They're
+    /// still seen as ordinary pages, and get split up again if any part of
+    /// them is changed.
+    fn map_contiguous(&'a mut self,
+                      vaddr:     usize,
+                      paddr:     Self::Paddr,
+                      pages:     usize,
+                      page_type: PageType)
+                      -> Result<(), Self::E> {
+
+        let page_size = Self::page_size();
+
+        self.map_pages(vaddr, (0..pages).map(|index|
+            (paddr.offset(index * page_size), page_type)))
+    }
 }
 
 pub trait PagesetExt<'a>: Pageset<'a> {
@@ -17,6 +17,7 @@ use alloc::boxed::Box;
 
 use crate::sync::Spinlock;
 use crate::memory::InitMemoryMap;
+use crate::constants::{KERNEL_OFFSET, KERNEL_LOW_END};
 
 pub mod generic;
 
@@ -29,7 +30,7 @@ pub mod x86_64;
 #[cfg(target_arch = "x86_64")]
 pub use self::x86_64 as target;
 
-pub use self::target::{PAGE_SIZE, Pageset, Error};
+pub use self::target::{PAGE_SIZE, Pageset, Error, without_write_protect};
 
 // Must be non-zero.
 const_assert!(PAGE_SIZE > 0);
@@ -97,6 +98,30 @@ pub fn initialized() -> bool {
     unsafe { INITIALIZED }
 }
 
+/// Check that kernel memory at `vaddr..(vaddr + len)` is mapped, so it can be
+/// read without faulting. Before paging is initialized, only the boot identity
+/// map is considered.
+pub fn is_kernel_mapped(vaddr: usize, len: usize) -> bool {
+    let end = match vaddr.checked_add(len) {
+        Some(end) => end,
+        None => return false,
+    };
+
+    if vaddr < target::KERNEL_SPACE_START {
+        return false;
+    }
+
+    if initialized() {
+        let pageset = unsafe { kernel_pageset() };
+
+        (vaddr..end).step_by(PAGE_SIZE).chain(Some(end - 1))
+            .all(|page| pageset.lookup(page).is_some())
+    } else {
+        vaddr >= KERNEL_OFFSET &&
+            end <= KERNEL_OFFSET + KERNEL_LOW_END as usize
+    }
+}
+
 /// Call this on system initialization.
 pub unsafe fn initialize(init_memory_map: &InitMemoryMap) {
     if INITIALIZED {
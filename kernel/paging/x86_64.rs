@@ -11,6 +11,24 @@
  ******************************************************************************/
 
 //! x86-64 architecture-specific page tables.
+//!
+//! Everything is presented as 4 KiB pages through the generic interface, but
+//! physically contiguous memory mapped with
+//! [map_contiguous](generic::Pageset::map_contiguous) uses 2 MiB entries in
+//! the `Pd` and 1 GiB entries in the `Pdpt` where it can. A large page is split
+//! into smaller ones as soon as any page inside it is changed.
+//!
+//! Pages that aren't executable get the no-execute bit if the processor
+//! supports it, and write protection applies to the kernel as well as user
+//! code, so read-only pages really are read-only. See [without_write_protect]
+//! for the few places the kernel has to write to them anyway.
+//!
+//! Kernel space is the same in every pageset, so its pages are global and stay
+//! in the TLB when switching pagesets. If the processor supports
+//! process-context identifiers, each user pageset gets one too, so that its
+//! entries survive switching to other pagesets and back. Changes to a loaded pageset are
+//! flushed page by page; changes to one that isn't loaded are flushed all at
+//! once when it's loaded next.
 
 // FIXME: race conditions. needs atomics.
 
@@ -18,11 +36,15 @@ use core::ops::Range;
 use core::mem;
 use core::fmt;
 use core::ptr;
+use core::cmp;
+use core::arch::x86_64::__cpuid;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 
 use crate::error;
 
 use alloc::boxed::Box;
 
+#[cfg_attr(test, allow(unused_imports))]
 use crate::constants::{KERNEL_OFFSET, KERNEL_LOW_START, KERNEL_LOW_END};
 use crate::memory::InitMemoryMap;
 
@@ -32,6 +54,54 @@ use displaydoc::Display;
 
 pub const PAGE_SIZE: usize = 4096;
 
+/// Size of the large pages mapped directly by `Pd` entries.
+pub const LARGE_PAGE_SIZE: usize = 0x20_0000; // 2 MiB
+
+/// Size of the huge pages mapped directly by `Pdpt` entries, which not every
+/// processor supports.
+pub const HUGE_PAGE_SIZE: usize = 0x4000_0000; // 1 GiB
+
+/// Whether the processor supports [HUGE_PAGE_SIZE] pages.
+static HUGE_PAGES: AtomicBool = AtomicBool::new(false);
+
+/// Whether `EFER.NXE` is set, so that the no-execute bit can be used.
+static NO_EXECUTE: AtomicBool = AtomicBool::new(false);
+
+/// Whether `CR4.PCIDE` is set, so that user pagesets get PCIDs.
+static PCIDS: AtomicBool = AtomicBool::new(false);
+
+/// The next PCID to hand out. Zero is the kernel pageset's.
+static PCID_NEXT: AtomicUsize = AtomicUsize::new(1);
+
+/// Bumped when the PCIDs run out and are all handed out again, after flushing
+/// the whole TLB. Pagesets with a PCID from an older generation need a new one.
+static PCID_GENERATION: AtomicUsize = AtomicUsize::new(0);
+
+const PCID_COUNT: usize = 4096;
+
+const IA32_EFER: u32 = 0xc000_0080;
+const EFER_NXE:  u64 = 1 << 11;
+const CR0_WP:    u64 = 1 << 16;
+const CR4_PGE:   u64 = 1 << 7;
+const CR4_PCIDE: u64 = 1 << 17;
+
+/// Set when loading CR3 to keep the TLB entries tagged with the new PCID.
+const CR3_NO_FLUSH: u64 = 1 << 63;
+const CR3_PCID:     u64 = 0xfff;
+
+// Page table entry bits
+const ENTRY_PRESENT:  usize = 0;
+const ENTRY_WRITABLE: usize = 1;
+const ENTRY_USER:     usize = 2;
+/// Set in a `Pd` or `Pdpt` entry that maps a page rather than a table.
+const ENTRY_LARGE:    usize = 7;
+/// Kept in the TLB when CR3 is loaded. Only used in kernel space.
+const ENTRY_GLOBAL:   usize = 8;
+const ENTRY_NO_EXECUTE: usize = 63;
+
+/// Start of the upper canonical half, which is shared kernel space.
+pub const KERNEL_SPACE_START: usize = 0xffff_8000_0000_0000;
+
 macro_rules! assert_page_aligned {
     ($value:expr) => {
         if $value & (PAGE_SIZE - 1) != 0 {
@@ -48,6 +118,7 @@ macro_rules! assert_page_aligned {
 /// place, and `kernel_pageset_unsafe()` is used so that we can still do lookups
 /// while modifying the kernel pageset. This in itself is technically unsafe,
 /// but there's really no other option within this module.
+#[cfg(not(test))]
 fn safe_lookup<T>(ptr: *const T) -> Option<usize> {
 
     if super::initialized() {
@@ -67,8 +138,222 @@ fn safe_lookup<T>(ptr: *const T) -> Option<usize> {
     }
 }
 
+/// There's no physical memory to look up in tests, so tables are taken to be at
+/// their virtual addresses.
+#[cfg(test)]
+fn safe_lookup<T>(ptr: *const T) -> Option<usize> {
+    Some(ptr as usize)
+}
+
 /// Architecture-specific initialization.
 pub unsafe fn arch_initialize() {
+    let basic = __cpuid(1);
+
+    // CPUID.01H:EDX.PGE
+    if basic.edx & (1 << 13) != 0 {
+        write_cr4(read_cr4() | CR4_PGE);
+
+        // CPUID.01H:ECX.PCID. Only worth it with global pages, and flushing
+        // everything relies on them.
+        if basic.ecx & (1 << 17) != 0 {
+            write_cr4(read_cr4() | CR4_PCIDE);
+
+            PCIDS.store(true, Ordering::Relaxed);
+        }
+    }
+
+    debug!("PCIDs supported: {}", PCIDS.load(Ordering::Relaxed));
+
+    let features = if __cpuid(0x8000_0000).eax >= 0x8000_0001 {
+        __cpuid(0x8000_0001).edx
+    } else {
+        0
+    };
+
+    // CPUID.80000001H:EDX.NX
+    if features & (1 << 20) != 0 {
+        wrmsr(IA32_EFER, rdmsr(IA32_EFER) | EFER_NXE);
+
+        NO_EXECUTE.store(true, Ordering::Relaxed);
+    } else {
+        warn!("No-execute pages not supported; all memory is executable");
+    }
+
+    // CPUID.80000001H:EDX.Page1GB
+    if features & (1 << 26) != 0 {
+        HUGE_PAGES.store(true, Ordering::Relaxed);
+    }
+
+    debug!("1 GiB pages supported: {}", HUGE_PAGES.load(Ordering::Relaxed));
+
+    write_cr0(read_cr0() | CR0_WP);
+}
+
+/// Run `f` with write protection turned off, so that the kernel can write to
+/// pages that aren't writable, like code that a debugger is setting a
+/// breakpoint in or the loader is relocating.
+///
+/// `f` must not sleep or switch processes.
+pub fn without_write_protect<T, F>(f: F) -> T where F: FnOnce() -> T {
+    unsafe {
+        let cr0 = read_cr0();
+
+        write_cr0(cr0 & !CR0_WP);
+
+        let result = f();
+
+        write_cr0(cr0);
+
+        result
+    }
+}
+
+unsafe fn read_cr0() -> u64 {
+    let cr0: u64;
+    asm!("mov %cr0, {}", out(reg) cr0, options(att_syntax, nomem, nostack));
+    cr0
+}
+
+unsafe fn write_cr0(cr0: u64) {
+    asm!("mov {}, %cr0", in(reg) cr0, options(att_syntax, nostack));
+}
+
+#[cfg(not(test))]
+unsafe fn read_cr3() -> u64 {
+    let cr3: u64;
+    asm!("mov %cr3, {}", out(reg) cr3, options(att_syntax, nomem, nostack));
+    cr3
+}
+
+/// Tests run in user mode, where none of our pagesets are loaded.
+#[cfg(test)]
+unsafe fn read_cr3() -> u64 {
+    0
+}
+
+unsafe fn read_cr4() -> u64 {
+    let cr4: u64;
+    asm!("mov %cr4, {}", out(reg) cr4, options(att_syntax, nomem, nostack));
+    cr4
+}
+
+unsafe fn write_cr4(cr4: u64) {
+    asm!("mov {}, %cr4", in(reg) cr4, options(att_syntax, nostack));
+}
+
+/// Flush every TLB entry, global or not, for every PCID, by toggling
+/// `CR4.PGE`.
+unsafe fn flush_all() {
+    let cr4 = read_cr4();
+
+    write_cr4(cr4 & !CR4_PGE);
+    write_cr4(cr4);
+}
+
+unsafe fn rdmsr(msr: u32) -> u64 {
+    let (low, high): (u32, u32);
+    asm!("rdmsr", in("ecx") msr, out("eax") low, out("edx") high,
+        options(att_syntax, nomem, nostack));
+    ((high as u64) << 32) | low as u64
+}
+
+unsafe fn wrmsr(msr: u32, value: u64) {
+    asm!("wrmsr", in("ecx") msr, in("eax") value as u32,
+        in("edx") (value >> 32) as u32, options(att_syntax, nostack));
+}
+
+/// The largest page that can map `vaddr` to `paddr` without going past `len`
+/// bytes.
+fn page_size_for(vaddr: usize, paddr: usize, len: usize) -> usize {
+    let fits = |size: usize|
+        vaddr % size == 0 && paddr % size == 0 && len >= size;
+
+    if HUGE_PAGES.load(Ordering::Relaxed) && fits(HUGE_PAGE_SIZE) {
+        HUGE_PAGE_SIZE
+    } else if fits(LARGE_PAGE_SIZE) {
+        LARGE_PAGE_SIZE
+    } else {
+        PAGE_SIZE
+    }
+}
+
+/// Encode a page as a page table entry, which should be
+/// [global](is_global) or not.
+fn page_entry(page: Page<usize>, global: bool) -> u64 {
+    if let Some((paddr, page_type)) = page {
+        let mut entry: u64 = 1 << ENTRY_PRESENT;
+
+        if global { entry.set_bit(ENTRY_GLOBAL, true); }
+
+        entry.set_bits(12..48, (paddr >> 12) as u64);
+
+        if page_type.is_writable() { entry.set_bit(ENTRY_WRITABLE, true); }
+        if page_type.is_user()     { entry.set_bit(ENTRY_USER,     true); }
+
+        if !page_type.is_executable() && NO_EXECUTE.load(Ordering::Relaxed) {
+            entry.set_bit(ENTRY_NO_EXECUTE, true);
+        }
+
+        entry
+    } else {
+        0
+    }
+}
+
+/// True if pages at `vaddr` are the same in every pageset, so their entries
+/// can be global.
+fn is_global(vaddr: usize) -> bool {
+    vaddr >= KERNEL_SPACE_START
+}
+
+/// Decode the page mapped by a page table entry. For a large page, that's the
+/// start of it.
+fn entry_page(entry: u64) -> Page<usize> {
+    if entry.bit(ENTRY_PRESENT) {
+        let mut page_type = PageType::default();
+
+        if entry.bit(ENTRY_WRITABLE) { page_type = page_type.writable(); }
+        if entry.bit(ENTRY_USER)     { page_type = page_type.user();     }
+
+        if !entry.bit(ENTRY_NO_EXECUTE) {
+            page_type = page_type.executable();
+        }
+
+        Some(((entry.bits(12..48) << 12) as usize, page_type))
+    } else {
+        None
+    }
+}
+
+/// The 4 KiB page at `offset` within a large page.
+fn page_within(large: Page<usize>, offset: usize) -> Page<usize> {
+    large.map(|(paddr, page_type)|
+        (paddr + (offset & !(PAGE_SIZE - 1)), page_type))
+}
+
+fn invlpg(vaddr: usize) {
+    unsafe {
+        asm!("invlpg ({})", in(reg) vaddr, options(att_syntax));
+    }
+}
+
+/// How changed pages are dropped from the TLB.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Flush {
+    /// The pageset is loaded, or the pages are in kernel space, which every
+    /// pageset shares: `invlpg` each page as it's changed.
+    Page,
+    /// The pageset isn't loaded. Everything it has in the TLB is flushed when
+    /// it's loaded next instead.
+    Deferred,
+}
+
+impl Flush {
+    fn page(self, vaddr: usize) {
+        if self == Flush::Page {
+            invlpg(vaddr);
+        }
+    }
 }
 
 #[repr(align(4096))]
@@ -78,6 +363,16 @@ pub struct Pageset {
     cr3:    u64,
     pml4:   Box<Pml4>,
     kernel: bool,
+
+    /// The PCID tagging this pageset's TLB entries, and the
+    /// [generation](PCID_GENERATION) it was handed out in. Zero until it's
+    /// first loaded.
+    pcid:            u64,
+    pcid_generation: usize,
+
+    /// Changed while it wasn't loaded, so the TLB might have old entries for
+    /// it.
+    stale:  bool,
 }
 
 impl fmt::Debug for Pageset {
@@ -86,42 +381,88 @@ impl fmt::Debug for Pageset {
             .field("cr3", &self.cr3)
             .field("pml4", &(&*self.pml4 as *const Pml4))
             .field("kernel", &self.kernel)
+            .field("pcid", &self.pcid)
             .finish()
     }
 }
 
+impl Pageset {
+    fn with_pml4(pml4: Box<Pml4>, kernel: bool) -> Pageset {
+        let paddr = safe_lookup(&pml4.entries)
+            .expect("failed to find pml4's physical address");
+
+        assert_page_aligned!(paddr);
+
+        Pageset {
+            cr3:             paddr as u64,
+            pml4:            pml4,
+            kernel:          kernel,
+            pcid:            0,
+            pcid_generation: 0,
+            stale:           false,
+        }
+    }
+
+    fn is_loaded(&self) -> bool {
+        unsafe { read_cr3() & !CR3_PCID == self.cr3 }
+    }
+
+    /// How to flush the changes about to be made from the TLB.
+    fn flush(&mut self) -> Flush {
+        if self.kernel || self.is_loaded() {
+            Flush::Page
+        } else {
+            self.stale = true;
+            Flush::Deferred
+        }
+    }
+
+    /// Make sure the pageset has a PCID from the current generation. Returns
+    /// true if it got a new one.
+    unsafe fn assign_pcid(&mut self) -> bool {
+        let mut generation = PCID_GENERATION.load(Ordering::Relaxed);
+
+        if self.pcid != 0 && self.pcid_generation == generation {
+            return false;
+        }
+
+        let mut pcid = PCID_NEXT.fetch_add(1, Ordering::Relaxed);
+
+        if pcid >= PCID_COUNT {
+            // Everyone else's PCID is about to be out of date, and the new
+            // owners mustn't see what the old ones left behind.
+            flush_all();
+
+            generation = PCID_GENERATION.fetch_add(1, Ordering::Relaxed) + 1;
+
+            PCID_NEXT.store(2, Ordering::Relaxed);
+            pcid = 1;
+        }
+
+        self.pcid = pcid as u64;
+        self.pcid_generation = generation;
+
+        true
+    }
+}
+
 impl<'a> generic::Pageset<'a> for Pageset {
     type Paddr = usize;
     type Iter  = Iter<'a>;
     type E     = Error;
 
     fn new() -> Pageset {
-        let pml4  = Pml4::alloc(User);
-        let paddr = safe_lookup(&pml4.entries).
-            expect("failed to find (User) pml4's physical address");
-        let cr3   = paddr as u64;
-
-        assert_page_aligned!(paddr);
-
-        Pageset { cr3: cr3, pml4: pml4, kernel: false }
+        Pageset::with_pml4(Pml4::alloc(User), false)
     }
 
     fn new_kernel(init_memory_map: &InitMemoryMap) -> Pageset {
-        let pml4  = Pml4::alloc(Kernel);
-        let paddr = safe_lookup(&pml4.entries)
-            .expect("failed to find (Kernel) pml4's physical address");
-        let cr3   = paddr as u64;
-
-        assert_page_aligned!(paddr);
-
-        let mut pageset = Pageset { cr3: cr3, pml4: pml4, kernel: true };
+        let mut pageset = Pageset::with_pml4(Pml4::alloc(Kernel), true);
 
         // Insert the identity map.
         for &(vaddr, pages, page) in &init_memory_map.boot_mappings {
             if let Some((paddr, page_type)) = page {
-                pageset.map_pages_with_type(vaddr,
-                    Pageset::range(paddr, paddr + pages * PAGE_SIZE),
-                    page_type).unwrap();
+                pageset.map_contiguous(vaddr, paddr, pages, page_type)
+                    .unwrap();
             } else {
                 pageset.unmap_pages(vaddr, pages).unwrap();
             }
@@ -131,11 +472,26 @@ impl<'a> generic::Pageset<'a> for Pageset {
     }
 
     unsafe fn load_into_hw(&mut self) {
+        let mut cr3 = self.cr3;
+
         if !self.is_kernel_pageset() {
             self.pml4.copy_latest_from_kernel();
+
+            if PCIDS.load(Ordering::Relaxed) {
+                let new_pcid = self.assign_pcid();
+
+                cr3 |= self.pcid;
+
+                if !new_pcid && !self.stale {
+                    cr3 |= CR3_NO_FLUSH;
+                }
+            }
         }
 
-        asm!("mov {}, %cr3", in(reg) self.cr3, options(att_syntax));
+        // Loading CR3 without CR3_NO_FLUSH flushes the PCID, if there is one.
+        self.stale = false;
+
+        asm!("mov {}, %cr3", in(reg) cr3, options(att_syntax));
     }
 
     #[inline]
@@ -159,11 +515,117 @@ impl<'a> generic::Pageset<'a> for Pageset {
                        -> Result<(), Error>
         where F: FnMut(Page<usize>) -> Option<Page<usize>> {
 
-        Pml4::modify_while(&mut *self.pml4, vaddr, &mut callback).into_result()
+        let flush = self.flush();
+
+        Pml4::modify_while(&mut *self.pml4, vaddr, flush, &mut callback)
+            .into_result()
+    }
+
+    fn map_contiguous(&mut self,
+                      vaddr:     usize,
+                      paddr:     usize,
+                      pages:     usize,
+                      page_type: PageType)
+                      -> Result<(), Error> {
+
+        let len = pages * PAGE_SIZE;
+
+        let flush = self.flush();
+
+        let mut offset = 0;
+
+        while offset < len {
+            let vaddr = vaddr + offset;
+            let paddr = paddr + offset;
+
+            let size = page_size_for(vaddr, paddr, len - offset);
+
+            let mapped = if size > PAGE_SIZE {
+                self.pml4.map_large(vaddr, (paddr, page_type), size, flush)?;
+                size
+            } else {
+                // Small pages up to where a large one might fit
+                let run = cmp::min(len - offset,
+                    LARGE_PAGE_SIZE - vaddr.offset_2m());
+
+                self.map_pages_with_type(vaddr,
+                    Pageset::range(paddr, paddr + run), page_type)?;
+                run
+            };
+
+            offset += mapped;
+        }
+
+        Ok(())
     }
 }
 
-#[derive(PartialEq, Eq, Debug, Display)]
+impl Pageset {
+    /// Call `callback(vaddr, paddr, page_type)` for every mapped page, in
+    /// address order. Only walks the tables that are present, so this is fast
+    /// enough to dump a whole address space. Large pages are reported as the
+    /// 4 KiB pages they contain.
+    pub fn for_each_mapped<F>(&self, mut callback: F)
+        where F: FnMut(usize, usize, PageType) {
+
+        let mut emit_large = |vaddr: usize, large: Page<usize>, size: usize| {
+            if let Some((paddr, page_type)) = large {
+                for offset in (0..size).step_by(PAGE_SIZE) {
+                    callback(vaddr + offset, paddr + offset, page_type);
+                }
+            }
+        };
+
+        for pml4_index in 0..512 {
+            let pdpt = match self.pml4.get(pml4_index) {
+                Some(pdpt) => pdpt,
+                None => continue
+            };
+
+            // Sign-extend into the upper canonical half.
+            let prefix = if pml4_index >= 256 { KERNEL_SPACE_START } else { 0 };
+
+            for pdpt_index in 0..512 {
+                let pdpt_vaddr = prefix |
+                    (pml4_index << 39) |
+                    (pdpt_index << 30);
+
+                let pd = match pdpt.get(pdpt_index) {
+                    Some(pd) => pd,
+                    None => {
+                        emit_large(pdpt_vaddr, pdpt.large(pdpt_index),
+                            HUGE_PAGE_SIZE);
+                        continue
+                    }
+                };
+
+                for pd_index in 0..512 {
+                    let pd_vaddr = pdpt_vaddr | (pd_index << 21);
+
+                    let pt = match pd.get(pd_index) {
+                        Some(pt) => pt,
+                        None => {
+                            emit_large(pd_vaddr, pd.large(pd_index),
+                                LARGE_PAGE_SIZE);
+                            continue
+                        }
+                    };
+
+                    for pt_index in 0..512 {
+                        if let Some((paddr, page_type)) = pt.get(pt_index) {
+                            let vaddr = pd_vaddr | (pt_index << 12);
+
+                            emit_large(vaddr, Some((paddr, page_type)),
+                                PAGE_SIZE);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Display)]
 pub enum Error {
     /**
      * Tried to modify a page (0x{0:016x}) in the kernel pageset outside the
@@ -201,7 +663,15 @@ impl<'a> Iterator for Iter<'a> {
         }
 
         unsafe {
-            Some(self.pt.as_ref().and_then(|pt| pt.get(vaddr.pt_index())))
+            Some(if let Some(pt) = self.pt.as_ref() {
+                pt.get(vaddr.pt_index())
+            } else if let Some(pd) = self.pd.as_ref() {
+                page_within(pd.large(vaddr.pd_index()), vaddr.offset_2m())
+            } else if let Some(pdpt) = self.pdpt.as_ref() {
+                page_within(pdpt.large(vaddr.pdpt_index()), vaddr.offset_1g())
+            } else {
+                None
+            })
         }
     }
 }
@@ -388,6 +858,9 @@ trait PageDirectory {
 }
 
 trait InnerPageDirectory: PageDirectory {
+    /// Size of the pages that entries can map directly.
+    const LARGE_PAGE_SIZE: usize;
+
     fn within_same(vaddr1: usize, vaddr2: usize) -> bool;
 
     fn alloc() -> Box<Self>;
@@ -396,12 +869,79 @@ trait InnerPageDirectory: PageDirectory {
                         -> &'a mut Option<Box<Self::Next>>;
 
     fn update_entry(&mut self, index: usize);
+
+    /// The large page mapped directly by the entry at `index`, if it is one.
+    fn large(&self, index: usize) -> Page<usize>;
+
+    /// Map a large page at `vaddr` with the entry at `index`, replacing the
+    /// table or page that was there.
+    fn set_large(&mut self,
+                 index: usize,
+                 vaddr: usize,
+                 page: (usize, PageType),
+                 flush: Flush);
+
+    /// Replace the large page at `index` with a table mapping the same memory
+    /// in smaller pages.
+    fn split(&mut self, index: usize);
+}
+
+/// Run `callback` over the 4 KiB pages of the large page at `index` in `table`,
+/// starting at `vaddr`. It's only split once the callback changes one of them.
+fn modify_large<T, F>(table: &mut T,
+                      index: usize,
+                      mut vaddr: usize,
+                      flush: Flush,
+                      callback: &mut F)
+                      -> ModifyWhileState
+    where T: InnerPageDirectory,
+          F: FnMut(Page<usize>) -> Option<Page<usize>> {
+
+    use self::ModifyWhileState::*;
+
+    let large = table.large(index);
+    let mask = T::LARGE_PAGE_SIZE - 1;
+    let end = (vaddr & !mask).wrapping_add(T::LARGE_PAGE_SIZE);
+
+    while vaddr != end {
+        let old = page_within(large, vaddr & mask);
+
+        match callback(old) {
+            None => return Done,
+
+            Some(new) if new == old => {
+                vaddr = vaddr.wrapping_add(PAGE_SIZE);
+            },
+
+            Some(new) => {
+                table.split(index);
+
+                // The table we split into gets the new page first.
+                let mut new = Some(new);
+                let mut chained = |page| new.take().or_else(|| callback(page));
+
+                let state = T::Next::modify_while(table.get_mut_hole(index),
+                                                  vaddr,
+                                                  flush,
+                                                  &mut chained);
+
+                table.update_entry(index);
+
+                return state;
+            }
+        }
+    }
+
+    Continue(end)
 }
 
 trait ModifyWhile {
     type Hole;
 
-    fn modify_while<F>(hole: &mut Self::Hole, vaddr: usize, callback: &mut F)
+    fn modify_while<F>(hole: &mut Self::Hole,
+                       vaddr: usize,
+                       flush: Flush,
+                       callback: &mut F)
                        -> ModifyWhileState
         where F: FnMut(Page<usize>) -> Option<Page<usize>>;
 }
@@ -409,7 +949,10 @@ trait ModifyWhile {
 impl ModifyWhile for Pml4 {
     type Hole = Pml4;
 
-    fn modify_while<F>(pml4: &mut Pml4, mut vaddr: usize, callback: &mut F)
+    fn modify_while<F>(pml4: &mut Pml4,
+                       mut vaddr: usize,
+                       flush: Flush,
+                       callback: &mut F)
                        -> ModifyWhileState
         where F: FnMut(Page<usize>) -> Option<Page<usize>> {
 
@@ -434,6 +977,7 @@ impl ModifyWhile for Pml4 {
 
             let state = Pdpt::modify_while(&mut pml4.pdpts[index % 256],
                                            vaddr,
+                                           flush,
                                            callback);
 
             pml4.update_entry(index);
@@ -453,6 +997,7 @@ impl<T: InnerPageDirectory> ModifyWhile for T {
 
     fn modify_while<F>(hole: &mut Option<Box<T>>,
                        mut vaddr: usize,
+                       flush: Flush,
                        callback: &mut F)
                        -> ModifyWhileState
         where F: FnMut(Page<usize>) -> Option<Page<usize>> {
@@ -465,15 +1010,22 @@ impl<T: InnerPageDirectory> ModifyWhile for T {
             let state;
 
             if let Some(ref mut me) = *hole {
-                state = T::Next::modify_while(me.get_mut_hole(index),
-                                              vaddr,
-                                              callback);
+                if me.large(index).is_some() {
+                    state = modify_large(&mut **me, index, vaddr, flush,
+                                         callback);
+                } else {
+                    state = T::Next::modify_while(me.get_mut_hole(index),
+                                                  vaddr,
+                                                  flush,
+                                                  callback);
 
-                me.update_entry(index)
+                    me.update_entry(index)
+                }
             } else {
                 let mut my_next = None;
 
-                state = T::Next::modify_while(&mut my_next, vaddr, callback);
+                state = T::Next::modify_while(&mut my_next, vaddr, flush,
+                                              callback);
 
                 if my_next.is_some() {
                     trace!("Allocating {} for vaddr={:016x}",
@@ -512,18 +1064,13 @@ impl ModifyWhile for Pt {
 
     fn modify_while<F>(hole: &mut Option<Box<Pt>>,
                        mut vaddr: usize,
+                       flush: Flush,
                        callback: &mut F)
                        -> ModifyWhileState
         where F: FnMut(Page<usize>) -> Option<Page<usize>> {
 
         use self::ModifyWhileState::*;
 
-        fn invlpg(vaddr: usize) {
-            unsafe {
-                asm!("invlpg ({})", in(reg) vaddr, options(att_syntax));
-            }
-        }
-
         let mut index = vaddr.pt_index();
 
         while index < 512 {
@@ -532,11 +1079,9 @@ impl ModifyWhile for Pt {
                     trace!("Setting page pte={:p}, vaddr={:016x}, {:016x?}",
                         &pt.entries.0[index], vaddr, page);
 
-                    pt.set(index, page);
+                    pt.set(index, page, is_global(vaddr));
 
-                    // FIXME: not necessary if this is neither the current nor
-                    // kernel pageset
-                    invlpg(vaddr);
+                    flush.page(vaddr);
 
                 } else {
                     return Done;
@@ -560,11 +1105,9 @@ impl ModifyWhile for Pt {
                     trace!("Setting page pte={:p}, vaddr={:016x}, {:016x?}",
                         &pt.entries.0[index], vaddr, page);
 
-                    pt.set(index, page);
+                    pt.set(index, page, is_global(vaddr));
 
-                    // FIXME: not necessary if this is neither the current nor
-                    // kernel pageset
-                    invlpg(vaddr);
+                    flush.page(vaddr);
                 } else {
                     return Done;
                 }
@@ -666,6 +1209,60 @@ impl Pml4 {
         }
     }
 
+    /// Map a page of `size` ([LARGE_PAGE_SIZE] or [HUGE_PAGE_SIZE]) at
+    /// `vaddr`. Both addresses must be aligned to it.
+    fn map_large(&mut self,
+                 vaddr: usize,
+                 page: (usize, PageType),
+                 size: usize,
+                 flush: Flush)
+                 -> Result<(), Error> {
+
+        debug_assert!(vaddr % size == 0 && page.0 % size == 0);
+
+        let index = self.index_if_ok(vaddr).ok_or_else(|| match self.kind {
+            User   => Error::OutOfUserRange(vaddr),
+            Kernel => Error::OutOfKernelRange(vaddr),
+        })?;
+
+        if self.pdpts[index % 256].is_none() {
+            let pdpt_new = Pdpt::alloc();
+
+            // Allocating might have set it... see modify_while()
+            if self.pdpts[index % 256].is_none() {
+                self.pdpts[index % 256] = Some(pdpt_new);
+            }
+        }
+
+        let pdpt = self.pdpts[index % 256].as_mut().unwrap();
+        let pdpt_index = vaddr.pdpt_index();
+
+        if size == HUGE_PAGE_SIZE {
+            pdpt.set_large(pdpt_index, vaddr, page, flush);
+        } else {
+            if pdpt.large(pdpt_index).is_some() {
+                pdpt.split(pdpt_index);
+            }
+
+            if pdpt.pds[pdpt_index].is_none() {
+                let pd_new = Pd::alloc();
+
+                if pdpt.pds[pdpt_index].is_none() {
+                    pdpt.pds[pdpt_index] = Some(pd_new);
+                }
+            }
+
+            pdpt.pds[pdpt_index].as_mut().unwrap()
+                .set_large(vaddr.pd_index(), vaddr, page, flush);
+
+            pdpt.update_entry(pdpt_index);
+        }
+
+        self.update_entry(index);
+
+        Ok(())
+    }
+
     fn copy_latest_from_kernel(&mut self) {
         assert_eq!(self.kind, User);
 
@@ -719,6 +1316,8 @@ impl PageDirectory for Pdpt {
 }
 
 impl InnerPageDirectory for Pdpt {
+    const LARGE_PAGE_SIZE: usize = HUGE_PAGE_SIZE;
+
     fn alloc() -> Box<Pdpt> {
         trace!("Pdpt::alloc()");
         box Pdpt::new()
@@ -749,10 +1348,56 @@ impl InnerPageDirectory for Pdpt {
             entry.set_bits(12..48, (paddr >> 12) as u64);
 
             self.entries.0[index] = entry;
-        } else {
+        } else if !self.entries.0[index].bit(ENTRY_LARGE) {
             self.entries.0[index] = 0;
         }
     }
+
+    fn large(&self, index: usize) -> Page<usize> {
+        let entry = self.entries.0[index];
+
+        if entry.bit(ENTRY_LARGE) { entry_page(entry) } else { None }
+    }
+
+    fn set_large(&mut self,
+                 index: usize,
+                 vaddr: usize,
+                 page: (usize, PageType),
+                 flush: Flush) {
+
+        let old_entry = self.entries.0[index];
+        let old_pd = self.pds[index].take();
+
+        self.entries.0[index] =
+            page_entry(Some(page), is_global(vaddr)) | (1 << ENTRY_LARGE);
+
+        if old_entry.bit(ENTRY_PRESENT) {
+            for offset in (0..HUGE_PAGE_SIZE).step_by(PAGE_SIZE) {
+                flush.page(vaddr + offset);
+            }
+        }
+
+        // Only free the old table once nothing can be using it.
+        drop(old_pd);
+    }
+
+    fn split(&mut self, index: usize) {
+        if let Some((paddr, page_type)) = self.large(index) {
+            let global = self.entries.0[index].bit(ENTRY_GLOBAL);
+
+            let mut pd = Pd::alloc();
+
+            for pd_index in 0..512 {
+                pd.entries.0[pd_index] = page_entry(Some((
+                    paddr + pd_index * LARGE_PAGE_SIZE,
+                    page_type
+                )), global) | (1 << ENTRY_LARGE);
+            }
+
+            self.pds[index] = Some(pd);
+            self.update_entry(index);
+        }
+    }
 }
 
 pub struct Pd {
@@ -777,6 +1422,8 @@ impl PageDirectory for Pd {
 }
 
 impl InnerPageDirectory for Pd {
+    const LARGE_PAGE_SIZE: usize = LARGE_PAGE_SIZE;
+
     fn alloc() -> Box<Pd> {
         trace!("Pd::alloc()");
         box Pd::new()
@@ -806,10 +1453,54 @@ impl InnerPageDirectory for Pd {
             entry.set_bits(12..48, (paddr >> 12) as u64);
 
             self.entries.0[index] = entry;
-        } else {
+        } else if !self.entries.0[index].bit(ENTRY_LARGE) {
             self.entries.0[index] = 0;
         }
     }
+
+    fn large(&self, index: usize) -> Page<usize> {
+        let entry = self.entries.0[index];
+
+        if entry.bit(ENTRY_LARGE) { entry_page(entry) } else { None }
+    }
+
+    fn set_large(&mut self,
+                 index: usize,
+                 vaddr: usize,
+                 page: (usize, PageType),
+                 flush: Flush) {
+
+        let old_entry = self.entries.0[index];
+        let old_pt = self.pts[index].take();
+
+        self.entries.0[index] =
+            page_entry(Some(page), is_global(vaddr)) | (1 << ENTRY_LARGE);
+
+        if old_entry.bit(ENTRY_PRESENT) {
+            for offset in (0..LARGE_PAGE_SIZE).step_by(PAGE_SIZE) {
+                flush.page(vaddr + offset);
+            }
+        }
+
+        // Only free the old table once nothing can be using it.
+        drop(old_pt);
+    }
+
+    fn split(&mut self, index: usize) {
+        if let Some((paddr, page_type)) = self.large(index) {
+            let global = self.entries.0[index].bit(ENTRY_GLOBAL);
+
+            let mut pt = Pt::alloc();
+
+            for pt_index in 0..512 {
+                pt.set(pt_index,
+                    Some((paddr + pt_index * PAGE_SIZE, page_type)), global);
+            }
+
+            self.pts[index] = Some(pt);
+            self.update_entry(index);
+        }
+    }
 }
 
 pub struct Pt {
@@ -827,40 +1518,183 @@ impl Pt {
     }
 
     fn get(&self, index: usize) -> Page<usize> {
-        self.entries.0.get(index).and_then(|entry| {
-            let present  = 0;
-            let writable = 1;
-            let user     = 2;
+        self.entries.0.get(index).and_then(|&entry| entry_page(entry))
+    }
 
-            if entry.bit(present) {
-                let mut page_type = PageType::default();
+    fn set(&mut self, index: usize, page: Page<usize>, global: bool) {
+        self.entries.0[index] = page_entry(page, global);
+    }
+}
 
-                if entry.bit(writable) { page_type = page_type.writable(); }
-                if entry.bit(user)     { page_type = page_type.user();     }
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-                Some(((entry.bits(12..48) << 12) as usize, page_type))
-            } else {
-                None
-            }
-        })
+    use alloc::vec::Vec;
+
+    use crate::paging::GenericPageset;
+
+    #[test]
+    fn entries_round_trip() {
+        NO_EXECUTE.store(true, Ordering::Relaxed);
+
+        let page = Some((0x1234_5000, PageType::default().writable().user()));
+        let code = Some((0x1000, PageType::default().executable()));
+
+        assert_eq!(entry_page(page_entry(page, false)), page);
+        assert_eq!(entry_page(page_entry(code, true)), code);
+        assert_eq!(entry_page(page_entry(None, true)), None);
+
+        assert!(page_entry(page, false).bit(ENTRY_NO_EXECUTE));
+        assert!(!page_entry(code, false).bit(ENTRY_NO_EXECUTE));
+
+        assert!(page_entry(code, true).bit(ENTRY_GLOBAL));
+        assert!(!page_entry(page, false).bit(ENTRY_GLOBAL));
+
+        let large = page_entry(Some((0x4000_0000, PageType::default())), false)
+            | (1 << ENTRY_LARGE);
+
+        assert_eq!(entry_page(large), Some((0x4000_0000, PageType::default())));
+        assert_eq!(page_within(entry_page(large), 0x3456),
+            Some((0x4000_3000, PageType::default())));
     }
 
-    fn set(&mut self, index: usize, page: Page<usize>) {
-        self.entries.0[index] =
-            if let Some((paddr, page_type)) = page {
-                let writable = 1;
-                let user     = 2;
+    #[test]
+    fn largest_page_that_fits_is_chosen() {
+        // 1 GiB pages are only used if the processor has them.
+        assert_eq!(page_size_for(0x4000_0000, 0x4000_0000, HUGE_PAGE_SIZE),
+            LARGE_PAGE_SIZE);
+        assert_eq!(page_size_for(0x20_0000, 0x40_0000, LARGE_PAGE_SIZE),
+            LARGE_PAGE_SIZE);
+        assert_eq!(page_size_for(0x20_0000, 0x40_0000, LARGE_PAGE_SIZE - 1),
+            PAGE_SIZE);
+        assert_eq!(page_size_for(0x20_0000, 0x40_1000, LARGE_PAGE_SIZE),
+            PAGE_SIZE);
+        assert_eq!(page_size_for(0x1000, 0x1000, LARGE_PAGE_SIZE),
+            PAGE_SIZE);
+    }
 
-                let mut entry: u64 = 1;
+    #[test]
+    fn split_large_entry_keeps_paddr_and_flags() {
+        NO_EXECUTE.store(true, Ordering::Relaxed);
 
-                entry.set_bits(12..48, (paddr >> 12) as u64);
+        let page_type = PageType::default().writable();
+        let vaddr = KERNEL_SPACE_START | (3 << 21);
 
-                if page_type.is_writable() { entry.set_bit(writable, true); }
-                if page_type.is_user()     { entry.set_bit(user,     true); }
+        let mut pd = Pd::new();
 
-                entry
-            } else {
-                0
-            };
+        pd.set_large(3, vaddr, (0x4000_0000, page_type), Flush::Deferred);
+
+        assert_eq!(pd.large(3), Some((0x4000_0000, page_type)));
+        assert!(pd.get(3).is_none());
+
+        pd.split(3);
+
+        assert_eq!(pd.large(3), None);
+
+        let pt = pd.get(3).expect("no table after split");
+
+        for index in 0..512 {
+            assert_eq!(pt.get(index),
+                Some((0x4000_0000 + index * PAGE_SIZE, page_type)));
+
+            // Kernel space, so still global.
+            assert!(pt.entries.0[index].bit(ENTRY_GLOBAL));
+            assert!(pt.entries.0[index].bit(ENTRY_NO_EXECUTE));
+        }
+    }
+
+    #[test]
+    fn large_pages_are_only_split_when_changed() {
+        NO_EXECUTE.store(true, Ordering::Relaxed);
+
+        let page_type = PageType::default().user();
+        let changed = Some((0x1234_5000, page_type.writable()));
+
+        let mut hole = Some(Pd::alloc());
+
+        hole.as_mut().unwrap()
+            .set_large(0, 0, (0x20_0000, page_type), Flush::Deferred);
+
+        // Going over the pages without changing any leaves it alone.
+        let mut remaining = 512;
+
+        let state = Pd::modify_while(&mut hole, 0, Flush::Deferred,
+            &mut |page| {
+                if remaining == 0 { return None; }
+                remaining -= 1;
+                Some(page)
+            });
+
+        assert_eq!(state, ModifyWhileState::Done);
+
+        let pd = hole.as_ref().unwrap();
+
+        assert_eq!(remaining, 0);
+        assert_eq!(pd.large(0), Some((0x20_0000, page_type)));
+
+        // Changing the sixth page splits it, and only that page changes.
+        let mut index = 0;
+
+        let state = Pd::modify_while(&mut hole, 0, Flush::Deferred,
+            &mut |page| {
+                index += 1;
+                match index {
+                    6 => Some(changed),
+                    7 => None,
+                    _ => Some(page),
+                }
+            });
+
+        assert_eq!(state, ModifyWhileState::Done);
+
+        let pd = hole.as_ref().unwrap();
+        let pt = pd.get(0).expect("no table after split");
+
+        assert_eq!(pd.large(0), None);
+        assert_eq!(pt.get(4), Some((0x20_4000, page_type)));
+        assert_eq!(pt.get(5), changed);
+        assert_eq!(pt.get(6), Some((0x20_6000, page_type)));
+        assert_eq!(pt.get(511), Some((0x3f_f000, page_type)));
+    }
+
+    #[test]
+    fn large_pages_are_walked_as_small_pages() {
+        NO_EXECUTE.store(true, Ordering::Relaxed);
+
+        let page_type = PageType::default().user().executable();
+
+        let mut pageset = Pageset {
+            cr3:             0,
+            pml4:            Pml4::alloc(User),
+            kernel:          false,
+            pcid:            0,
+            pcid_generation: 0,
+            stale:           false,
+        };
+
+        pageset.pml4.map_large(0x4000_0000, (0x80_0000, page_type),
+            LARGE_PAGE_SIZE, Flush::Deferred).unwrap();
+
+        let mut mapped = vec![];
+
+        pageset.for_each_mapped(|vaddr, paddr, page_type| {
+            mapped.push((vaddr, paddr, page_type));
+        });
+
+        assert_eq!(mapped.len(), 512);
+        assert_eq!(mapped[0], (0x4000_0000, 0x80_0000, page_type));
+        assert_eq!(mapped[511], (0x401f_f000, 0x9f_f000, page_type));
+
+        assert_eq!(pageset.get(0x4000_5000), Some((0x80_5000, page_type)));
+        assert_eq!(pageset.get(0x4020_0000), None);
+
+        let pages: Vec<_> = pageset.from(0x401f_e000).take(3).collect();
+
+        assert_eq!(pages, [
+            Some((0x9f_e000, page_type)),
+            Some((0x9f_f000, page_type)),
+            None,
+        ]);
     }
 }
@@ -0,0 +1,133 @@
+/*******************************************************************************
+ *
+ * kit/kernel/process/layout.rs
+ *
+ * vim:ft=rust:ts=4:sw=4:et:tw=80
+ *
+ * Copyright (C) 2015-2021, Devyn Cairns
+ * Redistribution of this file is permitted under the terms of the simplified
+ * BSD license. See LICENSE for more information.
+ *
+ ******************************************************************************/
+
+//! Where things go in a user address space.
+//!
+//! Each process gets its own layout when it's created, with the stack, heap,
+//! archive mapping and position-independent programs slid by a random number of
+//! pages from their usual addresses. Pass `aslr=off` on the kernel command line
+//! to use the usual addresses every time, for reproducible debugging.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::cmdline::Cmdline;
+use crate::paging::PAGE_SIZE;
+use crate::random;
+
+use super::target;
+
+static ENABLED: AtomicBool = AtomicBool::new(true);
+
+/// How far below [STACK_BASE_ADDR](target::STACK_BASE_ADDR) the stack can be
+/// moved. There's plenty of room above the argument area.
+const STACK_SLIDE:   usize = 0x0000_0004_0000_0000; // 16 GiB
+
+/// How far the heap, archive and PIE base can be moved up.
+const HEAP_SLIDE:    usize = 0x0000_0100_0000_0000; // 1 TiB
+const ARCHIVE_SLIDE: usize = 0x0000_0100_0000_0000; // 1 TiB
+const PIE_SLIDE:     usize = 0x0000_0100_0000_0000; // 1 TiB
+
+/// Configure address space layout randomization from the command line.
+pub fn initialize(cmdline: &Cmdline) {
+    for (key, value) in cmdline.iter() {
+        if key == "aslr" {
+            set_enabled(value != "off");
+        }
+    }
+}
+
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+pub fn set_enabled(enabled: bool) {
+    ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+/// The addresses of the parts of a user address space that aren't decided by
+/// the program.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Layout {
+    /// The top of the stack, which grows down from here.
+    pub stack_top:    usize,
+    pub heap_base:    usize,
+    /// Where `syscall_mmap_archive()` maps the system archive.
+    pub archive_addr: usize,
+    /// Where position-independent executables are loaded.
+    pub pie_base:     usize,
+}
+
+impl Layout {
+    /// A random layout if ASLR is enabled, or else [Layout::fixed].
+    pub fn new() -> Layout {
+        if enabled() {
+            Layout::random()
+        } else {
+            Layout::fixed()
+        }
+    }
+
+    /// The usual addresses.
+    pub fn fixed() -> Layout {
+        Layout {
+            stack_top:    target::STACK_BASE_ADDR,
+            heap_base:    target::HEAP_BASE_ADDR,
+            archive_addr: target::ARCHIVE_ADDR,
+            pie_base:     target::PIE_BASE_ADDR,
+        }
+    }
+
+    /// The usual addresses, each moved by a random number of pages.
+    pub fn random() -> Layout {
+        let fixed = Layout::fixed();
+
+        Layout {
+            stack_top:    fixed.stack_top - slide(STACK_SLIDE),
+            heap_base:    fixed.heap_base + slide(HEAP_SLIDE),
+            archive_addr: fixed.archive_addr + slide(ARCHIVE_SLIDE),
+            pie_base:     fixed.pie_base + slide(PIE_SLIDE),
+        }
+    }
+}
+
+/// A random multiple of the page size less than `range`.
+fn slide(range: usize) -> usize {
+    (random::next_u64() as usize % (range / PAGE_SIZE)) * PAGE_SIZE
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn random_layouts_stay_in_their_areas() {
+        let fixed = Layout::fixed();
+
+        for _ in 0..100 {
+            let layout = Layout::random();
+
+            assert!(layout.stack_top <= fixed.stack_top);
+            assert!(layout.stack_top > fixed.stack_top - STACK_SLIDE);
+            assert!(layout.stack_top - target::STACK_LIMIT -
+                target::STACK_GUARD_SIZE > target::ARGS_TOP_ADDR);
+
+            assert!(layout.heap_base + 0x1_0000_0000 < fixed.archive_addr);
+            assert!(layout.archive_addr < fixed.pie_base);
+            assert!(layout.pie_base < target::LIBRARY_BASE_ADDR);
+
+            for addr in &[layout.stack_top, layout.heap_base,
+                          layout.archive_addr, layout.pie_base] {
+                assert_eq!(addr % PAGE_SIZE, 0);
+            }
+        }
+    }
+}
@@ -13,6 +13,7 @@
 //! Process management functions.
 
 use core::{i32, u32, usize};
+use core::fmt;
 use core::slice;
 use core::mem;
 
@@ -32,6 +33,10 @@ use crate::memory::{self, RegionUser};
 use crate::memory::{VirtualAddress, PhysicalAddress, PageCount};
 use crate::scheduler;
 use crate::syscall;
+use crate::debugger;
+use crate::coredump;
+use crate::archive;
+use crate::interrupt::InterruptStack;
 use crate::util::{copy_memory, align_up, align_down};
 use crate::sync::WaitQueue;
 use crate::sync::Spinlock;
@@ -39,6 +44,12 @@ use crate::sync::Spinlock;
 pub mod x86_64;
 pub use self::x86_64 as target;
 
+pub mod vma;
+use self::vma::{Vma, Vmas, Backing, Growth, Overflow, Access};
+
+pub mod layout;
+use self::layout::Layout;
+
 pub type Id = u32;
 
 #[derive(PartialEq, Eq, PartialOrd, Ord, Copy, Clone, Debug)]
@@ -46,6 +57,7 @@ pub enum State {
     Loading,
     Running,
     Sleeping,
+    Stopped,
     Dead,
 }
 
@@ -55,11 +67,29 @@ impl State {
             State::Loading  => "Load",
             State::Running  => "Run",
             State::Sleeping => "Slp",
+            State::Stopped  => "Stop",
             State::Dead     => "Dead",
         }
     }
 }
 
+/// Exit status of a process killed because of a bad memory access.
+pub const SIG_BAD_MEM_ACCESS: i32 = -2;
+
+/// Exit status of a process killed by an interrupt request (e.g. Ctrl-C).
+pub const SIG_INTERRUPT: i32 = -3;
+
+/// Exit status of a process killed by a breakpoint or single-step trap while no
+/// debugger was attached.
+pub const SIG_TRAP: i32 = -4;
+
+/// Exit status of a process killed from the kernel monitor.
+pub const SIG_KILL: i32 = -5;
+
+/// Exit status of a process killed for running its stack into the guard below
+/// it.
+pub const SIG_STACK_OVERFLOW: i32 = -6;
+
 struct GlobalState {
     kernel_process: RcProcess,
     current_process: RcProcess,
@@ -84,12 +114,17 @@ pub unsafe fn initialize() {
     let kernel_process = Arc::new(Spinlock::new(Process {
         id:          0, // only process that can have ID 0
         pgid:        0, // all kernel subprocesses share this value too
+        parent:      0,
         name:        Arc::new("kernel".into()),
         state:       State::Running,
+        resume_state: State::Running,
+        stop_reported: false,
         hw_state:    Box::into_raw(box target::HwState::new()),
         mem:         None,
+        sched:       scheduler::Entity::default(),
+        tracee:      None,
         exit_status: 0,
-        exit_wait:   WaitQueue::new(),
+        status_wait: WaitQueue::new(),
     }));
 
     let current_process = kernel_process.clone();
@@ -171,6 +206,21 @@ pub fn all() -> Vec<RcProcess> {
     global_state().lock().process_tree.values().cloned().collect()
 }
 
+/// Get the current process ID and all processes, without waiting for the lock.
+///
+/// For debuggers, which may have stopped code that holds it. Returns `None` if
+/// the lock is held or processes are not initialized yet.
+pub fn try_snapshot() -> Option<(Id, Vec<RcProcess>)> {
+    if !initialized() {
+        return None;
+    }
+
+    let state = global_state().try_lock()?;
+    let current_id = state.current_process.try_lock()?.id;
+
+    Some((current_id, state.process_tree.values().cloned().collect()))
+}
+
 /// Change the current process (immediately).
 ///
 /// # Panics
@@ -219,7 +269,7 @@ extern {
     fn process_hw_enter_kernel();
 }
 
-fn new_user_hw_state() -> Box<target::HwState> {
+fn new_user_hw_state(stack_top: usize) -> Box<target::HwState> {
     let mut hw_state = target::HwState::new();
 
     unsafe {
@@ -229,7 +279,7 @@ fn new_user_hw_state() -> Box<target::HwState> {
 
         // Set the stack pointer for the user code.
         hw_state.user_mut()
-            .set_stack_pointer(target::STACK_BASE_ADDR);
+            .set_stack_pointer(stack_top);
     }
 
     Box::new(hw_state)
@@ -244,8 +294,18 @@ pub struct Process {
     /// Process group: subprocesses spawned from the same process will have the
     /// same PGID. Exit() will cause all processes with that PGID to exit.
     pgid:        Id,
+
+    /// The process that created this one. Zero if created by the kernel.
+    parent:      Id,
     name:        Arc<String>,
     state:       State,
+
+    /// The state to return to when a `Stopped` process is continued.
+    resume_state: State,
+
+    /// Whether the most recent stop has already been reported to a waiter.
+    stop_reported: bool,
+
     hw_state:    *mut target::HwState,
 
     /// Information about the memory space of the process.
@@ -253,10 +313,16 @@ pub struct Process {
     /// Can be shared between processes.
     mem:         Option<RcProcessMem>,
 
+    /// Scheduler bookkeeping: priority and virtual runtime.
+    sched:       scheduler::Entity,
+
+    /// Debugger state, if a host process is attached.
+    tracee:      Option<debugger::Tracee>,
+
     exit_status: i32,
 
-    /// Wait queue for exit event.
-    exit_wait:   WaitQueue,
+    /// Wait queue for exit and stop events.
+    status_wait: WaitQueue,
 }
 
 impl Process {
@@ -272,30 +338,52 @@ impl Process {
 
     pub fn create<S>(name: S) -> RcProcess where S: Into<String> {
         let id = Process::next_id();
+        let parent = current().lock().id;
+
+        let layout = Layout::new();
 
         let mut process_mem = ProcessMem {
             id:            id,
             pageset:       Pageset::alloc(),
-            heap_base:     target::HEAP_BASE_ADDR,
+            layout:        layout,
+            heap_base:     layout.heap_base,
             heap_length:   0,
             owned_regions: vec![],
+            cow_pages:     BTreeMap::new(),
+            vmas:          Vmas::new(),
+            image_base:    0,
+            library_next:  target::LIBRARY_BASE_ADDR,
+            aux_vector:    vec![],
+            environment:   vec![],
+            jit:           false,
         };
 
         // FIXME? This assumes a downward growing stack, like x86
-        process_mem.map_allocate(
-            target::STACK_BASE_ADDR - target::STACK_SIZE,
-            target::STACK_SIZE,
-            PageType::default().writable()).unwrap();
+        process_mem.map_lazy(Vma {
+            start:     layout.stack_top - target::STACK_SIZE,
+            end:       layout.stack_top,
+            page_type: PageType::default().writable(),
+            backing:   Backing::Zero,
+            growth:    Growth::Down {
+                limit: layout.stack_top - target::STACK_LIMIT,
+                guard: target::STACK_GUARD_SIZE,
+            },
+        }).unwrap();
 
         let process = Process {
             id:          id,
             pgid:        id,
+            parent:      parent,
             name:        Arc::new(name.into()),
             state:       State::Loading,
-            hw_state:    Box::into_raw(new_user_hw_state()),
+            resume_state: State::Loading,
+            stop_reported: false,
+            hw_state:    Box::into_raw(new_user_hw_state(layout.stack_top)),
             mem:         Some(Arc::new(Spinlock::new(process_mem))),
+            sched:       scheduler::Entity::default(),
+            tracee:      None,
             exit_status: 0,
-            exit_wait:   WaitQueue::new(),
+            status_wait: WaitQueue::new(),
         };
 
         debug!("New process: {:?}", process);
@@ -315,15 +403,24 @@ impl Process {
 
         assert!(!self.is_dead());
 
+        let stack_top = self.mem.as_ref()
+            .map_or(target::STACK_BASE_ADDR,
+                |mem| mem.lock().layout().stack_top);
+
         let process = Process {
             id: id,
             pgid: self.pgid,
+            parent: self.id,
             name: self.name.clone(),
             state: State::Loading,
-            hw_state: Box::into_raw(new_user_hw_state()),
+            resume_state: State::Loading,
+            stop_reported: false,
+            hw_state: Box::into_raw(new_user_hw_state(stack_top)),
             mem: self.mem.clone(),
+            sched: scheduler::Entity::new(self.sched.nice()),
+            tracee: None,
             exit_status: 0,
-            exit_wait: WaitQueue::new(),
+            status_wait: WaitQueue::new(),
         };
 
         debug!("New subprocess: {:?}", process);
@@ -337,6 +434,52 @@ impl Process {
         rc_process
     }
 
+    /// Creates a copy of this process with a copy-on-write copy of its memory
+    /// space, in the same process group. The copy starts with the same user
+    /// registers, except that the system call this process is in returns 0.
+    pub fn fork(&self) -> Result<RcProcess, Error> {
+        let mem = self.mem.as_ref().ok_or(Error::InvalidState(self.state))?;
+
+        assert!(!self.is_dead());
+
+        let id = Process::next_id();
+
+        let child_mem = mem.lock().fork(id)?;
+
+        let mut hw_state = new_user_hw_state(child_mem.layout().stack_top);
+
+        unsafe {
+            *hw_state.user_mut().registers_mut() =
+                self.hw_state().user().registers().clone();
+        }
+
+        hw_state.user_mut().set_return_value(0);
+
+        let process = Process {
+            id: id,
+            pgid: self.pgid,
+            parent: self.id,
+            name: self.name.clone(),
+            state: State::Loading,
+            resume_state: State::Loading,
+            stop_reported: false,
+            hw_state: Box::into_raw(hw_state),
+            mem: Some(Arc::new(Spinlock::new(child_mem))),
+            sched: scheduler::Entity::new(self.sched.nice()),
+            tracee: None,
+            exit_status: 0,
+            status_wait: WaitQueue::new(),
+        };
+
+        debug!("New forked process: {:?}", process);
+
+        let rc_process = Arc::new(Spinlock::new(process));
+
+        global_state().lock().process_tree.insert(id, rc_process.clone());
+
+        Ok(rc_process)
+    }
+
     pub fn id(&self) -> Id {
         self.id
     }
@@ -345,6 +488,10 @@ impl Process {
         self.pgid
     }
 
+    pub fn parent(&self) -> Id {
+        self.parent
+    }
+
     pub fn name(&self) -> Arc<String> {
         self.name.clone()
     }
@@ -365,6 +512,27 @@ impl Process {
         self.mem.as_ref().map(|rc| rc.lock().pageset.clone())
     }
 
+    pub fn sched(&self) -> &scheduler::Entity {
+        &self.sched
+    }
+
+    pub fn sched_mut(&mut self) -> &mut scheduler::Entity {
+        &mut self.sched
+    }
+
+    pub fn tracee(&self) -> Option<&debugger::Tracee> {
+        self.tracee.as_ref()
+    }
+
+    pub fn tracee_mut(&mut self) -> Option<&mut debugger::Tracee> {
+        self.tracee.as_mut()
+    }
+
+    pub fn set_tracee(&mut self, tracee: Option<debugger::Tracee>)
+        -> Option<debugger::Tracee> {
+        mem::replace(&mut self.tracee, tracee)
+    }
+
     pub fn is_running(&self) -> bool {
         self.state == State::Running
     }
@@ -373,9 +541,13 @@ impl Process {
         self.state == State::Sleeping
     }
 
-    /// True if the process is `Running` or `Sleeping`.
+    pub fn is_stopped(&self) -> bool {
+        self.state == State::Stopped
+    }
+
+    /// True if the process is `Running`, `Sleeping` or `Stopped`.
     pub fn is_alive(&self) -> bool {
-        self.is_running() || self.is_sleeping()
+        self.is_running() || self.is_sleeping() || self.is_stopped()
     }
 
     pub fn is_loading(&self) -> bool {
@@ -458,14 +630,39 @@ impl Process {
         kstate.push_stack(ptr);
     }
 
-    pub fn set_args<A>(&mut self, args: &[A]) -> Result<(), Error>
+    /// Set the arguments and environment passed to the program, along with
+    /// the auxiliary vector left by the loader. Each environment entry is a
+    /// `NAME=value` string.
+    pub fn set_args<A, E>(&mut self, args: &[A], env: &[E]) -> Result<(), Error>
     where
         A: AsRef<[u8]>,
+        E: AsRef<[u8]>,
     {
         assert_eq!(self.state, State::Loading);
 
         if let Some(ref mem) = self.mem {
-            let params = mem.lock().setup_args(args)?;
+            let (image_base, stack_limit, layout) = {
+                let mem = mem.lock();
+                (mem.image_base(), mem.stack_limit(), mem.layout())
+            };
+
+            let sysinfo = SystemInfo {
+                size:         mem::size_of::<SystemInfo>() as u64,
+                pid:          self.id,
+                parent:       self.parent,
+                pgid:         self.pgid,
+                cpu_count:    1,
+                page_size:    PAGE_SIZE as u64,
+                image_base:   image_base as u64,
+                heap_base:    layout.heap_base as u64,
+                stack_top:    layout.stack_top as u64,
+                stack_size:   stack_limit as u64,
+                archive_addr: layout.archive_addr as u64,
+                kernel_name:  *b"Kit\0\0\0\0\0\0\0\0\0\0\0\0\0",
+                archive_length: archive::system().len() as u64,
+            };
+
+            let params = mem.lock().setup_args(args, env, &sysinfo)?;
 
             // Safety: hwstate exclusive ownership due to Loading state
             unsafe {
@@ -512,7 +709,7 @@ impl Process {
     ///
     /// Panics if the current state is neither `Running` nor `Sleeping`.
     pub fn sleep(&mut self) {
-        if !self.is_alive() {
+        if !self.is_running() && !self.is_sleeping() {
             panic!("Tried to put a {:?} process to sleep", self.state);
         }
 
@@ -521,17 +718,73 @@ impl Process {
 
     /// Set the process's state to `Running` if it was `Sleeping`.
     ///
+    /// If the process is `Stopped`, the wakeup is remembered instead, and the
+    /// process will be `Running` once it is continued.
+    ///
     /// Make sure to call this before pushing a process onto the scheduler.
     ///
     /// # Panics
     ///
-    /// Panics if the current state is neither `Running` nor `Sleeping`.
+    /// Panics if the process is not alive.
     pub fn awaken(&mut self) {
         if !self.is_alive() {
             panic!("Tried to wake a {:?} process", self.state);
         }
 
-        self.state = State::Running;
+        if self.is_stopped() {
+            self.resume_state = State::Running;
+        } else {
+            self.state = State::Running;
+        }
+    }
+
+    /// Set the process's state to `Stopped`, remembering the state to return
+    /// to on [Process::resume].
+    ///
+    /// Returns false if the process was already stopped.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the current state is neither `Running` nor `Sleeping`.
+    pub fn stop(&mut self) -> bool {
+        match self.state {
+            State::Running | State::Sleeping => {
+                self.resume_state = self.state;
+                self.state = State::Stopped;
+                self.stop_reported = false;
+                true
+            },
+            State::Stopped => false,
+            _ => panic!("Tried to stop a {:?} process", self.state),
+        }
+    }
+
+    /// Return a `Stopped` process to the state it was in before it was
+    /// stopped.
+    ///
+    /// Returns true if the process is now `Running`, in which case the caller
+    /// must push it onto the scheduler.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the current state is not `Stopped`.
+    pub fn resume(&mut self) -> bool {
+        assert_eq!(self.state, State::Stopped,
+                "Tried to resume a non-Stopped process");
+
+        self.state = self.resume_state;
+        self.is_running()
+    }
+
+    /// Returns true exactly once for each stop: if the process is `Stopped`
+    /// and that has not yet been reported to a waiter.
+    pub fn take_stop_report(&mut self) -> bool {
+        if self.is_stopped() && !self.stop_reported {
+            self.stop_reported = true;
+            true
+        } else {
+            false
+        }
     }
 
     /// Set the process's state to `Dead` and set its exit status to the given
@@ -565,19 +818,87 @@ struct ProcessOwnedRegion {
     pages: PageCount,
 }
 
+/// The registers a program starts with: `(argc, argv, envp, auxv)`.
+#[derive(Debug, Clone, Copy)]
+pub struct EntryArgs {
+    pub argc: i32,
+    pub argv: usize,
+    pub envp: usize,
+    pub auxv: usize,
+}
+
+/// Information about the system and the process, pointed to by the
+/// `AT_KIT_SYSINFO` auxiliary vector entry. See `kit/auxv.h` in libc.
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct SystemInfo {
+    /// Size of this structure, so fields can be added at the end.
+    pub size:         u64,
+    pub pid:          Id,
+    pub parent:       Id,
+    pub pgid:         Id,
+    pub cpu_count:    u32,
+    pub page_size:    u64,
+    /// Offset of the program from its link-time addresses.
+    pub image_base:   u64,
+    pub heap_base:    u64,
+    pub stack_top:    u64,
+    /// How far the stack can grow down from `stack_top`.
+    pub stack_size:   u64,
+    /// Where `syscall_mmap_archive()` maps the system archive.
+    pub archive_addr: u64,
+    /// NUL-padded kernel name.
+    pub kernel_name:  [u8; 16],
+    /// Length of the system archive in bytes.
+    pub archive_length: u64,
+}
+
 pub type RcProcessMem = Arc<Spinlock<ProcessMem>>;
 
 #[derive(Debug)]
 pub struct ProcessMem {
     id:            Id,
     pageset:       RcPageset,
+    layout:        Layout,
     heap_base:     usize,
     heap_length:   usize,
     owned_regions: Vec<ProcessOwnedRegion>,
+
+    /// Owned pages that may be shared with another process since a
+    /// [fork](ProcessMem::fork), and the page type each should have once it's
+    /// been copied. They're mapped read-only until then.
+    cow_pages:     BTreeMap<VirtualAddress, PageType>,
+
+    /// Areas whose pages are filled in on first access. Pages that have been
+    /// filled in are in `owned_regions` like any other.
+    vmas:          Vmas,
+
+    /// Offset of the loaded program from its link-time addresses. Nonzero for
+    /// position-independent executables.
+    image_base:    usize,
+
+    /// Where the next shared library will be loaded.
+    library_next:  usize,
+
+    /// `(type, value)` pairs left by the loader for the entry point. See
+    /// [elf::AT_NULL](crate::elf::AT_NULL).
+    aux_vector:    Vec<(usize, usize)>,
+
+    /// The `NAME=value` strings the program was started with, so that
+    /// programs it spawns can inherit them.
+    environment:   Vec<Vec<u8>>,
+
+    /// Whether pages can be both writable and executable. Only programs that
+    /// ask for it when they're loaded get this, for generating code at runtime.
+    jit:           bool,
 }
 
 impl ProcessMem {
 
+    pub fn layout(&self) -> Layout {
+        self.layout
+    }
+
     pub fn heap_base(&self) -> usize {
         self.heap_base
     }
@@ -602,6 +923,93 @@ impl ProcessMem {
         self.pageset.clone()
     }
 
+    pub fn image_base(&self) -> usize {
+        self.image_base
+    }
+
+    pub fn set_image_base(&mut self, image_base: usize) {
+        self.image_base = image_base;
+    }
+
+    pub fn set_aux_vector(&mut self, aux_vector: Vec<(usize, usize)>) {
+        self.aux_vector = aux_vector;
+    }
+
+    pub fn environment(&self) -> &[Vec<u8>] {
+        &self.environment
+    }
+
+    /// True if pages can be mapped both writable and executable.
+    pub fn jit_allowed(&self) -> bool {
+        self.jit
+    }
+
+    /// Let pages be mapped both writable and executable from now on.
+    pub fn allow_jit(&mut self) {
+        self.jit = true;
+    }
+
+    /// Refuse to map pages at `vaddr` as `page_type` if they'd be both writable
+    /// and executable, unless [JIT](ProcessMem::allow_jit) is allowed.
+    fn check_write_xor_execute(&self, vaddr: usize, page_type: PageType)
+                               -> Result<(), Error> {
+        if page_type.is_writable() && page_type.is_executable() && !self.jit {
+            Err(Error::WritableExecutable(vaddr))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Reserve `size` bytes of address space in the library area, and return
+    /// the start address. Libraries are kept a page apart.
+    pub fn reserve_library_space(&mut self, size: usize)
+        -> Result<usize, Error> {
+
+        let vaddr = self.library_next;
+
+        let next = align_up(size, PAGE_SIZE).checked_add(PAGE_SIZE)
+            .and_then(|len| vaddr.checked_add(len))
+            .filter(|&next| next <= target::LIBRARY_LIMIT_ADDR)
+            .ok_or(Error::LibraryAreaFull(size))?;
+
+        self.library_next = next;
+
+        Ok(vaddr)
+    }
+
+    /// Undo [reserve_library_space](ProcessMem::reserve_library_space) for a
+    /// library that couldn't be loaded. Whatever was mapped of it is unmapped,
+    /// and the space is given back if nothing was reserved after it.
+    pub fn release_library_space(&mut self, vaddr: usize, size: usize)
+        -> Result<(), Error> {
+
+        let (vaddr, pages) = page_span(vaddr, size)?;
+
+        let end = vaddr + pages * PAGE_SIZE;
+
+        // Segments waiting to be filled in
+        let lazy: Vec<usize> = self.vmas.iter()
+            .filter(|vma| vma.start < end && vma.end > vaddr)
+            .map(|vma| vma.start)
+            .collect();
+
+        for start in lazy {
+            self.vmas.remove(start);
+        }
+
+        self.unmap_deallocate(vaddr, pages * PAGE_SIZE)?;
+
+        // Pages left are shared with the system archive, and aren't ours to
+        // release.
+        self.pageset.lock().modify_pages(vaddr, pages, |_| None)?;
+
+        if self.library_next == end + PAGE_SIZE {
+            self.library_next = vaddr;
+        }
+
+        Ok(())
+    }
+
     /// Allocates at least enough pages at `vaddr` to contain `size`.
     pub fn map_allocate(&mut self,
                         vaddr: usize,
@@ -609,10 +1017,9 @@ impl ProcessMem {
                         page_type: PageType)
                         -> Result<(), Error> {
 
-        let vaddr_aligned = align_down(vaddr, PAGE_SIZE);
-        let size_aligned = align_up(size, PAGE_SIZE);
+        let (vaddr_aligned, pages) = page_span(vaddr, size)?;
 
-        let pages = size_aligned / PAGE_SIZE;
+        self.check_write_xor_execute(vaddr_aligned, page_type)?;
 
         let mut mapped = 0;
 
@@ -622,8 +1029,8 @@ impl ProcessMem {
 
         while mapped < pages {
             let (paddr_start, acq_pages) =
-                memory::acquire_region(RegionUser::Process(self.id),
-                                            pages - mapped)
+                memory::acquire_up_to(RegionUser::Process(self.id),
+                                      pages - mapped)
                      .ok_or(Error::OutOfMemory(mapped))?;
 
             let paddr_end = paddr_start + acq_pages * PAGE_SIZE;
@@ -669,6 +1076,10 @@ impl ProcessMem {
             // Clear the pages
             pageset.modify_pages(region.vaddr, region.pages, |_| None)?;
 
+            for index in 0..region.pages {
+                self.cow_pages.remove(&(region.vaddr + index * PAGE_SIZE));
+            }
+
             // Release them
             memory::release_region(
                 RegionUser::Process(self.id),
@@ -690,10 +1101,9 @@ impl ProcessMem {
         size: usize,
         page_type: PageType
     ) -> Result<(), Error> {
-        let vaddr_aligned = align_down(vaddr, PAGE_SIZE);
-        let size_aligned = align_up(size, PAGE_SIZE);
+        let (vaddr_aligned, pages) = page_span(vaddr, size)?;
 
-        let pages = size_aligned / PAGE_SIZE;
+        self.check_write_xor_execute(vaddr_aligned, page_type)?;
 
         let mut pageset = self.pageset.lock();
 
@@ -701,9 +1111,398 @@ impl ProcessMem {
             page.map(|(paddr, _)| (paddr, page_type.user()))
         })?;
 
+        // Shared pages stay read-only until they're copied.
+        let end = vaddr_aligned + pages * PAGE_SIZE;
+
+        for (&page, cow_type) in self.cow_pages.range_mut(vaddr_aligned..end) {
+            *cow_type = page_type;
+            pageset.set_page_type(page, page_type.not_writable().user())?;
+        }
+
+        Ok(())
+    }
+
+    /// Reserve an area of memory whose pages will be allocated and filled in
+    /// when they're first accessed.
+    pub fn map_lazy(&mut self, vma: Vma) -> Result<(), Error> {
+        let start = vma.start;
+
+        if vma.end > target::USER_ADDR_LIMIT {
+            return Err(Error::NotMapped(start));
+        }
+
+        self.check_write_xor_execute(start, vma.page_type)?;
+
+        self.vmas.insert(vma).map_err(|_| Error::Overlapping(start))
+    }
+
+    /// Make sure every page in `vaddr..(vaddr + size)` is present, filling in
+    /// any that belong to a [Vma] and haven't been touched yet.
+    ///
+    /// If `write` is true, the kernel is about to write to the pages regardless
+    /// of their permissions, so any that share their frame with the system
+    /// archive or another process get a copy of their own.
+    pub fn populate(&mut self, vaddr: usize, size: usize, write: bool)
+                    -> Result<(), Error> {
+        let (vaddr_aligned, pages) = page_span(vaddr, size)?;
+
+        for page in (0..pages).map(|index| vaddr_aligned + index * PAGE_SIZE) {
+            let frame = self.pageset.lock().get(page).map(|(paddr, _)| paddr);
+
+            match frame {
+                None => {
+                    self.grow_stack(page)?;
+                    self.fault_in(page, write)?;
+                },
+
+                Some(_) if write && self.cow_pages.contains_key(&page) => {
+                    self.break_cow(page)?;
+                },
+
+                Some(paddr) if write &&
+                               archive::system().contains_frame(paddr) => {
+                    // Only a Vma can fill in a copy, so leave anything else
+                    // (like syscall_mmap_archive()'s area) mapped as it is.
+                    if self.vmas.find(page).is_none() {
+                        return Err(Error::ProtectionViolation(page));
+                    }
+
+                    self.pageset.lock().set(page, None)?;
+                    self.fault_in(page, write)?;
+                },
+
+                Some(_) => (),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Resolve a page fault at `vaddr` caused by a page not being present, or
+    /// by a write to a page shared since a [fork](ProcessMem::fork). The
+    /// process's pageset must be the current one.
+    ///
+    /// The fault is rejected if `vaddr` isn't in a [Vma] (or just below the
+    /// stack), or the area doesn't allow `access`.
+    pub fn handle_page_fault(&mut self, vaddr: usize, access: Access)
+                             -> Result<(), Error> {
+
+        let page = align_down(vaddr, PAGE_SIZE);
+
+        let present = self.pageset.lock().get(page);
+
+        if let Some((_, page_type)) = present {
+            let copy_on_write = access == Access::Write &&
+                !page_type.is_writable() &&
+                self.cow_pages.get(&page)
+                    .map_or(false, |cow_type| cow_type.is_writable());
+
+            return if copy_on_write {
+                self.break_cow(page)
+            } else {
+                Err(Error::ProtectionViolation(vaddr))
+            };
+        }
+
+        self.grow_stack(vaddr)?;
+
+        let vma = self.vmas.find(page).ok_or(Error::NotMapped(vaddr))?;
+
+        if !vma.allows(access) {
+            return Err(Error::ProtectionViolation(vaddr));
+        }
+
+        self.fault_in(page, access == Access::Write)
+    }
+
+    /// If `vaddr` is just below the stack, grow the stack to cover it.
+    fn grow_stack(&mut self, vaddr: usize) -> Result<(), Error> {
+        match self.vmas.grow(vaddr) {
+            Ok(_) => Ok(()),
+            Err(Overflow) => Err(Error::StackOverflow(vaddr)),
+        }
+    }
+
+    /// True if `vaddr` is in the guard below the stack.
+    pub fn in_stack_guard(&self, vaddr: usize) -> bool {
+        self.vmas.in_guard(vaddr)
+    }
+
+    /// How far the stack can grow, in bytes.
+    pub fn stack_limit(&self) -> usize {
+        let top = self.layout.stack_top;
+
+        match self.vmas.find(top - 1).map(|stack| stack.growth) {
+            Some(Growth::Down { limit, .. }) => top - limit,
+            _ => 0,
+        }
+    }
+
+    /// Let the stack grow to `size` bytes, or however much of it is in use
+    /// already if that's more.
+    pub fn set_stack_limit(&mut self, size: usize) -> Result<(), Error> {
+        let top = self.layout.stack_top;
+
+        let old = self.vmas.remove(top - 1).ok_or(Error::NotMapped(top - 1))?;
+
+        let mut stack = old.clone();
+
+        let size = align_up(size, PAGE_SIZE).max(stack.end - stack.start);
+
+        if let Growth::Down { ref mut limit, guard } = stack.growth {
+            *limit = top.checked_sub(size)
+                .filter(|&limit| limit >= guard)
+                .unwrap_or(guard);
+        }
+
+        self.vmas.insert(stack).or_else(|_| {
+            self.vmas.insert(old).expect("couldn't put the stack back");
+            Err(Error::Overlapping(top - size))
+        })
+    }
+
+    /// Fill in the page at `page`, which belongs to a [Vma] and isn't present.
+    ///
+    /// Read-only pages that are a plain copy of part of the system archive are
+    /// mapped straight to the archive's frame, unless `write` is true. They
+    /// aren't owned by the process. Anything else gets a new page.
+    fn fault_in(&mut self, page: usize, write: bool) -> Result<(), Error> {
+        let vma = self.vmas.find(page).ok_or(Error::NotMapped(page))?.clone();
+
+        let shared = vma.source_page(page).filter(|_| !write)
+            .and_then(|source| archive::system().frame(source));
+
+        if let Some(paddr) = shared {
+            self.pageset.lock().set(page, Some((paddr, vma.page_type.user())))?;
+
+            return Ok(());
+        }
+
+        let paddr = memory::acquire_region(RegionUser::Process(self.id), 1)
+            .ok_or(Error::OutOfMemory(0))?;
+
+        self.owned_regions.push(ProcessOwnedRegion {
+            vaddr: page,
+            paddr: paddr,
+            pages: 1,
+        });
+
+        let pageset = self.pageset.clone();
+
+        self.with_pageset_loaded(|| unsafe {
+            // Only the kernel can see it until it's filled in.
+            pageset.lock()
+                .set(page, Some((paddr, PageType::default().writable())))
+                .map(|_| {
+                    vma.fill(page,
+                        slice::from_raw_parts_mut(page as *mut u8, PAGE_SIZE));
+                })
+                .and_then(|_| {
+                    pageset.lock().set_page_type(page, vma.page_type.user())
+                })
+        })?;
+
+        Ok(())
+    }
+
+    /// Make a copy of this memory space for the process `id`.
+    ///
+    /// Owned pages aren't copied. Both memory spaces map the same frames
+    /// read-only, and each gets its own copy of a page the first time it writes
+    /// to it: see [handle_page_fault](ProcessMem::handle_page_fault). Pages
+    /// shared with the system archive are just mapped in the copy too.
+    pub fn fork(&mut self, id: Id) -> Result<ProcessMem, Error> {
+        let mut child = ProcessMem {
+            id:            id,
+            pageset:       Pageset::alloc(),
+            layout:        self.layout,
+            heap_base:     self.heap_base,
+            heap_length:   self.heap_length,
+            owned_regions: vec![],
+            cow_pages:     BTreeMap::new(),
+            vmas:          self.vmas.clone(),
+            image_base:    self.image_base,
+            library_next:  self.library_next,
+            aux_vector:    self.aux_vector.clone(),
+            environment:   self.environment.clone(),
+            jit:           self.jit,
+        };
+
+        let mut pageset = self.pageset.lock();
+        let mut child_pageset = child.pageset.lock();
+
+        for region in self.owned_regions.iter() {
+            memory::share_region(RegionUser::Process(self.id),
+                RegionUser::Process(id), region.paddr, region.pages);
+
+            // If anything below fails, the child releases it when dropped.
+            child.owned_regions.push(*region);
+
+            for index in 0..region.pages {
+                let page = region.vaddr + index * PAGE_SIZE;
+
+                if let Some((paddr, page_type)) = pageset.get(page) {
+                    let cow_type = *self.cow_pages.entry(page)
+                        .or_insert(page_type);
+
+                    child.cow_pages.insert(page, cow_type);
+
+                    pageset.set_page_type(page, page_type.not_writable())?;
+                    child_pageset.set(page,
+                        Some((paddr, page_type.not_writable())))?;
+                }
+            }
+        }
+
+        // Pages shared with the system archive might be in any of the areas,
+        // and wherever syscall_mmap_archive() put it.
+        let system = archive::system();
+
+        let archive_area = self.layout.archive_addr ..
+            (self.layout.archive_addr + align_up(system.len(), PAGE_SIZE));
+
+        let areas = self.vmas.iter().map(|vma| vma.start .. vma.end)
+            .chain(Some(archive_area));
+
+        for area in areas {
+            for page in area.step_by(PAGE_SIZE) {
+                match pageset.get(page) {
+                    Some((paddr, page_type))
+                        if system.contains_frame(paddr) => {
+                        child_pageset.set(page, Some((paddr, page_type)))?;
+                    },
+                    _ => (),
+                }
+            }
+        }
+
+        drop(child_pageset);
+
+        Ok(child)
+    }
+
+    /// Give this memory space its own copy of a page shared since a
+    /// [fork](ProcessMem::fork), with the page type it's meant to have. The
+    /// copy is skipped if nothing else uses the frame anymore.
+    fn break_cow(&mut self, page: usize) -> Result<(), Error> {
+        let page_type = match self.cow_pages.get(&page) {
+            Some(&page_type) => page_type,
+            None => return Ok(()),
+        };
+
+        let old_paddr = self.pageset.lock().get(page)
+            .map(|(paddr, _)| paddr)
+            .ok_or(Error::NotMapped(page))?;
+
+        if memory::region_user_count(old_paddr) <= 1 {
+            self.pageset.lock().set_page_type(page, page_type.user())?;
+            self.cow_pages.remove(&page);
+            return Ok(());
+        }
+
+        let paddr = memory::acquire_region(RegionUser::Process(self.id), 1)
+            .ok_or(Error::OutOfMemory(0))?;
+
+        let pageset = self.pageset.clone();
+
+        let result = self.with_pageset_loaded(|| unsafe {
+            let mut data = vec![0u8; PAGE_SIZE];
+
+            data.copy_from_slice(
+                slice::from_raw_parts(page as *const u8, PAGE_SIZE));
+
+            // Only the kernel can see it until it's filled in.
+            pageset.lock()
+                .set(page, Some((paddr, PageType::default().writable())))
+                .map(|_| {
+                    slice::from_raw_parts_mut(page as *mut u8, PAGE_SIZE)
+                        .copy_from_slice(&data);
+                })
+                .and_then(|_| {
+                    pageset.lock().set_page_type(page, page_type.user())
+                })
+        });
+
+        if let Err(err) = result {
+            let _ = pageset.lock()
+                .set(page, Some((old_paddr, page_type.not_writable().user())));
+
+            memory::release_region(RegionUser::Process(self.id), paddr, 1);
+
+            return Err(err.into());
+        }
+
+        // Only let go of the old frame after copying it, so that whoever else
+        // has it doesn't start writing to it first.
+        self.replace_owned_page(page, paddr);
+        self.cow_pages.remove(&page);
+
+        memory::release_region(RegionUser::Process(self.id), old_paddr, 1);
+
         Ok(())
     }
 
+    /// Record that the owned page at `page` is now backed by `paddr`, splitting
+    /// the region it was part of.
+    fn replace_owned_page(&mut self, page: usize, paddr: PhysicalAddress) {
+        let index = self.owned_regions.iter()
+            .position(|region| page >= region.vaddr &&
+                page < region.vaddr + region.pages * PAGE_SIZE)
+            .expect("replaced page is not owned");
+
+        let region = self.owned_regions.remove(index);
+
+        let before = (page - region.vaddr) / PAGE_SIZE;
+        let after = region.pages - before - 1;
+
+        if before > 0 {
+            self.owned_regions.push(ProcessOwnedRegion {
+                vaddr: region.vaddr,
+                paddr: region.paddr,
+                pages: before,
+            });
+        }
+
+        self.owned_regions.push(ProcessOwnedRegion {
+            vaddr: page,
+            paddr: paddr,
+            pages: 1,
+        });
+
+        if after > 0 {
+            self.owned_regions.push(ProcessOwnedRegion {
+                vaddr: page + PAGE_SIZE,
+                paddr: region.paddr + (before + 1) * PAGE_SIZE,
+                pages: after,
+            });
+        }
+    }
+
+    /// Run `f` with this memory space loaded, so that its pages can be accessed
+    /// through their user addresses, and then put back whatever was loaded.
+    fn with_pageset_loaded<T, F>(&self, f: F) -> T where F: FnOnce() -> T {
+        let pageset = self.pageset.clone();
+
+        unsafe {
+            let old_pageset = paging::current_pageset();
+
+            let switch = !old_pageset.as_ref()
+                .map_or(false, |old| Arc::ptr_eq(old, &pageset));
+
+            if switch {
+                paging::set_current_pageset(Some(pageset));
+            }
+
+            let result = f();
+
+            if switch {
+                paging::set_current_pageset(old_pageset);
+            }
+
+            result
+        }
+    }
+
     /// Adjusts the process's heap by the requested amount.
     pub fn adjust_heap(&mut self, amount: isize) -> Result<(), Error> {
         if amount < 0 && self.heap_length < -amount as usize {
@@ -743,29 +1542,174 @@ impl ProcessMem {
         Ok(())
     }
 
-    /// Sets up args in the memory space, and returns the parameters that should
-    /// be passed to the entry point.
+    /// The memory mapped in this memory space as `(vaddr, size, page_type)`, in
+    /// address order, split wherever the permissions change.
+    pub fn mapped_regions(&self) -> Vec<(VirtualAddress, usize, PageType)> {
+        let pageset = self.pageset.lock();
+
+        let mut spans: Vec<(VirtualAddress, usize)> = self.owned_regions.iter()
+            .map(|region| (region.vaddr, region.pages))
+            .collect();
+
+        // Pages shared with the system archive aren't owned, but they're
+        // still mapped.
+        let system = archive::system();
+
+        for vma in self.vmas.iter() {
+            spans.extend((vma.start..vma.end).step_by(PAGE_SIZE)
+                .filter(|&page| pageset.get(page)
+                    .map_or(false, |(paddr, _)| system.contains_frame(paddr)))
+                .map(|page| (page, 1)));
+        }
+
+        spans.sort_by_key(|&(vaddr, _)| vaddr);
+
+        let mut regions: Vec<(VirtualAddress, usize, PageType)> = vec![];
+
+        for (start, count) in spans {
+            let pages = pageset.from(start).take(count);
+
+            for (index, page) in pages.enumerate() {
+                let vaddr = start + index * PAGE_SIZE;
+                let page_type = page.map(|(_, t)| t).unwrap_or_default();
+
+                match regions.last_mut() {
+                    Some(last) if last.0 + last.1 == vaddr &&
+                                  last.2 == page_type => {
+                        last.1 += PAGE_SIZE;
+                    },
+                    _ => regions.push((vaddr, PAGE_SIZE, page_type)),
+                }
+            }
+        }
+
+        regions
+    }
+
+    /// Copy memory out of this memory space, which need not be the current one.
+    pub fn copy_out(&mut self, vaddr: usize, buf: &mut [u8])
+                    -> Result<(), Error> {
+        self.with_user_memory(vaddr, buf.len(), false, |ptr| unsafe {
+            buf.as_mut_ptr().copy_from(ptr, buf.len());
+        })
+    }
+
+    /// Copy memory into this memory space, which need not be the current one.
+    ///
+    /// The pages only have to be mapped for user access, not writable, so this
+    /// can be used to modify code.
+    pub fn copy_in(&mut self, vaddr: usize, data: &[u8]) -> Result<(), Error> {
+        self.with_user_memory(vaddr, data.len(), true, |ptr| {
+            paging::without_write_protect(|| unsafe {
+                ptr.copy_from(data.as_ptr(), data.len());
+            })
+        })
+    }
+
+    /// Run `f` on the memory at `vaddr..(vaddr + len)` with this memory space
+    /// loaded, after making sure it's all mapped for user access. See
+    /// [populate](ProcessMem::populate) for `write`.
+    fn with_user_memory<F>(&mut self,
+                           vaddr: usize,
+                           len: usize,
+                           write: bool,
+                           f: F)
+        -> Result<(), Error>
+        where F: FnOnce(*mut u8) {
+
+        if len == 0 {
+            return Ok(());
+        }
+
+        let end = vaddr.checked_add(len)
+            .filter(|&end| end <= target::USER_ADDR_LIMIT)
+            .ok_or(Error::NotMapped(vaddr))?;
+
+        // Pages that haven't been touched yet can't be read in place. If that
+        // fails, the check below reports where. A failure to get private copies
+        // of shared pages for a write is an error of its own though, since the
+        // pages are still mapped and we'd write through to the shared frames.
+        let populated = self.populate(vaddr, len, write);
+
+        if write {
+            populated?;
+        }
+
+        unsafe {
+            // Swap in our pageset.
+            // Careful: must reset to old pageset after!
+            let old_pageset = paging::current_pageset();
+            paging::set_current_pageset(Some(self.pageset()));
+
+            let result = {
+                let pageset = self.pageset.lock();
+
+                let unmapped = (align_down(vaddr, PAGE_SIZE)..end)
+                    .step_by(PAGE_SIZE)
+                    .find(|&page| match pageset.get(page) {
+                        Some((_, page_type)) => !page_type.is_user(),
+                        None => true,
+                    });
+
+                if let Some(page) = unmapped {
+                    Err(Error::NotMapped(page.max(vaddr)))
+                } else {
+                    f(vaddr as *mut u8);
+                    Ok(())
+                }
+            };
+
+            // Reset to old pageset.
+            paging::set_current_pageset(old_pageset);
+
+            result
+        }
+    }
+
+    /// Sets up args, environment and the auxiliary vector in the memory space,
+    /// and returns the parameters that should be passed to the entry point.
+    ///
+    /// The block holds, in order:
+    ///
+    /// - the `argv` pointer table, ending with NULL
+    /// - the `envp` pointer table, ending with NULL
+    /// - the auxiliary vector, ending with `AT_NULL`
+    /// - 16 random bytes for `AT_RANDOM`
+    /// - the [SystemInfo] for `AT_KIT_SYSINFO`
+    /// - the strings
     ///
     /// See [HwState::set_args]
-    pub fn setup_args<A>(&mut self, args: &[A])
-        -> Result<Option<(i32, usize)>, Error>
+    pub fn setup_args<A, E>(&mut self,
+                            args: &[A],
+                            env: &[E],
+                            sysinfo: &SystemInfo)
+        -> Result<EntryArgs, Error>
     where
         A: AsRef<[u8]>,
+        E: AsRef<[u8]>,
     {
-
-        // Special case: if there are no args, just set HwState.
-        if args.is_empty() {
-            return Ok(None);
-        }
+        use crate::elf::{AT_NULL, AT_RANDOM, AT_KIT_SYSINFO};
 
         // Args length must fit within an i32.
         assert!(args.len() <= i32::MAX as usize);
 
-        // Size of all args + size of null bytes at end of each arg + size of
-        // pointer table
-        let args_size =
-            args.iter().map(|a| a.as_ref().len()).sum::<usize>() + args.len() +
-            mem::size_of::<usize>() * args.len();
+        let word = mem::size_of::<usize>();
+
+        let strings = || args.iter().map(|a| a.as_ref())
+            .chain(env.iter().map(|e| e.as_ref()));
+
+        // Offsets of each part from the start of the block
+        let argv_offset    = 0;
+        let envp_offset    = argv_offset + word * (args.len() + 1);
+        let auxv_offset    = envp_offset + word * (env.len() + 1);
+        let random_offset  =
+            auxv_offset + word * 2 * (self.aux_vector.len() + 3);
+        let sysinfo_offset = random_offset + 16;
+        let strings_offset = sysinfo_offset + mem::size_of::<SystemInfo>();
+
+        // + size of all strings + size of null bytes at end of each string
+        let args_size = strings_offset +
+            strings().map(|s| s.len() + 1).sum::<usize>();
 
         let page_size = <Pageset as GenericPageset>::page_size();
 
@@ -774,35 +1718,55 @@ impl ProcessMem {
 
         self.map_allocate(vaddr, args_size, PageType::default().writable())?;
 
+        let mut aux_vector = self.aux_vector.clone();
+
+        aux_vector.push((AT_RANDOM, vaddr + random_offset));
+        aux_vector.push((AT_KIT_SYSINFO, vaddr + sysinfo_offset));
+        aux_vector.push((AT_NULL, 0));
+
         unsafe {
             // Swap in process pageset.
             // Careful: must reset to old pageset after!
             let old_pageset = paging::current_pageset();
             paging::set_current_pageset(Some(self.pageset()));
 
-            // Set pointer table and copy args.
-            let ptr_table: &mut [usize] =
-                slice::from_raw_parts_mut(vaddr as *mut usize, args.len());
+            // Both pointer tables are contiguous, with a NULL after each.
+            let ptr_table: &mut [usize] = slice::from_raw_parts_mut(
+                vaddr as *mut usize, args.len() + env.len() + 2);
 
-            let mut next_ptr =
-                vaddr + ptr_table.len() * mem::size_of::<usize>();
+            // Set pointer tables and copy strings.
+            let mut next_ptr = vaddr + strings_offset;
 
-            for (index, arg) in args.iter().enumerate() {
-                let arg = arg.as_ref();
+            let slots = (0..args.len()).chain((args.len() + 1)..);
 
-                let arg_dest: &mut [u8] =
+            for (index, string) in slots.zip(strings()) {
+                let string_dest: &mut [u8] =
                     slice::from_raw_parts_mut(next_ptr as *mut u8,
-                                              arg.len() + 1);
+                                              string.len() + 1);
 
                 ptr_table[index] = next_ptr;
 
-                copy_memory(arg, arg_dest);
+                copy_memory(string, string_dest);
 
-                arg_dest[arg.len()] = 0;
+                string_dest[string.len()] = 0;
 
-                next_ptr += arg.len() + 1;
+                next_ptr += string.len() + 1;
             }
 
+            ptr_table[args.len()] = 0;
+            ptr_table[args.len() + env.len() + 1] = 0;
+
+            // Copy the auxiliary vector and what it points to.
+            slice::from_raw_parts_mut(
+                (vaddr + auxv_offset) as *mut (usize, usize),
+                aux_vector.len()
+            ).copy_from_slice(&aux_vector);
+
+            crate::random::fill(slice::from_raw_parts_mut(
+                (vaddr + random_offset) as *mut u8, 16));
+
+            *((vaddr + sysinfo_offset) as *mut SystemInfo) = *sysinfo;
+
             // Reset to old pageset.
             paging::set_current_pageset(old_pageset);
         }
@@ -810,11 +1774,31 @@ impl ProcessMem {
         // Set the permissions to read-only
         self.set_permissions(vaddr, args_size, PageType::default())?;
 
+        self.environment = env.iter().map(|e| e.as_ref().to_vec()).collect();
+
         // Return parameters that should be put in HwState
-        Ok(Some((args.len() as i32, vaddr)))
+        Ok(EntryArgs {
+            argc: args.len() as i32,
+            argv: vaddr + argv_offset,
+            envp: vaddr + envp_offset,
+            auxv: vaddr + auxv_offset,
+        })
     }
 }
 
+/// The first page and number of pages covering `vaddr..(vaddr + size)`, which
+/// might not be page-aligned at either end.
+fn page_span(vaddr: usize, size: usize) -> Result<(usize, usize), Error> {
+    let end = vaddr.checked_add(size)
+        .and_then(|end| end.checked_add(PAGE_SIZE - 1))
+        .ok_or(Error::Overflow)?;
+
+    let vaddr_aligned = align_down(vaddr, PAGE_SIZE);
+    let pages = (align_down(end, PAGE_SIZE) - vaddr_aligned) / PAGE_SIZE;
+
+    Ok((vaddr_aligned, pages))
+}
+
 impl Drop for ProcessMem {
     fn drop(&mut self) {
         debug!("Destructor running for {:?}", self);
@@ -833,7 +1817,7 @@ impl Drop for ProcessMem {
     }
 }
 
-#[derive(PartialEq, Eq, Debug, Display)]
+#[derive(PartialEq, Eq, Debug, Clone, Display)]
 pub enum Error {
     /// An error occurred while trying to modify pages: {0}
     PagingError(paging::Error),
@@ -843,6 +1827,22 @@ pub enum Error {
     Overflow,
     /// Unknown process id {0}
     UnknownPid(Id),
+    /// The operation is not allowed for a process in the {0:?} state
+    InvalidState(State),
+    /// Address 0x{0:x} is not mapped for user access
+    NotMapped(usize),
+    /// The program image is invalid: {0}
+    InvalidImage(&'static str),
+    /// No room left in the library area for {0} bytes
+    LibraryAreaFull(usize),
+    /// The mapping at 0x{0:x} would overlap an existing one
+    Overlapping(usize),
+    /// Access to 0x{0:x} is not allowed by its mapping
+    ProtectionViolation(usize),
+    /// Stack overflow: 0x{0:x} is in the guard below the stack
+    StackOverflow(usize),
+    /// Pages at 0x{0:x} can't be writable and executable without JIT permission
+    WritableExecutable(usize),
 }
 
 impl error::Error for Error {
@@ -869,6 +1869,9 @@ pub fn exit(status: i32) -> ! {
     {
         let rc_process = current();
 
+        // Let go of anything we were debugging, so it doesn't stay stopped.
+        debugger::detach_all(rc_process.lock().id);
+
         let mut process = rc_process.lock();
 
         assert!(process.id != 0, "attempted to exit({}) kernel!", status);
@@ -876,7 +1879,7 @@ pub fn exit(status: i32) -> ! {
         process.exit(status);
 
         // Notify wait queue
-        process.exit_wait.awaken_all();
+        process.status_wait.awaken_all();
 
         drop(process);
         drop(rc_process);
@@ -887,16 +1890,200 @@ pub fn exit(status: i32) -> ! {
     panic!("returned to process {} after exit", current().lock().id);
 }
 
+/// Kill a process, setting its exit status to `signal`.
+///
+/// If the process is the current process, this does not return.
+pub fn signal(id: Id, signal: i32) -> Result<(), Error> {
+    let process = by_id(id).ok_or(Error::UnknownPid(id))?;
+
+    if (signal == SIG_BAD_MEM_ACCESS || signal == SIG_STACK_OVERFLOW) &&
+        !process.lock().is_dead() {
+        if let Err(e) = coredump::dump(&process, signal) {
+            warn!("Failed to write core dump of process {}: {}", id, e);
+        }
+    }
+
+    // Case 1: this is the current process, so just exit
+    if current().lock().id == id {
+        drop(process);
+        exit(signal);
+        // the function will not return!
+    }
+
+    // Case 2: we're telling another process to exit.
+    {
+        let mut process = process.lock();
+
+        if process.is_dead() {
+            return Ok(());
+        }
+
+        process.exit(signal);
+
+        // Let waiting processes know
+        process.status_wait.awaken_all();
+    }
+
+    debugger::detach_all(id);
+
+    Ok(())
+}
+
+/// Kill every process in a process group with [signal].
+///
+/// If the current process is in the group, it is killed last, and this does
+/// not return.
+pub fn signal_group(pgid: Id, signal: i32) {
+    let current_id = current().lock().id;
+
+    let mut includes_current = false;
+
+    for process in by_pgid(pgid) {
+        let id = process.lock().id;
+
+        if id == current_id {
+            includes_current = true;
+        } else {
+            let _ = self::signal(id, signal);
+        }
+    }
+
+    if includes_current {
+        exit(signal);
+    }
+}
+
+/// Stop a process until it is continued with [r#continue].
+///
+/// If the process is the current process, this returns once it has been
+/// continued.
+pub fn stop(id: Id) -> Result<(), Error> {
+    let process = by_id(id).ok_or(Error::UnknownPid(id))?;
+
+    let is_current = current().lock().id == id;
+
+    {
+        let mut process = process.lock();
+
+        if !process.is_running() && !process.is_sleeping() {
+            return Err(Error::InvalidState(process.state()));
+        }
+
+        process.stop();
+
+        // Let waiting processes know
+        process.status_wait.awaken_all();
+    }
+
+    drop(process);
+
+    if is_current {
+        scheduler::r#yield();
+    }
+
+    Ok(())
+}
+
+/// Stop every process in a process group.
+///
+/// If the current process is in the group, it is stopped last, and this
+/// returns once it has been continued.
+pub fn stop_group(pgid: Id) {
+    let current_id = current().lock().id;
+
+    let mut includes_current = false;
+
+    for process in by_pgid(pgid) {
+        let id = process.lock().id;
+
+        if id == current_id {
+            includes_current = true;
+        } else {
+            let _ = stop(id);
+        }
+    }
+
+    if includes_current {
+        let _ = stop(current_id);
+    }
+}
+
+/// Continue a process that was stopped with [stop].
+pub fn r#continue(id: Id) -> Result<(), Error> {
+    let process = by_id(id).ok_or(Error::UnknownPid(id))?;
+
+    let now_running = {
+        let mut process = process.lock();
+
+        if !process.is_stopped() {
+            return Err(Error::InvalidState(process.state()));
+        }
+
+        process.resume()
+    };
+
+    if now_running {
+        scheduler::push(process);
+    }
+
+    Ok(())
+}
+
+/// Continue every stopped process in a process group.
+pub fn continue_group(pgid: Id) {
+    for process in by_pgid(pgid) {
+        let id = process.lock().id;
+
+        let _ = r#continue(id);
+    }
+}
+
 /// Sleep until the given process id wakes up.
 pub fn wait(id: Id) -> Result<(), Error> {
     let queue = by_id(id).ok_or(Error::UnknownPid(id))?
-        .lock().exit_wait.clone();
+        .lock().status_wait.clone();
 
     wait!(by_id(id).map(|p| p.lock().is_dead()).unwrap_or(true), [queue]);
 
     Ok(())
 }
 
+/// What a process did to end a [wait_status].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WaitStatus {
+    /// The process exited with the given status.
+    Exited(i32),
+    /// The process was stopped.
+    Stopped,
+}
+
+/// Sleep until the given process id exits or stops.
+///
+/// Each stop is only reported once; waiting again on a process that is still
+/// stopped sleeps until it is continued and then exits or stops again.
+pub fn wait_status(id: Id) -> Result<WaitStatus, Error> {
+    let queue = by_id(id).ok_or(Error::UnknownPid(id))?
+        .lock().status_wait.clone();
+
+    let changed = || by_id(id).map(|p| {
+        let p = p.lock();
+        p.is_dead() || (p.is_stopped() && !p.stop_reported)
+    }).unwrap_or(true);
+
+    wait!(changed(), [queue]);
+
+    let process = by_id(id).ok_or(Error::UnknownPid(id))?;
+    let mut process = process.lock();
+
+    if let Some(exit_status) = process.exit_status() {
+        Ok(WaitStatus::Exited(exit_status))
+    } else if process.take_stop_report() {
+        Ok(WaitStatus::Stopped)
+    } else {
+        Err(Error::InvalidState(process.state()))
+    }
+}
+
 /// Set our state to sleep and then yield to the scheduler.
 pub fn sleep() {
     current().lock().sleep();
@@ -946,21 +2133,20 @@ unsafe extern "C" fn kthread_entry(boxed_fun: usize) -> i32 {
     0
 }
 
-/// Dumps a list of processes to the console, for debugging.
-pub fn debug_print_processes() {
-    use crate::terminal::console;
-
+/// Writes a list of processes to `out`, for debugging.
+pub fn debug_print_processes<W: fmt::Write + ?Sized>(out: &mut W) {
     let processes = all();
 
-    let _ = writeln!(console(), "ID    PGID  STATE NAME");
+    let _ = writeln!(out, "ID    PGID  STATE NI  NAME");
 
     for rc_process in processes {
         let process = rc_process.lock();
 
-        let _ = writeln!(console(), "{:<5} {:<5} {:<5} {}",
+        let _ = writeln!(out, "{:<5} {:<5} {:<5} {:<3} {}",
             process.id(),
             process.pgid(),
             process.state().short_description(),
+            process.sched().nice(),
             process.name());
     }
 }
@@ -968,6 +2154,7 @@ pub fn debug_print_processes() {
 /// C interface. See `kit/kernel/include/process.h`.
 pub mod ffi {
     use crate::c_ffi::*;
+    use crate::ptr::UserPtr;
 
     #[no_mangle]
     pub extern fn process_current_id() -> uint32_t {
@@ -981,27 +2168,7 @@ pub mod ffi {
 
     #[no_mangle]
     pub unsafe extern fn process_signal(pid: uint32_t, signal: c_int) -> c_int {
-        if let Some(process) = super::by_id(pid) {
-            // Case 1: this is the current process, so just exit
-            let current_pid = super::current().lock().id;
-            let target_pid = process.lock().id;
-            if current_pid == target_pid {
-                drop(process);
-                super::exit(signal);
-                // the function will not return!
-            }
-
-            // Case 2: we're telling another process to exit.
-            process.lock().exit(signal);
-
-            // Let waiting processes know
-            process.lock().exit_wait.awaken_all();
-
-            1
-        } else {
-            // Case 3: we can't find the process.
-            0
-        }
+        super::signal(pid, signal).is_ok() as c_int
     }
 
     #[no_mangle]
@@ -1025,6 +2192,50 @@ pub mod ffi {
         }
     }
 
+    #[no_mangle]
+    pub extern fn process_wait_status(pid: uint32_t,
+                                      status: UserPtr<c_int>)
+                                      -> c_int {
+        match super::wait_status(pid) {
+            Ok(super::WaitStatus::Exited(exit_status)) => {
+                // Leave the process around to be waited on again if the
+                // status can't be delivered.
+                if status.write(exit_status).is_err() {
+                    return -1;
+                }
+
+                drop(super::cleanup(pid));
+                0
+            },
+            Ok(super::WaitStatus::Stopped) => 1,
+            Err(_) => -1,
+        }
+    }
+
+    /// Called on entry to an interrupt taken in user mode, so the user
+    /// registers are in the `HwState` while the process is in the kernel.
+    #[no_mangle]
+    pub unsafe extern fn process_save_user_interrupt(
+        stack: *const super::InterruptStack
+    ) {
+        let current = super::current();
+        let mut process = current.lock();
+
+        process.hw_state_mut().user_mut().save_interrupt_stack(&*stack);
+    }
+
+    /// Called before returning from an interrupt taken in user mode, to apply
+    /// any changes made to the saved user registers.
+    #[no_mangle]
+    pub unsafe extern fn process_restore_user_interrupt(
+        stack: *mut super::InterruptStack
+    ) {
+        let current = super::current();
+        let process = current.lock();
+
+        process.hw_state().user().restore_interrupt_stack(&mut *stack);
+    }
+
     //void *process_adjust_heap(int64_t amount);
     #[no_mangle]
     pub unsafe extern fn process_adjust_heap(amount: int64_t) -> *mut c_void {
@@ -1041,4 +2252,55 @@ pub mod ffi {
 
         heap_end
     }
+
+    //uint64_t process_archive_addr();
+    #[no_mangle]
+    pub extern fn process_archive_addr() -> uint64_t {
+        super::current().lock().mem()
+            .map(|mem| mem.lock().layout().archive_addr as uint64_t)
+            .unwrap_or(super::target::ARCHIVE_ADDR as uint64_t)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn process_mem() -> ProcessMem {
+        let layout = Layout::fixed();
+
+        ProcessMem {
+            id:            1,
+            pageset:       Pageset::alloc(),
+            layout:        layout,
+            heap_base:     layout.heap_base,
+            heap_length:   0,
+            owned_regions: vec![],
+            cow_pages:     BTreeMap::new(),
+            vmas:          Vmas::new(),
+            image_base:    0,
+            library_next:  target::LIBRARY_BASE_ADDR,
+            aux_vector:    vec![],
+            environment:   vec![],
+            jit:           false,
+        }
+    }
+
+    #[test]
+    fn archive_area_is_kept_when_written() {
+        let mut mem = process_mem();
+
+        // Mapped like syscall_mmap_archive() does, without a Vma.
+        let page = mem.layout().archive_addr;
+        let paddr = archive::TEST_SYSTEM_ADDR + PAGE_SIZE;
+        let page_type = PageType::default().user();
+
+        mem.pageset.lock().set(page, Some((paddr, page_type))).unwrap();
+
+        assert_eq!(mem.populate(page + 8, 8, true),
+            Err(Error::ProtectionViolation(page)));
+        assert_eq!(mem.pageset.lock().get(page), Some((paddr, page_type)));
+
+        assert_eq!(mem.populate(page + 8, 8, false), Ok(()));
+    }
 }
@@ -0,0 +1,395 @@
+/*******************************************************************************
+ *
+ * kit/kernel/process/vma.rs
+ *
+ * vim:ft=rust:ts=4:sw=4:et:tw=80
+ *
+ * Copyright (C) 2015-2021, Devyn Cairns
+ * Redistribution of this file is permitted under the terms of the simplified
+ * BSD license. See LICENSE for more information.
+ *
+ ******************************************************************************/
+
+//! Virtual memory areas: ranges of a process's address space whose pages are
+//! filled in the first time they're touched, rather than up front.
+//!
+//! Each area records the permissions its pages get, where their contents come
+//! from, and whether it can grow. Page faults in user memory are resolved or
+//! rejected according to them: see
+//! [ProcessMem::handle_page_fault](super::ProcessMem::handle_page_fault).
+
+use core::fmt;
+
+use alloc::vec::Vec;
+
+use crate::memory::VirtualAddress;
+use crate::paging::{PageType, PAGE_SIZE};
+
+/// A page-aligned range of user memory, and what its pages should contain.
+#[derive(Debug, Clone)]
+pub struct Vma {
+    pub start:     VirtualAddress,
+    pub end:       VirtualAddress,
+    /// The permissions of the pages once they're filled in. Always user
+    /// accessible.
+    pub page_type: PageType,
+    pub backing:   Backing,
+    pub growth:    Growth,
+}
+
+/// Whether a [Vma] can be extended on demand.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Growth {
+    Fixed,
+    /// Extended downward to cover pages that are touched below it, like a
+    /// stack, but not below `limit`. The `guard` bytes below that are kept
+    /// free so that running into them can be told apart from other faults.
+    Down { limit: VirtualAddress, guard: usize },
+}
+
+/// A kind of memory access, as made by the instruction that faulted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+    Execute,
+}
+
+/// Where the contents of a [Vma]'s pages come from.
+#[derive(Clone, Copy)]
+pub enum Backing {
+    /// Zero-filled.
+    Zero,
+    /// `data` is loaded at `vaddr`, which is within the area but need not be
+    /// page-aligned, followed by zeros up to `size` bytes. Everything else is
+    /// zero-filled too.
+    ///
+    /// The data is usually part of the system archive, which is never freed.
+    File { vaddr: VirtualAddress, data: &'static [u8], size: usize },
+}
+
+impl fmt::Debug for Backing {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Backing::Zero => write!(f, "Zero"),
+            Backing::File { vaddr, data, size } =>
+                write!(f, "File({:#x}, {}/{} bytes)", vaddr, data.len(), size),
+        }
+    }
+}
+
+impl Vma {
+    pub fn contains(&self, vaddr: VirtualAddress) -> bool {
+        vaddr >= self.start && vaddr < self.end
+    }
+
+    /// True if the area's permissions allow `access` to its pages.
+    pub fn allows(&self, access: Access) -> bool {
+        match access {
+            Access::Read    => true,
+            Access::Write   => self.page_type.is_writable(),
+            Access::Execute => self.page_type.is_executable(),
+        }
+    }
+
+    /// The lowest address the area could ever take up, including its guard,
+    /// which nothing else may overlap.
+    pub fn reserved_start(&self) -> VirtualAddress {
+        match self.growth {
+            Growth::Fixed => self.start,
+            Growth::Down { limit, guard } => limit.saturating_sub(guard),
+        }
+    }
+
+    /// Write the contents of the page at `page` into `out`, which must be one
+    /// page long.
+    pub fn fill(&self, page: VirtualAddress, out: &mut [u8]) {
+        debug_assert_eq!(out.len(), PAGE_SIZE);
+
+        for byte in out.iter_mut() {
+            *byte = 0;
+        }
+
+        if let Backing::File { vaddr, data, .. } = self.backing {
+            // Intersection of the page and the data
+            let start = page.max(vaddr);
+            let end = (page + PAGE_SIZE).min(vaddr + data.len());
+
+            if start < end {
+                out[(start - page)..(end - page)]
+                    .copy_from_slice(&data[(start - vaddr)..(end - vaddr)]);
+            }
+        }
+    }
+
+    /// If the page at `page` would be a plain copy of page-aligned file data,
+    /// the address of that data. The page can then share its frame with the
+    /// file rather than holding a copy, as long as nothing writes to it.
+    ///
+    /// Bytes outside of the segment are allowed to differ from [fill]: they
+    /// aren't part of anything.
+    ///
+    /// [fill]: Vma::fill
+    pub fn source_page(&self, page: VirtualAddress) -> Option<VirtualAddress> {
+        if self.page_type.is_writable() {
+            return None;
+        }
+
+        let (vaddr, data, size) = match self.backing {
+            Backing::File { vaddr, data, size } => (vaddr, data, size),
+            Backing::Zero => return None,
+        };
+
+        let page_end = page + PAGE_SIZE;
+        let data_end = vaddr + data.len();
+
+        // Must have some data, and no zero-filled part of the segment.
+        if page_end <= vaddr || page >= data_end ||
+            (page_end > data_end && vaddr + size > data_end) {
+            return None;
+        }
+
+        let source = (data.as_ptr() as usize).wrapping_add(page)
+            .wrapping_sub(vaddr);
+
+        if source % PAGE_SIZE == 0 { Some(source) } else { None }
+    }
+}
+
+/// A process's [Vma]s, in address order.
+#[derive(Debug, Default, Clone)]
+pub struct Vmas {
+    areas: Vec<Vma>,
+}
+
+impl Vmas {
+    pub fn new() -> Vmas {
+        Vmas { areas: vec![] }
+    }
+
+    /// Add an area. Returns it back if it (or the space it could grow into)
+    /// overlaps an existing one, or isn't page-aligned.
+    pub fn insert(&mut self, vma: Vma) -> Result<(), Vma> {
+        if vma.start % PAGE_SIZE != 0 || vma.end % PAGE_SIZE != 0 ||
+            vma.start >= vma.end || vma.reserved_start() > vma.start ||
+            vma.reserved_start() % PAGE_SIZE != 0 {
+            return Err(vma);
+        }
+
+        let index = self.areas.iter()
+            .position(|area| area.reserved_start() >= vma.end)
+            .unwrap_or(self.areas.len());
+
+        if index > 0 && self.areas[index - 1].end > vma.reserved_start() {
+            return Err(vma);
+        }
+
+        self.areas.insert(index, vma);
+
+        Ok(())
+    }
+
+    /// The area containing `vaddr`, if any.
+    pub fn find(&self, vaddr: VirtualAddress) -> Option<&Vma> {
+        self.areas.iter().find(|area| area.contains(vaddr))
+    }
+
+    /// Remove the area containing `vaddr`, and return it.
+    pub fn remove(&mut self, vaddr: VirtualAddress) -> Option<Vma> {
+        let index = self.areas.iter().position(|area| area.contains(vaddr))?;
+
+        Some(self.areas.remove(index))
+    }
+
+    /// If `vaddr` is below an area that grows down, and within its limit,
+    /// extend the area to cover it. Returns false if there's no such area, or
+    /// [Overflow] if `vaddr` is in its guard.
+    pub fn grow(&mut self, vaddr: VirtualAddress) -> Result<bool, Overflow> {
+        let page = vaddr - vaddr % PAGE_SIZE;
+
+        let area = self.areas.iter_mut()
+            .find(|area| page >= area.reserved_start() && page < area.start);
+
+        match area.map(|area| (area.growth, area)) {
+            Some((Growth::Down { limit, .. }, area)) if page >= limit => {
+                area.start = page;
+                Ok(true)
+            },
+            Some(_) => Err(Overflow),
+            None => Ok(false),
+        }
+    }
+
+    /// True if `vaddr` is in the guard below an area that grows down.
+    pub fn in_guard(&self, vaddr: VirtualAddress) -> bool {
+        self.areas.iter().any(|area| match area.growth {
+            Growth::Down { limit, .. } =>
+                vaddr >= area.reserved_start() && vaddr < limit,
+            Growth::Fixed => false,
+        })
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item=&Vma> {
+        self.areas.iter()
+    }
+}
+
+/// An access ran into the guard below an area that grows down.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Overflow;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn zero(start: usize, end: usize) -> Vma {
+        Vma {
+            start, end,
+            page_type: PageType::default(),
+            backing: Backing::Zero,
+            growth: Growth::Fixed,
+        }
+    }
+
+    #[test]
+    fn overlapping_areas_are_rejected() {
+        let mut vmas = Vmas::new();
+
+        assert!(vmas.insert(zero(0x3000, 0x5000)).is_ok());
+        assert!(vmas.insert(zero(0x1000, 0x3000)).is_ok());
+        assert!(vmas.insert(zero(0x4000, 0x6000)).is_err());
+        assert!(vmas.insert(zero(0x2000, 0x4000)).is_err());
+        assert!(vmas.insert(zero(0x5000, 0x5800)).is_err());
+
+        let starts: Vec<_> = vmas.iter().map(|vma| vma.start).collect();
+
+        assert_eq!(starts, [0x1000, 0x3000]);
+        assert_eq!(vmas.find(0x4fff).map(|vma| vma.start), Some(0x3000));
+        assert!(vmas.find(0x5000).is_none());
+    }
+
+    #[test]
+    fn stacks_grow_down_to_their_guard() {
+        let mut vmas = Vmas::new();
+
+        let stack = Vma {
+            growth: Growth::Down { limit: 0x8000, guard: 0x2000 },
+            ..zero(0xe000, 0x10000)
+        };
+
+        assert!(vmas.insert(stack).is_ok());
+
+        // Nothing else can take the space it grows into, or its guard.
+        assert!(vmas.insert(zero(0x7000, 0x8000)).is_err());
+        assert!(vmas.insert(zero(0x5000, 0x6000)).is_ok());
+
+        assert_eq!(vmas.grow(0xdff8), Ok(true));
+        assert_eq!(vmas.find(0xd000).map(|vma| vma.start), Some(0xd000));
+
+        assert_eq!(vmas.grow(0x8010), Ok(true));
+        assert_eq!(vmas.grow(0x7ff8), Err(Overflow));
+        assert!(vmas.in_guard(0x6000));
+        assert!(!vmas.in_guard(0x8000));
+
+        assert_eq!(vmas.grow(0x5000), Ok(false));
+        assert_eq!(vmas.grow(0x11000), Ok(false));
+    }
+
+    #[test]
+    fn file_backed_pages_are_filled_and_zero_padded() {
+        static DATA: [u8; 0x1100] = [0xaa; 0x1100];
+
+        let vma = Vma {
+            backing: Backing::File { vaddr: 0x1800, data: &DATA, size: 0x1100 },
+            ..zero(0x1000, 0x4000)
+        };
+
+        let mut page = vec![0xffu8; PAGE_SIZE];
+
+        vma.fill(0x1000, &mut page);
+        assert!(page[..0x800].iter().all(|&b| b == 0));
+        assert!(page[0x800..].iter().all(|&b| b == 0xaa));
+
+        vma.fill(0x2000, &mut page);
+        assert!(page[..0x900].iter().all(|&b| b == 0xaa));
+        assert!(page[0x900..].iter().all(|&b| b == 0));
+
+        vma.fill(0x3000, &mut page);
+        assert!(page.iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn only_aligned_read_only_data_is_shared() {
+        #[repr(align(4096))]
+        struct Aligned([u8; 0x3000]);
+
+        static FILE: Aligned = Aligned([0xaa; 0x3000]);
+
+        let base = FILE.0.as_ptr() as usize;
+
+        // The data starts 0x800 into the file, at 0x1800 in memory.
+        let file = |data_len: usize, size| Vma {
+            backing: Backing::File {
+                vaddr: 0x1800,
+                data: &FILE.0[0x800..(0x800 + data_len)],
+                size,
+            },
+            ..zero(0x1000, 0x4000)
+        };
+
+        let vma = file(0x1800, 0x1800);
+
+        assert_eq!(vma.source_page(0x1000), Some(base));
+        assert_eq!(vma.source_page(0x2000), Some(base + 0x1000));
+        assert_eq!(vma.source_page(0x3000), None);
+
+        // The rest of the last page doesn't matter if it's past the end...
+        let vma = file(0x1700, 0x1700);
+
+        assert_eq!(vma.source_page(0x2000), Some(base + 0x1000));
+
+        // ...but it does if it's supposed to be zero-filled.
+        let vma = file(0x1700, 0x1800);
+
+        assert_eq!(vma.source_page(0x1000), Some(base));
+        assert_eq!(vma.source_page(0x2000), None);
+
+        let vma = Vma {
+            page_type: PageType::default().writable(),
+            ..file(0x1800, 0x1800)
+        };
+
+        assert_eq!(vma.source_page(0x1000), None);
+
+        // Misaligned with the file
+        let vma = Vma {
+            backing: Backing::File {
+                vaddr: 0x1900,
+                data: &FILE.0[0x800..0x1000],
+                size: 0x800,
+            },
+            ..zero(0x1000, 0x4000)
+        };
+
+        assert_eq!(vma.source_page(0x1000), None);
+    }
+
+    #[test]
+    fn accesses_are_checked_against_permissions() {
+        let mut vma = zero(0x1000, 0x2000);
+
+        assert!(vma.allows(Access::Read));
+        assert!(!vma.allows(Access::Write));
+        assert!(!vma.allows(Access::Execute));
+
+        vma.page_type = PageType::default().writable();
+
+        assert!(vma.allows(Access::Write));
+        assert!(!vma.allows(Access::Execute));
+
+        vma.page_type = PageType::default().executable();
+
+        assert!(!vma.allows(Access::Write));
+        assert!(vma.allows(Access::Execute));
+    }
+}
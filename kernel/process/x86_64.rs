@@ -13,13 +13,15 @@
 //! x86-64 architecture-specific process logic and hardware state.
 
 use crate::memory;
+use crate::interrupt::InterruptStack;
+use crate::process::EntryArgs;
+use crate::ptr::AlwaysUserSafe;
 
-use core::ptr;
 use core::mem;
 
 /// A complete set of registers
 #[repr(C, align(16))]
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Registers {
     rax:     usize,       // 0x00
     rcx:     usize,       // 0x08
@@ -59,16 +61,124 @@ impl Default for Registers {
     }
 }
 
+impl Registers {
+    /// Copy out the general purpose registers.
+    pub fn general(&self) -> GeneralRegisters {
+        GeneralRegisters {
+            rax: self.rax as u64, rcx: self.rcx as u64,
+            rdx: self.rdx as u64, rbx: self.rbx as u64,
+            rsp: self.rsp as u64, rbp: self.rbp as u64,
+            rsi: self.rsi as u64, rdi: self.rdi as u64,
+            r8:  self.r8  as u64, r9:  self.r9  as u64,
+            r10: self.r10 as u64, r11: self.r11 as u64,
+            r12: self.r12 as u64, r13: self.r13 as u64,
+            r14: self.r14 as u64, r15: self.r15 as u64,
+            rip: self.rip as u64,
+            rflags: self.eflags as u64,
+        }
+    }
+
+    /// Replace the general purpose registers. Only the flags in
+    /// `USER_EFLAGS_MASK` are taken from `regs.rflags`.
+    ///
+    /// Returns false without changing anything if `regs.rip` is not a user
+    /// address, as returning to it would fault in the kernel.
+    pub fn set_general(&mut self, regs: &GeneralRegisters) -> bool {
+        if regs.rip as usize >= USER_ADDR_LIMIT {
+            return false;
+        }
+
+        self.rax = regs.rax as usize; self.rcx = regs.rcx as usize;
+        self.rdx = regs.rdx as usize; self.rbx = regs.rbx as usize;
+        self.rsp = regs.rsp as usize; self.rbp = regs.rbp as usize;
+        self.rsi = regs.rsi as usize; self.rdi = regs.rdi as usize;
+        self.r8  = regs.r8  as usize; self.r9  = regs.r9  as usize;
+        self.r10 = regs.r10 as usize; self.r11 = regs.r11 as usize;
+        self.r12 = regs.r12 as usize; self.r13 = regs.r13 as usize;
+        self.r14 = regs.r14 as usize; self.r15 = regs.r15 as usize;
+        self.rip = regs.rip as usize;
+
+        self.eflags = (self.eflags & !USER_EFLAGS_MASK) |
+            (regs.rflags as u32 & USER_EFLAGS_MASK);
+        true
+    }
+
+    pub fn instruction_pointer(&self) -> usize {
+        self.rip
+    }
+
+    pub fn set_instruction_pointer(&mut self, vaddr: usize) {
+        self.rip = vaddr;
+    }
+
+    /// Set or clear the trap flag, which causes a debug exception after the
+    /// next user instruction.
+    pub fn set_trap_flag(&mut self, enabled: bool) {
+        if enabled {
+            self.eflags |= EFLAGS_TF;
+        } else {
+            self.eflags &= !EFLAGS_TF;
+        }
+    }
+}
+
+/// The general purpose registers of a user process, as seen by a debugger.
+///
+/// Must match `trace_registers_t` in `kit/system/libc/include/kit/syscall.h`.
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct GeneralRegisters {
+    pub rax: u64, pub rcx: u64, pub rdx: u64, pub rbx: u64,
+    pub rsp: u64, pub rbp: u64, pub rsi: u64, pub rdi: u64,
+    pub r8:  u64, pub r9:  u64, pub r10: u64, pub r11: u64,
+    pub r12: u64, pub r13: u64, pub r14: u64, pub r15: u64,
+    pub rip: u64,
+    pub rflags: u64,
+}
+
+unsafe impl AlwaysUserSafe for GeneralRegisters { }
+
 #[repr(simd, align(16))]
 #[derive(Debug, Default, Clone, Copy)]
 pub struct SSEReg(u32, u32, u32, u32);
 
+/// Flags that user code may change: CF, PF, AF, ZF, SF, TF, DF, OF.
+pub const USER_EFLAGS_MASK: u32 = 0x0000_0dd5;
+
+/// Trap flag: single-step.
+pub const EFLAGS_TF: u32 = 1 << 8;
+
+/// Addresses from here on up are not in the lower canonical half.
+pub const USER_ADDR_LIMIT: usize = 0x0000_8000_0000_0000;
+
 pub const ARGS_TOP_ADDR:   usize = 0x0000_7fee_ffff_ffff;
+
+// The stack, heap, PIE base and archive mapping are only here if address space
+// layout randomization is off. See [layout](super::layout).
+
 pub const STACK_BASE_ADDR: usize = 0x0000_7fff_ffff_f000;
 pub const HEAP_BASE_ADDR:  usize = 0x0000_0001_0000_0000;
 
+/// Where position-independent executables are loaded.
+pub const PIE_BASE_ADDR:   usize = 0x0000_5555_5555_4000;
+
+/// Where `syscall_mmap_archive()` maps the system archive. (ace = 'archive')
+pub const ARCHIVE_ADDR:    usize = 0x0000_0ace_0000_0000;
+
+/// The area that the dynamic linker and shared libraries are loaded into.
+pub const LIBRARY_BASE_ADDR:  usize = 0x0000_7f00_0000_0000;
+pub const LIBRARY_LIMIT_ADDR: usize = 0x0000_7fe0_0000_0000;
+
+/// How much of the stack is set up at first. It grows on demand from there.
 pub const STACK_SIZE:      usize = 32768;
 
+/// How far the stack can grow, unless the program asks for a different limit
+/// with `PT_GNU_STACK`.
+pub const STACK_LIMIT:     usize = 8 * 1024 * 1024;
+
+/// Space below the stack's limit that's never mapped, to catch overflows.
+pub const STACK_GUARD_SIZE: usize = 64 * 1024;
+
 /// The hardware state of a process. Usually mutated by foreign code.
 #[repr(C, align(16))]
 #[derive(Debug)]
@@ -126,6 +236,24 @@ impl KernelHwState {
         }
     }
 
+    /// The registers saved when the process was last switched away from. Only
+    /// the callee-saved registers, `rip` and `rsp` are known.
+    pub fn general_registers(&self) -> GeneralRegisters {
+        let r = &self.registers;
+
+        GeneralRegisters {
+            rip: r.rip as u64,
+            rsp: r.rsp as u64,
+            rbp: r.rbp as u64,
+            rbx: r.rbx as u64,
+            r12: r.r12 as u64,
+            r13: r.r13 as u64,
+            r14: r.r14 as u64,
+            r15: r.r15 as u64,
+            ..GeneralRegisters::default()
+        }
+    }
+
     /// Set the address to jump to on process switch.
     ///
     /// # Unsafety
@@ -209,17 +337,12 @@ impl UserHwState {
         }
     }
 
-    /// Set the entry point arguments to `(argc, argv)`.
-    ///
-    /// Pass `None` to set to `(0, NULL)`.
-    pub fn set_args(&mut self, args: Option<(i32, usize)>) {
-        if let Some((argc, argv)) = args {
-            self.registers.rdi = argc as usize;
-            self.registers.rsi = argv;
-        } else {
-            self.registers.rdi = 0;
-            self.registers.rsi = ptr::null::<u8>() as usize;
-        }
+    /// Set the entry point arguments to `(argc, argv, envp, auxv)`.
+    pub fn set_args(&mut self, args: EntryArgs) {
+        self.registers.rdi = args.argc as usize;
+        self.registers.rsi = args.argv;
+        self.registers.rdx = args.envp;
+        self.registers.rcx = args.auxv;
     }
 
     /// Set the instruction pointer to the given address.
@@ -232,4 +355,54 @@ impl UserHwState {
         self.registers.rsp = vaddr;
         self.registers.rbp = vaddr;
     }
+
+    /// Set the value returned to user code by the system call it's in.
+    pub fn set_return_value(&mut self, value: usize) {
+        self.registers.rax = value;
+    }
+
+    pub fn registers(&self) -> &Registers {
+        &self.registers
+    }
+
+    pub fn registers_mut(&mut self) -> &mut Registers {
+        &mut self.registers
+    }
+
+    /// Save the user registers from an interrupt taken in user mode, so that
+    /// they can be inspected and changed the same way as on a syscall.
+    ///
+    /// The x87/SSE state is left alone.
+    pub fn save_interrupt_stack(&mut self, stack: &InterruptStack) {
+        let r = &mut self.registers;
+
+        r.rax = stack.rax as usize; r.rcx = stack.rcx as usize;
+        r.rdx = stack.rdx as usize; r.rbx = stack.rbx as usize;
+        r.rsp = stack.user_rsp as usize; r.rbp = stack.rbp as usize;
+        r.rsi = stack.rsi as usize; r.rdi = stack.rdi as usize;
+        r.r8  = stack.r8  as usize; r.r9  = stack.r9  as usize;
+        r.r10 = stack.r10 as usize; r.r11 = stack.r11 as usize;
+        r.r12 = stack.r12 as usize; r.r13 = stack.r13 as usize;
+        r.r14 = stack.r14 as usize; r.r15 = stack.r15 as usize;
+        r.rip = stack.rip as usize;
+        r.eflags = stack.rflags as u32;
+    }
+
+    /// Write the user registers back into the interrupt stack they were saved
+    /// from, so that any changes take effect on return to user mode.
+    pub fn restore_interrupt_stack(&self, stack: &mut InterruptStack) {
+        let r = &self.registers;
+
+        stack.rax = r.rax as u64; stack.rcx = r.rcx as u64;
+        stack.rdx = r.rdx as u64; stack.rbx = r.rbx as u64;
+        stack.user_rsp = r.rsp as u64; stack.rbp = r.rbp as u64;
+        stack.rsi = r.rsi as u64; stack.rdi = r.rdi as u64;
+        stack.r8  = r.r8  as u64; stack.r9  = r.r9  as u64;
+        stack.r10 = r.r10 as u64; stack.r11 = r.r11 as u64;
+        stack.r12 = r.r12 as u64; stack.r13 = r.r13 as u64;
+        stack.r14 = r.r14 as u64; stack.r15 = r.r15 as u64;
+        stack.rip = r.rip as u64;
+        stack.rflags = (stack.rflags & !(USER_EFLAGS_MASK as u64)) |
+            (r.eflags & USER_EFLAGS_MASK) as u64;
+    }
 }
@@ -12,11 +12,12 @@
 
 //! Safe pointer manipulation.
 
-use core::mem::MaybeUninit;
+use core::mem::{self, MaybeUninit};
 use core::slice;
 
-use crate::paging::{current_pageset, GenericPageset, PAGE_SIZE};
+use crate::paging::{current_pageset, GenericPageset, Pageset, PAGE_SIZE};
 use crate::c_ffi::CStr;
+use crate::process;
 
 use alloc::vec::Vec;
 
@@ -76,12 +77,110 @@ pub enum Error {
 
 impl crate::error::Error for Error { }
 
+/// Fill in any pages of `vaddr..(vaddr + size)` that the current process hasn't
+/// touched yet, so they can be checked and accessed, and give it its own copy
+/// of any shared pages if it's going to be written. Whatever can't be filled in
+/// is left for the check to reject.
+fn populate_user_pages(vaddr: usize, size: usize, write: bool) {
+    let mem = process::current().lock().mem();
+
+    if let Some(mem) = mem {
+        let _ = mem.lock().populate(vaddr, size, write);
+    }
+}
+
+/// Ensure that every page in `vaddr..(vaddr + size)` is mapped and accessible
+/// by user code, and writable if `write` is true.
+fn check_user_pages(pageset: &Pageset, vaddr: usize, size: usize, write: bool)
+    -> Result<(), Error> {
+
+    if size == 0 {
+        return Ok(());
+    }
+
+    let last_vaddr = vaddr.checked_add(size - 1)
+        .ok_or(Error::InaccessiblePage)?;
+
+    let min_vaddr_page = vaddr & !(PAGE_SIZE - 1);
+    let max_vaddr_page = last_vaddr & !(PAGE_SIZE - 1);
+    let expected_pages = (max_vaddr_page - min_vaddr_page) / PAGE_SIZE + 1;
+
+    let mut found_pages = 0;
+
+    // Walk pageset, ensure all mapped and accessible by user
+    for page in pageset.from(min_vaddr_page).take(expected_pages) {
+        match page {
+            Some((_, page_type))
+                if page_type.is_user() &&
+                    (!write || page_type.is_writable()) => {
+                found_pages += 1;
+            },
+            _ => return Err(Error::InaccessiblePage),
+        }
+    }
+
+    if found_pages != expected_pages {
+        return Err(Error::InaccessiblePage);
+    }
+
+    Ok(())
+}
+
 /// A C-compatible pointer type with safe accessors that validate data before
 /// returning anything.
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 #[repr(transparent)]
 pub struct UserPtr<T>(*mut T);
 
+impl<T> UserPtr<T> {
+    /// Make a user pointer from an address passed by user code.
+    pub fn from_addr(vaddr: usize) -> UserPtr<T> {
+        UserPtr(vaddr as *mut T)
+    }
+
+    pub fn is_null(self) -> bool {
+        self.0.is_null()
+    }
+
+    /// The pointer `count` elements further on. Nothing is checked until it's
+    /// read from or written to.
+    pub fn add(self, count: usize) -> UserPtr<T> {
+        UserPtr(self.0.wrapping_add(count))
+    }
+}
+
+impl<T: Copy> UserPtr<T> {
+    /// Write a value to user memory safely.
+    pub fn write(self, value: T) -> Result<(), Error> {
+        self.write_from_slice(slice::from_ref(&value))
+    }
+
+    /// Write a slice of data to user memory safely.
+    ///
+    /// Each page will be checked for user accessibility and writability before
+    /// writing to it.
+    pub fn write_from_slice(self, data: &[T]) -> Result<(), Error> {
+        let size = data.len() * mem::size_of::<T>();
+
+        populate_user_pages(self.0 as usize, size, true);
+
+        // SAFETY: we are only reading the pageset, this is always ok
+        let pageset_ref = unsafe {
+            current_pageset().expect("paging not initialized")
+        };
+        let pageset = pageset_ref.lock();
+
+        check_user_pages(&pageset, self.0 as usize, size, true)?;
+
+        // Copy is safe now, and we hold the pageset lock so it can't change.
+        unsafe {
+            self.0.copy_from(data.as_ptr(), data.len());
+        }
+
+        Ok(())
+    }
+}
+
 impl<T: UserSafe + Copy> UserPtr<T> {
     /// A maximally safe option for reading user data.
     ///
@@ -133,40 +232,19 @@ impl<T: UserSafe + Copy> UserPtr<T> {
             }
         }
 
-        // Ensure pages can be accessed.
-        let min_vaddr = self.0 as usize;
-        let max_vaddr = self.0.wrapping_offset(out.len() as isize) as usize;
-
-        assert!(max_vaddr >= min_vaddr);
-
-        let min_vaddr_page = min_vaddr & !(PAGE_SIZE - 1);
-        let max_vaddr_page = max_vaddr & !(PAGE_SIZE - 1);
-        let expected_pages = (max_vaddr_page - min_vaddr_page) / PAGE_SIZE;
+        let size = out.len() * mem::size_of::<T>();
 
-        let mut found_pages = 0;
+        populate_user_pages(self.0 as usize, size, false);
 
+        // Ensure pages can be accessed.
+        //
         // SAFETY: we are only reading the pageset, this is always ok
         let pageset_ref = unsafe {
             current_pageset().expect("paging not initialized")
         };
         let pageset = pageset_ref.lock();
 
-        // Walk pageset, ensure all mapped and accessible by user
-        for page in pageset.from(min_vaddr_page).take(expected_pages) {
-            if let Some((_, page_type)) = page {
-                if page_type.is_user() {
-                    found_pages += 1;
-                } else {
-                    return Err(Error::InaccessiblePage);
-                }
-            } else {
-                return Err(Error::InaccessiblePage);
-            }
-        }
-
-        if found_pages != expected_pages {
-            return Err(Error::InaccessiblePage);
-        }
+        check_user_pages(&pageset, self.0 as usize, size, false)?;
 
         // Copy is safe now.
         unsafe {
@@ -218,6 +296,9 @@ impl UserPtr<u8> {
 
         let mut size = 0;
 
+        // The string can't be longer than the buffer.
+        populate_user_pages(min_vaddr, buffer.len(), false);
+
         // SAFETY: reading the current pageset is always safe
         let pageset_ref = unsafe {
             current_pageset().expect("paging not initialized")
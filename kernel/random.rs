@@ -0,0 +1,100 @@
+/*******************************************************************************
+ *
+ * kit/kernel/random.rs
+ *
+ * vim:ft=rust:ts=4:sw=4:et:tw=80
+ *
+ * Copyright (C) 2015-2021, Devyn Cairns
+ * Redistribution of this file is permitted under the terms of the simplified
+ * BSD license. See LICENSE for more information.
+ *
+ ******************************************************************************/
+
+//! Random numbers for user programs and the loader.
+//!
+//! Not suitable for cryptography: the state is mixed from the time stamp
+//! counter, plus `RDRAND` if the processor has it.
+
+use core::sync::atomic::{AtomicU8, AtomicU64, Ordering::*};
+
+static STATE: AtomicU64 = AtomicU64::new(0);
+
+/// Whether `RDRAND` is available: 0 if not checked yet, 1 if not, 2 if so.
+static HAS_RDRAND: AtomicU8 = AtomicU8::new(0);
+
+const GOLDEN_GAMMA: u64 = 0x9e37_79b9_7f4a_7c15;
+
+/// Get a random 64-bit number.
+pub fn next_u64() -> u64 {
+    let increment = GOLDEN_GAMMA.wrapping_add(timestamp() << 1);
+
+    let mut z = STATE.fetch_add(increment, Relaxed).wrapping_add(increment);
+
+    // SplitMix64 finalizer
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^= z >> 31;
+
+    z ^ rdrand().unwrap_or(0)
+}
+
+/// Fill a buffer with random bytes.
+pub fn fill(buffer: &mut [u8]) {
+    for chunk in buffer.chunks_mut(8) {
+        let bytes = next_u64().to_le_bytes();
+
+        chunk.copy_from_slice(&bytes[0..chunk.len()]);
+    }
+}
+
+fn timestamp() -> u64 {
+    let lo: u32;
+    let hi: u32;
+
+    unsafe {
+        asm!("rdtsc", out("eax") lo, out("edx") hi,
+            options(nomem, nostack));
+    }
+
+    ((hi as u64) << 32) | (lo as u64)
+}
+
+fn rdrand() -> Option<u64> {
+    if HAS_RDRAND.load(Relaxed) == 0 {
+        // CPUID.01H:ECX.RDRAND[bit 30]
+        #[allow(unused_unsafe)]
+        let ecx = unsafe { core::arch::x86_64::__cpuid(1).ecx };
+
+        HAS_RDRAND.store(if ecx & (1 << 30) != 0 { 2 } else { 1 }, Relaxed);
+    }
+
+    if HAS_RDRAND.load(Relaxed) != 2 {
+        return None;
+    }
+
+    let value: u64;
+    let ok: u8;
+
+    unsafe {
+        asm!("rdrand {}", "setc {}", out(reg) value, out(reg_byte) ok,
+            options(nomem, nostack));
+    }
+
+    if ok != 0 { Some(value) } else { None }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fill_covers_partial_chunks() {
+        let mut buffer = [0u8; 21];
+
+        fill(&mut buffer);
+
+        // 13 zero bytes at the end would be quite a coincidence.
+        assert!(buffer[8..].iter().any(|&byte| byte != 0));
+        assert_ne!(next_u64(), next_u64());
+    }
+}
@@ -11,15 +11,139 @@
  ******************************************************************************/
 
 //! Time and event based task scheduler.
+//!
+//! Processes are scheduled fairly according to their *virtual runtime*: the
+//! amount of processor time they have consumed, weighted by their priority
+//! (nice value). The runnable process with the lowest virtual runtime is always
+//! the next to run, so a process with a lower nice value gets a proportionally
+//! larger share of the processor, and processes that spend most of their time
+//! sleeping (like the shell waiting for a key) are picked quickly once they
+//! wake up.
 
-use alloc::collections::VecDeque;
+use core::cmp::max;
+use core::convert::TryFrom;
+
+use alloc::collections::BTreeMap;
+
+use displaydoc::Display;
 
 use crate::process::{self, RcProcess};
 use crate::interrupt;
 use crate::sync::Spinlock;
 
+/// A process priority. Lower values get more processor time.
+pub type Nice = i8;
+
+/// The highest priority a process may have.
+pub const NICE_MIN: Nice = -20;
+
+/// The lowest priority a process may have.
+pub const NICE_MAX: Nice = 19;
+
+/// The default priority of a process.
+pub const NICE_DEFAULT: Nice = 0;
+
+/// Relative share of processor time for each nice value, from `NICE_MIN` to
+/// `NICE_MAX`. Each step is roughly 25% more (or less) than its neighbor.
+static NICE_TO_WEIGHT: [u64; 40] = [
+    /* -20 */ 88761, 71755, 56483, 46273, 36291,
+    /* -15 */ 29154, 23254, 18705, 14949, 11916,
+    /* -10 */  9548,  7620,  6100,  4904,  3906,
+    /*  -5 */  3121,  2501,  1991,  1586,  1277,
+    /*   0 */  1024,   820,   655,   526,   423,
+    /*   5 */   335,   272,   215,   172,   137,
+    /*  10 */   110,    87,    70,    56,    45,
+    /*  15 */    36,    29,    23,    18,    15,
+];
+
+const NICE_0_WEIGHT: u64 = 1024;
+
+/// How far (in weighted cycles) the current process may run ahead of the
+/// leftmost queued process before the timer preempts it.
+const PREEMPT_GRANULARITY: u64 = 4_000_000;
+
+/// How much credit (in weighted cycles) a waking process may keep from having
+/// slept, relative to the leftmost queued process. This is what keeps
+/// interactive processes responsive without letting them starve others after
+/// a long sleep.
+const SLEEPER_CREDIT: u64 = 12_000_000;
+
+/// Scheduler bookkeeping stored in each process.
+#[derive(Debug, Clone)]
+pub struct Entity {
+    nice: Nice,
+    vruntime: u64,
+    exec_start: u64,
+}
+
+impl Entity {
+    pub fn new(nice: Nice) -> Entity {
+        // A process that's already running when it's created (the kernel's)
+        // is never enqueued, so start the clock now rather than at boot.
+        Entity { nice, vruntime: 0, exec_start: now() }
+    }
+
+    pub fn nice(&self) -> Nice {
+        self.nice
+    }
+
+    pub fn vruntime(&self) -> u64 {
+        self.vruntime
+    }
+
+    fn weight(&self) -> u64 {
+        NICE_TO_WEIGHT[(self.nice - NICE_MIN) as usize]
+    }
+
+    /// Charge the time elapsed since `exec_start` to the virtual runtime.
+    fn account(&mut self, now: u64) {
+        let delta = now.saturating_sub(self.exec_start) as u128;
+
+        let weighted = delta * NICE_0_WEIGHT as u128 / self.weight() as u128;
+
+        self.vruntime = self.vruntime
+            .saturating_add(u64::try_from(weighted).unwrap_or(u64::MAX));
+        self.exec_start = now;
+    }
+}
+
+impl Default for Entity {
+    fn default() -> Entity {
+        Entity::new(NICE_DEFAULT)
+    }
+}
+
+/// How a process's virtual runtime should be adjusted as it enters the queue.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Placement {
+    /// A new process starts no earlier than the leftmost process.
+    New,
+    /// A woken process may keep a limited amount of sleeper credit.
+    Woken,
+    /// A preempted process keeps its virtual runtime.
+    Requeue,
+}
+
+struct RunQueue {
+    /// Ordered by virtual runtime, with the process ID to break ties.
+    queue: BTreeMap<(u64, process::Id), RcProcess>,
+    /// Monotonically increasing lower bound of the queue's virtual runtimes.
+    min_vruntime: u64,
+}
+
+impl RunQueue {
+    fn update_min_vruntime(&mut self, candidate: u64) {
+        let leftmost = self.queue.keys().next()
+            .map(|&(vruntime, _)| vruntime)
+            .unwrap_or(candidate);
+
+        self.min_vruntime =
+            max(self.min_vruntime, core::cmp::min(leftmost, candidate));
+    }
+}
+
 struct GlobalState {
-    run_queue: Spinlock<VecDeque<RcProcess>>,
+    run_queue: Spinlock<RunQueue>,
     preempt_lock: Spinlock<()>,
 }
 
@@ -36,7 +160,10 @@ pub unsafe fn initialize() {
     }
 
     GLOBAL_STATE = Some(GlobalState {
-        run_queue: Spinlock::new(VecDeque::new()),
+        run_queue: Spinlock::new(RunQueue {
+            queue: BTreeMap::new(),
+            min_vruntime: 0,
+        }),
         preempt_lock: Spinlock::new(()),
     });
 
@@ -50,24 +177,71 @@ fn global_state<'a>() -> &'a GlobalState {
     }
 }
 
-/// Pushes a process on to the end of the run queue.
+/// Read the processor's time stamp counter, which is the clock used for
+/// runtime accounting.
+fn now() -> u64 {
+    let lo: u32;
+    let hi: u32;
+
+    unsafe {
+        asm!("rdtsc", out("eax") lo, out("edx") hi,
+            options(nomem, nostack));
+    }
+
+    ((hi as u64) << 32) | (lo as u64)
+}
+
+/// Pushes a newly runnable process on to the run queue.
 ///
 /// # Panics
 ///
 /// Panics if the process is not in the `Running` state.
 pub fn push(process: RcProcess) {
-    assert!(process.lock().is_running());
+    enqueue(process, Placement::New);
+}
 
-    global_state().run_queue.lock().push_back(process);
+fn enqueue(process: RcProcess, placement: Placement) {
+    let mut run_queue = global_state().run_queue.lock();
+
+    let key = {
+        let mut p = process.lock();
+
+        assert!(p.is_running());
+
+        let min_vruntime = run_queue.min_vruntime;
+        let sched = p.sched_mut();
+
+        match placement {
+            Placement::New => {
+                sched.vruntime = max(sched.vruntime, min_vruntime);
+            },
+            Placement::Woken => {
+                sched.vruntime = max(sched.vruntime,
+                    min_vruntime.saturating_sub(SLEEPER_CREDIT));
+            },
+            Placement::Requeue => (),
+        }
+
+        // Time spent off the queue isn't charged.
+        sched.exec_start = now();
+
+        (sched.vruntime, p.id())
+    };
+
+    run_queue.queue.insert(key, process);
 }
 
-/// Gets the first running process from the queue, discarding non-running
-/// processes.
+/// Gets the running process with the lowest virtual runtime from the queue,
+/// discarding non-running processes. Sleeping and `Stopped` processes are put
+/// back by `awaken` and `process::r#continue` respectively.
 fn pop_running() -> Option<RcProcess> {
     let mut run_queue = global_state().run_queue.lock();
 
-    while let Some(process) = run_queue.pop_front() {
+    while let Some(&key) = run_queue.queue.keys().next() {
+        let process = run_queue.queue.remove(&key).unwrap();
+
         if process.lock().is_running() {
+            run_queue.update_min_vruntime(key.0);
             return Some(process);
         }
     }
@@ -75,16 +249,24 @@ fn pop_running() -> Option<RcProcess> {
     None
 }
 
-/// Given a sleeping process, wakes it up and pushes it on to the end of the run
-/// queue.
+/// The virtual runtime of the leftmost queued process, if any.
+fn leftmost_vruntime() -> Option<u64> {
+    global_state().run_queue.lock().queue.keys().next()
+        .map(|&(vruntime, _)| vruntime)
+}
+
+/// Given a sleeping process, wakes it up and pushes it on to the run queue.
+///
+/// A `Stopped` process is not resumed, but will be `Running` rather than
+/// `Sleeping` once it is continued.
 ///
 /// # Returns
 ///
 /// - `Ok(true)` if the process was awoken
-/// - `Ok(false)` if the process was already running
-/// - `Err(state)` if the process was neither `Running` nor `Sleeping`.
+/// - `Ok(false)` if the process was already running, or is stopped
+/// - `Err(state)` if the process was not `Running`, `Sleeping` or `Stopped`.
 pub fn awaken(process: RcProcess) -> Result<bool, process::State> {
-    use process::State::{Running, Sleeping};
+    use process::State::{Running, Sleeping, Stopped};
 
     let state = process.lock().state();
 
@@ -92,13 +274,44 @@ pub fn awaken(process: RcProcess) -> Result<bool, process::State> {
         Running => Ok(false),
         Sleeping => {
             process.lock().awaken();
-            push(process);
+            enqueue(process, Placement::Woken);
             Ok(true)
         },
+        Stopped => {
+            process.lock().awaken();
+            Ok(false)
+        },
         _ => Err(state)
     }
 }
 
+/// Change the priority of a process.
+///
+/// The new weight applies to runtime charged from now on; runtime already
+/// consumed is not reweighted. A queued process keeps its place, since its
+/// virtual runtime doesn't change until it runs again.
+///
+/// Returns the previous nice value.
+pub fn set_nice(process: &RcProcess, nice: Nice) -> Result<Nice, Error> {
+    if nice < NICE_MIN || nice > NICE_MAX {
+        return Err(Error::NiceOutOfRange(nice));
+    }
+
+    let current_id = process::current().lock().id();
+
+    let mut p = process.lock();
+
+    if p.id() == current_id {
+        // Charge the current process at its old weight first.
+        p.sched_mut().account(now());
+    }
+
+    let old_nice = p.sched().nice;
+
+    p.sched_mut().nice = nice;
+
+    Ok(old_nice)
+}
 
 /// Iterates the scheduler loop so that other processes may execute.
 ///
@@ -158,7 +371,8 @@ pub fn r#yield() {
 ///
 /// 1. the preempt lock can't be acquired
 /// 2. there are no other processes waiting
-/// 3. or the scheduler hasn't been initialized yet.
+/// 3. the current process has not yet used up its fair share of time
+/// 4. or the scheduler hasn't been initialized yet.
 ///
 /// This is intended to be called by the timer to enable timesharing.
 ///
@@ -169,6 +383,26 @@ pub fn preempt() -> bool {
     let next_process;
 
     if let Some(preempt_lock) = global_state().preempt_lock.try_lock() {
+        let (current_vruntime, current_running) = {
+            let current = process::current();
+            let mut current = current.lock();
+
+            current.sched_mut().account(now());
+
+            (current.sched().vruntime(), current.is_running())
+        };
+
+        // Only preempt a running process once it has gotten far enough ahead
+        // of the leftmost process in the queue.
+        if current_running {
+            match leftmost_vruntime() {
+                Some(leftmost)
+                    if leftmost.saturating_add(PREEMPT_GRANULARITY) <
+                        current_vruntime => (),
+                _ => return false
+            }
+        }
+
         if let Some(next) = pop_running() {
             next_process = next;
         } else {
@@ -216,8 +450,13 @@ fn switch(next_process: RcProcess) -> bool {
         if current_process.id == next_process.id { return true; }
     }
 
+    let now = now();
+
+    current_process.lock().sched_mut().account(now);
+    next_process.lock().sched_mut().exec_start = now;
+
     if current_process_is_running {
-        push(current_process);
+        enqueue(current_process, Placement::Requeue);
     } else {
         drop(current_process);
     }
@@ -227,6 +466,14 @@ fn switch(next_process: RcProcess) -> bool {
     true
 }
 
+#[derive(Debug, Display, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// Nice value {0} is outside of the allowed range
+    NiceOutOfRange(Nice),
+}
+
+impl crate::error::Error for Error { }
+
 /// C interface. See `kit/kernel/include/scheduler.h`.
 pub mod ffi {
     use crate::process;
@@ -10,7 +10,7 @@
  *
  ******************************************************************************/
 
-//! Serial console for debugging
+//! Serial ports, for logging and debugging
 
 use core::fmt;
 
@@ -32,6 +32,10 @@ pub fn com1() -> SerialPort {
     SerialPort { io_addr: 0x3F8 }
 }
 
+pub fn com2() -> SerialPort {
+    SerialPort { io_addr: 0x2F8 }
+}
+
 impl SerialPort {
     pub fn initialize(&mut self) -> Result<(), Error> {
         unsafe {
@@ -67,6 +71,30 @@ impl SerialPort {
         }
     }
 
+    pub fn receive_ready(&self) -> bool {
+        unsafe {
+            inb(self.io_addr + 5) & 0x01 != 0
+        }
+    }
+
+    /// Wait for a byte to be received.
+    pub fn read_byte(&mut self) -> Result<u8, Error> {
+        while !self.receive_ready() { core::hint::spin_loop() }
+
+        unsafe {
+            Ok(inb(self.io_addr))
+        }
+    }
+
+    /// Get a byte if one has been received.
+    pub fn try_read_byte(&mut self) -> Option<u8> {
+        if self.receive_ready() {
+            unsafe { Some(inb(self.io_addr)) }
+        } else {
+            None
+        }
+    }
+
     pub fn write_byte(&mut self, byte: u8) -> Result<(), Error> {
         while !self.transmit_ready() { core::hint::spin_loop() }
 
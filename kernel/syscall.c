@@ -63,12 +63,6 @@ void syscall_initialize()
   wrmsr(SYSCALL_FLAG_MASK, IA32_FMASK);
 }
 
-int syscall_key_get(keyboard_event_t *event)
-{
-  keyboard_sleep_dequeue(event);
-  return 0;
-}
-
 archive_header_t *syscall_mmap_archive()
 {
   // Find the extent of the archive
@@ -87,7 +81,8 @@ archive_header_t *syscall_mmap_archive()
   // but oh well. We should be able to do a better job with the page iterators
   // implemented in Rust.
   uint64_t src_address = (uint64_t) archive_system & ((uint64_t) -1 << 12);
-  uint64_t dst_address = 0x00000ace00000000; // ace = 'archive'
+  uint64_t dst_start   = process_archive_addr();
+  uint64_t dst_address = dst_start;
   uint64_t phy_address;
 
   uint64_t src_limit = src_address + size;
@@ -114,5 +109,5 @@ archive_header_t *syscall_mmap_archive()
     dst_address += 0x1000;
   }
 
-  return (archive_header_t *) 0x00000ace00000000;
+  return (archive_header_t *) dst_start;
 }
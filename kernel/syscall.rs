@@ -11,10 +11,15 @@
  ******************************************************************************/
 
 use crate::process;
+use crate::process::target::GeneralRegisters;
+use crate::archive;
+use crate::elf;
 use crate::memory;
+use crate::debugger;
+use crate::coredump;
 use crate::scheduler;
 use crate::c_ffi::*;
-use crate::terminal::console;
+use crate::terminal::{self, console};
 use crate::ptr::UserPtr;
 
 use core::slice;
@@ -67,7 +72,7 @@ macro_rules! syscalls {
     }
 }
 
-pub const SYSCALL_MAX: usize = 9;
+pub const SYSCALL_MAX: usize = 16;
 
 syscalls!(TABLE; table_init;
     0, SYSCALL_EXIT, syscall_exit;
@@ -80,6 +85,13 @@ syscalls!(TABLE; table_init;
     7, SYSCALL_ADJUST_HEAP, syscall_adjust_heap;
     8, SYSCALL_MMAP_ARCHIVE, syscall_mmap_archive;
     9, SYSCALL_DEBUG, syscall_debug;
+    10, SYSCALL_SET_PRIORITY, syscall_set_priority;
+    11, SYSCALL_SET_FOREGROUND, syscall_set_foreground;
+    12, SYSCALL_STOP_PROCESS, syscall_stop_process;
+    13, SYSCALL_CONTINUE_PROCESS, syscall_continue_process;
+    14, SYSCALL_TRACE, syscall_trace;
+    15, SYSCALL_LOAD_LIBRARY, syscall_load_library;
+    16, SYSCALL_FORK, syscall_fork;
 );
 
 pub extern fn syscall_exit(status: c_int) -> ! {
@@ -97,10 +109,26 @@ pub unsafe extern fn syscall_twrite(length: usize, buffer: *const u8) -> c_int {
         .unwrap_or(-1)
 }
 
+/// Must match `keyboard_event_t` in `kit/kernel/include/keyboard.h`.
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct KeyboardEvent([u8; 3]);
+
 extern {
     // FIXME: C
-    // FIXME: unsafe user ptr handling
-    pub fn syscall_key_get(event: *mut u8) -> c_int;
+    fn keyboard_sleep_dequeue(event: *mut KeyboardEvent);
+}
+
+/// Wait for the next keyboard event and store it in `event`.
+///
+/// Returns 0 on success, or -1 if `event` can't be written to.
+#[no_mangle]
+pub extern fn syscall_key_get(event: UserPtr<KeyboardEvent>) -> c_int {
+    let mut key = KeyboardEvent::default();
+
+    unsafe { keyboard_sleep_dequeue(&mut key); }
+
+    event.write(key).map(|_| 0).unwrap_or(-1)
 }
 
 #[no_mangle]
@@ -121,17 +149,17 @@ pub unsafe extern fn syscall_spawn(
     file: UserPtr<u8>,
     argc: c_int,
     argv: UserPtr<UserPtr<u8>>,
+    envp: UserPtr<UserPtr<u8>>,
 ) -> int64_t {
-    crate::archive::ffi::archive_utils_spawn(file, argc, argv)
+    crate::archive::ffi::archive_utils_spawn(file, argc, argv, envp)
 }
 
-// FIXME: unsafe user ptr handling
 #[no_mangle]
-pub unsafe extern fn syscall_wait_process(
+pub extern fn syscall_wait_process(
     id: process::Id,
-    exit_status: *mut c_int
+    exit_status: UserPtr<c_int>
 ) -> c_int {
-    process::ffi::process_wait_exit_status(id, exit_status)
+    process::ffi::process_wait_status(id, exit_status)
 }
 
 #[no_mangle]
@@ -147,6 +175,7 @@ extern {
 pub const SYSCALL_DEBUG_PRINT_PROCESSES: u32 = 1;
 pub const SYSCALL_DEBUG_PRINT_ALLOCATOR_STATS: u32 = 2;
 pub const SYSCALL_DEBUG_PRINT_PHYSICAL_MEM_STATS: u32 = 3;
+pub const SYSCALL_DEBUG_WRITE_CORE_DUMP: u32 = 4;
 pub const SYSCALL_DEBUG_TEST_KERNEL_THREAD: u32 = 9001;
 
 /// Interface not stable.
@@ -155,13 +184,23 @@ pub const SYSCALL_DEBUG_TEST_KERNEL_THREAD: u32 = 9001;
 pub unsafe extern fn syscall_debug(operation: u32, argument: usize) -> i32 {
     match operation {
         SYSCALL_DEBUG_PRINT_PROCESSES => {
-            process::debug_print_processes();
+            process::debug_print_processes(console());
         },
         SYSCALL_DEBUG_PRINT_ALLOCATOR_STATS => {
-            memory::debug_print_allocator_stats();
+            memory::debug_print_allocator_stats(console());
         },
         SYSCALL_DEBUG_PRINT_PHYSICAL_MEM_STATS => {
-            memory::debug_print_physical_mem_stats();
+            memory::debug_print_physical_mem_stats(console());
+        },
+        SYSCALL_DEBUG_WRITE_CORE_DUMP => {
+            match coredump::write_last_to_serial() {
+                Ok(true) => (),
+                Ok(false) => return -1,
+                Err(e) => {
+                    warn!("Failed to write core dump: {}", e);
+                    return -1;
+                }
+            }
         },
         SYSCALL_DEBUG_TEST_KERNEL_THREAD => {
             let name = format!("TEST_KERNEL_THREAD-{}", argument);
@@ -178,3 +217,268 @@ pub unsafe extern fn syscall_debug(operation: u32, argument: usize) -> i32 {
 
     0
 }
+
+/// Look up a process that the calling process is allowed to control.
+///
+/// A `pid` of zero refers to the calling process. Otherwise, the target must be
+/// in the same process group as the caller, or have been created by it.
+fn controllable_process(pid: process::Id) -> Option<process::RcProcess> {
+    let current = process::current();
+
+    if pid == 0 {
+        return Some(current);
+    }
+
+    let target = process::by_id(pid)?;
+
+    let (current_id, current_pgid) = {
+        let current = current.lock();
+        (current.id(), current.pgid())
+    };
+
+    drop(current);
+
+    let allowed = {
+        let target = target.lock();
+        target.pgid() == current_pgid || target.parent() == current_id
+    };
+
+    if allowed { Some(target) } else { None }
+}
+
+/// Change the priority (nice value) of a process.
+///
+/// A `pid` of zero refers to the calling process. Only processes in the same
+/// process group as the caller, or created by it, may be changed.
+///
+/// Returns 0 on success, or -1 if the process could not be found, is not
+/// allowed to be changed, or the nice value is out of range.
+#[no_mangle]
+pub extern fn syscall_set_priority(pid: process::Id, nice: c_int) -> c_int {
+    let target = match controllable_process(pid) {
+        Some(target) => target,
+        None => return -1,
+    };
+
+    if nice < scheduler::NICE_MIN as c_int ||
+        nice > scheduler::NICE_MAX as c_int {
+        return -1;
+    }
+
+    scheduler::set_nice(&target, nice as scheduler::Nice)
+        .map(|_| 0)
+        .unwrap_or(-1)
+}
+
+/// Stop a process until it is continued with `syscall_continue_process`.
+///
+/// A `pid` of zero stops the calling process, in which case this returns once
+/// it has been continued. Processes waiting on the target are woken up.
+///
+/// Returns 0 on success, or -1 if the process could not be found, is not
+/// allowed to be stopped, or is not running or sleeping.
+#[no_mangle]
+pub extern fn syscall_stop_process(pid: process::Id) -> c_int {
+    let id = match controllable_process(pid) {
+        Some(target) => target.lock().id(),
+        None => return -1,
+    };
+
+    process::stop(id).map(|_| 0).unwrap_or(-1)
+}
+
+/// Continue a process that was stopped.
+///
+/// Returns 0 on success, or -1 if the process could not be found, is not
+/// allowed to be continued, or is not stopped.
+#[no_mangle]
+pub extern fn syscall_continue_process(pid: process::Id) -> c_int {
+    let id = match controllable_process(pid) {
+        Some(target) => target.lock().id(),
+        None => return -1,
+    };
+
+    process::r#continue(id).map(|_| 0).unwrap_or(-1)
+}
+
+/// Whether the calling process may control some process in `group`. See
+/// [controllable_process].
+fn controls_any(group: &[process::RcProcess]) -> bool {
+    group.iter().any(|process| {
+        let id = process.lock().id();
+        id != 0 && controllable_process(id).is_some()
+    })
+}
+
+/// Set the console's foreground process group, which receives keyboard job
+/// control (Ctrl-C, Ctrl-Z). A `pgid` of zero means no foreground group.
+///
+/// The caller must be allowed to control a process in the new group, and in the
+/// current foreground group if it still has any processes, under the same rules
+/// as [syscall_set_priority].
+///
+/// Returns the previous foreground process group (zero if none), or -1 if no
+/// process is in the given group or the caller isn't allowed to change it.
+#[no_mangle]
+pub extern fn syscall_set_foreground(pgid: process::Id) -> int64_t {
+    if pgid != 0 {
+        let group = process::by_pgid(pgid);
+
+        if group.is_empty() || !controls_any(&group) {
+            return -1;
+        }
+    }
+
+    if let Some(current_pgid) = terminal::foreground_pgid() {
+        let group = process::by_pgid(current_pgid);
+
+        if !group.is_empty() && !controls_any(&group) {
+            return -1;
+        }
+    }
+
+    let pgid = Some(pgid).filter(|&pgid| pgid != 0);
+
+    terminal::set_foreground_pgid(pgid).unwrap_or(0) as int64_t
+}
+
+pub const SYSCALL_TRACE_ATTACH: u32 = 1;
+pub const SYSCALL_TRACE_DETACH: u32 = 2;
+pub const SYSCALL_TRACE_STOP: u32 = 3;
+pub const SYSCALL_TRACE_CONTINUE: u32 = 4;
+pub const SYSCALL_TRACE_STEP: u32 = 5;
+pub const SYSCALL_TRACE_GET_EVENT: u32 = 6;
+pub const SYSCALL_TRACE_PEEK: u32 = 7;
+pub const SYSCALL_TRACE_POKE: u32 = 8;
+pub const SYSCALL_TRACE_GET_REGS: u32 = 9;
+pub const SYSCALL_TRACE_SET_REGS: u32 = 10;
+pub const SYSCALL_TRACE_SET_BREAKPOINT: u32 = 11;
+pub const SYSCALL_TRACE_CLEAR_BREAKPOINT: u32 = 12;
+
+/// Debug a process created by the calling process. See [debugger].
+///
+/// `addr` is an address in the target, and `data` depends on the request:
+///
+/// - `PEEK`: where to store the 8 bytes read from `addr`
+/// - `POKE`: the 8 bytes to write to `addr`
+/// - `GET_REGS`, `SET_REGS`: pointer to a `trace_registers_t`
+///
+/// Returns -1 on error. `GET_EVENT` returns why the target stopped (1 =
+/// stopped, 2 = breakpoint, 3 = step); everything else returns 0 on success.
+#[no_mangle]
+pub extern fn syscall_trace(
+    request: u32,
+    pid: process::Id,
+    addr: usize,
+    data: usize,
+) -> int64_t {
+    let host = process::current().lock().id();
+
+    let result = match request {
+        SYSCALL_TRACE_ATTACH => debugger::attach(host, pid).map(|_| 0),
+        SYSCALL_TRACE_DETACH => debugger::detach(host, pid).map(|_| 0),
+        SYSCALL_TRACE_STOP => debugger::stop(host, pid).map(|_| 0),
+        SYSCALL_TRACE_CONTINUE => debugger::resume(host, pid, false).map(|_| 0),
+        SYSCALL_TRACE_STEP => debugger::resume(host, pid, true).map(|_| 0),
+        SYSCALL_TRACE_GET_EVENT =>
+            debugger::event(host, pid).map(|event| event.code() as int64_t),
+        SYSCALL_TRACE_PEEK => {
+            let mut word = [0u8; 8];
+
+            debugger::read_memory(host, pid, addr, &mut word).map(|_| {
+                UserPtr::<u64>::from_addr(data)
+                    .write(u64::from_ne_bytes(word))
+                    .map(|_| 0)
+                    .unwrap_or(-1)
+            })
+        },
+        SYSCALL_TRACE_POKE => {
+            let word = (data as u64).to_ne_bytes();
+
+            debugger::write_memory(host, pid, addr, &word).map(|_| 0)
+        },
+        SYSCALL_TRACE_GET_REGS => {
+            debugger::registers(host, pid).map(|regs| {
+                UserPtr::<GeneralRegisters>::from_addr(data)
+                    .write(regs)
+                    .map(|_| 0)
+                    .unwrap_or(-1)
+            })
+        },
+        SYSCALL_TRACE_SET_REGS => {
+            match UserPtr::<GeneralRegisters>::from_addr(data).read() {
+                Ok(regs) => debugger::set_registers(host, pid, &regs)
+                    .map(|_| 0),
+                Err(_) => return -1,
+            }
+        },
+        SYSCALL_TRACE_SET_BREAKPOINT =>
+            debugger::set_breakpoint(host, pid, addr).map(|_| 0),
+        SYSCALL_TRACE_CLEAR_BREAKPOINT =>
+            debugger::clear_breakpoint(host, pid, addr).map(|_| 0),
+        _ => return -1,
+    };
+
+    result.unwrap_or_else(|err| {
+        debug!("syscall_trace({}, {}): {}", request, pid, err);
+        -1
+    })
+}
+
+/// Load a shared library from the system archive into the calling process, for
+/// the dynamic linker. Only its relative relocations are applied.
+///
+/// Returns the library's base (the offset from its link-time addresses), or -1
+/// on error.
+pub extern fn syscall_load_library(name: UserPtr<u8>) -> int64_t {
+    let mut name_buffer = [0u8; 256];
+
+    let name = match name.read_c_string(&mut name_buffer) {
+        Ok(name) => name,
+        Err(_) => return -1,
+    };
+
+    let system = archive::system();
+
+    let data = match system.get(name) {
+        Some(data) => data,
+        None => return -1,
+    };
+
+    let mem = match process::current().lock().mem() {
+        Some(mem) => mem,
+        None => return -1,
+    };
+
+    elf::load_library(&mem, data)
+        .map(|base| base as int64_t)
+        .unwrap_or_else(|err| {
+            debug!("syscall_load_library: {}", err);
+            -1
+        })
+}
+
+/// Create a copy of the calling process. The copy shares the caller's memory
+/// until either of them writes to it, and continues from the same place.
+///
+/// Returns the new process's ID to the caller and 0 to the copy, or -1 on error.
+#[no_mangle]
+pub extern fn syscall_fork() -> int64_t {
+    let child = match process::current().lock().fork() {
+        Ok(child) => child,
+        Err(err) => {
+            debug!("syscall_fork: {}", err);
+            return -1;
+        }
+    };
+
+    let id = {
+        let mut child = child.lock();
+        child.run();
+        child.id()
+    };
+
+    scheduler::push(child);
+
+    id as int64_t
+}
@@ -14,11 +14,14 @@
 
 use core::fmt;
 use core::mem;
+use core::sync::atomic::AtomicU32;
+use core::sync::atomic::Ordering::*;
 
 use alloc::boxed::Box;
 
 use crate::multiboot;
 use crate::constants::translate_low_addr;
+use crate::process::Id as ProcessId;
 
 pub mod vga;
 pub use vga::{VgaConfig, Vga};
@@ -582,6 +585,23 @@ pub fn console() -> &'static mut dyn Terminal {
     }
 }
 
+/// The process group receiving keyboard job control for the console, or zero if
+/// there is none. The kernel's own group (zero) can never be the target.
+static FOREGROUND_PGID: AtomicU32 = AtomicU32::new(0);
+
+/// Get the console's foreground process group, if any.
+///
+/// Keyboard job control (Ctrl-C, Ctrl-Z) applies to this process group.
+pub fn foreground_pgid() -> Option<ProcessId> {
+    Some(FOREGROUND_PGID.load(Relaxed)).filter(|&pgid| pgid != 0)
+}
+
+/// Set the console's foreground process group, returning the previous one.
+pub fn set_foreground_pgid(pgid: Option<ProcessId>) -> Option<ProcessId> {
+    Some(FOREGROUND_PGID.swap(pgid.unwrap_or(0), Relaxed))
+        .filter(|&pgid| pgid != 0)
+}
+
 /// C (legacy) interface. See `kit/kernel/include/terminal.h`.
 pub mod ffi {
     use super::*;
@@ -10,12 +10,18 @@ files      = ARGV[1..-1]
 
 Entry = Struct.new(:name, :offset, :checksum, :size)
 
+# Files start on page boundaries, so the kernel can map their pages straight
+# into programs
+PAGE_SIZE = 4096
+
+def page_align(offset)
+  (offset + PAGE_SIZE - 1) / PAGE_SIZE * PAGE_SIZE
+end
+
 entries = []
 
 warn "\e[1;34m# \e[0;1mBuilding entry list\e[0m"
 
-offset = 0
-
 files.each do |file|
   path = File.join(system_dir, file)
 
@@ -31,16 +37,20 @@ files.each do |file|
     size = f.size
   end
 
-  entries << Entry.new(file, offset, checksum, size)
-
-  offset += size
+  entries << Entry.new(file, nil, checksum, size)
 end
 
-# Calculate the size of the header and offset the entries
+# Calculate the size of the header and place the entries after it
 
 header_size = 16 + entries.map { |e| 32 + e.name.bytesize }.inject(:+)
 
-entries.each { |entry| entry.offset += header_size }
+offset = page_align(header_size)
+
+entries.each do |entry|
+  entry.offset = offset
+
+  offset = page_align(offset + entry.size)
+end
 
 # Write the header
 
@@ -96,5 +106,10 @@ entries.each do |entry|
   offset += written
 end
 
+# Pad out the last page
+print "\0" * (page_align(offset) - offset)
+
+offset = page_align(offset)
+
 warn "\e[1;34m# \e[0;1mEnd of archive\e[0m"
 warn "  #{offset} bytes written."
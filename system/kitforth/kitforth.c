@@ -17,6 +17,9 @@
 #include <stdint.h>
 #include <string.h>
 
+#include <kit/syscall.h>
+#include <kit/auxv.h>
+
 #include "engine.h"
 #include "boot.h"
 
@@ -95,11 +98,102 @@ bool readline() {
 
 bool ok;
 
-int main(UNUSED int argc, UNUSED char **argv) {
+void evaluate(char *addr, uint64_t len);
+
+/**
+ * Finds `name` in the system archive. Returns NULL if it isn't there.
+ */
+char *archive_file(const char *name, uint64_t *length) {
+  const kit_sysinfo_t *info = kit_sysinfo();
+
+  // Without the archive's length, nothing in it can be checked.
+  if (info == NULL || info->size < sizeof(kit_sysinfo_t)) {
+    return NULL;
+  }
+
+  char *archive = syscall_mmap_archive();
+  uint64_t total = info->archive_length;
+
+  if (total < 16 || memcmp(archive, "kit AR01", 8) != 0) {
+    return NULL;
+  }
+
+  uint64_t count;
+  memcpy(&count, archive + 8, 8);
+
+  if (count > (total - 16) / 32) {
+    return NULL;
+  }
+
+  // Each entry: offset, length, checksum, name length, then the name.
+  uint64_t position = 16;
+  size_t name_length = strlen(name);
+
+  for (uint64_t i = 0; i < count; i++) {
+    uint64_t fields[4];
+
+    if (total - position < sizeof(fields)) {
+      return NULL;
+    }
+
+    memcpy(fields, archive + position, sizeof(fields));
+    position += sizeof(fields);
+
+    if (fields[3] > total - position) {
+      return NULL;
+    }
+
+    if (fields[3] == name_length &&
+        memcmp(archive + position, name, name_length) == 0) {
+      if (fields[0] > total || fields[1] > total - fields[0]) {
+        return NULL;
+      }
+
+      *length = fields[1];
+      return archive + fields[0];
+    }
+
+    position += fields[3];
+  }
+
+  return NULL;
+}
+
+/**
+ * Runs a source file from the system archive, skipping its `#!` line if it's a
+ * script. Returns false if it failed.
+ */
+bool run_file(const char *name) {
+  uint64_t length;
+  char *source = archive_file(name, &length);
+
+  if (source == NULL) {
+    printf("\x1b[1;31mkitforth: %s not found\x1b[0m\n", name);
+    return false;
+  }
+
+  if (length >= 2 && source[0] == '#' && source[1] == '!') {
+    while (length > 0 && *source != '\n') {
+      source++;
+      length--;
+    }
+  }
+
+  ok = true;
+  evaluate(source, length);
+  return ok;
+}
+
+int main(int argc, char **argv) {
   here = calloc(1, DATA_SPACE_SIZE);
   there = here + DATA_SPACE_SIZE;
   init_dict();
 
+  // kitforth FILE: run it instead of reading from stdin.
+  if (argc > 1) {
+    return run_file(argv[1]) ? 0 : 1;
+  }
+
   while (!feof(stdin)) {
     putchar('\n');
     printdata();
@@ -29,10 +29,10 @@ build/system/kitforth/.dir: build/system/.dir
 	mkdir -p build/system/kitforth
 	touch build/system/kitforth/.dir
 
-build/system/bin/kitforth: ${KFORTH_OBJECTS} ${LIBC} \
+build/system/bin/kitforth: ${KFORTH_OBJECTS} ${LIBC_CRT0} ${LIBC} \
 		build/system/bin/.dir
 	@${ECHO_LD} $@
-	@${LD} ${LDFLAGS} ${SYSTEM_LDFLAGS} ${KFORTH_OBJECTS} ${LIBC} \
+	@${LD} ${LDFLAGS} ${SYSTEM_LDFLAGS} ${LIBC_CRT0} ${KFORTH_OBJECTS} ${LIBC} \
 		-o $@
 
 build/system/kitforth/%.o: system/kitforth/%.c build/system/kitforth/.dir
@@ -0,0 +1,474 @@
+/*******************************************************************************
+ *
+ * kit/system/ld/ld.c
+ * - dynamic linker (lib/ld-kit.so)
+ *
+ * vim:ts=2:sw=2:et:tw=80:ft=c
+ *
+ * Copyright (C) 2015-2021, Devyn Cairns
+ * Redistribution of this file is permitted under the terms of the simplified
+ * BSD license. See LICENSE for more information.
+ *
+ ******************************************************************************/
+
+/**
+ * The kernel loads this as the interpreter of any program with a PT_INTERP
+ * header, and starts it instead of the program. By then the kernel has loaded
+ * the program and applied its relative relocations, and we've been fully
+ * relocated already, so nothing here may need a symbol relocation of its own.
+ *
+ * We load the program's DT_NEEDED libraries with syscall_load_library(), bind
+ * the symbol relocations of the libraries and then the program, and jump to
+ * the program's entry point (see start.S).
+ *
+ * Symbols are looked up through DT_HASH, so everything must be linked with
+ * --hash-style=sysv. Libraries must be linked at address zero with the ELF
+ * header in their first segment.
+ */
+
+#include <stddef.h>
+#include <stdint.h>
+#include <stdbool.h>
+
+#include <kit/syscall.h>
+#include <kit/auxv.h>
+
+#define MAX_OBJECTS 16
+#define MAX_PATH    256
+
+#define PT_DYNAMIC 2
+
+#define DT_NULL     0
+#define DT_NEEDED   1
+#define DT_PLTRELSZ 2
+#define DT_HASH     4
+#define DT_STRTAB   5
+#define DT_SYMTAB   6
+#define DT_RELA     7
+#define DT_RELASZ   8
+#define DT_JMPREL   23
+
+#define R_X86_64_NONE      0
+#define R_X86_64_64        1
+#define R_X86_64_COPY      5
+#define R_X86_64_GLOB_DAT  6
+#define R_X86_64_JUMP_SLOT 7
+#define R_X86_64_RELATIVE  8
+
+#define STB_GLOBAL 1
+#define STB_WEAK   2
+
+#define SHN_UNDEF 0
+
+typedef struct elf_header
+{
+  uint8_t  ident[16];
+  uint16_t type;
+  uint16_t machine;
+  uint32_t version;
+  uint64_t entry;
+  uint64_t phoff;
+  uint64_t shoff;
+  uint32_t flags;
+  uint16_t ehsize;
+  uint16_t phentsize;
+  uint16_t phnum;
+  uint16_t shentsize;
+  uint16_t shnum;
+  uint16_t shstrndx;
+} elf_header_t;
+
+typedef struct elf_phdr
+{
+  uint32_t type;
+  uint32_t flags;
+  uint64_t offset;
+  uint64_t vaddr;
+  uint64_t paddr;
+  uint64_t filesz;
+  uint64_t memsz;
+  uint64_t align;
+} elf_phdr_t;
+
+typedef struct elf_dyn
+{
+  int64_t  tag;
+  uint64_t value;
+} elf_dyn_t;
+
+typedef struct elf_sym
+{
+  uint32_t name;
+  uint8_t  info;
+  uint8_t  other;
+  uint16_t shndx;
+  uint64_t value;
+  uint64_t size;
+} elf_sym_t;
+
+typedef struct elf_rela
+{
+  uint64_t offset;
+  uint64_t info;
+  int64_t  addend;
+} elf_rela_t;
+
+/**
+ * A loaded program or library, with the parts of its dynamic section we need.
+ */
+typedef struct object
+{
+  const char       *name;      // as given by DT_NEEDED; NULL for the program
+  uint64_t          bias;
+  const elf_dyn_t  *dynamic;
+  const uint32_t   *hash;
+  const elf_sym_t  *symtab;
+  const char       *strtab;
+} object_t;
+
+static object_t objects[MAX_OBJECTS];
+static size_t   objects_count;
+
+void *memcpy(void *restrict dest, const void *restrict src, size_t n)
+{
+  char *restrict dest_c = (char *restrict) dest;
+
+  const char *restrict src_c = (const char *restrict) src;
+
+  for (size_t i = 0; i < n; i++)
+  {
+    dest_c[i] = src_c[i];
+  }
+
+  return dest;
+}
+
+void *memset(void *s, int c, size_t n)
+{
+  unsigned char *ptr = (unsigned char *) s;
+  unsigned char byte = c;
+
+  for (size_t i = 0; i < n; i++)
+  {
+    ptr[i] = byte;
+  }
+
+  return s;
+}
+
+static size_t string_length(const char *s)
+{
+  size_t length = 0;
+
+  while (s[length] != '\0') length++;
+
+  return length;
+}
+
+static bool string_equal(const char *s1, const char *s2)
+{
+  while (*s1 != '\0' && *s1 == *s2)
+  {
+    s1++;
+    s2++;
+  }
+
+  return *s1 == *s2;
+}
+
+static void print(const char *s)
+{
+  syscall_twrite(string_length(s), s);
+}
+
+static void fail(const char *message, const char *name)
+{
+  print("ld-kit.so: ");
+  print(message);
+  print(name);
+  print("\n");
+
+  syscall_exit(127);
+  __builtin_trap();
+}
+
+static uint64_t dynamic_value(const object_t *object, int64_t tag)
+{
+  for (const elf_dyn_t *dyn = object->dynamic; dyn->tag != DT_NULL; dyn++)
+  {
+    if (dyn->tag == tag) return dyn->value;
+  }
+
+  return 0;
+}
+
+/**
+ * Fills in an object from its dynamic section. Addresses in the dynamic
+ * section are link-time addresses, so they need the bias added.
+ */
+static void object_init(object_t *object, const char *name, uint64_t bias,
+                        const elf_dyn_t *dynamic)
+{
+  object->name    = name;
+  object->bias    = bias;
+  object->dynamic = dynamic;
+  object->symtab  = (const elf_sym_t *)
+                    (bias + dynamic_value(object, DT_SYMTAB));
+  object->strtab  = (const char *) (bias + dynamic_value(object, DT_STRTAB));
+
+  uint64_t hash = dynamic_value(object, DT_HASH);
+
+  object->hash = hash != 0 ? (const uint32_t *) (bias + hash) : NULL;
+}
+
+/**
+ * Finds PT_DYNAMIC among the program headers at `phdrs`, and adds the object
+ * to the list. Objects without one have nothing to link, and are skipped.
+ */
+static void object_add(const char *name, uint64_t bias,
+                       const elf_phdr_t *phdrs, size_t phnum)
+{
+  if (objects_count >= MAX_OBJECTS)
+  {
+    fail("too many libraries", "");
+  }
+
+  for (size_t i = 0; i < phnum; i++)
+  {
+    if (phdrs[i].type == PT_DYNAMIC)
+    {
+      object_init(&objects[objects_count++], name, bias,
+                  (const elf_dyn_t *) (bias + phdrs[i].vaddr));
+      return;
+    }
+  }
+}
+
+static uint32_t elf_hash(const char *name)
+{
+  uint32_t h = 0;
+
+  for (const unsigned char *c = (const unsigned char *) name; *c; c++)
+  {
+    h = (h << 4) + *c;
+
+    uint32_t g = h & 0xf0000000;
+
+    if (g) h ^= g >> 24;
+
+    h &= ~g;
+  }
+
+  return h;
+}
+
+/**
+ * Looks up a defined global or weak symbol in one object.
+ */
+static const elf_sym_t *object_lookup(const object_t *object, const char *name,
+                                      uint32_t hash)
+{
+  if (object->hash == NULL) return NULL;
+
+  uint32_t nbucket = object->hash[0];
+
+  const uint32_t *bucket = &object->hash[2];
+  const uint32_t *chain  = &bucket[nbucket];
+
+  for (uint32_t i = bucket[hash % nbucket]; i != 0; i = chain[i])
+  {
+    const elf_sym_t *sym = &object->symtab[i];
+
+    uint8_t bind = sym->info >> 4;
+
+    if (sym->shndx != SHN_UNDEF &&
+        (bind == STB_GLOBAL || bind == STB_WEAK) &&
+        string_equal(object->strtab + sym->name, name))
+    {
+      return sym;
+    }
+  }
+
+  return NULL;
+}
+
+/**
+ * Finds the address of a symbol, searching the program first and then the
+ * libraries in load order. Copy relocations skip the program, since it's the
+ * program's own copy that's being initialized.
+ *
+ * Returns false if it's not defined anywhere.
+ */
+static bool resolve(const char *name, bool skip_program, uint64_t *address,
+                    uint64_t *size)
+{
+  uint32_t hash = elf_hash(name);
+
+  for (size_t i = skip_program ? 1 : 0; i < objects_count; i++)
+  {
+    const elf_sym_t *sym = object_lookup(&objects[i], name, hash);
+
+    if (sym != NULL)
+    {
+      *address = objects[i].bias + sym->value;
+      *size    = sym->size;
+      return true;
+    }
+  }
+
+  return false;
+}
+
+static void relocate_table(const object_t *object, const elf_rela_t *table,
+                           uint64_t table_size)
+{
+  for (size_t i = 0; i < table_size / sizeof(elf_rela_t); i++)
+  {
+    const elf_rela_t *rela = &table[i];
+
+    uint32_t type  = (uint32_t) rela->info;
+    uint32_t index = (uint32_t) (rela->info >> 32);
+
+    if (type == R_X86_64_NONE || type == R_X86_64_RELATIVE)
+    {
+      // Already applied by the kernel.
+      continue;
+    }
+
+    const elf_sym_t *sym  = &object->symtab[index];
+    const char      *name = object->strtab + sym->name;
+
+    uint64_t *where = (uint64_t *) (object->bias + rela->offset);
+
+    uint64_t address = 0, size = 0;
+
+    if (!resolve(name, type == R_X86_64_COPY, &address, &size) &&
+        (sym->info >> 4) != STB_WEAK)
+    {
+      fail("undefined symbol: ", name);
+    }
+
+    switch (type)
+    {
+      case R_X86_64_64:
+      case R_X86_64_GLOB_DAT:
+      case R_X86_64_JUMP_SLOT:
+        *where = address + rela->addend;
+        break;
+
+      case R_X86_64_COPY:
+        memcpy(where, (const void *) address, size);
+        break;
+
+      default:
+        fail("unsupported relocation for ", name);
+    }
+  }
+}
+
+static void relocate(const object_t *object)
+{
+  relocate_table(object,
+    (const elf_rela_t *) (object->bias + dynamic_value(object, DT_RELA)),
+    dynamic_value(object, DT_RELASZ));
+
+  relocate_table(object,
+    (const elf_rela_t *) (object->bias + dynamic_value(object, DT_JMPREL)),
+    dynamic_value(object, DT_PLTRELSZ));
+}
+
+static bool is_loaded(const char *name)
+{
+  for (size_t i = 0; i < objects_count; i++)
+  {
+    if (objects[i].name != NULL && string_equal(objects[i].name, name))
+    {
+      return true;
+    }
+  }
+
+  return false;
+}
+
+/**
+ * Loads the libraries that `object` needs, unless they're loaded already.
+ * Names are looked up under "lib/" in the system archive.
+ */
+static void load_needed(const object_t *object)
+{
+  for (const elf_dyn_t *dyn = object->dynamic; dyn->tag != DT_NULL; dyn++)
+  {
+    if (dyn->tag != DT_NEEDED) continue;
+
+    const char *name = object->strtab + dyn->value;
+
+    if (is_loaded(name)) continue;
+
+    char path[MAX_PATH] = "lib/";
+
+    size_t length = string_length(name);
+
+    if (length + 5 > MAX_PATH) fail("library name too long: ", name);
+
+    memcpy(path + 4, name, length + 1);
+
+    int64_t bias = syscall_load_library(path);
+
+    if (bias == -1) fail("can't load library: ", path);
+
+    const elf_header_t *header = (const elf_header_t *) bias;
+
+    object_add(name, bias, (const elf_phdr_t *) (bias + header->phoff),
+               header->phnum);
+  }
+}
+
+/**
+ * Links the program described by `auxv`, and returns its entry point.
+ * Called from _ld_start.
+ */
+uint64_t _ld_link(const uint64_t *auxv)
+{
+  const elf_phdr_t    *phdrs   = NULL;
+  size_t               phnum   = 0;
+  uint64_t             entry   = 0;
+  const kit_sysinfo_t *sysinfo = NULL;
+
+  for (const uint64_t *aux = auxv; aux[0] != AT_NULL; aux += 2)
+  {
+    switch (aux[0])
+    {
+      case AT_PHDR:  phdrs = (const elf_phdr_t *) aux[1]; break;
+      case AT_PHNUM: phnum = aux[1]; break;
+      case AT_ENTRY: entry = aux[1]; break;
+      case AT_KIT_SYSINFO: sysinfo = (const kit_sysinfo_t *) aux[1]; break;
+    }
+  }
+
+  if (phdrs == NULL || entry == 0 || sysinfo == NULL)
+  {
+    fail("bad auxiliary vector", "");
+  }
+
+  // The kernel says where it put the program. Its headers can't tell us, since
+  // a position-independent program doesn't need a PT_PHDR.
+  object_add(NULL, sysinfo->image_base, phdrs, phnum);
+
+  if (objects_count == 0) return entry;
+
+  // Libraries are added to the list as they're loaded, so this also picks up
+  // what they need in turn.
+  for (size_t i = 0; i < objects_count; i++)
+  {
+    load_needed(&objects[i]);
+  }
+
+  // Libraries first, so that the program's copy relocations see their data
+  // fully relocated.
+  for (size_t i = objects_count; i > 0; i--)
+  {
+    relocate(&objects[i - 1]);
+  }
+
+  return entry;
+}
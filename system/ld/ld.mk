@@ -0,0 +1,47 @@
+################################################################################
+#
+# kit/system/ld/ld.mk
+# - dynamic linker build rules
+#
+# vim:ts=2:sw=2:et:tw=80:ft=make
+#
+# Copyright (C) 2015-2021, Devyn Cairns
+# Redistribution of this file is permitted under the terms of the simplified BSD
+# license. See LICENSE for more information.
+#
+################################################################################
+
+LD_SO=build/system/lib/ld-kit.so
+
+# The dynamic linker can't have any symbol relocations of its own, since
+# nothing links it. The kernel only applies relative ones.
+LD_SO_CFLAGS=-fPIC -fvisibility=hidden -ffreestanding
+LD_SO_LDFLAGS=-O1 -nostdlib -shared -Bsymbolic -z norelro --hash-style=sysv \
+              -e _ld_start
+
+LD_SO_OBJECTS:=$(patsubst %.c,build/%.o,$(wildcard system/ld/*.c))
+LD_SO_OBJECTS+=$(patsubst %.S,build/%.o,$(wildcard system/ld/*.S))
+
+all-ld: ${LD_SO}
+
+clean-ld:
+	rm -rf build/system/ld
+	rm -f ${LD_SO}
+
+.PHONY: all-ld clean-ld
+
+build/system/ld/.dir: build/system/.dir
+	mkdir -p build/system/ld
+	touch build/system/ld/.dir
+
+build/system/ld/%.o: system/ld/%.c build/system/ld/.dir
+	@${ECHO_CC} $@
+	@${CC} ${CFLAGS} ${SYSTEM_CFLAGS} ${LD_SO_CFLAGS} -c $< -o $@
+
+build/system/ld/%.o: system/ld/%.S build/system/ld/.dir
+	@${ECHO_AS} $@
+	@${AS} ${ASFLAGS} ${SYSTEM_ASFLAGS} -c $< -o $@
+
+${LD_SO}: ${LD_SO_OBJECTS} build/system/lib/.dir
+	@${ECHO_LD} $@
+	@${LD} ${LDFLAGS} ${LD_SO_LDFLAGS} ${LD_SO_OBJECTS} -o $@
@@ -0,0 +1,60 @@
+/*******************************************************************************
+ *
+ * kit/system/libc/env.c
+ * - environment and auxiliary vector
+ *
+ * vim:ts=2:sw=2:et:tw=80:ft=c
+ *
+ * Copyright (C) 2015-2021, Devyn Cairns
+ * Redistribution of this file is permitted under the terms of the simplified
+ * BSD license. See LICENSE for more information.
+ *
+ ******************************************************************************/
+
+#include <stdlib.h>
+#include <kit/auxv.h>
+
+char **environ;
+
+const uint64_t *_libc_auxv;
+
+char *getenv(const char *name)
+{
+  if (environ == NULL) return NULL;
+
+  for (char **entry = environ; *entry != NULL; entry++)
+  {
+    const char *c = *entry;
+    const char *n = name;
+
+    while (*n != '\0' && *c == *n)
+    {
+      c++;
+      n++;
+    }
+
+    if (*n == '\0' && *c == '=')
+    {
+      return (char *) c + 1;
+    }
+  }
+
+  return NULL;
+}
+
+uint64_t getauxval(uint64_t type)
+{
+  if (_libc_auxv == NULL) return 0;
+
+  for (const uint64_t *aux = _libc_auxv; aux[0] != AT_NULL; aux += 2)
+  {
+    if (aux[0] == type) return aux[1];
+  }
+
+  return 0;
+}
+
+const kit_sysinfo_t *kit_sysinfo()
+{
+  return (const kit_sysinfo_t *) getauxval(AT_KIT_SYSINFO);
+}
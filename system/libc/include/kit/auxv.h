@@ -0,0 +1,59 @@
+/*******************************************************************************
+ *
+ * kit/system/libc/include/kit/auxv.h
+ * - auxiliary vector passed to the program by the kernel
+ *
+ * vim:ts=2:sw=2:et:tw=80:ft=c
+ *
+ * Copyright (C) 2015-2021, Devyn Cairns
+ * Redistribution of this file is permitted under the terms of the simplified
+ * BSD license. See LICENSE for more information.
+ *
+ ******************************************************************************/
+
+#ifndef _KIT_AUXV_H
+#define _KIT_AUXV_H
+
+#include <stdint.h>
+
+#define AT_NULL        0
+#define AT_PHDR        3  // program headers of the program
+#define AT_PHENT       4  // size of a program header
+#define AT_PHNUM       5  // number of program headers
+#define AT_PAGESZ      6
+#define AT_BASE        7  // base of the dynamic linker, if any
+#define AT_ENTRY       9  // entry point of the program
+#define AT_RANDOM      25 // 16 random bytes
+#define AT_KIT_SYSINFO 0x4b1700 // kit_sysinfo_t *
+
+/**
+ * Information about the system and the process, from the kernel.
+ */
+typedef struct kit_sysinfo
+{
+  uint64_t size; // of this structure; fields may be added at the end
+  uint32_t pid;
+  uint32_t parent;
+  uint32_t pgid;
+  uint32_t cpu_count;
+  uint64_t page_size;
+  uint64_t image_base; // offset of the program from its link-time addresses
+  uint64_t heap_base;
+  uint64_t stack_top;
+  uint64_t stack_size; // how far the stack can grow down from stack_top
+  uint64_t archive_addr; // where syscall_mmap_archive() maps the archive
+  char     kernel_name[16];
+  uint64_t archive_length; // in bytes
+} kit_sysinfo_t;
+
+/**
+ * Get the value of an auxiliary vector entry, or zero if there isn't one.
+ */
+uint64_t getauxval(uint64_t type);
+
+/**
+ * Get the system information from AT_KIT_SYSINFO.
+ */
+const kit_sysinfo_t *kit_sysinfo();
+
+#endif
@@ -45,6 +45,16 @@
       : "a" (number), "D" (arg1), "S" (arg2), "d" (arg3) \
       : "%rcx", "%r11")
 
+# define SYSCALL4(number, ret, arg1, arg2, arg3, arg4) \
+  do { \
+    register uint64_t __arg4 __asm__("r10") = (uint64_t) (arg4); \
+    __asm__ volatile( \
+        "syscall" \
+        : "=a" (ret) \
+        : "a" (number), "D" (arg1), "S" (arg2), "d" (arg3), "r" (__arg4) \
+        : "%rcx", "%r11"); \
+  } while (0)
+
 static inline int syscall_exit(int status)
 {
 # define SYSCALL_EXIT 0x0
@@ -112,13 +122,13 @@ static inline int syscall_sleep()
 }
 
 static inline int64_t syscall_spawn(const char *file, int argc,
-    const char *const *argv)
+    const char *const *argv, const char *const *envp)
 {
 # define SYSCALL_SPAWN 0x5
 
   int64_t ret; // PID or error
 
-  SYSCALL3(SYSCALL_SPAWN, ret, file, argc, argv);
+  SYSCALL4(SYSCALL_SPAWN, ret, file, argc, argv, envp);
 
   return ret;
 }
@@ -127,7 +137,7 @@ static inline int syscall_wait_process(uint16_t id, int *exit_status)
 {
 # define SYSCALL_WAIT_PROCESS 0x6
 
-  int ret;
+  int ret; // 0 if exited, 1 if stopped, or error
 
   SYSCALL2(SYSCALL_WAIT_PROCESS, ret, id, exit_status);
 
@@ -156,4 +166,119 @@ static inline void *syscall_mmap_archive()
   return ret;
 }
 
+static inline int syscall_set_priority(uint32_t pid, int nice)
+{
+# define SYSCALL_SET_PRIORITY 0xa
+
+  int ret;
+
+  SYSCALL2(SYSCALL_SET_PRIORITY, ret, pid, nice);
+
+  return ret;
+}
+
+static inline int64_t syscall_set_foreground(uint32_t pgid)
+{
+# define SYSCALL_SET_FOREGROUND 0xb
+
+  int64_t ret; // previous PGID or error
+
+  SYSCALL1(SYSCALL_SET_FOREGROUND, ret, pgid);
+
+  return ret;
+}
+
+static inline int syscall_stop_process(uint32_t pid)
+{
+# define SYSCALL_STOP_PROCESS 0xc
+
+  int ret;
+
+  SYSCALL1(SYSCALL_STOP_PROCESS, ret, pid);
+
+  return ret;
+}
+
+static inline int syscall_continue_process(uint32_t pid)
+{
+# define SYSCALL_CONTINUE_PROCESS 0xd
+
+  int ret;
+
+  SYSCALL1(SYSCALL_CONTINUE_PROCESS, ret, pid);
+
+  return ret;
+}
+
+// Requests for syscall_trace()
+#define TRACE_ATTACH           1
+#define TRACE_DETACH           2
+#define TRACE_STOP             3
+#define TRACE_CONTINUE         4
+#define TRACE_STEP             5
+#define TRACE_GET_EVENT        6  // returns one of TRACE_EVENT_*
+#define TRACE_PEEK             7  // data: uint64_t *
+#define TRACE_POKE             8  // data: the value to write
+#define TRACE_GET_REGS         9  // data: trace_registers_t *
+#define TRACE_SET_REGS         10 // data: const trace_registers_t *
+#define TRACE_SET_BREAKPOINT   11
+#define TRACE_CLEAR_BREAKPOINT 12
+
+#define TRACE_EVENT_STOPPED    1
+#define TRACE_EVENT_BREAKPOINT 2
+#define TRACE_EVENT_STEP       3
+
+typedef struct trace_registers
+{
+  uint64_t rax, rcx, rdx, rbx, rsp, rbp, rsi, rdi;
+  uint64_t r8, r9, r10, r11, r12, r13, r14, r15;
+  uint64_t rip, rflags;
+} trace_registers_t;
+
+static inline int64_t syscall_trace(uint32_t request, uint32_t pid,
+                                    uint64_t addr, uint64_t data)
+{
+# define SYSCALL_TRACE 0xe
+
+  int64_t ret;
+
+  SYSCALL4(SYSCALL_TRACE, ret, request, pid, addr, data);
+
+  return ret;
+}
+
+/**
+ * Loads a shared library from the system archive (e.g. "lib/libc.so") into
+ * the address space. Only its relative relocations are applied; the rest are
+ * up to the caller.
+ *
+ * Returns the library's load bias, or -1 on error.
+ */
+static inline int64_t syscall_load_library(const char *name)
+{
+# define SYSCALL_LOAD_LIBRARY 0xf
+
+  int64_t ret;
+
+  SYSCALL1(SYSCALL_LOAD_LIBRARY, ret, name);
+
+  return ret;
+}
+
+/**
+ * Creates a copy of the calling process, sharing its memory copy-on-write.
+ *
+ * Returns the new process's ID in the caller and 0 in the copy, or -1 on error.
+ */
+static inline int64_t syscall_fork()
+{
+# define SYSCALL_FORK 0x10
+
+  int64_t ret;
+
+  SYSCALL0(SYSCALL_FORK, ret);
+
+  return ret;
+}
+
 #endif
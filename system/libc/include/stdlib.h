@@ -79,4 +79,16 @@ void free(void *ptr);
  */
 long int strtol(const char *nptr, char **endptr, int base);
 
+/**
+ * Get the value of the environment variable 'name', or NULL if it isn't set.
+ */
+char *getenv(const char *name);
+
+/**
+ * The environment, as a NULL-terminated array of "NAME=value" strings.
+ *
+ * (POSIX declares this in <unistd.h>, which we don't have yet.)
+ */
+extern char **environ;
+
 #endif
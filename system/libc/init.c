@@ -17,8 +17,14 @@ extern uint64_t  _libc_heap_length;
 extern void     *_libc_heap_start;
 extern void     *_libc_heap_end;
 
-void _libc_init()
+extern char           **environ;
+extern const uint64_t  *_libc_auxv;
+
+void _libc_init(char **envp, const uint64_t *auxv)
 {
+  environ    = envp;
+  _libc_auxv = auxv;
+
   _libc_heap_length = 0;
 
   _libc_heap_start = syscall_adjust_heap(0);
@@ -11,18 +11,21 @@
 #
 ################################################################################
 
-LIBC=build/system/libc.o
+LIBC=build/system/lib/libc.so
+LIBC_CRT0=build/system/libc/crt0.o
 
-LIBC_LDFLAGS=
+LIBC_CFLAGS=-fPIC
+LIBC_LDFLAGS=-O1 -nostdlib -shared -soname libc.so --hash-style=sysv
 
 LIBC_OBJECTS:=$(patsubst %.c,build/%.o,$(wildcard system/libc/*.c))
-LIBC_OBJECTS+=$(patsubst %.S,build/%.o,$(wildcard system/libc/*.S))
+LIBC_OBJECTS+=$(patsubst %.S,build/%.o,\
+                $(filter-out system/libc/crt0.S,$(wildcard system/libc/*.S)))
 
-all-libc: build/system/libc.o
+all-libc: ${LIBC} ${LIBC_CRT0}
 
 clean-libc:
 	rm -rf build/system/libc
-	rm build/system/libc.o
+	rm -f ${LIBC}
 
 .PHONY: all-libc clean-libc
 
@@ -32,12 +35,12 @@ build/system/libc/.dir:
 
 build/system/libc/%.o: system/libc/%.c build/system/libc/.dir
 	@${ECHO_CC} $@
-	@${CC} ${CFLAGS} ${SYSTEM_CFLAGS} -c $< -o $@
+	@${CC} ${CFLAGS} ${SYSTEM_CFLAGS} ${LIBC_CFLAGS} -c $< -o $@
 
 build/system/libc/%.o: system/libc/%.S build/system/libc/.dir
 	@${ECHO_AS} $@
 	@${AS} ${ASFLAGS} ${SYSTEM_ASFLAGS} -c $< -o $@
 
-${LIBC}: ${LIBC_OBJECTS}
+${LIBC}: ${LIBC_OBJECTS} build/system/lib/.dir
 	@${ECHO_LD} $@
-	@${LD} ${LDFLAGS} ${LIBC_LDFLAGS} -r $^ -o $@
+	@${LD} ${LDFLAGS} ${LIBC_LDFLAGS} ${LIBC_OBJECTS} -o $@
@@ -15,6 +15,7 @@
 #include <stdint.h>
 #include <stddef.h>
 #include <stdio.h>
+#include <stdlib.h>
 #include <string.h>
 #include <kit/syscall.h>
 
@@ -22,6 +23,8 @@
 
 static int last_exit_code = 0;
 
+static int last_stopped_pid = 0;
+
 static void display_prompt(uint64_t lineno)
 {
   putchar('\n');
@@ -38,6 +41,54 @@ static void display_prompt(uint64_t lineno)
   printf("user %lu>>\033[0;1m ", lineno);
 }
 
+static void wait_foreground(int pid, uint64_t lineno)
+{
+  // Give the job the console so Ctrl-C / Ctrl-Z apply to it.
+  int64_t previous_foreground = syscall_set_foreground(pid);
+
+  int result = syscall_wait_process(pid, &last_exit_code);
+
+  if (result < 0)
+  {
+    last_exit_code = -99;
+    puts("\033[31m E: wait_process() failed\033[0m");
+  }
+  else if (result == 1)
+  {
+    last_stopped_pid = pid;
+    printf("\n[%lu] %d Stopped\n", lineno, pid);
+  }
+
+  if (previous_foreground >= 0)
+  {
+    syscall_set_foreground(previous_foreground);
+  }
+}
+
+static void builtin_fg(command_t *command, uint64_t lineno)
+{
+  int pid = last_stopped_pid;
+
+  if (command->args.len > 1)
+  {
+    pid = (int) strtol((char *) command->args.ptr[1], NULL, 10);
+  }
+
+  if (pid <= 0 || syscall_continue_process(pid) < 0)
+  {
+    last_exit_code = -98;
+    puts("\033[31m E: fg: no such stopped job\033[0m");
+    return;
+  }
+
+  if (pid == last_stopped_pid)
+  {
+    last_stopped_pid = 0;
+  }
+
+  wait_foreground(pid, lineno);
+}
+
 static void execute(char *line, uint64_t lineno)
 {
   char *current_line = line;
@@ -47,11 +98,17 @@ static void execute(char *line, uint64_t lineno)
   do {
     current_line = parse_command(current_line, &command);
 
-    if (command.filename != NULL)
+    if (command.filename != NULL &&
+        strcmp((char *) command.args.ptr[0], "fg") == 0)
+    {
+      builtin_fg(&command, lineno);
+    }
+    else if (command.filename != NULL)
     {
       const char *const *argv = (const char *const *) command.args.ptr;
 
-      int pid = syscall_spawn(command.filename, command.args.len, argv);
+      // A NULL envp passes our environment on.
+      int pid = syscall_spawn(command.filename, command.args.len, argv, NULL);
 
       if (pid <= 0)
       {
@@ -60,11 +117,9 @@ static void execute(char *line, uint64_t lineno)
         printf("\033[31m E: spawn('%s', %lu, argv) failed; => %d\033[0m\n",
             command.filename, command.args.len, pid);
       }
-      else if (command.foreground &&
-               syscall_wait_process(pid, &last_exit_code) < 0)
+      else if (command.foreground)
       {
-        last_exit_code = -99;
-        puts("\033[31m E: wait_process() failed\033[0m");
+        wait_foreground(pid, lineno);
       }
 
       if (!command.foreground)
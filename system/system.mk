@@ -14,7 +14,7 @@
 SYSTEM_CFLAGS=-O3 -g -std=c99 -pedantic -Wall -Wextra -Werror \
               -march=core2 -mtune=generic -mno-mmx -mno-sse3 -mno-ssse3 \
               -mno-3dnow -nostdlibinc -fno-builtin
-SYSTEM_LDFLAGS=-O1 -nostdlib
+SYSTEM_LDFLAGS=-O1 -nostdlib --hash-style=sysv --dynamic-linker lib/ld-kit.so
 SYSTEM_ASFLAGS=-march=generic64
 
 ifeq ($(CC),clang)
@@ -37,17 +37,24 @@ build/system/bin/.dir: build/system/.dir
 	mkdir -p build/system/bin
 	touch build/system/bin/.dir
 
+build/system/lib/.dir: build/system/.dir
+	mkdir -p build/system/lib
+	touch build/system/lib/.dir
+
 build/system/hello.txt: system/hello.txt build/system/.dir
 	cp $< $@
 
 SYSTEM_APPS=
 
 include system/libc/libc.mk
+include system/ld/ld.mk
 include system/util/util.mk
 include system/shell/shell.mk
 include system/kitforth/kitforth.mk
 
 build/system.kit: build/system/hello.txt \
+	                ${LD_SO} \
+	                ${LIBC} \
 	                ${SYSTEM_UTILS} \
 	                build/system/bin/shell \
 									build/system/bin/kitforth \
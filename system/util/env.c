@@ -0,0 +1,57 @@
+/*******************************************************************************
+ *
+ * kit/system/util/env.c
+ * - prints the environment, or with -s, the kernel's system information
+ *
+ * vim:ts=2:sw=2:et:tw=80:ft=c
+ *
+ * Copyright (C) 2015-2021, Devyn Cairns
+ * Redistribution of this file is permitted under the terms of the simplified
+ * BSD license. See LICENSE for more information.
+ *
+ ******************************************************************************/
+
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+#include <kit/auxv.h>
+
+static int print_sysinfo()
+{
+  const kit_sysinfo_t *info = kit_sysinfo();
+
+  if (info == NULL)
+  {
+    fputs("env: no system information\n", stderr);
+    return 1;
+  }
+
+  printf("kernel:     %s\n", info->kernel_name);
+  printf("pid:        %u (parent %u, pgid %u)\n",
+      info->pid, info->parent, info->pgid);
+  printf("cpus:       %u\n", info->cpu_count);
+  printf("page size:  %lu\n", info->page_size);
+  printf("image base: %#lx\n", info->image_base);
+  printf("heap base:  %#lx\n", info->heap_base);
+  printf("stack:      %#lx (%lu bytes)\n", info->stack_top, info->stack_size);
+  printf("archive:    %#lx (%lu bytes)\n", info->archive_addr,
+      info->archive_length);
+  printf("entry:      %#lx\n", getauxval(AT_ENTRY));
+
+  return 0;
+}
+
+int main(int argc, char **argv)
+{
+  if (argc > 1 && strcmp(argv[1], "-s") == 0)
+  {
+    return print_sysinfo();
+  }
+
+  for (char **entry = environ; entry != NULL && *entry != NULL; entry++)
+  {
+    puts(*entry);
+  }
+
+  return 0;
+}
@@ -26,6 +26,7 @@ build/system/util/%.o: system/util/%.c build/system/util/.dir
 	@${ECHO_CC} $@
 	@${CC} ${CFLAGS} ${SYSTEM_CFLAGS} -c $< -o $@
 
-build/system/bin/%: build/system/util/%.o ${LIBC} build/system/bin/.dir
+build/system/bin/%: build/system/util/%.o ${LIBC_CRT0} ${LIBC} \
+		build/system/bin/.dir
 	@${ECHO_LD} $@
-	@${LD} ${LDFLAGS} ${SYSTEM_LDFLAGS} $< ${LIBC} -o $@
+	@${LD} ${LDFLAGS} ${SYSTEM_LDFLAGS} ${LIBC_CRT0} $< ${LIBC} -o $@